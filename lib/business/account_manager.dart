@@ -225,20 +225,20 @@ class AccountManager extends ChangeNotifier {
     return account;
   }
 
-  _dropAccounts() {
-    for (Account account in accounts) {
-      account.wallet.drop();
-    }
-
+  Future<void> _dropAccounts() async {
+    final dropping = accounts.map((account) => account.wallet.drop()).toList();
     accounts.clear();
+
+    await Future.wait(dropping);
   }
 
-  _restoreAccounts() {
+  _restoreAccounts() async {
     if (_syncTimer != null) {
       _syncTimer!.cancel();
     }
 
-    _dropAccounts();
+    // The wallet databases have to be closed before they can be reopened
+    await _dropAccounts();
     if (_ls.prefs.containsKey(_ACCOUNTS_PREFS)) {
       var storedAccounts = jsonDecode(_ls.prefs.getString(_ACCOUNTS_PREFS)!);
       for (var account in storedAccounts) {
@@ -290,8 +290,8 @@ class AccountManager extends ChangeNotifier {
     notifyListeners();
   }
 
-  deleteAccount(Account account) {
-    account.wallet.drop();
+  deleteAccount(Account account) async {
+    await account.wallet.drop();
 
     // Delete the BDK DB so it doesn't get confused on re-pair
     final dir = Directory(LocalStorage().appDocumentsDir.path +
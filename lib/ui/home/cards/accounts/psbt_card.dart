@@ -73,12 +73,20 @@ class PsbtCard extends StatelessWidget with NavigationCard {
                     return ScannerPage.tx((psbt) {
                       print(psbt);
                       account.wallet.decodePsbt(psbt).then((decoded) {
+                        final rawTx = decoded.rawTx;
+                        if (rawTx == null) {
+                          ScaffoldMessenger.of(context).showSnackBar(SnackBar(
+                            content: Text(S().envoy_psbt_transaction_not_sent),
+                          ));
+                          return;
+                        }
+
                         account.wallet
                             .broadcastTx(
                                 Settings()
                                     .electrumAddress(account.wallet.network),
                                 Tor().port,
-                                decoded.rawTx)
+                                rawTx)
                             .then((_) {
                           navigator!.pop(depth: 3);
                           ScaffoldMessenger.of(context).showSnackBar(SnackBar(
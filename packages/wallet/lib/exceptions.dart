@@ -64,5 +64,5 @@ String _lastErrorMessage(DynamicLibrary lib) {
       'wallet_last_error_message');
   final dartFunction = rustFunction.asFunction<LastErrorMessageDart>();
 
-  return dartFunction().cast<Utf8>().toDartString();
+  return takeRustString(lib, dartFunction());
 }
@@ -2,6 +2,29 @@
 //
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+import 'dart:ffi';
+
+import 'package:ffi/ffi.dart';
+
+typedef WalletStringFreeRust = Void Function(Pointer<Utf8> string);
+typedef WalletStringFreeDart = void Function(Pointer<Utf8> string);
+
+// Copies a string returned by the library and hands it back to be freed
+String takeRustString(DynamicLibrary lib, Pointer<Utf8> string) {
+  if (string == nullptr) {
+    return "";
+  }
+
+  String copy = string.toDartString();
+
+  final rustFunction =
+      lib.lookup<NativeFunction<WalletStringFreeRust>>('wallet_string_free');
+  final dartFunction = rustFunction.asFunction<WalletStringFreeDart>();
+  dartFunction(string);
+
+  return copy;
+}
+
 String captureBetween(String string, String first, String second) {
   int firstIndex = string.indexOf(first) + first.length;
   int secondIndex = firstIndex + string.substring(firstIndex).indexOf(second);
@@ -9,12 +9,23 @@ import 'package:flutter/foundation.dart';
 import 'dart:io';
 import 'package:json_annotation/json_annotation.dart';
 import 'package:wallet/exceptions.dart';
+import 'package:wallet/utils.dart';
 
 // Generated
 part 'wallet.g.dart';
 
 enum Network { Mainnet, Testnet, Signet, Regtest }
 
+enum OutputOrdering { Bip69, Untouched, Shuffle }
+
+enum CoinSelectionStrategy {
+  BranchAndBound,
+  OldestFirst,
+  LargestFirst,
+  Privacy,
+  AddressClusters
+}
+
 @JsonSerializable()
 class Transaction {
   final String memo;
@@ -57,6 +68,8 @@ class NativeTransaction extends Struct {
   external int confirmationHeight;
   @Uint64()
   external int confirmationTime;
+  @Bool()
+  external bool feeKnown;
 }
 
 class NativeSeed extends Struct {
@@ -72,9 +85,74 @@ class NativePsbt extends Struct {
   external int received;
   @Uint64()
   external int fee;
+  @Bool()
+  external bool feeKnown;
   external Pointer<Uint8> base64;
   external Pointer<Uint8> txid;
   external Pointer<Uint8> rawtx;
+  @Uint64()
+  external int size;
+  @Double()
+  external double feeRate;
+  @Uint32()
+  external int inputsLen;
+  external Pointer<NativePsbtInput> inputs;
+  @Uint32()
+  external int outputsLen;
+  external Pointer<NativePsbtOutput> outputs;
+}
+
+class NativePsbtInput extends Struct {
+  external Pointer<Uint8> previousTxid;
+  @Uint32()
+  external int previousVout;
+  @Uint64()
+  external int value;
+  @Bool()
+  external bool valueKnown;
+  @Bool()
+  external bool isMine;
+}
+
+class NativePsbtOutput extends Struct {
+  external Pointer<Uint8> address;
+  @Uint64()
+  external int value;
+  @Bool()
+  external bool isMine;
+  @Bool()
+  external bool isChange;
+}
+
+class NativeCreatePsbtOptions extends Struct {
+  @Int32()
+  external int ordering;
+  @Bool()
+  external bool spendUnconfirmed;
+  @Uint64()
+  external int maxFee;
+  @Double()
+  external double maxFeeRate;
+  @Uint32()
+  external int excludedLen;
+  external Pointer<Pointer<Utf8>> excluded;
+  @Int32()
+  external int strategy;
+  external Pointer<Utf8> changeAddress;
+  @Uint32()
+  external int dataLen;
+  external Pointer<Uint8> data;
+  @Uint64()
+  external int feeAbsolute;
+  @Bool()
+  external bool changeless;
+  @Uint64()
+  external int maxExcess;
+  external Pointer<Utf8> group;
+  @Uint32()
+  external int lockTime;
+  @Uint64()
+  external int maxPsbtBytes;
 }
 
 class NativeServerFeatures extends Struct {
@@ -83,6 +161,8 @@ class NativeServerFeatures extends Struct {
   external Pointer<Uint8> protocolMax;
   @Int64()
   external int pruning;
+  @Uint32()
+  external int genesisHashLen;
   external Pointer<Uint8> genesisHash;
 }
 
@@ -130,9 +210,17 @@ typedef WalletGetTransactionsDart = NativeTransactionList Function(
     Pointer<Uint8> wallet);
 
 typedef WalletCreatePsbtRust = NativePsbt Function(
-    Pointer<Uint8> wallet, Pointer<Utf8> sendTo, Uint64 amount, Double feeRate);
+    Pointer<Uint8> wallet,
+    Pointer<Utf8> sendTo,
+    Uint64 amount,
+    Double feeRate,
+    Pointer<NativeCreatePsbtOptions> options);
 typedef WalletCreatePsbtDart = NativePsbt Function(
-    Pointer<Uint8> wallet, Pointer<Utf8> sendTo, int amount, double feeRate);
+    Pointer<Uint8> wallet,
+    Pointer<Utf8> sendTo,
+    int amount,
+    double feeRate,
+    Pointer<NativeCreatePsbtOptions> options);
 
 typedef WalletBroadcastTxRust = Pointer<Utf8> Function(
     Pointer<Utf8> electrumAddress, Int32 torPort, Pointer<Utf8> tx);
@@ -144,6 +232,19 @@ typedef WalletDecodePsbtRust = NativePsbt Function(
 typedef WalletDecodePsbtDart = NativePsbt Function(
     Pointer<Uint8> wallet, Pointer<Utf8> psbt);
 
+typedef WalletPsbtFreeRust = Void Function(NativePsbt psbt);
+typedef WalletPsbtFreeDart = void Function(NativePsbt psbt);
+
+typedef WalletTransactionListFreeRust = Void Function(
+    NativeTransactionList list);
+typedef WalletTransactionListFreeDart = void Function(
+    NativeTransactionList list);
+
+typedef WalletServerFeaturesFreeRust = Void Function(
+    NativeServerFeatures features);
+typedef WalletServerFeaturesFreeDart = void Function(
+    NativeServerFeatures features);
+
 typedef WalletValidateAddressRust = Uint8 Function(
     Pointer<Uint8> wallet, Pointer<Utf8> address);
 typedef WalletValidateAddressDart = int Function(
@@ -168,20 +269,31 @@ class Psbt {
   final int fee;
   final String base64;
   final String txid;
-  final String rawTx;
+  // Null until the PSBT is finalized and ready to broadcast
+  final String? rawTx;
 
   get amount => received - sent;
 
   Psbt(this.sent, this.received, this.fee, this.base64, this.txid, this.rawTx);
 
-  factory Psbt.fromNative(NativePsbt psbt) {
-    return Psbt(
+  // Takes ownership of the native PSBT, which is freed once copied
+  factory Psbt.fromNative(DynamicLibrary lib, NativePsbt psbt) {
+    Psbt copy = Psbt(
         psbt.sent,
         psbt.received,
         psbt.fee,
         psbt.base64.cast<Utf8>().toDartString(),
         psbt.txid.cast<Utf8>().toDartString(),
-        psbt.rawtx.cast<Utf8>().toDartString());
+        psbt.rawtx == nullptr
+            ? null
+            : psbt.rawtx.cast<Utf8>().toDartString());
+
+    final rustFunction =
+        lib.lookup<NativeFunction<WalletPsbtFreeRust>>('wallet_psbt_free');
+    final dartFunction = rustFunction.asFunction<WalletPsbtFreeDart>();
+    dartFunction(psbt);
+
+    return copy;
   }
 }
 
@@ -196,16 +308,27 @@ class ElectrumServerFeatures {
   ElectrumServerFeatures(this.serverVersion, this.protocolMin, this.protocolMax,
       this.pruning, this.genesisHash);
 
-  factory ElectrumServerFeatures.fromNative(NativeServerFeatures features) {
-    List<int> genesisHash = List.from(features.genesisHash.asTypedList(32));
-    //malS().free(features.genesisHash);
+  // Takes ownership of the native features, which are freed once copied
+  factory ElectrumServerFeatures.fromNative(
+      DynamicLibrary lib, NativeServerFeatures features) {
+    List<int> genesisHash =
+        List.from(features.genesisHash.asTypedList(features.genesisHashLen));
 
-    return ElectrumServerFeatures(
+    ElectrumServerFeatures copy = ElectrumServerFeatures(
         features.serverVersion.cast<Utf8>().toDartString(),
         features.protocolMin.cast<Utf8>().toDartString(),
         features.protocolMax.cast<Utf8>().toDartString(),
         features.pruning,
         genesisHash);
+
+    final rustFunction =
+        lib.lookup<NativeFunction<WalletServerFeaturesFreeRust>>(
+            'wallet_server_features_free');
+    final dartFunction =
+        rustFunction.asFunction<WalletServerFeaturesFreeDart>();
+    dartFunction(features);
+
+    return copy;
   }
 }
 
@@ -217,6 +340,9 @@ class Wallet {
   Pointer<Uint8> _self = nullptr;
   bool _currentlySyncing = false;
 
+  // Background work that still holds the wallet pointer
+  final Set<Future> _inFlight = {};
+
   final String name;
   final String externalDescriptor;
   final String internalDescriptor;
@@ -285,9 +411,8 @@ class Wallet {
         lib.lookup<NativeFunction<WalletGetAddressRust>>('wallet_get_address');
     final dartFunction = rustFunction.asFunction<WalletGetAddressDart>();
 
-    return dartFunction(Pointer.fromAddress(walletAddress))
-        .cast<Utf8>()
-        .toDartString();
+    return takeRustString(
+        lib, dartFunction(Pointer.fromAddress(walletAddress)));
   }
 
   Wallet(this.name, this.network, this.externalDescriptor,
@@ -312,21 +437,46 @@ class Wallet {
     }
   }
 
-  drop() {
+  // Frees the native wallet once no isolate is using it any more.
+  // Safe to call more than once.
+  Future<void> drop() async {
+    if (_self == nullptr) {
+      return;
+    }
+
+    final self = _self;
+    _self = nullptr;
+
+    // Isolates keep running past a Dart timeout, so wait for them
+    await Future.wait(
+        _inFlight.map((work) => work.catchError((_) => null)).toList());
+
     final rustFunction =
         _lib.lookup<NativeFunction<WalletDropRust>>('wallet_drop');
     final dartFunction = rustFunction.asFunction<WalletDropDart>();
 
-    dartFunction(_self);
+    dartFunction(self);
+  }
+
+  Future<T> _track<T>(Future<T> work) {
+    _inFlight.add(work);
+    return work.whenComplete(() => _inFlight.remove(work));
+  }
+
+  void _checkNotDropped() {
+    if (_self == nullptr) {
+      throw Exception("Wallet has been dropped");
+    }
   }
 
   Future<String> getAddress() async {
-    return compute(_getAddress, _self.address);
+    _checkNotDropped();
+    return _track(compute(_getAddress, _self.address));
   }
 
   // Returns true if there have been changes
   Future<bool?> sync(String electrumAddress, int torPort) async {
-    if (_currentlySyncing) {
+    if (_currentlySyncing || _self == nullptr) {
       return null;
     }
 
@@ -338,7 +488,7 @@ class Wallet {
     map['electrum_address'] = electrumAddress;
     map['tor_port'] = torPort;
 
-    return compute(_sync, map).then((var walletState) {
+    return _track(compute(_sync, map)).then((var walletState) {
       _currentlySyncing = false;
 
       if (walletState == null) {
@@ -393,19 +543,85 @@ class Wallet {
     });
   }
 
-  Future<Psbt> createPsbt(String sendTo, int amount, double feeRate) async {
+  // feeRate and maxFeeRate in BTC per kb, maxFee in sats, caps of 0 are off.
+  // excluded coins are "txid:vout" strings. changeAddress of null is the
+  // wallet's own next change address. data, up to 80 bytes, goes in an
+  // OP_RETURN output. feeAbsolute in sats, when not 0, replaces feeRate.
+  // changeless payments overpay the fee by at most maxExcess sats instead of
+  // having change. group limits spending to the coins in that coin group.
+  // lockTime, a block height or unix timestamp, schedules the payment.
+  // maxPsbtBytes caps the PSBT's size for QR signers, best with LargestFirst.
+  Future<Psbt> createPsbt(String sendTo, int amount, double feeRate,
+      {OutputOrdering ordering = OutputOrdering.Shuffle,
+      bool spendUnconfirmed = true,
+      int maxFee = 0,
+      double maxFeeRate = 0,
+      List<String> excluded = const [],
+      CoinSelectionStrategy strategy = CoinSelectionStrategy.BranchAndBound,
+      String? changeAddress,
+      List<int> data = const [],
+      int feeAbsolute = 0,
+      bool changeless = false,
+      int maxExcess = 0,
+      String? group,
+      int lockTime = 0,
+      int maxPsbtBytes = 0}) async {
     final rustFunction =
         _lib.lookup<NativeFunction<WalletCreatePsbtRust>>('wallet_create_psbt');
     final dartFunction = rustFunction.asFunction<WalletCreatePsbtDart>();
 
     return Future(() {
-      NativePsbt psbt =
-          dartFunction(_self, sendTo.toNativeUtf8(), amount, feeRate);
+      _checkNotDropped();
+
+      final options = calloc<NativeCreatePsbtOptions>();
+      final excludedList = calloc<Pointer<Utf8>>(excluded.length);
+      for (var i = 0; i < excluded.length; i++) {
+        excludedList[i] = excluded[i].toNativeUtf8();
+      }
+      options.ref
+        ..ordering = ordering.index
+        ..spendUnconfirmed = spendUnconfirmed
+        ..maxFee = maxFee
+        ..maxFeeRate = maxFeeRate
+        ..excludedLen = excluded.length
+        ..excluded = excludedList
+        ..strategy = strategy.index
+        ..changeAddress =
+            changeAddress == null ? nullptr : changeAddress.toNativeUtf8()
+        ..dataLen = data.length
+        ..data = calloc<Uint8>(data.length)
+        ..feeAbsolute = feeAbsolute
+        ..changeless = changeless
+        ..maxExcess = maxExcess
+        ..group = group == null ? nullptr : group.toNativeUtf8()
+        ..lockTime = lockTime
+        ..maxPsbtBytes = maxPsbtBytes;
+      options.ref.data.asTypedList(data.length).setAll(0, data);
+
+      NativePsbt psbt;
+      try {
+        psbt = dartFunction(
+            _self, sendTo.toNativeUtf8(), amount, feeRate, options);
+      } finally {
+        for (var i = 0; i < excluded.length; i++) {
+          malloc.free(excludedList[i]);
+        }
+        calloc.free(excludedList);
+        if (options.ref.changeAddress != nullptr) {
+          malloc.free(options.ref.changeAddress);
+        }
+        calloc.free(options.ref.data);
+        if (options.ref.group != nullptr) {
+          malloc.free(options.ref.group);
+        }
+        calloc.free(options);
+      }
+
       if (psbt.base64 == nullptr) {
         throwRustException(_lib);
       }
 
-      return Psbt.fromNative(psbt);
+      return Psbt.fromNative(_lib, psbt);
     });
   }
 
@@ -415,13 +631,14 @@ class Wallet {
     final dartFunction = rustFunction.asFunction<WalletDecodePsbtDart>();
 
     return Future(() {
+      _checkNotDropped();
       NativePsbt psbt = dartFunction(_self, base64Psbt.toNativeUtf8());
 
       if (psbt.base64 == nullptr) {
         throwRustException(_lib);
       }
 
-      return Psbt.fromNative(psbt);
+      return Psbt.fromNative(_lib, psbt);
     });
   }
 
@@ -475,7 +692,7 @@ class Wallet {
       throwRustException(lib);
     }
 
-    return ElectrumServerFeatures.fromNative(features);
+    return ElectrumServerFeatures.fromNative(lib, features);
   }
 
   static List<Transaction> _getTransactions(int walletAddress) {
@@ -501,6 +718,11 @@ class Wallet {
           tx.confirmationHeight));
     }
 
+    final freeFunction =
+        lib.lookup<NativeFunction<WalletTransactionListFreeRust>>(
+            'wallet_transaction_list_free');
+    freeFunction.asFunction<WalletTransactionListFreeDart>()(txList);
+
     return transactions;
   }
 
@@ -511,10 +733,10 @@ class Wallet {
     final dartFunction = rustFunction.asFunction<WalletBroadcastTxDart>();
 
     return Future(() {
-      var txid = dartFunction(
-              electrumAddress.toNativeUtf8(), torPort, tx.toNativeUtf8())
-          .cast<Utf8>()
-          .toDartString();
+      var txid = takeRustString(
+          _lib,
+          dartFunction(
+              electrumAddress.toNativeUtf8(), torPort, tx.toNativeUtf8()));
 
       if (txid.isEmpty) {
         throwRustException(_lib);
@@ -529,6 +751,7 @@ class Wallet {
         'wallet_validate_address');
     final dartFunction = rustFunction.asFunction<WalletValidateAddressDart>();
 
+    _checkNotDropped();
     return dartFunction(
                 Pointer.fromAddress(_self.address), address.toNativeUtf8()) ==
             1
@@ -6,36 +6,71 @@
 extern crate log;
 
 use std::ffi::{CStr, CString};
-use std::os::raw::c_char;
+use std::os::raw::{c_char, c_void};
 use std::ptr;
 
 extern crate rand;
 
+use rand::{Rng, RngCore};
+
 use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
 use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
 
-use bdk::bitcoin::{Address, Network};
-use bdk::blockchain::{ConfigurableBlockchain, ElectrumBlockchain, ElectrumBlockchainConfig};
-use bdk::database::ConfigurableDatabase;
+use bdk::bitcoin::{Address, BlockHash, BlockHeader, Network, OutPoint, Script, Txid};
+use bdk::blockchain::{
+    ConfigurableBlockchain, ElectrumBlockchain, ElectrumBlockchainConfig, Progress,
+};
+use bdk::database::{ConfigurableDatabase, Database, MemoryDatabase};
+use bdk::descriptor::policy::SatisfiableItem;
 use bdk::electrum_client::{ConfigBuilder, ElectrumApi, Socks5Config};
 use bdk::sled::Tree;
 use bdk::wallet::AddressIndex;
 use bdk::{electrum_client, SyncOptions};
-use bdk::{FeeRate, Wallet};
+use bdk::{
+    FeeRate, KeychainKind, LocalUtxo, SignOptions, TransactionDetails, Wallet, WeightedUtxo,
+};
 use std::str::FromStr;
 
+use bdk::bitcoin::blockdata::constants::genesis_block;
 use bdk::bitcoin::consensus::encode::deserialize;
+use bdk::bitcoin::consensus::encode::deserialize_partial;
 use bdk::bitcoin::consensus::encode::serialize;
+use bdk::bitcoin::consensus::encode::VarInt;
+use bdk::bitcoin::consensus::params::Params;
+use bdk::bitcoin::hashes::{sha256, Hash, HashEngine};
 
 use std::ptr::null_mut;
 
 use crate::electrum_client::Client;
+use bdk::bitcoin::blockdata::script::Builder as ScriptBuilder;
+use bdk::bitcoin::secp256k1::Message;
 use bdk::bitcoin::secp256k1::Secp256k1;
-use bdk::bitcoin::util::psbt::PartiallySignedTransaction;
+use bdk::bitcoin::util::bip32::{ChildNumber, DerivationPath, ExtendedPrivKey};
+use bdk::bitcoin::util::key::PrivateKey;
+use bdk::bitcoin::util::psbt::{PartiallySignedTransaction, PsbtSighashType};
+use bdk::bitcoin::util::sighash::SighashCache;
+use bdk::bitcoin::{EcdsaSig, EcdsaSighashType, Witness};
+use bdk::keys::bip39::{Language, Mnemonic};
+use bdk::miniscript::descriptor::{DescriptorPublicKey, DescriptorSecretKey, DescriptorType};
 use bdk::miniscript::psbt::PsbtExt;
-use bdk::wallet::tx_builder::TxOrdering;
+use bdk::miniscript::{Descriptor, ForEachKey};
+use bdk::wallet::coin_selection::{
+    BranchAndBoundCoinSelection, CoinSelectionAlgorithm, CoinSelectionResult,
+    DefaultCoinSelectionAlgorithm, Excess, LargestFirstCoinSelection, OldestFirstCoinSelection,
+};
+use bdk::wallet::tx_builder::{CreateTx, TxBuilder, TxOrdering};
 use bitcoin_hashes::hex::ToHex;
-use std::sync::Mutex;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
 #[repr(C)]
 pub enum NetworkType {
@@ -53,6 +88,46 @@ pub struct Transaction {
     fee: u64,
     confirmation_height: u32,
     confirmation_time: u64,
+    // BDK can't know the fee of incoming transactions spending coins that
+    // aren't ours, `fee` is 0 then
+    fee_known: bool,
+}
+
+#[repr(C)]
+pub struct TransactionInput {
+    previous_txid: *const c_char,
+    previous_vout: u32,
+    // Only known for outputs we have the transaction of
+    value: u64,
+    value_known: bool,
+}
+
+#[repr(C)]
+pub struct TransactionOutput {
+    // Null for scripts without an address form, e.g. OP_RETURN
+    address: *const c_char,
+    value: u64,
+    is_mine: bool,
+}
+
+/// Everything a transaction detail screen shows. `txid` is null if the
+/// lookup failed.
+#[repr(C)]
+pub struct TransactionInfo {
+    txid: *const c_char,
+    received: u64,
+    sent: u64,
+    fee: u64,
+    fee_known: bool,
+    vsize: u64,
+    // sat/vB, 0 if the fee isn't known
+    fee_rate: f64,
+    confirmation_height: u32,
+    confirmation_time: u64,
+    inputs_len: u32,
+    inputs: *const TransactionInput,
+    outputs_len: u32,
+    outputs: *const TransactionOutput,
 }
 
 #[repr(C)]
@@ -61,6 +136,280 @@ pub struct TransactionList {
     transactions: *const Transaction,
 }
 
+#[repr(C)]
+pub enum KeychainType {
+    External,
+    Internal,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputOrdering {
+    Bip69,
+    Untouched,
+    // Random, the default as it doesn't give away which output is change
+    Shuffle,
+}
+
+impl From<OutputOrdering> for TxOrdering {
+    fn from(ordering: OutputOrdering) -> Self {
+        match ordering {
+            OutputOrdering::Bip69 => TxOrdering::Bip69Lexicographic,
+            OutputOrdering::Untouched => TxOrdering::Untouched,
+            OutputOrdering::Shuffle => TxOrdering::Shuffle,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum SighashType {
+    // ALL for segwit v0 inputs, SIGHASH_DEFAULT for taproot ones
+    Default,
+    All,
+    None,
+    Single,
+    AllAnyoneCanPay,
+    NoneAnyoneCanPay,
+    SingleAnyoneCanPay,
+}
+
+impl SighashType {
+    // What goes in each input's PSBT_IN_SIGHASH_TYPE, None leaves it unset
+    fn psbt_sighash(self) -> Option<PsbtSighashType> {
+        let sighash = match self {
+            SighashType::Default => return None,
+            SighashType::All => EcdsaSighashType::All,
+            SighashType::None => EcdsaSighashType::None,
+            SighashType::Single => EcdsaSighashType::Single,
+            SighashType::AllAnyoneCanPay => EcdsaSighashType::AllPlusAnyoneCanPay,
+            SighashType::NoneAnyoneCanPay => EcdsaSighashType::NonePlusAnyoneCanPay,
+            SighashType::SingleAnyoneCanPay => EcdsaSighashType::SinglePlusAnyoneCanPay,
+        };
+        Some(sighash.into())
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub enum CoinSelectionStrategy {
+    // Looks for a changeless combination, BDK's default
+    BranchAndBound,
+    OldestFirst,
+    LargestFirst,
+    // Avoids linking coins together, see `PrivacyCoinSelection`
+    Privacy,
+    // Avoids linking addresses together, see `AddressClusterCoinSelection`
+    AddressClusters,
+}
+
+/// How `wallet_create_psbt` builds a payment, a null pointer meaning the
+/// defaults.
+#[repr(C)]
+pub struct CreatePsbtOptions {
+    ordering: OutputOrdering,
+    spend_unconfirmed: bool,
+    // Caps on the fee in sats and BTC/kvB like the fee rate, 0 for none
+    max_fee: u64,
+    max_fee_rate: f64,
+    // "<txid>:<vout>" of coins never to spend, on top of the ones no builder
+    // spends anyway
+    excluded_len: u32,
+    excluded: *const *const c_char,
+    strategy: CoinSelectionStrategy,
+    // Null for the wallet's next internal address. Another wallet's address
+    // counts towards `sent`.
+    change_address: *const c_char,
+    // Payload of an extra zero value OP_RETURN output, for proofs of payment
+    // or timestamps, none if empty
+    data_len: u32,
+    data: *const u8,
+    // Exact fee in sats, e.g. to match a fee already quoted to the user,
+    // overriding the fee rate. 0 to go by the fee rate.
+    fee_absolute: u64,
+    // Only pay without a change output, overpaying the fee by at most
+    // `max_excess` sats, whatever the strategy. Fails with
+    // `SelectionFailure::NoChangelessSolution` if the payment would only work
+    // with change.
+    changeless: bool,
+    max_excess: u64,
+    // Only spend coins in this do-not-combine group, as set with
+    // `wallet_set_coin_group`, e.g. to pay only with coins tagged "KYC-free".
+    // Fails with `SelectionFailure::InsufficientGroupFunds` if they can't
+    // cover amount and fee. Null to spend from anywhere.
+    group: *const c_char,
+    // Block height below 500000000, a unix timestamp otherwise, before which
+    // the transaction can't be mined. Signed ahead of time this makes a
+    // scheduled payment, e.g. for inheritance, though spending any of its
+    // coins in the meantime invalidates it. 0 for the usual anti fee sniping
+    // locktime.
+    lock_time: u32,
+    // Cap on the unsigned PSBT's size in bytes so it fits a QR signer, 0 for
+    // none. `CoinSelectionStrategy::LargestFirst` keeps the input count down;
+    // if the PSBT doesn't fit that way nothing will, and a consolidation is
+    // in order.
+    max_psbt_bytes: u64,
+}
+
+impl Default for CreatePsbtOptions {
+    fn default() -> Self {
+        CreatePsbtOptions {
+            ordering: OutputOrdering::Shuffle,
+            spend_unconfirmed: true,
+            max_fee: 0,
+            max_fee_rate: 0.0,
+            excluded_len: 0,
+            excluded: ptr::null(),
+            strategy: CoinSelectionStrategy::BranchAndBound,
+            change_address: ptr::null(),
+            data_len: 0,
+            data: ptr::null(),
+            fee_absolute: 0,
+            changeless: false,
+            max_excess: 0,
+            group: ptr::null(),
+            lock_time: 0,
+            max_psbt_bytes: 0,
+        }
+    }
+}
+
+#[repr(C)]
+pub struct Utxo {
+    txid: *const c_char,
+    vout: u32,
+    value: u64,
+    address: *const c_char,
+    keychain: KeychainType,
+    confirmation_height: u32,
+    age_blocks: u32,
+    address_reused: bool,
+    ancestor_input_count: u32,
+    privacy_score: u8,
+    is_coinbase: bool,
+    // First height the output can be mined in a spend, zero if not coinbase
+    maturity_height: u32,
+}
+
+#[repr(C)]
+pub struct UtxoList {
+    utxos_len: u32,
+    utxos: *const Utxo,
+}
+
+#[repr(C)]
+pub struct AddressList {
+    addresses_len: u32,
+    addresses: *const *const c_char,
+}
+
+// Frames of an animated QR code, UR or BBQr
+#[repr(C)]
+pub struct QrParts {
+    parts_len: u32,
+    parts: *const *const c_char,
+}
+
+/// What a BBQr carries, the file type letter in its header.
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq)]
+pub enum BbqrType {
+    Psbt,
+    Transaction,
+    Json,
+    // UTF-8 text, e.g. a descriptor
+    Text,
+}
+
+impl BbqrType {
+    fn code(self) -> char {
+        match self {
+            BbqrType::Psbt => 'P',
+            BbqrType::Transaction => 'T',
+            BbqrType::Json => 'J',
+            BbqrType::Text => 'U',
+        }
+    }
+
+    fn from_code(code: char) -> Option<Self> {
+        match code {
+            'P' => Some(BbqrType::Psbt),
+            'T' => Some(BbqrType::Transaction),
+            'J' => Some(BbqrType::Json),
+            'U' => Some(BbqrType::Text),
+            _ => None,
+        }
+    }
+}
+
+#[repr(C)]
+pub struct BbqrData {
+    file_type: BbqrType,
+    len: u32,
+    data: *const u8,
+}
+
+#[repr(C)]
+pub struct Balance {
+    confirmed: u64,
+    immature: u64,
+    trusted_pending: u64,
+    untrusted_pending: u64,
+    spendable: u64,
+}
+
+/// Wallet-wide tunables, persisted in the wallet's own sled tree.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct WalletDefaults {
+    privacy_weight_address_reuse: u32,
+    privacy_weight_consolidation: u32,
+    privacy_weight_coin_age: u32,
+    header_cache_size: u32,
+    sync_stop_gap: u32,
+    sync_stop_gap_tor: u32,
+    // sats, outputs below this are refused or warned about. 0 goes by what
+    // the network relays for each output's script, the floor anyway.
+    dust_limit: u32,
+}
+
+impl Default for WalletDefaults {
+    fn default() -> Self {
+        WalletDefaults {
+            privacy_weight_address_reuse: 40,
+            privacy_weight_consolidation: 30,
+            privacy_weight_coin_age: 30,
+            header_cache_size: 2016,
+            // Tor round trips take seconds, trade a slightly longer scan
+            // for fewer of them
+            sync_stop_gap: 50,
+            sync_stop_gap_tor: 100,
+            dust_limit: 0,
+        }
+    }
+}
+
+impl WalletDefaults {
+    // Every field along with the name it is persisted under
+    fn fields_mut(&mut self) -> Vec<(&'static str, &mut u32)> {
+        vec![
+            (
+                "privacy_weight_address_reuse",
+                &mut self.privacy_weight_address_reuse,
+            ),
+            (
+                "privacy_weight_consolidation",
+                &mut self.privacy_weight_consolidation,
+            ),
+            ("privacy_weight_coin_age", &mut self.privacy_weight_coin_age),
+            ("header_cache_size", &mut self.header_cache_size),
+            ("sync_stop_gap", &mut self.sync_stop_gap),
+            ("sync_stop_gap_tor", &mut self.sync_stop_gap_tor),
+            ("dust_limit", &mut self.dust_limit),
+        ]
+    }
+}
+
 #[repr(C)]
 pub struct Seed {
     mnemonic: *const c_char,
@@ -68,14 +417,184 @@ pub struct Seed {
     fingerprint: *const c_char,
 }
 
+#[repr(C)]
+pub struct Descriptors {
+    external: *const c_char,
+    internal: *const c_char,
+}
+
 #[repr(C)]
 pub struct Psbt {
     sent: u64,
     received: u64,
+    // Unknown if any spent output is neither in the PSBT nor in the wallet
     fee: u64,
+    fee_known: bool,
     base64: *const c_char,
     txid: *const c_char,
     raw_tx: *const c_char,
+    size: u64,
+    // sat/vB, estimated from the wallet's descriptor for inputs not signed yet
+    fee_rate: f64,
+    inputs_len: u32,
+    inputs: *const PsbtInput,
+    outputs_len: u32,
+    outputs: *const PsbtOutput,
+}
+
+#[repr(C)]
+pub struct PsbtInput {
+    previous_txid: *const c_char,
+    previous_vout: u32,
+    // Only known if the PSBT carries the spent output
+    value: u64,
+    value_known: bool,
+    is_mine: bool,
+}
+
+#[repr(C)]
+pub struct PsbtOutput {
+    // Null for scripts without an address form, e.g. OP_RETURN
+    address: *const c_char,
+    value: u64,
+    is_mine: bool,
+    // Paid to the wallet's internal keychain
+    is_change: bool,
+}
+
+#[repr(C)]
+pub struct Recipient {
+    address: *const c_char,
+    amount: u64,
+    label: *const c_char,
+}
+
+#[repr(C)]
+pub struct ScriptInfo {
+    found: bool,
+    address: *const c_char,
+    keychain: KeychainType,
+    index: u32,
+}
+
+#[repr(C)]
+pub struct OutputScripts {
+    // Hex, null when the output type doesn't have one
+    redeem_script: *const c_char,
+    witness_script: *const c_char,
+}
+
+#[repr(C)]
+pub struct SendPreview {
+    sent: u64,
+    fee: u64,
+    change_amount: u64,
+    // Null when the transaction has no change
+    change_address: *const c_char,
+}
+
+/// Outcome of coin selection for a payment, see `wallet_compose`.
+#[repr(C)]
+pub struct ComposeReport {
+    inputs_len: u32,
+    inputs: *const TransactionInput,
+    fee: u64,
+    // sat/vB, what the fee works out to once signed
+    fee_rate: f64,
+    change_amount: u64,
+    // sats, Bitcoin Core's waste metric: lower is a better selection
+    waste: i64,
+}
+
+#[repr(C)]
+pub struct TxEstimate {
+    vsize: u64,
+    fee: u64,
+    // Whether the wallet holds enough for the payment and its fee
+    sufficient: bool,
+}
+
+#[repr(C)]
+pub struct PrivatePsbt {
+    psbt: Psbt,
+    // Distinct addresses among the inputs, more than one means spending them
+    // together couldn't be avoided and links them on chain
+    input_addresses: u32,
+}
+
+/// A serialized PSBT, for passing PSBTs around without base64.
+#[repr(C)]
+pub struct PsbtBytes {
+    len: u32,
+    data: *const u8,
+}
+
+/// Whose signatures an input has, by master key fingerprint (hex) as listed in
+/// its derivation paths.
+#[repr(C)]
+pub struct InputSigningStatus {
+    signed_len: u32,
+    signed: *const *const c_char,
+    unsigned_len: u32,
+    unsigned: *const *const c_char,
+    // 0 if the script isn't a plain threshold, e.g. it has timelocks
+    required: u32,
+    // Signatures still needed, zero once the input can be finalized
+    missing: u32,
+    // Finalizing drops the signatures, the fingerprint lists are empty then
+    finalized: bool,
+}
+
+#[repr(C)]
+pub struct SigningStatus {
+    inputs_len: u32,
+    inputs: *const InputSigningStatus,
+    complete: bool,
+}
+
+#[repr(C)]
+pub struct CombinedPsbt {
+    psbt: Psbt,
+    // Every input has all the signatures it needs, `psbt` is finalized and
+    // its `raw_tx` ready to broadcast
+    complete: bool,
+}
+
+#[repr(C)]
+pub struct Migration {
+    psbt: Psbt,
+    destination: *const c_char,
+    labels_copied: u32,
+    notes_copied: u32,
+}
+
+#[repr(C)]
+pub struct RecoveryProbe {
+    highest_used_index: i64,
+    addresses_scanned: u32,
+    suggested_stop_gap: u32,
+    likely_complete: bool,
+    cancelled: bool,
+}
+
+#[repr(C)]
+pub struct NetworkConfirmation {
+    expected: *const c_char,
+    server_reported: *const c_char,
+    matches: bool,
+}
+
+pub const CAPABILITY_CAN_SIGN: u32 = 1 << 0;
+pub const CAPABILITY_CAN_FINALIZE_ALONE: u32 = 1 << 1;
+pub const CAPABILITY_HAS_INTERNAL_KEYCHAIN: u32 = 1 << 2;
+pub const CAPABILITY_SUPPORTS_TAPROOT: u32 = 1 << 3;
+pub const CAPABILITY_IS_MULTISIG: u32 = 1 << 4;
+
+#[repr(C)]
+pub struct Capabilities {
+    flags: u32,
+    required_signers: u32,
+    total_signers: u32,
 }
 
 #[repr(C)]
@@ -84,9 +603,20 @@ pub struct ServerFeatures {
     protocol_min: *const c_char,
     protocol_max: *const c_char,
     pruning: i64,
+    genesis_hash_len: u32,
     genesis_hash: *const u8,
 }
 
+#[repr(C)]
+pub struct PaymentUri {
+    address: *const c_char,
+    // sats, 0 if the URI doesn't ask for an amount
+    amount: u64,
+    label: *const c_char,
+    message: *const c_char,
+    payjoin_supported: bool,
+}
+
 thread_local! {
     static LAST_ERROR: RefCell<Option<Box<dyn Error>>> = RefCell::new(None);
 }
@@ -126,6 +656,73 @@ pub unsafe extern "C" fn wallet_last_error_message() -> *const c_char {
     CString::new(error_message).unwrap().into_raw()
 }
 
+#[repr(C)]
+pub enum ErrorCode {
+    None,
+    Generic,
+    NotSigner,
+    SelectionFailed,
+    NothingSigned,
+    Dust,
+    FeeAboveCap,
+}
+
+/// Why coin selection couldn't fund a transaction, so the UI can say what to
+/// do about it instead of showing a bare "insufficient funds".
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub enum SelectionFailure {
+    None,
+    // Nothing to spend at all
+    NoCoins,
+    // Enough once coinbase outputs mature
+    ImmatureCoins,
+    // Enough only by spending frozen coins
+    FrozenCoins,
+    // Enough only by combining coins from different do-not-combine groups
+    CoinGroupConflict,
+    // Every spendable coin costs more in fees than it's worth
+    DustCoinsOnly,
+    // Enough before fees, not after
+    InsufficientAfterFees,
+    InsufficientFunds,
+    // Enough funds, but no combination of coins avoids change
+    NoChangelessSolution,
+    // Enough only by spending coins that aren't confirmed yet
+    UnconfirmedCoins,
+    // Not enough in the coins of the group selection was restricted to
+    InsufficientGroupFunds,
+}
+
+/// Code of the most recent error without clearing it, so it can be checked
+/// before fetching the message with `wallet_last_error_message`.
+#[no_mangle]
+pub extern "C" fn wallet_last_error_code() -> ErrorCode {
+    LAST_ERROR.with(|prev| match prev.borrow().as_ref() {
+        None => ErrorCode::None,
+        Some(err) => match err.downcast_ref::<WalletError>() {
+            Some(wallet_error) => wallet_error.code(),
+            None => ErrorCode::Generic,
+        },
+    })
+}
+
+/// Reason of the most recent error if it was a coin selection failure, without
+/// clearing it.
+#[no_mangle]
+pub extern "C" fn wallet_last_selection_failure() -> SelectionFailure {
+    LAST_ERROR.with(|prev| {
+        match prev
+            .borrow()
+            .as_ref()
+            .and_then(|e| e.downcast_ref::<WalletError>())
+        {
+            Some(WalletError::SelectionFailed { failure, .. }) => *failure,
+            _ => SelectionFailure::None,
+        }
+    })
+}
+
 macro_rules! unwrap_or_return {
     ($a:expr,$b:expr) => {
         match $a {
@@ -138,416 +735,11207 @@ macro_rules! unwrap_or_return {
     };
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn wallet_init(
-    name: *const c_char,
-    external_descriptor: *const c_char,
-    internal_descriptor: *const c_char,
-    data_dir: *const c_char,
-    network: NetworkType,
-) -> *mut Mutex<Wallet<Tree>> {
-    let network = match network {
-        NetworkType::Mainnet => Network::Bitcoin,
-        NetworkType::Testnet => Network::Testnet,
-        NetworkType::Signet => Network::Signet,
-        NetworkType::Regtest => Network::Regtest,
-    };
-
-    let name = unwrap_or_return!(CStr::from_ptr(name).to_str(), null_mut());
-    let external_descriptor =
-        unwrap_or_return!(CStr::from_ptr(external_descriptor).to_str(), null_mut());
-    let internal_descriptor =
-        unwrap_or_return!(CStr::from_ptr(internal_descriptor).to_str(), null_mut());
-    let data_dir = unwrap_or_return!(CStr::from_ptr(data_dir).to_str(), null_mut());
-
-    let db_conf = bdk::database::any::SledDbConfiguration {
-        path: data_dir.to_string(),
-        tree_name: name.to_string(),
-    };
-
-    let db = unwrap_or_return!(sled::Tree::from_config(&db_conf), null_mut());
-
-    let wallet = Mutex::new(unwrap_or_return!(
-        Wallet::new(external_descriptor, Some(internal_descriptor), network, db),
-        null_mut()
-    ));
+#[derive(Debug)]
+pub enum WalletError {
+    InputIndexOutOfRange(u32),
+    InputNotOwned(u32),
+    NetworkMismatch(Network, Network),
+    TransactionNotFound(Txid),
+    PsbtTooLarge {
+        size: usize,
+        max_size: usize,
+    },
+    NoteDecryptionFailed(String),
+    InputTooLarge {
+        size: usize,
+        max_size: usize,
+    },
+    NotAPsbt,
+    MalformedPsbt(String),
+    PsbtTampered(String),
+    UnsupportedPsbtVersion(u32),
+    Base64(base64::DecodeError),
+    Hex(hex::FromHexError),
+    Encode(bdk::bitcoin::consensus::encode::Error),
+    MalformedNotesBackup,
+    MalformedWatchOnlyExport,
+    SameWallet,
+    NotSigner,
+    NothingSigned,
+    PsbtIncomplete,
+    InvalidWordCount(u32),
+    SyncCancelled,
+    SyncFailed(String),
+    TipUnknown,
+    HeaderNotConnected(u32),
+    HeaderInvalidPow(u32),
+    HeaderCacheEmpty,
+    TimestampOutOfRange(u64),
+    FeeReductionNotPossible(String),
+    TransactionConfirmed(Txid),
+    TransactionNotOurs(Txid),
+    NotReplaceable(Txid),
+    NoOutputToSpend(Txid),
+    FeeUnknown(Txid),
+    FeeRateNotHigher {
+        original: f64,
+        requested: f64,
+    },
+    InvalidFeeRate(f64),
+    ImmatureCoinSelected(OutPoint),
+    UnknownCoin(OutPoint),
+    InvalidOutpoint(String),
+    // Amounts in sats as BDK reported them, zero when selection didn't get that far
+    SelectionFailed {
+        failure: SelectionFailure,
+        needed: u64,
+        available: u64,
+    },
+    ZeroAmount(String),
+    AmountOverflow,
+    NothingToConsolidate,
+    NothingToSweep,
+    InvalidPrivateKey(String),
+    NondeterministicOrdering,
+    AmountBelowFee {
+        amount: u64,
+        fee: u64,
+    },
+    BelowDustLimit {
+        amount: u64,
+        limit: u64,
+    },
+    // Caps of zero aren't set
+    FeeAboveCap {
+        fee: u64,
+        fee_rate: f64,
+        max_fee: u64,
+        max_fee_rate: f64,
+    },
+    // A fee cap is set but an input's value isn't known to check it against
+    FeeUnknownForCap,
+    AddressNotOwned(String),
+    InvalidAddress(String),
+    MalformedUri(String),
+    InvalidUr(String),
+    InvalidBbqr(String),
+    NotScriptHash(String),
+    Descriptor(String),
+    Mnemonic(bdk::keys::bip39::Error),
+    Bip32(bdk::bitcoin::util::bip32::Error),
+    Bdk(bdk::Error),
+    Database(bdk::sled::Error),
+    Electrum(electrum_client::Error),
+    Psbt(bdk::bitcoin::util::psbt::Error),
+}
 
-    let wallet_box = Box::new(wallet);
-    Box::into_raw(wallet_box)
+impl From<bdk::Error> for WalletError {
+    fn from(e: bdk::Error) -> Self {
+        WalletError::Bdk(e)
+    }
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn wallet_drop(wallet: *mut Mutex<Wallet<Tree>>) {
-    drop(wallet);
+impl From<bdk::bitcoin::util::psbt::Error> for WalletError {
+    fn from(e: bdk::bitcoin::util::psbt::Error) -> Self {
+        WalletError::Psbt(e)
+    }
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn wallet_get_address(wallet: *mut Mutex<Wallet<Tree>>) -> *const c_char {
-    let wallet = get_wallet_mutex(wallet).lock().unwrap();
+impl From<bdk::keys::bip39::Error> for WalletError {
+    fn from(e: bdk::keys::bip39::Error) -> Self {
+        WalletError::Mnemonic(e)
+    }
+}
 
-    let address = wallet
-        .get_address(AddressIndex::New)
-        .unwrap()
-        .address
-        .to_string();
-    CString::new(address).unwrap().into_raw()
+impl From<bdk::bitcoin::util::bip32::Error> for WalletError {
+    fn from(e: bdk::bitcoin::util::bip32::Error) -> Self {
+        WalletError::Bip32(e)
+    }
 }
 
-#[no_mangle]
-pub unsafe extern "C" fn wallet_sync(
-    wallet: *mut Mutex<Wallet<Tree>>,
-    electrum_address: *const c_char,
-    tor_port: i32,
-) -> bool {
-    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), false);
+impl From<bdk::sled::Error> for WalletError {
+    fn from(e: bdk::sled::Error) -> Self {
+        WalletError::Database(e)
+    }
+}
 
-    let electrum_address = unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), false);
+impl From<electrum_client::Error> for WalletError {
+    fn from(e: electrum_client::Error) -> Self {
+        WalletError::Electrum(e)
+    }
+}
 
-    let blockchain = unwrap_or_return!(get_electrum_blockchain(tor_port, electrum_address), false);
-    unwrap_or_return!(
-        wallet.sync(&blockchain, SyncOptions { progress: None }),
-        false
-    );
+impl From<base64::DecodeError> for WalletError {
+    fn from(e: base64::DecodeError) -> Self {
+        WalletError::Base64(e)
+    }
+}
 
-    // Successful sync
-    true
+impl From<hex::FromHexError> for WalletError {
+    fn from(e: hex::FromHexError) -> Self {
+        WalletError::Hex(e)
+    }
 }
 
-unsafe fn get_wallet_mutex(wallet: *mut Mutex<Wallet<Tree>>) -> &'static mut Mutex<Wallet<Tree>> {
-    let wallet = {
-        assert!(!wallet.is_null());
-        &mut *wallet
-    };
-    wallet
+impl From<bdk::bitcoin::consensus::encode::Error> for WalletError {
+    fn from(e: bdk::bitcoin::consensus::encode::Error) -> Self {
+        WalletError::Encode(e)
+    }
 }
 
-fn get_electrum_blockchain_config(
-    tor_port: i32,
-    electrum_address: &str,
-) -> ElectrumBlockchainConfig {
-    if tor_port > 0 {
-        ElectrumBlockchainConfig {
-            url: electrum_address.parse().unwrap(),
-            socks5: Some("127.0.0.1:".to_owned() + &tor_port.to_string()),
-            retry: 0,
-            timeout: None,
-            stop_gap: 50,
-            validate_domain: false,
+impl WalletError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            WalletError::NotSigner => ErrorCode::NotSigner,
+            WalletError::SelectionFailed { .. } => ErrorCode::SelectionFailed,
+            WalletError::NothingSigned => ErrorCode::NothingSigned,
+            WalletError::BelowDustLimit { .. } => ErrorCode::Dust,
+            WalletError::FeeAboveCap { .. } => ErrorCode::FeeAboveCap,
+            WalletError::FeeUnknownForCap => ErrorCode::FeeAboveCap,
+            _ => ErrorCode::Generic,
         }
-    } else {
-        ElectrumBlockchainConfig {
-            url: electrum_address.parse().unwrap(),
-            socks5: None,
-            retry: 0,
-            timeout: Some(5),
-            stop_gap: 50,
-            validate_domain: false,
+    }
+}
+
+impl fmt::Display for WalletError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalletError::InputIndexOutOfRange(i) => {
+                write!(f, "PSBT has no input with index {}", i)
+            }
+            WalletError::InputNotOwned(i) => {
+                write!(f, "PSBT input {} does not belong to this wallet", i)
+            }
+            WalletError::NetworkMismatch(expected, actual) => {
+                write!(f, "Expected a {} wallet, got {}", expected, actual)
+            }
+            WalletError::TransactionNotFound(txid) => {
+                write!(f, "Transaction {} not found in wallet", txid)
+            }
+            WalletError::PsbtTooLarge { size, max_size } => write!(
+                f,
+                "PSBT would be {} bytes, over the {} byte limit. Consider consolidating coins first",
+                size, max_size
+            ),
+            WalletError::NoteDecryptionFailed(txid) => write!(
+                f,
+                "Couldn't decrypt note for {}, was it made with a different seed?",
+                txid
+            ),
+            WalletError::InputTooLarge { size, max_size } => write!(
+                f,
+                "Input is {} bytes, more than the {} bytes allowed",
+                size, max_size
+            ),
+            WalletError::NotAPsbt => write!(f, "Data is not a PSBT"),
+            WalletError::MalformedPsbt(reason) => write!(f, "Malformed PSBT: {}", reason),
+            WalletError::PsbtTampered(reason) => {
+                write!(f, "PSBT differs from what was intended: {}", reason)
+            }
+            WalletError::UnsupportedPsbtVersion(version) => {
+                write!(f, "PSBT version {} is not supported", version)
+            }
+            WalletError::PsbtIncomplete => {
+                write!(f, "PSBT is still missing signatures and can't be finalized")
+            }
+            WalletError::Base64(e) => write!(f, "{}", e),
+            WalletError::Hex(e) => write!(f, "{}", e),
+            WalletError::Encode(e) => write!(f, "{}", e),
+            WalletError::Psbt(e) => write!(f, "{}", e),
+            WalletError::MalformedNotesBackup => write!(f, "Notes backup is malformed"),
+            WalletError::MalformedWatchOnlyExport => {
+                write!(f, "Watch-only export is malformed or contains private keys")
+            }
+            WalletError::SameWallet => write!(f, "Source and destination wallet are the same"),
+            WalletError::NotSigner => write!(f, "This wallet holds no private keys"),
+            WalletError::SyncCancelled => write!(f, "Sync was cancelled"),
+            WalletError::SyncFailed(e) => write!(f, "Sync failed: {}", e),
+            WalletError::TipUnknown => {
+                write!(f, "Chain tip isn't known yet, the wallet has to sync first")
+            }
+            WalletError::InvalidWordCount(count) => {
+                write!(f, "Seeds have 12 or 24 words, not {}", count)
+            }
+            WalletError::NothingSigned => {
+                write!(f, "None of the PSBT inputs could be signed by this wallet")
+            }
+            WalletError::HeaderNotConnected(height) => write!(
+                f,
+                "Block header at height {} does not connect to the known chain",
+                height
+            ),
+            WalletError::HeaderInvalidPow(height) => write!(
+                f,
+                "Block header at height {} doesn't have valid proof of work",
+                height
+            ),
+            WalletError::HeaderCacheEmpty => write!(f, "Header cache is empty, sync first"),
+            WalletError::TimestampOutOfRange(timestamp) => write!(
+                f,
+                "Timestamp {} is too far in the future to estimate a height for",
+                timestamp
+            ),
+            WalletError::ImmatureCoinSelected(outpoint) => write!(
+                f,
+                "Coin {} was mined too recently to be spent, wait for more confirmations",
+                outpoint
+            ),
+            WalletError::InvalidOutpoint(outpoint) => {
+                write!(f, "{} is not a valid <txid>:<vout> outpoint", outpoint)
+            }
+            WalletError::UnknownCoin(outpoint) => {
+                write!(f, "Coin {} isn't an unspent output of this wallet", outpoint)
+            }
+            WalletError::InvalidFeeRate(rate) => write!(f, "{} is not a valid fee rate", rate),
+            WalletError::TransactionConfirmed(txid) => {
+                write!(f, "Transaction {} is already confirmed", txid)
+            }
+            WalletError::TransactionNotOurs(txid) => {
+                write!(f, "Transaction {} doesn't spend any of our coins", txid)
+            }
+            WalletError::NoOutputToSpend(txid) => {
+                write!(f, "Transaction {} has no unspent output of ours that isn't frozen", txid)
+            }
+            WalletError::FeeUnknown(txid) => {
+                write!(f, "Couldn't work out the fee of transaction {}", txid)
+            }
+            WalletError::NotReplaceable(txid) => {
+                write!(f, "Transaction {} doesn't signal replace-by-fee", txid)
+            }
+            WalletError::FeeRateNotHigher {
+                original,
+                requested,
+            } => write!(
+                f,
+                "New fee rate of {:.1} sat/vB must be higher than the current {:.1} sat/vB",
+                requested, original
+            ),
+            WalletError::FeeReductionNotPossible(reason) => {
+                write!(f, "Can't replace with a lower fee rate: {}", reason)
+            }
+            WalletError::SelectionFailed {
+                failure,
+                needed,
+                available,
+            } => {
+                let message = match failure {
+                    SelectionFailure::None => "Coin selection failed",
+                    SelectionFailure::NoCoins => "There are no coins to spend",
+                    SelectionFailure::ImmatureCoins => {
+                        "Not enough funds until mined coins mature, wait for more confirmations"
+                    }
+                    SelectionFailure::FrozenCoins => {
+                        "Not enough funds without spending frozen coins"
+                    }
+                    SelectionFailure::CoinGroupConflict => {
+                        "Not enough funds without combining coins from different groups"
+                    }
+                    SelectionFailure::DustCoinsOnly => {
+                        "Coins are worth less than the fee to spend them at this fee rate"
+                    }
+                    SelectionFailure::InsufficientAfterFees => {
+                        "Not enough funds to cover the fee, try a lower fee rate"
+                    }
+                    SelectionFailure::InsufficientFunds => "Not enough funds",
+                    SelectionFailure::InsufficientGroupFunds => {
+                        "Not enough funds in the chosen coin group"
+                    }
+                    SelectionFailure::UnconfirmedCoins => {
+                        "Not enough confirmed funds, wait for confirmations or allow spending unconfirmed coins"
+                    }
+                    SelectionFailure::NoChangelessSolution => {
+                        "No combination of coins pays this exactly, allow change or a larger overpayment"
+                    }
+                };
+                if *needed > 0 {
+                    write!(
+                        f,
+                        "{} ({} sat needed, {} sat available)",
+                        message, needed, available
+                    )
+                } else {
+                    write!(f, "{}", message)
+                }
+            }
+            WalletError::AmountBelowFee { amount, fee } => write!(
+                f,
+                "Sending {} sat leaves nothing for the recipient after a {} sat fee",
+                amount, fee
+            ),
+            WalletError::BelowDustLimit { amount, limit } => write!(
+                f,
+                "{} sat is below the dust limit of {} sat",
+                amount, limit
+            ),
+            WalletError::FeeAboveCap {
+                fee,
+                fee_rate,
+                max_fee,
+                max_fee_rate,
+            } => {
+                if *max_fee > 0 && fee > max_fee {
+                    write!(f, "Fee of {} sat is above the {} sat cap", fee, max_fee)
+                } else {
+                    write!(
+                        f,
+                        "Fee rate of {:.1} sat/vB is above the {:.1} sat/vB cap",
+                        fee_rate, max_fee_rate
+                    )
+                }
+            }
+            WalletError::FeeUnknownForCap => write!(
+                f,
+                "Can't check the fee against its cap, an input's value is unknown"
+            ),
+            WalletError::NondeterministicOrdering => {
+                write!(f, "Coordinated transactions can't shuffle their inputs and outputs")
+            }
+            WalletError::NothingToSweep => write!(f, "There are no funds on this key"),
+            WalletError::InvalidPrivateKey(reason) => {
+                write!(f, "Invalid private key: {}", reason)
+            }
+            WalletError::NothingToConsolidate => {
+                write!(f, "There are fewer than two coins worth consolidating")
+            }
+            WalletError::ZeroAmount(address) => write!(f, "Amount for {} is zero", address),
+            WalletError::AmountOverflow => write!(f, "Amounts add up to more than can exist"),
+            WalletError::AddressNotOwned(address) => {
+                write!(f, "Address {} does not belong to this wallet", address)
+            }
+            WalletError::InvalidUr(reason) => write!(f, "Invalid UR: {}", reason),
+            WalletError::InvalidBbqr(reason) => write!(f, "Invalid BBQr: {}", reason),
+            WalletError::InvalidAddress(address) => write!(
+                f,
+                "{} is not a valid address for this wallet's network",
+                address
+            ),
+            WalletError::MalformedUri(reason) => write!(f, "Not a valid bitcoin URI: {}", reason),
+            WalletError::NotScriptHash(address) => write!(
+                f,
+                "Address {} is not a script hash output, there is no script to show",
+                address
+            ),
+            WalletError::Descriptor(e) => write!(f, "{}", e),
+            WalletError::Mnemonic(e) => write!(f, "{}", e),
+            WalletError::Bip32(e) => write!(f, "{}", e),
+            WalletError::Bdk(e) => write!(f, "{}", e),
+            WalletError::Database(e) => write!(f, "{}", e),
+            WalletError::Electrum(e) => write!(f, "{}", e),
         }
     }
 }
 
-fn get_electrum_blockchain(
-    tor_port: i32,
-    electrum_address: &str,
-) -> Result<ElectrumBlockchain, bdk::Error> {
-    let config = get_electrum_blockchain_config(tor_port, electrum_address);
-    ElectrumBlockchain::from_config(&config)
+impl Error for WalletError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WalletError::Mnemonic(e) => Some(e),
+            WalletError::Bip32(e) => Some(e),
+            WalletError::Bdk(e) => Some(e),
+            WalletError::Database(e) => Some(e),
+            WalletError::Electrum(e) => Some(e),
+            WalletError::Base64(e) => Some(e),
+            WalletError::Hex(e) => Some(e),
+            WalletError::Encode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn get_network(network: NetworkType) -> Network {
+    match network {
+        NetworkType::Mainnet => Network::Bitcoin,
+        NetworkType::Testnet => Network::Testnet,
+        NetworkType::Signet => Network::Signet,
+        NetworkType::Regtest => Network::Regtest,
+    }
+}
+
+// 4 bytes of entropy for every 3 words
+fn seed_entropy_bytes(word_count: u32) -> Result<usize, WalletError> {
+    match word_count {
+        12 => Ok(16),
+        24 => Ok(32),
+        _ => Err(WalletError::InvalidWordCount(word_count)),
+    }
+}
+
+fn seed_error_return() -> Seed {
+    Seed {
+        mnemonic: ptr::null(),
+        xprv: ptr::null(),
+        fingerprint: ptr::null(),
+    }
+}
+
+fn seed_from_mnemonic(
+    mnemonic: &Mnemonic,
+    passphrase: &str,
+    network: Network,
+) -> Result<Seed, WalletError> {
+    let secp = Secp256k1::new();
+    let xprv = ExtendedPrivKey::new_master(network, &mnemonic.to_seed(passphrase))?;
+
+    Ok(Seed {
+        mnemonic: CString::new(mnemonic.to_string()).unwrap().into_raw(),
+        xprv: CString::new(xprv.to_string()).unwrap().into_raw(),
+        fingerprint: CString::new(xprv.fingerprint(&secp).to_string())
+            .unwrap()
+            .into_raw(),
+    })
+}
+
+/// Generate a fresh 12 or 24 word mnemonic along with its root xprv and master fingerprint.
+#[no_mangle]
+pub extern "C" fn wallet_generate_seed(network: NetworkType, word_count: u32) -> Seed {
+    let error_return = seed_error_return();
+
+    let entropy_bytes = unwrap_or_return!(seed_entropy_bytes(word_count), error_return);
+    let mut entropy = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut entropy[..entropy_bytes]);
+
+    let mnemonic = unwrap_or_return!(
+        Mnemonic::from_entropy(&entropy[..entropy_bytes]),
+        error_return
+    );
+    unwrap_or_return!(
+        seed_from_mnemonic(&mnemonic, "", get_network(network)),
+        error_return
+    )
+}
+
+/// Recover the root xprv and master fingerprint of an existing 12 or 24 word
+/// mnemonic. An invalid word, word count or checksum comes back as a null seed
+/// with the reason in LAST_ERROR.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_restore_seed(
+    mnemonic: *const c_char,
+    passphrase: *const c_char,
+    network: NetworkType,
+) -> Seed {
+    let error_return = seed_error_return();
+
+    let mnemonic = unwrap_or_return!(CStr::from_ptr(mnemonic).to_str(), error_return);
+    let passphrase = if passphrase.is_null() {
+        ""
+    } else {
+        unwrap_or_return!(CStr::from_ptr(passphrase).to_str(), error_return)
+    };
+
+    let mnemonic = unwrap_or_return!(
+        Mnemonic::parse_in(Language::English, mnemonic),
+        error_return
+    );
+    unwrap_or_return!(
+        seed_entropy_bytes(mnemonic.word_iter().count() as u32),
+        error_return
+    );
+    unwrap_or_return!(
+        seed_from_mnemonic(&mnemonic, passphrase, get_network(network)),
+        error_return
+    )
+}
+
+fn bip84_descriptors(
+    xprv: &ExtendedPrivKey,
+    network: Network,
+) -> Result<(String, String), WalletError> {
+    // xprv only tells mainnet from everything else
+    if (xprv.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+        return Err(WalletError::NetworkMismatch(network, xprv.network));
+    }
+
+    let secp = Secp256k1::new();
+    let coin_type = if network == Network::Bitcoin { 0 } else { 1 };
+    let path = DerivationPath::from_str(&format!("m/84'/{}'/0'", coin_type))?;
+    let account = xprv.derive_priv(&secp, &path)?;
+    let fingerprint = xprv.fingerprint(&secp);
+
+    let descriptor = |chain: u32| -> Result<String, WalletError> {
+        let descriptor = format!(
+            "wpkh([{}/84'/{}'/0']{}/{}/*)",
+            fingerprint, coin_type, account, chain
+        );
+        let (descriptor, key_map) =
+            Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, &descriptor)
+                .map_err(|e| WalletError::Descriptor(e.to_string()))?;
+        Ok(descriptor.to_string_with_secret(&key_map))
+    };
+
+    Ok((descriptor(0)?, descriptor(1)?))
+}
+
+/// BIP84 external and internal descriptors for `xprv`, with key origin and
+/// checksum, ready to be passed to `wallet_init`.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_derive_descriptors(
+    xprv: *const c_char,
+    network: NetworkType,
+) -> Descriptors {
+    let error_return = Descriptors {
+        external: ptr::null(),
+        internal: ptr::null(),
+    };
+
+    let xprv = unwrap_or_return!(CStr::from_ptr(xprv).to_str(), error_return);
+    let xprv = unwrap_or_return!(ExtendedPrivKey::from_str(xprv), error_return);
+
+    let (external, internal) =
+        unwrap_or_return!(bip84_descriptors(&xprv, get_network(network)), error_return);
+
+    Descriptors {
+        external: CString::new(external).unwrap().into_raw(),
+        internal: CString::new(internal).unwrap().into_raw(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_init(
+    name: *const c_char,
+    external_descriptor: *const c_char,
+    internal_descriptor: *const c_char,
+    data_dir: *const c_char,
+    network: NetworkType,
+) -> *mut Mutex<Wallet<Tree>> {
+    let network = get_network(network);
+
+    let name = unwrap_or_return!(CStr::from_ptr(name).to_str(), null_mut());
+    let external_descriptor =
+        unwrap_or_return!(CStr::from_ptr(external_descriptor).to_str(), null_mut());
+    let internal_descriptor =
+        unwrap_or_return!(CStr::from_ptr(internal_descriptor).to_str(), null_mut());
+    let data_dir = unwrap_or_return!(CStr::from_ptr(data_dir).to_str(), null_mut());
+
+    let wallet = unwrap_or_return!(
+        open_wallet(
+            name,
+            external_descriptor,
+            internal_descriptor,
+            data_dir,
+            network
+        ),
+        null_mut()
+    );
+
+    let wallet = Mutex::new(wallet);
+
+    let wallet_box = Box::new(wallet);
+    Box::into_raw(wallet_box)
+}
+
+fn open_wallet(
+    name: &str,
+    external_descriptor: &str,
+    internal_descriptor: &str,
+    data_dir: &str,
+    network: Network,
+) -> Result<Wallet<Tree>, bdk::Error> {
+    let db_conf = bdk::database::any::SledDbConfiguration {
+        path: data_dir.to_string(),
+        tree_name: name.to_string(),
+    };
+
+    let db = sled::Tree::from_config(&db_conf)?;
+    let wallet = Wallet::new(external_descriptor, Some(internal_descriptor), network, db)?;
+
+    // Backfills the index on the first open after an upgrade
+    if let Err(e) = update_script_index(&wallet) {
+        warn!("Couldn't update script index: {}", e);
+    }
+
+    Ok(wallet)
+}
+
+// Height the wallet's history starts at, as carried over by a watch-only import
+const BIRTHDAY_KEY: &str = "envoy/birthday";
+
+/// Everything a companion device needs to mirror this wallet without its
+/// keys, as "<field>:<value>" lines: network, the external and internal public
+/// descriptors (with key origins and checksums) and birthday, the height of
+/// the first confirmed transaction or the current tip for an unused wallet.
+/// Import it with `wallet_init_watch_only`.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_export_watch_only(
+    wallet: *mut Mutex<Wallet<Tree>>,
+) -> *const c_char {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), ptr::null());
+
+    let external = unwrap_or_return!(
+        wallet.public_descriptor(KeychainKind::External),
+        ptr::null()
+    );
+    let internal = unwrap_or_return!(
+        wallet.public_descriptor(KeychainKind::Internal),
+        ptr::null()
+    );
+    let (external, internal) = match (external, internal) {
+        (Some(external), Some(internal)) => (external, internal),
+        _ => {
+            update_last_error(WalletError::MalformedWatchOnlyExport);
+            return ptr::null();
+        }
+    };
+
+    let first_confirmed = unwrap_or_return!(wallet.list_transactions(false), ptr::null())
+        .iter()
+        .filter_map(|t| t.confirmation_time.as_ref().map(|c| c.height))
+        .min();
+    let birthday = match first_confirmed {
+        Some(height) => height,
+        None => unwrap_or_return!(get_tip_height(&wallet), ptr::null()),
+    };
+
+    let export = format!(
+        "network:{}\nexternal:{}\ninternal:{}\nbirthday:{}",
+        network_name(wallet.network()),
+        external,
+        internal,
+        birthday
+    );
+
+    unwrap_or_return!(CString::new(export), ptr::null()).into_raw()
+}
+
+// Unparseable descriptors count too, BDK would reject them anyway
+fn descriptor_has_secrets(descriptor: &str) -> bool {
+    let secp = Secp256k1::new();
+    match Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, descriptor) {
+        Ok((_, key_map)) => !key_map.is_empty(),
+        Err(_) => true,
+    }
+}
+
+/// Open a watch-only wallet from a `wallet_export_watch_only` string, keeping
+/// its birthday in the wallet's tree.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_init_watch_only(
+    name: *const c_char,
+    export: *const c_char,
+    data_dir: *const c_char,
+) -> *mut Mutex<Wallet<Tree>> {
+    let name = unwrap_or_return!(CStr::from_ptr(name).to_str(), null_mut());
+    let export = unwrap_or_return!(CStr::from_ptr(export).to_str(), null_mut());
+    let data_dir = unwrap_or_return!(CStr::from_ptr(data_dir).to_str(), null_mut());
+
+    let mut fields = HashMap::new();
+    for line in export.lines().filter(|l| !l.trim().is_empty()) {
+        let mut parts = line.trim().splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(field), Some(value)) => fields.insert(field, value),
+            _ => {
+                update_last_error(WalletError::MalformedWatchOnlyExport);
+                return null_mut();
+            }
+        };
+    }
+
+    let network = match fields.get("network") {
+        Some(&"mainnet") => Network::Bitcoin,
+        Some(&"testnet") => Network::Testnet,
+        Some(&"signet") => Network::Signet,
+        Some(&"regtest") => Network::Regtest,
+        _ => {
+            update_last_error(WalletError::MalformedWatchOnlyExport);
+            return null_mut();
+        }
+    };
+
+    let birthday = fields.get("birthday").and_then(|b| b.parse::<u32>().ok());
+    let (external, internal, birthday) =
+        match (fields.get("external"), fields.get("internal"), birthday) {
+            (Some(external), Some(internal), Some(birthday)) => (*external, *internal, birthday),
+            _ => {
+                update_last_error(WalletError::MalformedWatchOnlyExport);
+                return null_mut();
+            }
+        };
+
+    // Checked before anything is written to disk, an export carrying keys
+    // mustn't leave a database behind
+    if descriptor_has_secrets(external) || descriptor_has_secrets(internal) {
+        update_last_error(WalletError::MalformedWatchOnlyExport);
+        return null_mut();
+    }
+
+    let wallet = unwrap_or_return!(
+        open_wallet(name, external, internal, data_dir, network),
+        null_mut()
+    );
+
+    unwrap_or_return!(
+        metadata_set_u32(&wallet.database(), BIRTHDAY_KEY, birthday),
+        null_mut()
+    );
+
+    Box::into_raw(Box::new(Mutex::new(wallet)))
+}
+
+/// Free a wallet returned by `wallet_init` or `wallet_init_watch_only`. The
+/// handle must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_drop(wallet: *mut Mutex<Wallet<Tree>>) {
+    if wallet.is_null() {
+        return;
+    }
+
+    forget_script_cache(wallet as usize);
+    forget_listeners(wallet as usize);
+
+    let wallet = Box::from_raw(wallet);
+
+    // Sled only flushes on its own every so often, make sure nothing is lost
+    // if the app gets killed right after
+    match wallet.lock() {
+        Ok(wallet) => {
+            if let Err(e) = wallet.database().flush() {
+                warn!("Couldn't flush wallet database: {}", e);
+            }
+        }
+        Err(e) => warn!("Couldn't flush wallet database: {}", e),
+    }
+
+    drop(wallet);
+}
+
+// (required, total) signers of the external descriptor's spending policy
+fn get_signer_counts(wallet: &Wallet<Tree>) -> Result<(u32, u32), bdk::Error> {
+    let policy = match wallet.policies(KeychainKind::External)? {
+        None => return Ok((1, 1)),
+        Some(policy) => policy,
+    };
+
+    Ok(match policy.item {
+        SatisfiableItem::Multisig { keys, threshold } => (threshold as u32, keys.len() as u32),
+        SatisfiableItem::Thresh { items, threshold } => (threshold as u32, items.len() as u32),
+        _ => (1, 1),
+    })
+}
+
+fn wallet_can_sign(wallet: &Wallet<Tree>) -> bool {
+    !wallet.get_signers(KeychainKind::External).ids().is_empty()
+}
+
+fn get_capabilities(wallet: &Wallet<Tree>) -> Result<Capabilities, bdk::Error> {
+    let (required_signers, total_signers) = get_signer_counts(wallet)?;
+    let signers_held = wallet.get_signers(KeychainKind::External).ids().len() as u32;
+
+    let mut flags = 0;
+
+    if signers_held > 0 {
+        flags |= CAPABILITY_CAN_SIGN;
+    }
+
+    if signers_held >= required_signers {
+        flags |= CAPABILITY_CAN_FINALIZE_ALONE;
+    }
+
+    if wallet.public_descriptor(KeychainKind::Internal)?.is_some() {
+        flags |= CAPABILITY_HAS_INTERNAL_KEYCHAIN;
+    }
+
+    if let Descriptor::Tr(_) = wallet.get_descriptor_for_keychain(KeychainKind::External) {
+        flags |= CAPABILITY_SUPPORTS_TAPROOT;
+    }
+
+    if total_signers > 1 {
+        flags |= CAPABILITY_IS_MULTISIG;
+    }
+
+    Ok(Capabilities {
+        flags,
+        required_signers,
+        total_signers,
+    })
+}
+
+/// What this wallet can do given its descriptors, as CAPABILITY_* flags plus
+/// the signer counts of its spending policy. Functions that need keys fail
+/// with ErrorCode::NotSigner when CAPABILITY_CAN_SIGN is absent.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_capabilities(wallet: *mut Mutex<Wallet<Tree>>) -> Capabilities {
+    let error_return = Capabilities {
+        flags: 0,
+        required_signers: 0,
+        total_signers: 0,
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    unwrap_or_return!(get_capabilities(&wallet), error_return)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_address(wallet: *mut Mutex<Wallet<Tree>>) -> *const c_char {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), ptr::null());
+
+    let address = unwrap_or_return!(wallet.get_address(AddressIndex::New), ptr::null())
+        .address
+        .to_string();
+    CString::new(address).unwrap().into_raw()
+}
+
+/// `count` consecutive fresh receive addresses, e.g. to pre-generate invoices.
+/// Addresses that turn out to have received funds already (say, from a restore
+/// that synced ahead of the index) are skipped.
+///
+/// Like `wallet_get_address` this hands the addresses out: the external index
+/// is advanced past them and persisted, so they're never returned again.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_suggest_fresh_addresses(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    count: u32,
+) -> AddressList {
+    let error_return = AddressList {
+        addresses_len: 0,
+        addresses: ptr::null(),
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+
+    let mut addresses: Vec<*const c_char> = vec![];
+    while addresses.len() < count as usize {
+        let address =
+            unwrap_or_return!(wallet.get_address(AddressIndex::New), error_return).address;
+
+        let receiving_txs = unwrap_or_return!(
+            script_index_receiving_txids(&wallet.database(), &address.script_pubkey()),
+            error_return
+        );
+        if !receiving_txs.is_empty() {
+            continue;
+        }
+
+        addresses.push(CString::new(address.to_string()).unwrap().into_raw());
+    }
+
+    let addresses_len = addresses.len() as u32;
+    let addresses_box = addresses.into_boxed_slice();
+
+    AddressList {
+        addresses_len,
+        addresses: Box::into_raw(addresses_box) as _,
+    }
 }
 
-fn get_electrum_client(
-    tor_port: i32,
-    electrum_address: &str,
-) -> Result<Client, electrum_client::Error> {
-    let config: electrum_client::Config;
-    if tor_port > 0 {
-        let tor_config = Socks5Config {
-            addr: "127.0.0.1:".to_owned() + &tor_port.to_string(),
-            credentials: None,
+/// Every address of `keychain` handed out so far, by derivation index
+/// ascending, so position `i` is the address at index `i`.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_list_addresses(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    keychain: KeychainType,
+) -> AddressList {
+    let error_return = AddressList {
+        addresses_len: 0,
+        addresses: ptr::null(),
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let keychain = match keychain {
+        KeychainType::External => KeychainKind::External,
+        KeychainType::Internal => KeychainKind::Internal,
+    };
+
+    let last_index = unwrap_or_return!(wallet.database().get_last_index(keychain), error_return);
+
+    let mut addresses: Vec<*const c_char> = vec![];
+    for index in last_index.map_or(0..0, |last| 0..last + 1) {
+        let address = match keychain {
+            KeychainKind::External => wallet.get_address(AddressIndex::Peek(index)),
+            KeychainKind::Internal => wallet.get_internal_address(AddressIndex::Peek(index)),
+        };
+        let address = unwrap_or_return!(address, error_return).address;
+        addresses.push(CString::new(address.to_string()).unwrap().into_raw());
+    }
+
+    let addresses_len = addresses.len() as u32;
+    let addresses_box = addresses.into_boxed_slice();
+
+    AddressList {
+        addresses_len,
+        addresses: Box::into_raw(addresses_box) as _,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_sync(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    electrum_address: *const c_char,
+    tor_port: i32,
+) -> bool {
+    wallet_sync_with_stop_gap(wallet, electrum_address, tor_port, 0)
+}
+
+/// Sync with a gap limit of `stop_gap`: scanning a keychain stops after that
+/// many consecutive unused addresses. Zero picks `sync_stop_gap` or
+/// `sync_stop_gap_tor` from the wallet defaults.
+///
+/// BDK's Electrum backend also queries script histories `stop_gap` at a time,
+/// so a larger gap means fewer round trips, which matters most over Tor, at
+/// the cost of scanning further past the last used address. Servers cap how
+/// much they'll answer in one go so the value is clamped to
+/// [MIN_SYNC_STOP_GAP, MAX_SYNC_STOP_GAP].
+#[no_mangle]
+pub unsafe extern "C" fn wallet_sync_with_stop_gap(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    electrum_address: *const c_char,
+    tor_port: i32,
+    stop_gap: u32,
+) -> bool {
+    let electrum_address = unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), false);
+    sync_wallet(
+        wallet,
+        electrum_address,
+        tor_port,
+        stop_gap,
+        SyncProgress::new(None),
+    )
+}
+
+pub type SyncProgressCallback = extern "C" fn(f32, *const c_char);
+
+#[derive(Clone)]
+struct SyncProgress {
+    callback: Option<SyncProgressCallback>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl SyncProgress {
+    fn new(callback: Option<SyncProgressCallback>) -> Self {
+        SyncProgress {
+            callback,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // Doubles as a cancellation point between stages. A cancelled sync blocked
+    // on Electrum is cut off by its `SyncRelay` instead.
+    fn report(&self, progress: f32, message: Option<&str>) -> Result<(), WalletError> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return Err(WalletError::SyncCancelled);
+        }
+
+        if let Some(callback) = self.callback {
+            // Only valid for the duration of the call, the callback has to copy it
+            let message = message.and_then(|m| CString::new(m).ok());
+            callback(
+                progress,
+                message.as_ref().map_or(ptr::null(), |m| m.as_ptr()),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl Progress for SyncProgress {
+    fn update(&self, progress: f32, message: Option<String>) -> Result<(), bdk::Error> {
+        self.report(progress, message.as_deref())
+            .map_err(|e| bdk::Error::Generic(e.to_string()))
+    }
+}
+
+/// Sync like `wallet_sync`, calling `callback` with a percentage and an
+/// optional message at each stage of the sync. BDK's Electrum backend reports
+/// once, when it starts scanning, so there's nothing in between while it
+/// talks to the server. The callback runs on the syncing thread and never
+/// after this returns; a null callback is a plain `wallet_sync`.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_sync_with_progress(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    electrum_address: *const c_char,
+    tor_port: i32,
+    callback: Option<SyncProgressCallback>,
+) -> bool {
+    let electrum_address = unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), false);
+    sync_wallet(
+        wallet,
+        electrum_address,
+        tor_port,
+        0,
+        SyncProgress::new(callback),
+    )
+}
+
+/// A sync running on its own thread, see `wallet_sync_start`.
+pub struct SyncHandle {
+    cancelled: Arc<AtomicBool>,
+    relay: SyncRelay,
+    thread: std::thread::JoinHandle<Result<(), String>>,
+}
+
+const SYNC_RELAY_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A SOCKS5 proxy on localhost that a background sync connects through, so
+// cancelling can close the sockets and abort an Electrum round trip in
+// flight. BDK keeps its client to itself and only checks for cancellation
+// between stages. Over Tor the bytes go to Tor's own SOCKS5 port untouched;
+// otherwise the relay answers the handshake and connects to the server itself.
+struct SyncRelay {
+    port: u16,
+    streams: Arc<Mutex<Vec<TcpStream>>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl SyncRelay {
+    fn start(tor_port: i32) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let streams = Arc::new(Mutex::new(vec![]));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let accepted_streams = streams.clone();
+        let accept_closed = closed.clone();
+        std::thread::spawn(move || {
+            for client in listener.incoming() {
+                if accept_closed.load(Ordering::SeqCst) {
+                    break;
+                }
+                let client = match client {
+                    Ok(client) => client,
+                    Err(_) => continue,
+                };
+
+                let streams = accepted_streams.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = relay_connection(client, tor_port, &streams) {
+                        warn!("Sync relay connection failed: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(SyncRelay {
+            port,
+            streams,
+            closed,
+        })
+    }
+
+    // Closing the sockets fails whatever the sync is waiting on right away
+    fn close(&self) {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        if let Ok(streams) = self.streams.lock() {
+            for stream in streams.iter() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+
+        // Wake the accept loop so it sees `closed` and lets the listener go
+        let _ = TcpStream::connect(("127.0.0.1", self.port));
+    }
+}
+
+impl Drop for SyncRelay {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn relay_connection(
+    mut client: TcpStream,
+    tor_port: i32,
+    streams: &Mutex<Vec<TcpStream>>,
+) -> std::io::Result<()> {
+    let upstream = if tor_port > 0 {
+        TcpStream::connect(("127.0.0.1", tor_port as u16))?
+    } else {
+        socks5_accept(&mut client)?
+    };
+
+    {
+        let mut streams = streams
+            .lock()
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "relay poisoned"))?;
+        streams.push(client.try_clone()?);
+        streams.push(upstream.try_clone()?);
+    }
+
+    let mut client_reader = client.try_clone()?;
+    let mut upstream_writer = upstream.try_clone()?;
+    std::thread::spawn(move || {
+        let _ = std::io::copy(&mut client_reader, &mut upstream_writer);
+        let _ = upstream_writer.shutdown(Shutdown::Write);
+    });
+
+    let mut upstream_reader = upstream;
+    let _ = std::io::copy(&mut upstream_reader, &mut client);
+    let _ = client.shutdown(Shutdown::Write);
+
+    Ok(())
+}
+
+// Server side of a no-auth SOCKS5 CONNECT (RFC 1928), returning the
+// connection to the requested destination
+fn socks5_accept(client: &mut TcpStream) -> std::io::Result<TcpStream> {
+    let unsupported = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "unsupported SOCKS5 request",
+        )
+    };
+
+    let mut greeting = [0u8; 2];
+    client.read_exact(&mut greeting)?;
+    let mut methods = vec![0u8; greeting[1] as usize];
+    client.read_exact(&mut methods)?;
+    if greeting[0] != 5 || !methods.contains(&0) {
+        client.write_all(&[5, 0xff])?;
+        return Err(unsupported());
+    }
+    client.write_all(&[5, 0])?;
+
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request)?;
+    if request[0] != 5 || request[1] != 1 {
+        client.write_all(&[5, 7, 0, 1, 0, 0, 0, 0, 0, 0])?;
+        return Err(unsupported());
+    }
+
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            client.read_exact(&mut ip)?;
+            std::net::Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let mut len = [0u8; 1];
+            client.read_exact(&mut len)?;
+            let mut domain = vec![0u8; len[0] as usize];
+            client.read_exact(&mut domain)?;
+            String::from_utf8(domain).map_err(|_| unsupported())?
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            client.read_exact(&mut ip)?;
+            std::net::Ipv6Addr::from(ip).to_string()
+        }
+        _ => return Err(unsupported()),
+    };
+    let mut port = [0u8; 2];
+    client.read_exact(&mut port)?;
+    let port = u16::from_be_bytes(port);
+
+    let addresses: Vec<SocketAddr> = (host.as_str(), port).to_socket_addrs()?.collect();
+    let upstream = addresses
+        .iter()
+        .find_map(|address| TcpStream::connect_timeout(address, SYNC_RELAY_CONNECT_TIMEOUT).ok());
+
+    match upstream {
+        Some(upstream) => {
+            client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])?;
+            Ok(upstream)
+        }
+        None => {
+            client.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0])?;
+            Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!("couldn't connect to {}:{}", host, port),
+            ))
+        }
+    }
+}
+
+/// Start syncing on a background thread and return right away. `callback`
+/// (nullable) is called from that thread as the sync advances, up until
+/// `wallet_sync_finish` returns. The handle must always be passed to
+/// `wallet_sync_finish`, which waits for the sync and frees it.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_sync_start(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    electrum_address: *const c_char,
+    tor_port: i32,
+    callback: Option<SyncProgressCallback>,
+) -> *mut SyncHandle {
+    let electrum_address =
+        unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), null_mut()).to_owned();
+
+    let progress = SyncProgress::new(callback);
+    let cancelled = progress.cancelled.clone();
+    let relay = unwrap_or_return!(SyncRelay::start(tor_port), null_mut());
+    let relay_port = relay.port as i32;
+
+    // Raw pointers aren't Send, the wallet outlives the handle by contract
+    let wallet = wallet as usize;
+    let thread = std::thread::spawn(move || {
+        // The relay looks like a Tor port to the sync, keep the gap limit of
+        // the actual connection
+        let stop_gap = get_wallet_mutex(wallet as *mut Mutex<Wallet<Tree>>)
+            .lock()
+            .ok()
+            .and_then(|wallet| get_wallet_defaults(&wallet).ok())
+            .map_or(0, |defaults| sync_stop_gap(&defaults, tor_port, 0));
+
+        let synced = sync_wallet(
+            wallet as *mut Mutex<Wallet<Tree>>,
+            &electrum_address,
+            relay_port,
+            stop_gap,
+            progress,
+        );
+
+        // LAST_ERROR is thread local, carry it back over to the caller
+        if synced {
+            Ok(())
+        } else {
+            Err(take_last_error().map_or(String::new(), |e| e.to_string()))
+        }
+    });
+
+    Box::into_raw(Box::new(SyncHandle {
+        cancelled,
+        relay,
+        thread,
+    }))
+}
+
+/// Ask a sync started with `wallet_sync_start` to stop. Its Electrum
+/// connection is closed, so a round trip in flight fails right away instead
+/// of running to completion; the handle still has to be passed to
+/// `wallet_sync_finish`.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_sync_cancel(handle: *mut SyncHandle) {
+    if let Some(handle) = handle.as_ref() {
+        handle.cancelled.store(true, Ordering::SeqCst);
+        handle.relay.close();
+    }
+}
+
+/// Wait for a sync started with `wallet_sync_start` and free its handle.
+/// False if it failed or was cancelled; the wallet stays usable either way.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_sync_finish(handle: *mut SyncHandle) -> bool {
+    if handle.is_null() {
+        return false;
+    }
+
+    let handle = Box::from_raw(handle);
+    let cancelled = handle.cancelled.load(Ordering::SeqCst);
+    match handle.thread.join() {
+        Ok(Ok(())) => true,
+        Ok(Err(_)) if cancelled => {
+            update_last_error(WalletError::SyncCancelled);
+            false
+        }
+        Ok(Err(e)) => {
+            update_last_error(WalletError::SyncFailed(e));
+            false
+        }
+        Err(_) => {
+            update_last_error(WalletError::SyncFailed("sync thread panicked".to_owned()));
+            false
+        }
+    }
+}
+
+// The requested gap limit, or the default for the connection if that's 0
+fn sync_stop_gap(defaults: &WalletDefaults, tor_port: i32, requested: u32) -> u32 {
+    if requested > 0 {
+        requested
+    } else if tor_port > 0 {
+        defaults.sync_stop_gap_tor
+    } else {
+        defaults.sync_stop_gap
+    }
+}
+
+unsafe fn sync_wallet(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    electrum_address: &str,
+    tor_port: i32,
+    stop_gap: u32,
+    progress: SyncProgress,
+) -> bool {
+    let wallet_key = wallet as usize;
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), false);
+
+    unwrap_or_return!(progress.report(0.0, Some("Connecting")), false);
+
+    let defaults = unwrap_or_return!(get_wallet_defaults(&wallet), false);
+    let stop_gap = sync_stop_gap(&defaults, tor_port, stop_gap);
+
+    let blockchain = unwrap_or_return!(
+        get_electrum_blockchain(tor_port, electrum_address, stop_gap),
+        false
+    );
+
+    // BDK's Electrum backend only reports when it starts, so report our own
+    // stages around it too
+    unwrap_or_return!(progress.report(10.0, Some("Scanning")), false);
+
+    let balance_before = wallet.get_balance().ok();
+    unwrap_or_return!(
+        wallet.sync(
+            &blockchain,
+            SyncOptions {
+                progress: Some(Box::new(progress.clone())),
+            }
+        ),
+        false
+    );
+
+    unwrap_or_return!(progress.report(80.0, Some("Updating transactions")), false);
+
+    // The header cache is a convenience, don't fail the sync over it
+    match get_electrum_client(tor_port, electrum_address) {
+        Ok(client) => {
+            if let Err(e) = update_header_cache(&wallet, &client) {
+                warn!("Couldn't update header cache: {}", e);
+            }
+        }
+        Err(e) => warn!("Couldn't update header cache: {}", e),
+    }
+
+    if let Err(e) = update_script_index(&wallet) {
+        warn!("Couldn't update script index: {}", e);
+    }
+
+    let changes = match update_transaction_tracking(&wallet) {
+        Ok(changes) => changes,
+        Err(e) => {
+            warn!("Couldn't update transaction tracking: {}", e);
+            TrackingChanges::default()
+        }
+    };
+
+    if let Err(e) = rebuild_script_cache(wallet_key, &wallet) {
+        warn!("Couldn't rebuild script cache: {}", e);
+    }
+
+    let mut events = vec![];
+    match wallet.get_balance() {
+        Ok(balance) => {
+            if balance_before.as_ref() != Some(&balance) {
+                events.push((
+                    WALLET_EVENT_BALANCE_CHANGED,
+                    format!(
+                        "{{\"confirmed\":{},\"immature\":{},\"trusted_pending\":{},\"untrusted_pending\":{}}}",
+                        balance.confirmed,
+                        balance.immature,
+                        balance.trusted_pending,
+                        balance.untrusted_pending
+                    ),
+                ));
+            }
+        }
+        Err(e) => warn!("Couldn't check balance for listeners: {}", e),
+    }
+    for txid in changes.added {
+        events.push((
+            WALLET_EVENT_TRANSACTION_ADDED,
+            format!("{{\"txid\":\"{}\"}}", txid),
+        ));
+    }
+    for (txid, height) in changes.confirmed {
+        events.push((
+            WALLET_EVENT_TRANSACTION_CONFIRMED,
+            format!("{{\"txid\":\"{}\",\"height\":{}}}", txid, height),
+        ));
+    }
+    let tip_height = get_tip_height(&wallet).unwrap_or(0);
+    events.push((
+        WALLET_EVENT_SYNC_COMPLETED,
+        format!("{{\"height\":{}}}", tip_height),
+    ));
+
+    drop(wallet);
+    notify_listeners(wallet_key, events);
+
+    // Past the point of cancelling, don't let a late cancel turn this into a failure
+    if let Some(callback) = progress.callback {
+        callback(100.0, ptr::null());
+    }
+
+    // Successful sync
+    true
+}
+
+unsafe fn get_wallet_mutex(wallet: *mut Mutex<Wallet<Tree>>) -> &'static mut Mutex<Wallet<Tree>> {
+    let wallet = {
+        assert!(!wallet.is_null());
+        &mut *wallet
+    };
+    wallet
+}
+
+// The gap limit, which BDK's Electrum backend also uses as the number of
+// script histories per batch. It never goes below the gap we've always used
+// so a small value can't make the sync miss funds.
+const MIN_SYNC_STOP_GAP: u32 = 50;
+// Roughly what public ElectrumX/Fulcrum servers accept before they start
+// throttling or dropping a batch
+const MAX_SYNC_STOP_GAP: u32 = 500;
+
+fn get_electrum_blockchain_config(
+    tor_port: i32,
+    electrum_address: &str,
+    stop_gap: u32,
+) -> ElectrumBlockchainConfig {
+    let stop_gap = stop_gap.clamp(MIN_SYNC_STOP_GAP, MAX_SYNC_STOP_GAP) as usize;
+
+    if tor_port > 0 {
+        ElectrumBlockchainConfig {
+            url: electrum_address.parse().unwrap(),
+            socks5: Some("127.0.0.1:".to_owned() + &tor_port.to_string()),
+            retry: 0,
+            timeout: None,
+            stop_gap,
+            validate_domain: false,
+        }
+    } else {
+        ElectrumBlockchainConfig {
+            url: electrum_address.parse().unwrap(),
+            socks5: None,
+            retry: 0,
+            timeout: Some(5),
+            stop_gap,
+            validate_domain: false,
+        }
+    }
+}
+
+fn get_electrum_blockchain(
+    tor_port: i32,
+    electrum_address: &str,
+    stop_gap: u32,
+) -> Result<ElectrumBlockchain, bdk::Error> {
+    let config = get_electrum_blockchain_config(tor_port, electrum_address, stop_gap);
+    ElectrumBlockchain::from_config(&config)
+}
+
+fn get_electrum_client(
+    tor_port: i32,
+    electrum_address: &str,
+) -> Result<Client, electrum_client::Error> {
+    let config: electrum_client::Config;
+    if tor_port > 0 {
+        let tor_config = Socks5Config {
+            addr: "127.0.0.1:".to_owned() + &tor_port.to_string(),
+            credentials: None,
+        };
+        config = ConfigBuilder::new()
+            .validate_domain(false)
+            .socks5(Some(tor_config))
+            .unwrap()
+            .build();
+    } else {
+        config = ConfigBuilder::new()
+            .validate_domain(false)
+            .socks5(None)
+            .unwrap()
+            .timeout(Some(5))
+            .unwrap()
+            .build();
+    }
+
+    Client::from_config(electrum_address, config)
+}
+
+// Addresses queried per Electrum round trip while probing
+const RECOVERY_PROBE_BATCH: u32 = 20;
+
+// Never suggest a stop_gap below BDK's default
+const RECOVERY_PROBE_MIN_GAP: u32 = 20;
+
+fn recovery_probe_error_return() -> RecoveryProbe {
+    RecoveryProbe {
+        highest_used_index: -1,
+        addresses_scanned: 0,
+        suggested_stop_gap: 0,
+        likely_complete: false,
+        cancelled: false,
+    }
+}
+
+/// Scan the first `max_gap` addresses of `descriptor` without creating a wallet,
+/// reporting the highest used index and the smallest stop_gap that would have
+/// found every used address seen.
+///
+/// The scan stops early once the run of unused addresses after the last used one
+/// is at least as long as the suggested stop_gap, in which case the recovery is
+/// considered likely complete. Hitting `max_gap` first means it is not. Use
+/// `wallet_recovery_probe_start` for a probe that can be cancelled.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_recovery_probe(
+    electrum_address: *const c_char,
+    tor_port: i32,
+    descriptor: *const c_char,
+    network: NetworkType,
+    max_gap: u32,
+) -> RecoveryProbe {
+    let error_return = recovery_probe_error_return();
+    let electrum_address =
+        unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), error_return);
+    let descriptor = unwrap_or_return!(CStr::from_ptr(descriptor).to_str(), error_return);
+
+    probe_recovery(
+        electrum_address,
+        tor_port,
+        descriptor,
+        get_network(network),
+        max_gap,
+        &AtomicBool::new(false),
+    )
+}
+
+fn probe_recovery(
+    electrum_address: &str,
+    tor_port: i32,
+    descriptor: &str,
+    network: Network,
+    max_gap: u32,
+    cancelled_flag: &AtomicBool,
+) -> RecoveryProbe {
+    let error_return = recovery_probe_error_return();
+
+    let client = unwrap_or_return!(
+        get_electrum_client(tor_port, electrum_address),
+        error_return
+    );
+
+    // Throwaway in-memory wallet, only used to derive scripts
+    let wallet = unwrap_or_return!(
+        Wallet::new(descriptor, None, network, MemoryDatabase::default()),
+        error_return
+    );
+
+    let mut highest_used: Option<u32> = None;
+    let mut largest_gap = 0;
+    let mut scanned = 0;
+    let mut cancelled = false;
+
+    while scanned < max_gap {
+        if cancelled_flag.load(Ordering::SeqCst) {
+            cancelled = true;
+            break;
+        }
+
+        let batch_end = (scanned + RECOVERY_PROBE_BATCH).min(max_gap);
+        let mut scripts = vec![];
+        for index in scanned..batch_end {
+            let info =
+                unwrap_or_return!(wallet.get_address(AddressIndex::Peek(index)), error_return);
+            scripts.push(info.address.script_pubkey());
+        }
+
+        let histories = unwrap_or_return!(
+            client.batch_script_get_history(scripts.iter()),
+            error_return
+        );
+
+        for (offset, history) in histories.iter().enumerate() {
+            if history.is_empty() {
+                continue;
+            }
+
+            let index = scanned + offset as u32;
+            let gap = match highest_used {
+                None => index + 1,
+                Some(previous) => index - previous,
+            };
+            largest_gap = largest_gap.max(gap);
+            highest_used = Some(index);
+        }
+
+        scanned = batch_end;
+
+        let suggested = largest_gap.max(RECOVERY_PROBE_MIN_GAP);
+        let trailing_unused = match highest_used {
+            None => scanned,
+            Some(index) => scanned - index - 1,
+        };
+
+        if trailing_unused >= suggested {
+            break;
+        }
+    }
+
+    let suggested_stop_gap = largest_gap.max(RECOVERY_PROBE_MIN_GAP);
+    let trailing_unused = match highest_used {
+        None => scanned,
+        Some(index) => scanned - index - 1,
+    };
+
+    RecoveryProbe {
+        highest_used_index: highest_used.map(|i| i as i64).unwrap_or(-1),
+        addresses_scanned: scanned,
+        suggested_stop_gap,
+        likely_complete: !cancelled && trailing_unused >= suggested_stop_gap,
+        cancelled,
+    }
+}
+
+/// A recovery probe running on its own thread, see `wallet_recovery_probe_start`.
+pub struct RecoveryProbeHandle {
+    cancelled: Arc<AtomicBool>,
+    thread: std::thread::JoinHandle<Result<RecoveryProbe, String>>,
+}
+
+/// Start `wallet_recovery_probe` on a background thread and return right away.
+/// The handle must always be passed to `wallet_recovery_probe_finish`, which
+/// waits for the probe and frees it. Each probe is cancelled on its own.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_recovery_probe_start(
+    electrum_address: *const c_char,
+    tor_port: i32,
+    descriptor: *const c_char,
+    network: NetworkType,
+    max_gap: u32,
+) -> *mut RecoveryProbeHandle {
+    let electrum_address =
+        unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), null_mut()).to_owned();
+    let descriptor = unwrap_or_return!(CStr::from_ptr(descriptor).to_str(), null_mut()).to_owned();
+    let network = get_network(network);
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let cancelled_flag = cancelled.clone();
+    let thread = std::thread::spawn(move || {
+        let probe = probe_recovery(
+            &electrum_address,
+            tor_port,
+            &descriptor,
+            network,
+            max_gap,
+            &cancelled_flag,
+        );
+
+        // LAST_ERROR is thread local, carry it back over to the caller
+        match take_last_error() {
+            None => Ok(probe),
+            Some(e) => Err(e.to_string()),
+        }
+    });
+
+    Box::into_raw(Box::new(RecoveryProbeHandle { cancelled, thread }))
+}
+
+/// Ask a probe started with `wallet_recovery_probe_start` to stop after its
+/// current batch. The handle still has to be passed to
+/// `wallet_recovery_probe_finish`.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_recovery_probe_cancel(handle: *mut RecoveryProbeHandle) {
+    if let Some(handle) = handle.as_ref() {
+        handle.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Wait for a probe started with `wallet_recovery_probe_start` and free its
+/// handle. A cancelled probe reports what it scanned so far.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_recovery_probe_finish(
+    handle: *mut RecoveryProbeHandle,
+) -> RecoveryProbe {
+    if handle.is_null() {
+        return recovery_probe_error_return();
+    }
+
+    let handle = Box::from_raw(handle);
+    match handle.thread.join() {
+        Ok(Ok(probe)) => probe,
+        Ok(Err(e)) => {
+            update_last_error(WalletError::SyncFailed(e));
+            recovery_probe_error_return()
+        }
+        Err(_) => {
+            update_last_error(WalletError::SyncFailed(
+                "recovery probe thread panicked".to_owned(),
+            ));
+            recovery_probe_error_return()
+        }
+    }
+}
+
+/// Check whether `address` is one of the first `max_index` addresses of
+/// `descriptor`, e.g. to verify an address against a vendor's published xpub.
+/// The descriptor is parsed on its own, no loaded wallet is involved. Only
+/// indexes 0 to `max_index - 1` are searched, so an address further out is
+/// reported as not found. Returns the derivation index, -1 if not found or -2
+/// on error.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_address_in_descriptor(
+    descriptor: *const c_char,
+    address: *const c_char,
+    max_index: u32,
+) -> i32 {
+    let descriptor = unwrap_or_return!(CStr::from_ptr(descriptor).to_str(), -2);
+    let address = unwrap_or_return!(CStr::from_ptr(address).to_str(), -2);
+    let address = unwrap_or_return!(Address::from_str(address), -2);
+
+    // Throwaway in-memory wallet, only used to derive scripts
+    let wallet = unwrap_or_return!(
+        Wallet::new(descriptor, None, address.network, MemoryDatabase::default()),
+        -2
+    );
+
+    let script = address.script_pubkey();
+    for index in 0..max_index.min(i32::MAX as u32) {
+        let info = unwrap_or_return!(wallet.get_address(AddressIndex::Peek(index)), -2);
+        if info.address.script_pubkey() == script {
+            return index as i32;
+        }
+    }
+
+    -1
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_balance(wallet: *mut Mutex<Wallet<Tree>>) -> u64 {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), 0);
+    let balance = unwrap_or_return!(wallet.get_balance(), 0);
+    balance.confirmed + balance.immature + balance.trusted_pending + balance.untrusted_pending
+}
+
+/// Balance split the way BDK reports it. `spendable` is what coin selection
+/// can actually use: confirmed plus our own unconfirmed change, never
+/// immature coinbase. All zero on error, with the reason in LAST_ERROR.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_balance_detailed(wallet: *mut Mutex<Wallet<Tree>>) -> Balance {
+    let error_return = Balance {
+        confirmed: 0,
+        immature: 0,
+        trusted_pending: 0,
+        untrusted_pending: 0,
+        spendable: 0,
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let balance = unwrap_or_return!(wallet.get_balance(), error_return);
+
+    Balance {
+        confirmed: balance.confirmed,
+        immature: balance.immature,
+        trusted_pending: balance.trusted_pending,
+        untrusted_pending: balance.untrusted_pending,
+        spendable: balance.get_spendable(),
+    }
+}
+
+/// Total of the coins with at least `min_confirmations` confirmations as of
+/// the last sync. Zero counts unconfirmed coins too.
+///
+/// Confirmations can't be counted before the wallet has synced once, so a
+/// non-zero `min_confirmations` then sets LAST_ERROR and returns 0 rather
+/// than a balance that only looks empty.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_balance_at(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    min_confirmations: u32,
+) -> u64 {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), 0);
+    let synced = unwrap_or_return!(wallet.database().get_sync_time(), 0).is_some();
+    if min_confirmations > 0 && !synced {
+        update_last_error(WalletError::TipUnknown);
+        return 0;
+    }
+    let tip_height = unwrap_or_return!(get_tip_height(&wallet), 0);
+
+    let transactions = unwrap_or_return!(wallet.list_transactions(false), 0);
+    let confirmation_heights: HashMap<Txid, u32> = transactions
+        .iter()
+        .filter_map(|t| t.confirmation_time.as_ref().map(|c| (t.txid, c.height)))
+        .collect();
+
+    let utxos = unwrap_or_return!(wallet.list_unspent(), 0);
+    utxos
+        .iter()
+        .filter(|utxo| {
+            let confirmations = match confirmation_heights.get(&utxo.outpoint.txid) {
+                Some(height) if tip_height >= *height => tip_height - height + 1,
+                _ => 0,
+            };
+            confirmations >= min_confirmations
+        })
+        .map(|utxo| utxo.txout.value)
+        .sum()
+}
+
+#[repr(C)]
+pub enum FeeUnit {
+    SatPerVb,
+    SatPerKvb,
+    BtcPerKvb,
+}
+
+// 1 BTC/kvB = 100,000,000 sat / 1000 vB
+const SAT_PER_VB_IN_BTC_PER_KVB: f64 = 100_000.0;
+
+// Anything above this is a slip of the finger, not a fee rate
+const MAX_FEE_RATE_SAT_PER_VB: f64 = 10_000.0;
+
+/// Convert a fee rate in `unit` to sat/vB, rejecting NaN, infinite, zero or
+/// negative rates and ones above MAX_FEE_RATE_SAT_PER_VB.
+fn normalize_fee_rate(input: f64, unit: FeeUnit) -> Result<f64, WalletError> {
+    let sat_per_vb = match unit {
+        FeeUnit::SatPerVb => input,
+        FeeUnit::SatPerKvb => input / 1000.0,
+        FeeUnit::BtcPerKvb => input * SAT_PER_VB_IN_BTC_PER_KVB,
+    };
+
+    if !sat_per_vb.is_finite() || sat_per_vb <= 0.0 || sat_per_vb > MAX_FEE_RATE_SAT_PER_VB {
+        return Err(WalletError::InvalidFeeRate(input));
+    }
+
+    Ok(sat_per_vb)
+}
+
+// The builders take fee rates in BTC/kvB, the unit Electrum estimates come in
+fn fee_rate_from_btc_per_kvb(fee_rate: f64) -> Result<FeeRate, WalletError> {
+    Ok(FeeRate::from_sat_per_vb(
+        normalize_fee_rate(fee_rate, FeeUnit::BtcPerKvb)? as f32,
+    ))
+}
+
+/// Fee rate from the UI in sat/vB, or -1 if it's not a sensible fee rate.
+#[no_mangle]
+pub extern "C" fn wallet_normalize_fee_rate(input: f64, unit: FeeUnit) -> f64 {
+    unwrap_or_return!(normalize_fee_rate(input, unit), -1.0)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_fee_rate(
+    electrum_address: *const c_char,
+    tor_port: i32,
+    target: u16,
+) -> f64 {
+    let electrum_address = unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), -1.0);
+    let client = match get_electrum_client(tor_port, electrum_address) {
+        Ok(c) => c,
+        Err(e) => {
+            update_last_error(e);
+            return -1.0;
+        }
+    };
+
+    // BTC per kb
+    client.estimate_fee(target as usize).unwrap_or(-1.0)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_server_features(
+    electrum_address: *const c_char,
+    tor_port: i32,
+) -> ServerFeatures {
+    let error_return = ServerFeatures {
+        server_version: ptr::null(),
+        protocol_min: ptr::null(),
+        protocol_max: ptr::null(),
+        pruning: 0,
+        genesis_hash_len: 0,
+        genesis_hash: ptr::null(),
+    };
+
+    let electrum_address =
+        unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), error_return);
+    let client = unwrap_or_return!(
+        get_electrum_client(tor_port, electrum_address),
+        error_return
+    );
+
+    match client.server_features() {
+        Ok(f) => {
+            // Freed with wallet_server_features_free
+            let genesis_hash = f.genesis_hash.to_vec().into_boxed_slice();
+            let genesis_hash_len = genesis_hash.len() as u32;
+
+            ServerFeatures {
+                server_version: CString::new(f.server_version).unwrap().into_raw(),
+                protocol_min: CString::new(f.protocol_min).unwrap().into_raw(),
+                protocol_max: CString::new(f.protocol_max).unwrap().into_raw(),
+                pruning: f.pruning.unwrap_or(-1),
+                genesis_hash_len,
+                genesis_hash: Box::into_raw(genesis_hash) as *const u8,
+            }
+        }
+        Err(e) => {
+            update_last_error(e);
+            error_return
+        }
+    }
+}
+
+// Canonical list orderings, part of the FFI contract so the UI doesn't have to
+// re-sort and rows don't jump around between syncs.
+
+/// Unconfirmed first, then by confirmation time descending, ties broken by txid.
+fn sort_transactions(transactions: &mut [TransactionDetails]) {
+    transactions.sort_by_cached_key(|t| {
+        (
+            t.confirmation_time.is_some(),
+            Reverse(t.confirmation_time.as_ref().map(|c| c.timestamp)),
+            t.txid.to_hex(),
+        )
+    });
+}
+
+/// By value descending, ties broken by outpoint.
+fn sort_utxos(utxos: &mut [LocalUtxo]) {
+    utxos.sort_by_cached_key(|u| {
+        (
+            Reverse(u.txout.value),
+            u.outpoint.txid.to_hex(),
+            u.outpoint.vout,
+        )
+    });
+}
+
+fn network_name(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "mainnet",
+        Network::Testnet => "testnet",
+        Network::Signet => "signet",
+        Network::Regtest => "regtest",
+    }
+}
+
+// Electrum reports the genesis hash in the usual reversed hex byte order
+fn network_from_genesis_hash(genesis_hash: &[u8; 32]) -> Option<Network> {
+    let genesis_hash = genesis_hash.to_hex();
+    [
+        Network::Bitcoin,
+        Network::Testnet,
+        Network::Signet,
+        Network::Regtest,
+    ]
+    .iter()
+    .find(|n| genesis_block(**n).block_hash().to_hex() == genesis_hash)
+    .copied()
+}
+
+/// Compare the server's genesis hash against the wallet's network. Meant to be
+/// called once after connecting so the UI can confirm which chain it is on.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_confirm_network(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    electrum_address: *const c_char,
+    tor_port: i32,
+) -> NetworkConfirmation {
+    let error_return = NetworkConfirmation {
+        expected: ptr::null(),
+        server_reported: ptr::null(),
+        matches: false,
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let electrum_address =
+        unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), error_return);
+    let client = unwrap_or_return!(
+        get_electrum_client(tor_port, electrum_address),
+        error_return
+    );
+    let features = unwrap_or_return!(client.server_features(), error_return);
+
+    let expected = wallet.network();
+    let server_reported = network_from_genesis_hash(&features.genesis_hash);
+
+    NetworkConfirmation {
+        expected: CString::new(network_name(expected)).unwrap().into_raw(),
+        server_reported: CString::new(server_reported.map(network_name).unwrap_or("unknown"))
+            .unwrap()
+            .into_raw(),
+        matches: server_reported == Some(expected),
+    }
+}
+
+fn transaction_list(transactions: Vec<TransactionDetails>) -> TransactionList {
+    let transactions_len = transactions.len() as u32;
+
+    let mut transactions_vec: Vec<Transaction> = vec![];
+
+    for transaction in transactions {
+        let (confirmation_height, confirmation_time) = match transaction.confirmation_time.as_ref()
+        {
+            None => (0, 0),
+            Some(time) => (time.height, time.timestamp),
+        };
+
+        let tx = Transaction {
+            txid: CString::new(format!("{}", transaction.txid))
+                .unwrap()
+                .into_raw(),
+            received: transaction.received,
+            sent: transaction.sent,
+            fee: transaction.fee.unwrap_or(0),
+            confirmation_height,
+            confirmation_time,
+            fee_known: transaction.fee.is_some(),
+        };
+
+        transactions_vec.push(tx);
+    }
+
+    let transactions_box = transactions_vec.into_boxed_slice();
+    let txs_ptr = Box::into_raw(transactions_box);
+
+    TransactionList {
+        transactions_len,
+        transactions: txs_ptr as _,
+    }
+}
+
+/// Transactions are ordered as described in `sort_transactions`.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_transactions(
+    wallet: *mut Mutex<Wallet<Tree>>,
+) -> TransactionList {
+    let error_return = TransactionList {
+        transactions_len: 0,
+        transactions: ptr::null(),
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+
+    let mut transactions = unwrap_or_return!(wallet.list_transactions(true), error_return);
+    sort_transactions(&mut transactions);
+    transaction_list(transactions)
+}
+
+/// Inputs, outputs, size and fee rate of one of our transactions. Whatever
+/// can't be worked out, like the fee of an incoming payment, is left at zero
+/// with its `_known` flag false rather than failing the lookup.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_transaction(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    txid: *const c_char,
+) -> TransactionInfo {
+    let error_return = TransactionInfo {
+        txid: ptr::null(),
+        received: 0,
+        sent: 0,
+        fee: 0,
+        fee_known: false,
+        vsize: 0,
+        fee_rate: 0.0,
+        confirmation_height: 0,
+        confirmation_time: 0,
+        inputs_len: 0,
+        inputs: ptr::null(),
+        outputs_len: 0,
+        outputs: ptr::null(),
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let txid = unwrap_or_return!(CStr::from_ptr(txid).to_str(), error_return);
+    let txid = unwrap_or_return!(Txid::from_str(txid), error_return);
+
+    let details = match unwrap_or_return!(wallet.get_tx(&txid, true), error_return) {
+        Some(details) => details,
+        None => {
+            update_last_error(WalletError::TransactionNotFound(txid));
+            return error_return;
+        }
+    };
+
+    let mut inputs = vec![];
+    let mut outputs = vec![];
+    let mut vsize = 0;
+    if let Some(tx) = details.transaction.as_ref() {
+        vsize = (tx.weight() as u64 + 3) / 4;
+
+        // Everything that can fail goes first, so nothing is allocated for the
+        // caller by the time we might bail out
+        let db = wallet.database();
+        let previous_outputs = unwrap_or_return!(
+            tx.input
+                .iter()
+                .map(|input| db.get_previous_output(&input.previous_output))
+                .collect::<Result<Vec<_>, _>>(),
+            error_return
+        );
+        let outputs_mine = unwrap_or_return!(
+            tx.output
+                .iter()
+                .map(|output| wallet.is_mine(&output.script_pubkey))
+                .collect::<Result<Vec<_>, _>>(),
+            error_return
+        );
+
+        for (input, previous_output) in tx.input.iter().zip(previous_outputs) {
+            inputs.push(TransactionInput {
+                previous_txid: CString::new(input.previous_output.txid.to_string())
+                    .unwrap()
+                    .into_raw(),
+                previous_vout: input.previous_output.vout,
+                value: previous_output.as_ref().map_or(0, |o| o.value),
+                value_known: previous_output.is_some(),
+            });
+        }
+
+        for (output, is_mine) in tx.output.iter().zip(outputs_mine) {
+            let address = match Address::from_script(&output.script_pubkey, wallet.network()) {
+                Some(address) => CString::new(address.to_string()).unwrap().into_raw(),
+                None => ptr::null_mut(),
+            };
+
+            outputs.push(TransactionOutput {
+                address,
+                value: output.value,
+                is_mine,
+            });
+        }
+    }
+
+    let (confirmation_height, confirmation_time) = match details.confirmation_time.as_ref() {
+        None => (0, 0),
+        Some(time) => (time.height, time.timestamp),
+    };
+
+    let inputs_len = inputs.len() as u32;
+    let outputs_len = outputs.len() as u32;
+
+    TransactionInfo {
+        txid: CString::new(txid.to_string()).unwrap().into_raw(),
+        received: details.received,
+        sent: details.sent,
+        fee: details.fee.unwrap_or(0),
+        fee_known: details.fee.is_some(),
+        vsize,
+        fee_rate: transaction_fee_rate(&details).unwrap_or(0.0),
+        confirmation_height,
+        confirmation_time,
+        inputs_len,
+        inputs: Box::into_raw(inputs.into_boxed_slice()) as _,
+        outputs_len,
+        outputs: Box::into_raw(outputs.into_boxed_slice()) as _,
+    }
+}
+
+// Fee rate in sat/vB, for transactions where BDK knows both the fee and the
+// transaction itself
+fn transaction_fee_rate(transaction: &TransactionDetails) -> Option<f64> {
+    let fee = transaction.fee?;
+    let vsize = (transaction.transaction.as_ref()?.weight() as u64 + 3) / 4;
+    if vsize == 0 {
+        return None;
+    }
+
+    Some(fee as f64 / vsize as f64)
+}
+
+/// Unconfirmed transactions we sent that pay more than `threshold_sat_per_vb`,
+/// so the UI can warn about a fee mistake while it may still be fixable.
+/// Ordered like `wallet_get_transactions`.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_flag_high_fee_transactions(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    threshold_sat_per_vb: f64,
+) -> TransactionList {
+    let error_return = TransactionList {
+        transactions_len: 0,
+        transactions: ptr::null(),
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let mut transactions: Vec<TransactionDetails> =
+        unwrap_or_return!(wallet.list_transactions(true), error_return)
+            .into_iter()
+            .filter(|t| t.confirmation_time.is_none() && t.sent > 0)
+            .filter(|t| {
+                transaction_fee_rate(t)
+                    .map(|rate| rate > threshold_sat_per_vb)
+                    .unwrap_or(false)
+            })
+            .collect();
+
+    sort_transactions(&mut transactions);
+    transaction_list(transactions)
+}
+
+/// Lowest fee rate among the `lookback` most recently confirmed transactions
+/// we know the fee of, as an empirical floor next to the server's estimates.
+/// In BTC per kb like `wallet_get_fee_rate`. Returns 0 when there is no such
+/// history and -1 on error.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_recent_confirmed_fee_floor(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    lookback: u32,
+) -> f64 {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), -1.0);
+    let mut confirmed: Vec<(u32, f64)> = unwrap_or_return!(wallet.list_transactions(true), -1.0)
+        .iter()
+        .filter_map(|t| {
+            let height = t.confirmation_time.as_ref()?.height;
+            Some((height, transaction_fee_rate(t)?))
+        })
+        .collect();
+
+    confirmed.sort_by_key(|(height, _)| Reverse(*height));
+
+    confirmed
+        .iter()
+        .take(lookback as usize)
+        .map(|(_, rate)| *rate)
+        .fold(None, |floor: Option<f64>, rate| {
+            Some(floor.map_or(rate, |f| f.min(rate)))
+        })
+        .map_or(0.0, |floor| floor / SAT_PER_VB_IN_BTC_PER_KVB)
+}
+
+// When we first saw each transaction and when its confirmation status last
+// changed, both as unix timestamps, plus the confirmation height we last saw
+// (0 for unconfirmed) to notice those changes. Updated at the end of every sync.
+const FIRST_SEEN_PREFIX: &str = "envoy/first_seen/";
+const STATUS_CHANGED_PREFIX: &str = "envoy/status_changed/";
+const LAST_STATUS_PREFIX: &str = "envoy/last_status/";
+// Transactions that went from confirmed back to unconfirmed, with the height
+// they were confirmed at. Cleared once they confirm again.
+const REORGED_PREFIX: &str = "envoy/reorged/";
+
+fn metadata_get_u64(db: &Tree, key: &str) -> Result<Option<u64>, bdk::sled::Error> {
+    Ok(db
+        .get(key)?
+        .and_then(|v| v.as_ref().try_into().ok())
+        .map(u64::from_be_bytes))
+}
+
+fn metadata_set_u64(db: &Tree, key: &str, value: u64) -> Result<(), bdk::sled::Error> {
+    db.insert(key, &value.to_be_bytes()[..])?;
+    Ok(())
+}
+
+fn unix_time_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+const TRACKING_STARTED_KEY: &str = "envoy/tracking_started";
+
+// What a sync changed about our transactions, used to notify listeners
+#[derive(Default)]
+struct TrackingChanges {
+    added: Vec<Txid>,
+    confirmed: Vec<(Txid, u32)>,
+}
+
+fn update_transaction_tracking(wallet: &Wallet<Tree>) -> Result<TrackingChanges, WalletError> {
+    let now = unix_time_now();
+    let transactions = wallet.list_transactions(false)?;
+    let db = wallet.database();
+
+    // Don't report the whole history as new the first time around
+    let first_run = metadata_get_u64(&db, TRACKING_STARTED_KEY)?.is_none();
+    if first_run {
+        metadata_set_u64(&db, TRACKING_STARTED_KEY, now)?;
+    }
+    let first_seen = if first_run { 0 } else { now };
+
+    let mut changes = TrackingChanges::default();
+    for transaction in transactions {
+        let txid = transaction.txid.to_string();
+        let height = transaction.confirmation_time.map(|t| t.height).unwrap_or(0);
+
+        let first_seen_key = format!("{}{}", FIRST_SEEN_PREFIX, txid);
+        if metadata_get_u64(&db, &first_seen_key)?.is_none() {
+            metadata_set_u64(&db, &first_seen_key, first_seen)?;
+            if !first_run {
+                changes.added.push(transaction.txid);
+            }
+        }
+
+        let last_status_key = format!("{}{}", LAST_STATUS_PREFIX, txid);
+        match metadata_get_u32(&db, &last_status_key)? {
+            Some(last) if last == height => {}
+            last => {
+                metadata_set_u32(&db, &last_status_key, height)?;
+                if last.is_some() {
+                    metadata_set_u64(&db, &format!("{}{}", STATUS_CHANGED_PREFIX, txid), now)?;
+                }
+
+                let reorged_key = format!("{}{}", REORGED_PREFIX, txid);
+                match last {
+                    Some(last) if last > 0 && height == 0 => {
+                        metadata_set_u32(&db, &reorged_key, last)?;
+                    }
+                    _ if height > 0 => {
+                        db.remove(&reorged_key)?;
+                    }
+                    _ => {}
+                }
+                if !first_run && height > 0 {
+                    changes.confirmed.push((transaction.txid, height));
+                }
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+pub const WALLET_EVENT_BALANCE_CHANGED: u32 = 1 << 0;
+pub const WALLET_EVENT_TRANSACTION_ADDED: u32 = 1 << 1;
+pub const WALLET_EVENT_TRANSACTION_CONFIRMED: u32 = 1 << 2;
+pub const WALLET_EVENT_SYNC_COMPLETED: u32 = 1 << 3;
+
+pub type WalletListenerCallback =
+    extern "C" fn(event: u32, payload: *const c_char, user_data: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct Listener {
+    id: u32,
+    events: u32,
+    callback: WalletListenerCallback,
+    // Opaque to us, kept as an integer so the registry can live in a static
+    user_data: usize,
+}
+
+// Listeners per wallet handle, keyed like SCRIPT_CACHES
+static LISTENERS: Mutex<Option<HashMap<usize, Vec<Listener>>>> = Mutex::new(None);
+static NEXT_LISTENER_ID: Mutex<u32> = Mutex::new(1);
+
+/// Call `callback` whenever a sync of `wallet` detects one of the
+/// WALLET_EVENT_* bits in `events`. The payload is a small JSON object that is
+/// only valid for the duration of the call. Callbacks run on the syncing thread
+/// after the wallet lock is released, so they can call back into the library.
+/// Returns an id for `wallet_remove_listener`, or 0 on failure.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_add_listener(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    events: u32,
+    callback: WalletListenerCallback,
+    user_data: *mut c_void,
+) -> u32 {
+    let mut next_id = unwrap_or_return!(NEXT_LISTENER_ID.lock(), 0);
+    let id = *next_id;
+    *next_id = next_id.wrapping_add(1).max(1);
+
+    let mut listeners = unwrap_or_return!(LISTENERS.lock(), 0);
+    listeners
+        .get_or_insert_with(HashMap::new)
+        .entry(wallet as usize)
+        .or_insert_with(Vec::new)
+        .push(Listener {
+            id,
+            events,
+            callback,
+            user_data: user_data as usize,
+        });
+
+    id
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_remove_listener(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    listener_id: u32,
+) -> bool {
+    let mut listeners = unwrap_or_return!(LISTENERS.lock(), false);
+    match listeners
+        .as_mut()
+        .and_then(|l| l.get_mut(&(wallet as usize)))
+    {
+        None => false,
+        Some(wallet_listeners) => {
+            let before = wallet_listeners.len();
+            wallet_listeners.retain(|l| l.id != listener_id);
+            wallet_listeners.len() != before
+        }
+    }
+}
+
+fn forget_listeners(wallet_key: usize) {
+    if let Ok(mut listeners) = LISTENERS.lock() {
+        if let Some(listeners) = listeners.as_mut() {
+            listeners.remove(&wallet_key);
+        }
+    }
+}
+
+// Must not be called with the wallet locked, the callbacks may take it
+fn notify_listeners(wallet_key: usize, events: Vec<(u32, String)>) {
+    // Copy the listeners out so callbacks can add or remove some
+    let listeners = match LISTENERS.lock() {
+        Err(_) => return,
+        Ok(listeners) => match listeners.as_ref().and_then(|l| l.get(&wallet_key)) {
+            None => return,
+            Some(wallet_listeners) => wallet_listeners.clone(),
+        },
+    };
+
+    for (event, payload) in events {
+        let payload = match CString::new(payload) {
+            Err(_) => continue,
+            Ok(payload) => payload,
+        };
+
+        for listener in listeners.iter().filter(|l| l.events & event != 0) {
+            (listener.callback)(event, payload.as_ptr(), listener.user_data as *mut c_void);
+        }
+    }
+}
+
+/// Transactions first seen by a sync after `since_timestamp` (unix seconds),
+/// plus ones whose confirmation status changed since then, e.g. confirmed or
+/// reorged back to unconfirmed. Ordered like `wallet_get_transactions`.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_new_transactions_since(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    since_timestamp: u64,
+) -> TransactionList {
+    let error_return = TransactionList {
+        transactions_len: 0,
+        transactions: ptr::null(),
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let transactions = unwrap_or_return!(wallet.list_transactions(true), error_return);
+    let db = wallet.database();
+
+    let mut new_transactions = vec![];
+    for transaction in transactions {
+        let txid = transaction.txid.to_string();
+        let first_seen = unwrap_or_return!(
+            metadata_get_u64(&db, &format!("{}{}", FIRST_SEEN_PREFIX, txid)),
+            error_return
+        );
+        let status_changed = unwrap_or_return!(
+            metadata_get_u64(&db, &format!("{}{}", STATUS_CHANGED_PREFIX, txid)),
+            error_return
+        );
+
+        let is_new = first_seen.map(|t| t > since_timestamp).unwrap_or(false)
+            || status_changed.map(|t| t > since_timestamp).unwrap_or(false);
+
+        if is_new {
+            new_transactions.push(transaction);
+        }
+    }
+
+    sort_transactions(&mut new_transactions);
+    transaction_list(new_transactions)
+}
+
+/// Transactions that were confirmed but got reorged out of the best chain and
+/// haven't confirmed again since, as noticed by syncs. BDK already shows them
+/// as unconfirmed, this is for warning the user that a confirmation was lost.
+/// Ordered like `wallet_get_transactions`.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_reorged_transactions(
+    wallet: *mut Mutex<Wallet<Tree>>,
+) -> TransactionList {
+    let error_return = TransactionList {
+        transactions_len: 0,
+        transactions: ptr::null(),
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let transactions = unwrap_or_return!(wallet.list_transactions(false), error_return);
+    let db = wallet.database();
+
+    let mut reorged = vec![];
+    for transaction in transactions {
+        let key = format!("{}{}", REORGED_PREFIX, transaction.txid);
+        if unwrap_or_return!(metadata_get_u32(&db, &key), error_return).is_some() {
+            reorged.push(transaction);
+        }
+    }
+
+    sort_transactions(&mut reorged);
+    transaction_list(reorged)
+}
+
+/// Best-effort guess at whether an incoming transaction was a payjoin: we
+/// received more than we spent, and the inputs mix coins of ours with coins
+/// that aren't.
+///
+/// This can't be told apart on-chain from other collaborative transactions
+/// (e.g. a coinjoin paying us change), and payjoins where the receiver added no
+/// input of ours aren't payjoins from our point of view anyway. Inputs whose
+/// previous output we don't know are counted as foreign.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_is_payjoin_tx(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    txid: *const c_char,
+) -> bool {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), false);
+    let txid = unwrap_or_return!(CStr::from_ptr(txid).to_str(), false);
+    let txid = unwrap_or_return!(Txid::from_str(txid), false);
+
+    let details = match unwrap_or_return!(wallet.get_tx(&txid, true), false) {
+        Some(details) => details,
+        None => {
+            update_last_error(WalletError::TransactionNotFound(txid));
+            return false;
+        }
+    };
+
+    let tx = match details.transaction {
+        Some(tx) => tx,
+        None => return false,
+    };
+
+    if details.received <= details.sent {
+        return false;
+    }
+
+    let mut ours = 0;
+    let mut foreign = 0;
+    for input in tx.input.iter() {
+        let previous = unwrap_or_return!(
+            wallet
+                .database()
+                .get_previous_output(&input.previous_output),
+            false
+        );
+
+        let is_ours = match previous {
+            Some(txout) => unwrap_or_return!(wallet.is_mine(&txout.script_pubkey), false),
+            None => false,
+        };
+
+        if is_ours {
+            ours += 1;
+        } else {
+            foreign += 1;
+        }
+    }
+
+    ours > 0 && foreign > 0
+}
+
+// Keys we store next to BDK's own in the wallet tree. BDK only uses single
+// letter prefixes so anything under "envoy/" is ours.
+const DEFAULTS_PREFIX: &str = "envoy/defaults/";
+const LABELS_PREFIX: &str = "envoy/labels/";
+const FROZEN_PREFIX: &str = "envoy/frozen/";
+// Recipient labels of built but not yet broadcast transactions
+const PENDING_LABELS_PREFIX: &str = "envoy/pending_labels/";
+// Seed-bound encrypted notes, see `encrypt_note`
+const ENCRYPTED_NOTES_PREFIX: &str = "envoy/encrypted_notes/";
+// Do-not-combine group of an outpoint, see `wallet_set_coin_group`
+const COIN_GROUPS_PREFIX: &str = "envoy/coin_groups/";
+
+fn metadata_get_u32(db: &Tree, key: &str) -> Result<Option<u32>, bdk::sled::Error> {
+    Ok(db
+        .get(key)?
+        .and_then(|v| v.as_ref().try_into().ok())
+        .map(u32::from_be_bytes))
+}
+
+fn metadata_set_u32(db: &Tree, key: &str, value: u32) -> Result<(), bdk::sled::Error> {
+    db.insert(key, &value.to_be_bytes()[..])?;
+    Ok(())
+}
+
+// Copy every entry under `from_prefix` in one tree to `to_prefix` in another,
+// returning how many entries were copied
+fn metadata_copy_prefix(
+    from: &Tree,
+    to: &Tree,
+    from_prefix: &str,
+    to_prefix: &str,
+) -> Result<u32, bdk::sled::Error> {
+    let mut copied = 0;
+    for entry in from.scan_prefix(from_prefix) {
+        let (key, value) = entry?;
+        let mut new_key = to_prefix.as_bytes().to_vec();
+        new_key.extend_from_slice(&key[from_prefix.len()..]);
+        to.insert(new_key, value)?;
+        copied += 1;
+    }
+
+    Ok(copied)
+}
+
+fn get_wallet_defaults(wallet: &Wallet<Tree>) -> Result<WalletDefaults, bdk::sled::Error> {
+    let db = wallet.database();
+    let mut defaults = WalletDefaults::default();
+
+    for (field, value) in defaults.fields_mut() {
+        if let Some(stored) = metadata_get_u32(&db, &(DEFAULTS_PREFIX.to_owned() + field))? {
+            *value = stored;
+        }
+    }
+
+    Ok(defaults)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_defaults(wallet: *mut Mutex<Wallet<Tree>>) -> WalletDefaults {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), WalletDefaults::default());
+    unwrap_or_return!(get_wallet_defaults(&wallet), WalletDefaults::default())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_set_defaults(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    mut defaults: WalletDefaults,
+) -> bool {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), false);
+    let db = wallet.database();
+
+    for (field, value) in defaults.fields_mut() {
+        unwrap_or_return!(
+            metadata_set_u32(&db, &(DEFAULTS_PREFIX.to_owned() + field), *value),
+            false
+        );
+    }
+
+    true
+}
+
+const HEADERS_PREFIX: &str = "envoy/headers/";
+
+// Most headers an Electrum server hands out per request
+const ELECTRUM_MAX_HEADERS: u32 = 2016;
+
+// How far back we look for a fork point when the server's tip doesn't extend ours
+const MAX_REORG_DEPTH: u32 = 100;
+
+// Used to extrapolate heights outside of the cached window
+const TARGET_BLOCK_SPACING: i64 = 600;
+
+struct CachedHeader {
+    hash: BlockHash,
+    prev_hash: BlockHash,
+    time: u32,
+}
+
+// Heights are big endian so the keys sort by height
+fn header_key(height: u32) -> Vec<u8> {
+    let mut key = HEADERS_PREFIX.as_bytes().to_vec();
+    key.extend_from_slice(&height.to_be_bytes());
+    key
+}
+
+fn header_key_height(key: &[u8]) -> Option<u32> {
+    key[HEADERS_PREFIX.len()..]
+        .try_into()
+        .ok()
+        .map(u32::from_be_bytes)
+}
+
+fn get_cached_header(db: &Tree, height: u32) -> Result<Option<CachedHeader>, WalletError> {
+    let value = match db.get(header_key(height))? {
+        None => return Ok(None),
+        Some(value) => value,
+    };
+
+    if value.len() != 68 {
+        return Ok(None);
+    }
+
+    Ok(Some(CachedHeader {
+        hash: BlockHash::from_slice(&value[0..32]).unwrap(),
+        prev_hash: BlockHash::from_slice(&value[32..64]).unwrap(),
+        time: u32::from_be_bytes(value[64..68].try_into().unwrap()),
+    }))
+}
+
+fn insert_cached_header(db: &Tree, height: u32, header: &BlockHeader) -> Result<(), WalletError> {
+    let mut value = header.block_hash().into_inner().to_vec();
+    value.extend_from_slice(&header.prev_blockhash.into_inner());
+    value.extend_from_slice(&header.time.to_be_bytes());
+    db.insert(header_key(height), value)?;
+    Ok(())
+}
+
+// Lowest and highest cached heights
+fn cached_header_range(db: &Tree) -> Result<Option<(u32, u32)>, WalletError> {
+    let mut headers = db.scan_prefix(HEADERS_PREFIX).keys();
+    let first = match headers.next() {
+        None => return Ok(None),
+        Some(key) => header_key_height(&key?),
+    };
+    let last = match headers.next_back() {
+        None => first,
+        Some(key) => header_key_height(&key?),
+    };
+
+    Ok(first.zip(last))
+}
+
+fn remove_cached_headers(db: &Tree, keep: impl Fn(u32) -> bool) -> Result<(), WalletError> {
+    for key in db.scan_prefix(HEADERS_PREFIX).keys() {
+        let key = key?;
+        match header_key_height(&key) {
+            Some(height) if keep(height) => {}
+            _ => {
+                db.remove(key)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// A header must meet its own target, within the network's proof of work limit,
+// point at the cached header below it and be pointed at by the cached header
+// above it, so a server can't slip in a made up one or one from another chain
+fn check_header(
+    db: &Tree,
+    network: Network,
+    height: u32,
+    header: &BlockHeader,
+) -> Result<(), WalletError> {
+    let target = header.target();
+    if target > Params::new(network).pow_limit || header.validate_pow(&target).is_err() {
+        return Err(WalletError::HeaderInvalidPow(height));
+    }
+
+    if height > 0 {
+        if let Some(previous) = get_cached_header(db, height - 1)? {
+            if header.prev_blockhash != previous.hash {
+                return Err(WalletError::HeaderNotConnected(height));
+            }
+        }
+    }
+
+    if let Some(next) = get_cached_header(db, height + 1)? {
+        if next.prev_hash != header.block_hash() {
+            return Err(WalletError::HeaderNotConnected(height));
+        }
+    }
+
+    Ok(())
+}
+
+// Only headers next to a cached one, checked to link up with it, go into the
+// cache. Others are used once and forgotten, so the cache stays one chain
+// without gaps.
+fn cache_connected_header(
+    db: &Tree,
+    height: u32,
+    header: &BlockHeader,
+) -> Result<bool, WalletError> {
+    let below = height > 0 && get_cached_header(db, height - 1)?.is_some();
+    let above = get_cached_header(db, height + 1)?.is_some();
+    if !below && !above {
+        return Ok(false);
+    }
+
+    insert_cached_header(db, height, header)?;
+    Ok(true)
+}
+
+// A checked header from the server, cached if it extends the cached chain
+fn fetch_header(
+    db: &Tree,
+    network: Network,
+    client: &Client,
+    height: u32,
+) -> Result<CachedHeader, WalletError> {
+    let header = client.block_header(height as usize)?;
+    check_header(db, network, height, &header)?;
+    cache_connected_header(db, height, &header)?;
+
+    Ok(CachedHeader {
+        hash: header.block_hash(),
+        prev_hash: header.prev_blockhash,
+        time: header.time,
+    })
+}
+
+/// Extend the header cache up to the server's tip and trim it back to the
+/// `header_cache_size` most recent headers. Reorgs up to MAX_REORG_DEPTH deep
+/// replace the stale part of the cache, anything that doesn't connect is rejected.
+fn update_header_cache(wallet: &Wallet<Tree>, client: &Client) -> Result<(), WalletError> {
+    let window = get_wallet_defaults(wallet)?.header_cache_size.max(1);
+    let db = wallet.database();
+
+    let tip = client.block_headers_subscribe()?.height as u32;
+    let window_start = (tip + 1).saturating_sub(window);
+
+    let mut start = window_start;
+    if let Some((_, last)) = cached_header_range(&db)? {
+        if last >= window_start {
+            // Find the highest cached header the server still agrees with
+            let mut fork_point = None;
+            for depth in 0..=MAX_REORG_DEPTH.min(last) {
+                let height = last - depth;
+                if height > tip {
+                    continue;
+                }
+
+                let cached = match get_cached_header(&db, height)? {
+                    None => break,
+                    Some(cached) => cached,
+                };
+
+                if client.block_header(height as usize)?.block_hash() == cached.hash {
+                    fork_point = Some(height);
+                    break;
+                }
+            }
+
+            match fork_point {
+                None => return Err(WalletError::HeaderNotConnected(last)),
+                Some(fork_point) => {
+                    remove_cached_headers(&db, |h| h <= fork_point)?;
+                    start = fork_point + 1;
+                }
+            }
+        }
+    }
+
+    let mut height = start;
+    while height <= tip {
+        let count = (tip - height + 1).min(ELECTRUM_MAX_HEADERS);
+        let headers = client
+            .block_headers(height as usize, count as usize)?
+            .headers;
+
+        if headers.is_empty() {
+            break;
+        }
+
+        for header in headers.iter() {
+            check_header(&db, wallet.network(), height, header)?;
+            insert_cached_header(&db, height, header)?;
+            height += 1;
+        }
+    }
+
+    remove_cached_headers(&db, |h| h >= window_start)
+}
+
+/// Timestamp of the block at `height`, from the header cache or fetched from
+/// Electrum, cached if it extends the cached chain. Returns 0 on error.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_block_time(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    electrum_address: *const c_char,
+    tor_port: i32,
+    height: u32,
+) -> u64 {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), 0);
+    let db = wallet.database();
+
+    if let Some(header) = unwrap_or_return!(get_cached_header(&db, height), 0) {
+        return header.time as u64;
+    }
+
+    let electrum_address = unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), 0);
+    let client = unwrap_or_return!(get_electrum_client(tor_port, electrum_address), 0);
+    let header = unwrap_or_return!(fetch_header(&db, wallet.network(), &client, height), 0);
+
+    header.time as u64
+}
+
+/// Estimate the height of the first block mined at or after `timestamp`.
+///
+/// Inside the cached window this is a lookup. Older timestamps are approached
+/// by extrapolating at ten minutes per block and refining against fetched
+/// headers, newer ones are extrapolated forward from the cached tip.
+/// Block times aren't strictly monotonic so the result is an estimate.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_estimate_height_at_time(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    electrum_address: *const c_char,
+    tor_port: i32,
+    timestamp: u64,
+) -> u32 {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), 0);
+    let db = wallet.database();
+
+    let (first, last) = match unwrap_or_return!(cached_header_range(&db), 0) {
+        Some(range) => range,
+        None => {
+            update_last_error(WalletError::HeaderCacheEmpty);
+            return 0;
+        }
+    };
+
+    let first_time = unwrap_or_return!(get_cached_header(&db, first), 0).map(|h| h.time as u64);
+    let last_time = unwrap_or_return!(get_cached_header(&db, last), 0).map(|h| h.time as u64);
+
+    if let Some(last_time) = last_time {
+        if timestamp > last_time {
+            let blocks = (timestamp - last_time) / TARGET_BLOCK_SPACING as u64;
+            let height = u32::try_from(blocks)
+                .ok()
+                .and_then(|blocks| last.checked_add(blocks));
+            return unwrap_or_return!(height.ok_or(WalletError::TimestampOutOfRange(timestamp)), 0);
+        }
+    }
+
+    if first_time.map(|t| timestamp >= t).unwrap_or(false) {
+        for entry in db.scan_prefix(HEADERS_PREFIX) {
+            let (key, _) = unwrap_or_return!(entry, 0);
+            let height = match header_key_height(&key) {
+                None => continue,
+                Some(height) => height,
+            };
+
+            if let Some(header) = unwrap_or_return!(get_cached_header(&db, height), 0) {
+                if header.time as u64 >= timestamp {
+                    return height;
+                }
+            }
+        }
+
+        return last;
+    }
+
+    // Older than the cache, walk towards it using fetched headers
+    let electrum_address = unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), 0);
+    let client = unwrap_or_return!(get_electrum_client(tor_port, electrum_address), 0);
+
+    let mut height = first as i64;
+    let mut time = first_time.unwrap_or(0) as i64;
+    for _ in 0..8 {
+        let step = (timestamp as i64 - time) / TARGET_BLOCK_SPACING;
+        if step == 0 {
+            break;
+        }
+
+        height = (height + step).max(0);
+        time = unwrap_or_return!(
+            fetch_header(&db, wallet.network(), &client, height as u32),
+            0
+        )
+        .time as i64;
+    }
+
+    height as u32
+}
+
+// Encrypted notes.
+//
+// Notes are sealed with ChaCha20-Poly1305 under a key derived from the
+// wallet's BIP32 key, so a wallet restored from the same seed on another
+// device can read a notes backup while nothing else can. The key is the
+// secret of the account xprv's child at m/NOTES_KEY_INDEX', the account xprv
+// being the descriptor's key taken down the hardened part of its path, so it
+// is the same whether the descriptor holds the master key or the account
+// key. The txid goes in as associated data so notes can't be swapped around:
+//
+//   blob = nonce (12) || ciphertext || tag (16)
+const NOTES_KEY_INDEX: u32 = 0x6e6f7465; // "note"
+const NOTE_NONCE_LEN: usize = 12;
+const NOTE_TAG_LEN: usize = 16;
+
+fn notes_key(wallet: &Wallet<Tree>) -> Result<[u8; 32], WalletError> {
+    let secp = wallet.secp_ctx();
+    let key_map = wallet.get_signers(KeychainKind::External).as_key_map(secp);
+
+    let mut accounts = vec![];
+    for key in key_map.values() {
+        if let DescriptorSecretKey::XPrv(xprv) = key {
+            let hardened: Vec<ChildNumber> = xprv
+                .derivation_path
+                .into_iter()
+                .take_while(|child| child.is_hardened())
+                .cloned()
+                .collect();
+            accounts.push(xprv.xkey.derive_priv(secp, &hardened)?);
+        }
+    }
+
+    // A multisig wallet holding several keys picks the same one every time
+    let account = match accounts
+        .into_iter()
+        .min_by_key(|account| account.private_key.secret_bytes())
+    {
+        Some(account) => account,
+        None => return Err(WalletError::NotSigner),
+    };
+    let notes = account.derive_priv(secp, &[ChildNumber::from_hardened_idx(NOTES_KEY_INDEX)?])?;
+    Ok(notes.private_key.secret_bytes())
+}
+
+fn encrypt_note(key: &[u8; 32], txid: &Txid, note: &str) -> Vec<u8> {
+    let mut nonce = [0u8; NOTE_NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let txid = txid.to_string();
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let sealed = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: note.as_bytes(),
+                aad: txid.as_bytes(),
+            },
+        )
+        .expect("notes are far below ChaCha20-Poly1305's size limit");
+
+    let mut blob = nonce.to_vec();
+    blob.extend_from_slice(&sealed);
+    blob
+}
+
+fn decrypt_note(key: &[u8; 32], txid: &Txid, blob: &[u8]) -> Result<String, WalletError> {
+    if blob.len() < NOTE_NONCE_LEN + NOTE_TAG_LEN {
+        return Err(WalletError::MalformedNotesBackup);
+    }
+
+    let txid = txid.to_string();
+    let (nonce, sealed) = blob.split_at(NOTE_NONCE_LEN);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let plaintext = match cipher.decrypt(
+        Nonce::from_slice(nonce),
+        Payload {
+            msg: sealed,
+            aad: txid.as_bytes(),
+        },
+    ) {
+        Ok(plaintext) => plaintext,
+        Err(_) => return Err(WalletError::NoteDecryptionFailed(txid)),
+    };
+    String::from_utf8(plaintext).map_err(|_| WalletError::NoteDecryptionFailed(txid))
+}
+
+/// Store an encrypted, seed-bound note for a transaction. Fails with
+/// ErrorCode::NotSigner for wallets without a BIP32 private key, e.g.
+/// watch-only ones.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_set_encrypted_note(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    txid: *const c_char,
+    note: *const c_char,
+) -> bool {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), false);
+    let txid = unwrap_or_return!(CStr::from_ptr(txid).to_str(), false);
+    let txid = unwrap_or_return!(Txid::from_str(txid), false);
+    let note = unwrap_or_return!(CStr::from_ptr(note).to_str(), false);
+
+    let notes_key = unwrap_or_return!(notes_key(&wallet), false);
+    let blob = encrypt_note(&notes_key, &txid, note);
+
+    let key = format!("{}{}", ENCRYPTED_NOTES_PREFIX, txid);
+    unwrap_or_return!(wallet.database().insert(key, blob), false);
+
+    true
+}
+
+/// Decrypted note for a transaction, empty if there is none.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_encrypted_note(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    txid: *const c_char,
+) -> *const c_char {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), ptr::null());
+    let txid = unwrap_or_return!(CStr::from_ptr(txid).to_str(), ptr::null());
+    let txid = unwrap_or_return!(Txid::from_str(txid), ptr::null());
+
+    let key = format!("{}{}", ENCRYPTED_NOTES_PREFIX, txid);
+    let note = match unwrap_or_return!(wallet.database().get(key), ptr::null()) {
+        None => String::new(),
+        Some(blob) => {
+            let notes_key = unwrap_or_return!(notes_key(&wallet), ptr::null());
+            unwrap_or_return!(decrypt_note(&notes_key, &txid, &blob), ptr::null())
+        }
+    };
+
+    unwrap_or_return!(CString::new(note), ptr::null()).into_raw()
+}
+
+/// All encrypted notes as a portable backup, one "<txid>:<hex blob>" per line.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_export_encrypted_notes(
+    wallet: *mut Mutex<Wallet<Tree>>,
+) -> *const c_char {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), ptr::null());
+
+    let mut lines = vec![];
+    for entry in wallet.database().scan_prefix(ENCRYPTED_NOTES_PREFIX) {
+        let (key, blob) = unwrap_or_return!(entry, ptr::null());
+        let txid = String::from_utf8_lossy(&key[ENCRYPTED_NOTES_PREFIX.len()..]).to_string();
+        lines.push(format!("{}:{}", txid, blob.to_hex()));
+    }
+
+    CString::new(lines.join("\n")).unwrap().into_raw()
+}
+
+/// Import notes from a `wallet_export_encrypted_notes` backup, possibly made on
+/// another device. Every note must decrypt with this wallet's keys, so a backup
+/// from a different seed is rejected as a whole. Returns the number of notes
+/// imported, or -1 on error.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_recover_notes(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    backup: *const c_char,
+) -> i32 {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), -1);
+    let backup = unwrap_or_return!(CStr::from_ptr(backup).to_str(), -1);
+    let notes_key = unwrap_or_return!(notes_key(&wallet), -1);
+
+    let mut notes = vec![];
+    for line in backup.lines().filter(|l| !l.trim().is_empty()) {
+        let mut parts = line.trim().splitn(2, ':');
+        let (txid, blob) = match (parts.next(), parts.next()) {
+            (Some(txid), Some(blob)) => (txid, blob),
+            _ => {
+                update_last_error(WalletError::MalformedNotesBackup);
+                return -1;
+            }
+        };
+
+        let txid = unwrap_or_return!(Txid::from_str(txid), -1);
+        let blob = unwrap_or_return!(hex::decode(blob), -1);
+
+        // Only check here, the blob is stored as is
+        unwrap_or_return!(decrypt_note(&notes_key, &txid, &blob), -1);
+        notes.push((txid, blob));
+    }
+
+    let db = wallet.database();
+    for (txid, blob) in notes.iter() {
+        let key = format!("{}{}", ENCRYPTED_NOTES_PREFIX, txid);
+        unwrap_or_return!(db.insert(key, blob.clone()), -1);
+    }
+
+    notes.len() as i32
+}
+
+// Persistent script -> transaction index.
+//
+// "envoy/script_index/<script hex>/<txid>" holds the output indexes of that
+// transaction paying to the script (empty if it only spends from it), and
+// "envoy/indexed_txs/<txid>" the comma separated scripts the transaction was
+// indexed under so it can be taken out again. The index is brought up to date
+// at the end of every sync by indexing transactions it hasn't seen and
+// removing ones that left the wallet (e.g. dropped by a reorg or a rescan).
+const SCRIPT_INDEX_PREFIX: &str = "envoy/script_index/";
+const INDEXED_TXS_PREFIX: &str = "envoy/indexed_txs/";
+const SCRIPT_INDEX_VERSION_KEY: &str = "envoy/script_index_version";
+
+// Bump to force a full rebuild of the index on next open
+const SCRIPT_INDEX_VERSION: u32 = 1;
+
+fn script_index_key(script: &Script, txid: &Txid) -> String {
+    format!("{}{}/{}", SCRIPT_INDEX_PREFIX, script.to_hex(), txid)
+}
+
+fn index_transaction(
+    wallet: &Wallet<Tree>,
+    details: &TransactionDetails,
+) -> Result<(), WalletError> {
+    let tx = match details.transaction.as_ref() {
+        None => return Ok(()),
+        Some(tx) => tx,
+    };
+
+    let db = wallet.database();
+    let mut touched: HashMap<Script, Vec<u8>> = HashMap::new();
+
+    for (vout, output) in tx.output.iter().enumerate() {
+        if wallet.is_mine(&output.script_pubkey)? {
+            touched
+                .entry(output.script_pubkey.clone())
+                .or_default()
+                .extend_from_slice(&(vout as u32).to_be_bytes());
+        }
+    }
+
+    for input in tx.input.iter() {
+        if let Some(previous) = db.get_previous_output(&input.previous_output)? {
+            if wallet.is_mine(&previous.script_pubkey)? {
+                touched.entry(previous.script_pubkey).or_default();
+            }
+        }
+    }
+
+    let mut scripts = vec![];
+    for (script, vouts) in touched {
+        db.insert(script_index_key(&script, &details.txid), vouts)?;
+        scripts.push(script.to_hex());
+    }
+
+    db.insert(
+        format!("{}{}", INDEXED_TXS_PREFIX, details.txid),
+        scripts.join(",").as_bytes(),
+    )?;
+
+    Ok(())
+}
+
+fn unindex_transaction(db: &Tree, txid: &str) -> Result<(), WalletError> {
+    let key = format!("{}{}", INDEXED_TXS_PREFIX, txid);
+    if let Some(scripts) = db.remove(&key)? {
+        for script in String::from_utf8_lossy(&scripts).split(',') {
+            db.remove(format!("{}{}/{}", SCRIPT_INDEX_PREFIX, script, txid))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn update_script_index(wallet: &Wallet<Tree>) -> Result<(), WalletError> {
+    {
+        let db = wallet.database();
+        if metadata_get_u32(&db, SCRIPT_INDEX_VERSION_KEY)? != Some(SCRIPT_INDEX_VERSION) {
+            remove_prefix(&db, SCRIPT_INDEX_PREFIX)?;
+            remove_prefix(&db, INDEXED_TXS_PREFIX)?;
+        }
+    }
+
+    let transactions = wallet.list_transactions(true)?;
+    let current: HashSet<String> = transactions.iter().map(|t| t.txid.to_string()).collect();
+
+    let mut indexed = HashSet::new();
+    for key in wallet.database().scan_prefix(INDEXED_TXS_PREFIX).keys() {
+        let key = key?;
+        indexed.insert(String::from_utf8_lossy(&key[INDEXED_TXS_PREFIX.len()..]).to_string());
+    }
+
+    for txid in indexed.difference(&current) {
+        unindex_transaction(&wallet.database(), txid)?;
+    }
+
+    for details in transactions.iter() {
+        if !indexed.contains(&details.txid.to_string()) {
+            index_transaction(wallet, details)?;
+        }
+    }
+
+    metadata_set_u32(
+        &wallet.database(),
+        SCRIPT_INDEX_VERSION_KEY,
+        SCRIPT_INDEX_VERSION,
+    )?;
+    Ok(())
+}
+
+fn script_index_entries(db: &Tree, script: &Script) -> Result<Vec<(Txid, Vec<u32>)>, WalletError> {
+    let prefix = format!("{}{}/", SCRIPT_INDEX_PREFIX, script.to_hex());
+    let mut entries = vec![];
+
+    for entry in db.scan_prefix(&prefix) {
+        let (key, value) = entry?;
+        let txid = String::from_utf8_lossy(&key[prefix.len()..]).to_string();
+        let txid = match Txid::from_str(&txid) {
+            Ok(txid) => txid,
+            Err(_) => continue,
+        };
+
+        let vouts = value
+            .chunks_exact(4)
+            .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+            .collect();
+        entries.push((txid, vouts));
+    }
+
+    Ok(entries)
+}
+
+// Transactions that paid to the script
+fn script_index_receiving_txids(db: &Tree, script: &Script) -> Result<Vec<Txid>, WalletError> {
+    Ok(script_index_entries(db, script)?
+        .into_iter()
+        .filter(|(_, vouts)| !vouts.is_empty())
+        .map(|(txid, _)| txid)
+        .collect())
+}
+
+fn remove_prefix(db: &Tree, prefix: &str) -> Result<(), WalletError> {
+    for key in db.scan_prefix(prefix).keys() {
+        db.remove(key?)?;
+    }
+
+    Ok(())
+}
+
+// Coins younger than this many blocks (roughly a day) are penalised for age
+const PRIVACY_AGE_WINDOW: u32 = 144;
+
+// Funding transactions with this many inputs or more get the full consolidation penalty
+const PRIVACY_CONSOLIDATION_CAP: u32 = 5;
+
+/// Privacy score in the 0-100 range, higher is better.
+///
+/// Each signal contributes a penalty fraction between 0 and 1:
+///  - address reuse: 1 if the address received more than once, else 0
+///  - consolidation: (inputs - 1) / (PRIVACY_CONSOLIDATION_CAP - 1), capped at 1,
+///    where inputs is the input count of the funding transaction
+///  - coin age: (PRIVACY_AGE_WINDOW - age) / PRIVACY_AGE_WINDOW, floored at 0,
+///    where age is the number of confirmations (0 when unconfirmed)
+///
+/// score = round(100 * (1 - sum(weight * penalty) / sum(weight)))
+///
+/// Weights come from WalletDefaults. With all weights set to zero the score is 100.
+fn privacy_score(
+    defaults: &WalletDefaults,
+    age_blocks: u32,
+    address_reused: bool,
+    ancestor_input_count: u32,
+) -> u8 {
+    let reuse_penalty = if address_reused { 1.0 } else { 0.0 };
+
+    let consolidation_penalty = ancestor_input_count
+        .saturating_sub(1)
+        .min(PRIVACY_CONSOLIDATION_CAP - 1) as f64
+        / (PRIVACY_CONSOLIDATION_CAP - 1) as f64;
+
+    let age_penalty =
+        PRIVACY_AGE_WINDOW.saturating_sub(age_blocks) as f64 / PRIVACY_AGE_WINDOW as f64;
+
+    let weights = [
+        defaults.privacy_weight_address_reuse as f64,
+        defaults.privacy_weight_consolidation as f64,
+        defaults.privacy_weight_coin_age as f64,
+    ];
+    let total_weight: f64 = weights.iter().sum();
+
+    if total_weight == 0.0 {
+        return 100;
+    }
+
+    let penalty =
+        weights[0] * reuse_penalty + weights[1] * consolidation_penalty + weights[2] * age_penalty;
+
+    (100.0 * (1.0 - penalty / total_weight)).round() as u8
+}
+
+// Blocks a coinbase output has to wait before it can be spent
+const COINBASE_MATURITY: u32 = 100;
+
+// Height of the chain tip as of the last sync, zero if we never synced
+fn get_tip_height(wallet: &Wallet<Tree>) -> Result<u32, bdk::Error> {
+    Ok(match wallet.database().get_sync_time()? {
+        None => 0,
+        Some(sync_time) => sync_time.block_time.height,
+    })
+}
+
+// Unconfirmed coinbase can't mature, push it out of reach
+fn coinbase_maturity_height(confirmation_height: u32) -> u32 {
+    if confirmation_height == 0 {
+        u32::MAX
+    } else {
+        confirmation_height.saturating_add(COINBASE_MATURITY)
+    }
+}
+
+/// Coinbase outputs of ours that can't go into the next block yet. The PSBT
+/// builders mark these unspendable so coin selection doesn't produce a
+/// transaction the network will reject.
+fn immature_coinbase_outpoints(wallet: &Wallet<Tree>) -> Result<Vec<OutPoint>, bdk::Error> {
+    let next_height = get_tip_height(wallet)?.saturating_add(1);
+
+    let mut immature = vec![];
+    for utxo in wallet.list_unspent()? {
+        let details = match wallet.get_tx(&utxo.outpoint.txid, true)? {
+            None => continue,
+            Some(details) => details,
+        };
+
+        let is_coinbase = details
+            .transaction
+            .as_ref()
+            .map(|t| t.is_coin_base())
+            .unwrap_or(false);
+        if !is_coinbase {
+            continue;
+        }
+
+        let confirmation_height = details
+            .confirmation_time
+            .as_ref()
+            .map(|t| t.height)
+            .unwrap_or(0);
+        if next_height < coinbase_maturity_height(confirmation_height) {
+            immature.push(utxo.outpoint);
+        }
+    }
+
+    Ok(immature)
+}
+
+/// UTXOs are ordered as described in `sort_utxos`.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_list_utxos(wallet: *mut Mutex<Wallet<Tree>>) -> UtxoList {
+    let error_return = UtxoList {
+        utxos_len: 0,
+        utxos: ptr::null(),
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let defaults = unwrap_or_return!(get_wallet_defaults(&wallet), error_return);
+
+    let tip_height = unwrap_or_return!(get_tip_height(&wallet), error_return);
+
+    let transactions = unwrap_or_return!(wallet.list_transactions(true), error_return);
+    let mut utxos = unwrap_or_return!(wallet.list_unspent(), error_return);
+    sort_utxos(&mut utxos);
+
+    let transactions_by_txid: HashMap<Txid, _> = transactions.iter().map(|t| (t.txid, t)).collect();
+
+    let mut utxos_vec: Vec<Utxo> = vec![];
+
+    for utxo in utxos {
+        let details = transactions_by_txid.get(&utxo.outpoint.txid);
+
+        let confirmation_height = details
+            .and_then(|d| d.confirmation_time.as_ref())
+            .map(|t| t.height)
+            .unwrap_or(0);
+
+        let age_blocks = if confirmation_height > 0 && tip_height >= confirmation_height {
+            tip_height - confirmation_height + 1
+        } else {
+            0
+        };
+
+        let ancestor_input_count = details
+            .and_then(|d| d.transaction.as_ref())
+            .map(|t| t.input.len() as u32)
+            .unwrap_or(0);
+
+        let is_coinbase = details
+            .and_then(|d| d.transaction.as_ref())
+            .map(|t| t.is_coin_base())
+            .unwrap_or(false);
+
+        let maturity_height = if is_coinbase {
+            coinbase_maturity_height(confirmation_height)
+        } else {
+            0
+        };
+
+        let receiving_txs = unwrap_or_return!(
+            script_index_receiving_txids(&wallet.database(), &utxo.txout.script_pubkey),
+            error_return
+        );
+        let address_reused = receiving_txs.len() > 1;
+
+        let address = Address::from_script(&utxo.txout.script_pubkey, wallet.network())
+            .map(|a| a.to_string())
+            .unwrap_or_default();
+
+        utxos_vec.push(Utxo {
+            txid: CString::new(utxo.outpoint.txid.to_hex())
+                .unwrap()
+                .into_raw(),
+            vout: utxo.outpoint.vout,
+            value: utxo.txout.value,
+            address: CString::new(address).unwrap().into_raw(),
+            keychain: match utxo.keychain {
+                KeychainKind::External => KeychainType::External,
+                KeychainKind::Internal => KeychainType::Internal,
+            },
+            confirmation_height,
+            age_blocks,
+            address_reused,
+            ancestor_input_count,
+            privacy_score: privacy_score(
+                &defaults,
+                age_blocks,
+                address_reused,
+                ancestor_input_count,
+            ),
+            is_coinbase,
+            maturity_height,
+        });
+    }
+
+    let utxos_len = utxos_vec.len() as u32;
+    let utxos_box = utxos_vec.into_boxed_slice();
+    let utxos_ptr = Box::into_raw(utxos_box);
+
+    UtxoList {
+        utxos_len,
+        utxos: utxos_ptr as _,
+    }
+}
+
+/// Hash over the wallet's unspent outpoints and their values, sorted by outpoint
+/// so the result only changes when the UTXO set itself does.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_utxo_set_hash(wallet: *mut Mutex<Wallet<Tree>>) -> *const c_char {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), ptr::null());
+    let mut utxos = unwrap_or_return!(wallet.list_unspent(), ptr::null());
+    utxos.sort_by_key(|u| u.outpoint);
+
+    let mut engine = sha256::Hash::engine();
+    for utxo in utxos {
+        engine.input(&serialize(&utxo.outpoint));
+        engine.input(&utxo.txout.value.to_le_bytes());
+    }
+
+    let hash = sha256::Hash::from_engine(engine);
+    CString::new(hash.to_hex()).unwrap().into_raw()
+}
+
+struct ScriptCacheEntry {
+    address: String,
+    keychain: KeychainKind,
+    index: u32,
+}
+
+struct ScriptCache {
+    last_external_index: Option<u32>,
+    last_internal_index: Option<u32>,
+    scripts: HashMap<Script, ScriptCacheEntry>,
+}
+
+// Script lookups per wallet, keyed by wallet handle.
+//
+// A wallet's cache is built from the scripts BDK has already derived into its
+// database, so it covers the derivation lookahead as well. It is rebuilt after
+// every sync and whenever a lookup notices a keychain's last derivation index
+// has moved since the cache was built. Dropping the wallet drops its cache.
+static SCRIPT_CACHES: Mutex<Option<HashMap<usize, ScriptCache>>> = Mutex::new(None);
+
+fn last_derivation_indexes(
+    wallet: &Wallet<Tree>,
+) -> Result<(Option<u32>, Option<u32>), bdk::Error> {
+    let db = wallet.database();
+    Ok((
+        db.get_last_index(KeychainKind::External)?,
+        db.get_last_index(KeychainKind::Internal)?,
+    ))
+}
+
+fn build_script_cache(wallet: &Wallet<Tree>) -> Result<ScriptCache, bdk::Error> {
+    let (last_external_index, last_internal_index) = last_derivation_indexes(wallet)?;
+    let db = wallet.database();
+    let mut scripts = HashMap::new();
+
+    for keychain in [KeychainKind::External, KeychainKind::Internal].iter() {
+        for script in db.iter_script_pubkeys(Some(*keychain))? {
+            if let Some((keychain, index)) = db.get_path_from_script_pubkey(&script)? {
+                let address = Address::from_script(&script, wallet.network())
+                    .map(|a| a.to_string())
+                    .unwrap_or_default();
+
+                scripts.insert(
+                    script,
+                    ScriptCacheEntry {
+                        address,
+                        keychain,
+                        index,
+                    },
+                );
+            }
+        }
+    }
+
+    Ok(ScriptCache {
+        last_external_index,
+        last_internal_index,
+        scripts,
+    })
+}
+
+// Every cache entry is replaced whole, so a panic while the lock was held
+// can't leave one half built and the caches stay usable after poisoning
+fn script_caches() -> MutexGuard<'static, Option<HashMap<usize, ScriptCache>>> {
+    SCRIPT_CACHES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn rebuild_script_cache(wallet_key: usize, wallet: &Wallet<Tree>) -> Result<(), bdk::Error> {
+    let cache = build_script_cache(wallet)?;
+    let mut caches = script_caches();
+    caches
+        .get_or_insert_with(HashMap::new)
+        .insert(wallet_key, cache);
+    Ok(())
+}
+
+fn forget_script_cache(wallet_key: usize) {
+    if let Some(caches) = script_caches().as_mut() {
+        caches.remove(&wallet_key);
+    }
+}
+
+/// Look up which of our addresses a script belongs to. `found` is false for
+/// scripts that aren't ours.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_resolve_script(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    script_hex: *const c_char,
+) -> ScriptInfo {
+    let error_return = ScriptInfo {
+        found: false,
+        address: ptr::null(),
+        keychain: KeychainType::External,
+        index: 0,
+    };
+
+    let wallet_key = wallet as usize;
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let script_hex = unwrap_or_return!(CStr::from_ptr(script_hex).to_str(), error_return);
+    let script = Script::from(unwrap_or_return!(hex::decode(script_hex), error_return));
+
+    let indexes = unwrap_or_return!(last_derivation_indexes(&wallet), error_return);
+    let stale = match script_caches().as_ref().and_then(|c| c.get(&wallet_key)) {
+        None => true,
+        Some(cache) => (cache.last_external_index, cache.last_internal_index) != indexes,
+    };
+
+    if stale {
+        unwrap_or_return!(rebuild_script_cache(wallet_key, &wallet), error_return);
+    }
+
+    let caches = script_caches();
+    match caches
+        .as_ref()
+        .and_then(|c| c.get(&wallet_key))
+        .and_then(|c| c.scripts.get(&script))
+    {
+        None => error_return,
+        Some(entry) => ScriptInfo {
+            found: true,
+            address: CString::new(entry.address.clone()).unwrap().into_raw(),
+            keychain: match entry.keychain {
+                KeychainKind::External => KeychainType::External,
+                KeychainKind::Internal => KeychainType::Internal,
+            },
+            index: entry.index,
+        },
+    }
+}
+
+/// The redeem and/or witness script behind one of our P2SH/P2WSH addresses,
+/// so a multisig user can check or rebuild the spending conditions by hand.
+/// Fails for single-sig and taproot addresses.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_output_scripts(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    address: *const c_char,
+) -> OutputScripts {
+    let error_return = OutputScripts {
+        redeem_script: ptr::null(),
+        witness_script: ptr::null(),
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let address_str = unwrap_or_return!(CStr::from_ptr(address).to_str(), error_return);
+    let address = unwrap_or_return!(Address::from_str(address_str), error_return);
+
+    let path = unwrap_or_return!(
+        wallet
+            .database()
+            .get_path_from_script_pubkey(&address.script_pubkey()),
+        error_return
+    );
+    let (keychain, index) = match path {
+        None => {
+            update_last_error(WalletError::AddressNotOwned(address_str.to_string()));
+            return error_return;
+        }
+        Some(path) => path,
+    };
+
+    let secp = Secp256k1::verification_only();
+    let descriptor = unwrap_or_return!(
+        wallet
+            .get_descriptor_for_keychain(keychain)
+            .derived_descriptor(&secp, index)
+            .map_err(|e| WalletError::Descriptor(format!("{:?}", e))),
+        error_return
+    );
+
+    let (has_redeem_script, has_witness_script) = match descriptor.desc_type() {
+        DescriptorType::Sh | DescriptorType::ShSortedMulti => (true, false),
+        DescriptorType::Wsh | DescriptorType::WshSortedMulti => (false, true),
+        DescriptorType::ShWsh | DescriptorType::ShWshSortedMulti => (true, true),
+        _ => {
+            update_last_error(WalletError::NotScriptHash(address_str.to_string()));
+            return error_return;
+        }
+    };
+
+    let script = unwrap_or_return!(
+        descriptor
+            .explicit_script()
+            .map_err(|e| WalletError::Descriptor(e.to_string())),
+        error_return
+    );
+
+    let (redeem_script, witness_script) = if has_witness_script {
+        // Nested segwit redeems to the P2WSH program of the witness script
+        let redeem_script = if has_redeem_script {
+            Some(Script::new_v0_p2wsh(&script.wscript_hash()))
+        } else {
+            None
+        };
+        (redeem_script, Some(script))
+    } else {
+        (Some(script), None)
+    };
+
+    let to_c_hex = |script: Option<Script>| match script {
+        None => ptr::null(),
+        Some(script) => CString::new(script.to_hex()).unwrap().into_raw() as *const c_char,
+    };
+
+    OutputScripts {
+        redeem_script: to_c_hex(redeem_script),
+        witness_script: to_c_hex(witness_script),
+    }
+}
+
+// Upper bounds on what we hand to the consensus decoders. Way past anything
+// legitimate (a transaction can't be bigger than a block), but they stop us
+// from base64/hex decoding and allocating for arbitrarily large inputs.
+const MAX_PSBT_BYTES: usize = 16_000_000;
+const MAX_TRANSACTION_BYTES: usize = 4_000_000;
+
+const PSBT_MAGIC: &[u8] = b"psbt\xff";
+
+/// Decode a base64 PSBT from the app, checking size and magic before the
+/// actual deserialization.
+#[doc(hidden)]
+pub fn decode_psbt_base64(psbt: &str) -> Result<PartiallySignedTransaction, WalletError> {
+    // Base64 encodes 3 bytes in 4 characters
+    if psbt.len() / 4 * 3 > MAX_PSBT_BYTES {
+        return Err(WalletError::InputTooLarge {
+            size: psbt.len() / 4 * 3,
+            max_size: MAX_PSBT_BYTES,
+        });
+    }
+
+    let data = base64::decode(psbt.trim())?;
+    decode_psbt_bytes(&data)
+}
+
+/// Decode a binary PSBT from the app, e.g. read from an SD card, checking size
+/// and magic before the actual deserialization.
+#[doc(hidden)]
+pub fn decode_psbt_bytes(data: &[u8]) -> Result<PartiallySignedTransaction, WalletError> {
+    if data.len() > MAX_PSBT_BYTES {
+        return Err(WalletError::InputTooLarge {
+            size: data.len(),
+            max_size: MAX_PSBT_BYTES,
+        });
+    }
+
+    if !data.starts_with(PSBT_MAGIC) {
+        return Err(WalletError::NotAPsbt);
+    }
+
+    // rust-bitcoin only reads v0, BIP 370 PSBTs go through their v0 form
+    let raw = parse_raw_psbt(data)?;
+    if raw.version == 2 {
+        let data = psbt_v2_to_v0(raw)?;
+        return Ok(deserialize::<PartiallySignedTransaction>(&data)?);
+    }
+
+    Ok(deserialize::<PartiallySignedTransaction>(data)?)
+}
+
+// BIP 370 key types. Version 0 carries the same fields in the unsigned
+// transaction instead, so they're swapped in and out when converting.
+const PSBT_GLOBAL_UNSIGNED_TX: u8 = 0x00;
+const PSBT_GLOBAL_TX_VERSION: u8 = 0x02;
+const PSBT_GLOBAL_FALLBACK_LOCKTIME: u8 = 0x03;
+const PSBT_GLOBAL_INPUT_COUNT: u8 = 0x04;
+const PSBT_GLOBAL_OUTPUT_COUNT: u8 = 0x05;
+const PSBT_GLOBAL_TX_MODIFIABLE: u8 = 0x06;
+const PSBT_GLOBAL_VERSION: u8 = 0xfb;
+const PSBT_IN_PREVIOUS_TXID: u8 = 0x0e;
+const PSBT_IN_OUTPUT_INDEX: u8 = 0x0f;
+const PSBT_IN_SEQUENCE: u8 = 0x10;
+const PSBT_IN_REQUIRED_TIME_LOCKTIME: u8 = 0x11;
+const PSBT_IN_REQUIRED_HEIGHT_LOCKTIME: u8 = 0x12;
+const PSBT_OUT_AMOUNT: u8 = 0x03;
+const PSBT_OUT_SCRIPT: u8 = 0x04;
+
+// Lock times below this are block heights, the rest timestamps
+const LOCK_TIME_THRESHOLD: u32 = 500_000_000;
+
+// Key-value pairs in the order they were serialized
+type PsbtMap = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// A PSBT split into its maps without interpreting them, which works for any
+/// version.
+struct RawPsbt {
+    version: u32,
+    global: PsbtMap,
+    inputs: Vec<PsbtMap>,
+    outputs: Vec<PsbtMap>,
+}
+
+fn malformed_psbt(reason: &str) -> WalletError {
+    WalletError::MalformedPsbt(reason.to_string())
+}
+
+fn read_compact_size(data: &[u8], pos: &mut usize) -> Result<u64, WalletError> {
+    let (VarInt(n), len) = deserialize_partial::<VarInt>(&data[*pos..])?;
+    *pos += len;
+    Ok(n)
+}
+
+fn read_psbt_map(data: &[u8], pos: &mut usize) -> Result<PsbtMap, WalletError> {
+    let read_bytes = |pos: &mut usize| -> Result<Vec<u8>, WalletError> {
+        let len = read_compact_size(data, pos)?;
+        let end = len
+            .try_into()
+            .ok()
+            .and_then(|len: usize| pos.checked_add(len))
+            .filter(|end| *end <= data.len())
+            .ok_or_else(|| malformed_psbt("truncated"))?;
+        let bytes = data[*pos..end].to_vec();
+        *pos = end;
+        Ok(bytes)
+    };
+
+    let mut map = vec![];
+    loop {
+        let key = read_bytes(pos)?;
+        // A zero length key is the separator ending the map
+        if key.is_empty() {
+            return Ok(map);
+        }
+        let value = read_bytes(pos)?;
+        map.push((key, value));
+    }
+}
+
+fn psbt_map_value(map: &PsbtMap, key_type: u8) -> Option<&[u8]> {
+    map.iter()
+        .find(|(key, _)| key.as_slice() == [key_type])
+        .map(|(_, value)| value.as_slice())
+}
+
+fn psbt_map_u32(map: &PsbtMap, key_type: u8, name: &str) -> Result<Option<u32>, WalletError> {
+    match psbt_map_value(map, key_type) {
+        None => Ok(None),
+        Some(value) => {
+            let bytes: [u8; 4] = value
+                .try_into()
+                .map_err(|_| malformed_psbt(&format!("{} isn't 4 bytes", name)))?;
+            Ok(Some(u32::from_le_bytes(bytes)))
+        }
+    }
+}
+
+fn psbt_map_count(map: &PsbtMap, key_type: u8, name: &str) -> Result<u64, WalletError> {
+    let value =
+        psbt_map_value(map, key_type).ok_or_else(|| malformed_psbt(&format!("no {}", name)))?;
+    read_compact_size(value, &mut 0)
+}
+
+// Drops the keys of these types that have no key data, which is how all the
+// fields differing between versions are keyed
+fn strip_psbt_keys(map: PsbtMap, key_types: &[u8]) -> PsbtMap {
+    map.into_iter()
+        .filter(|(key, _)| !(key.len() == 1 && key_types.contains(&key[0])))
+        .collect()
+}
+
+fn parse_raw_psbt(data: &[u8]) -> Result<RawPsbt, WalletError> {
+    if !data.starts_with(PSBT_MAGIC) {
+        return Err(WalletError::NotAPsbt);
+    }
+
+    let mut pos = PSBT_MAGIC.len();
+    let global = read_psbt_map(data, &mut pos)?;
+    let version = psbt_map_u32(&global, PSBT_GLOBAL_VERSION, "version")?.unwrap_or(0);
+
+    let (inputs_len, outputs_len) = match version {
+        0 => {
+            let tx = psbt_map_value(&global, PSBT_GLOBAL_UNSIGNED_TX)
+                .ok_or_else(|| malformed_psbt("no unsigned transaction"))?;
+            let tx = deserialize::<bdk::bitcoin::Transaction>(tx)?;
+            (tx.input.len() as u64, tx.output.len() as u64)
+        }
+        2 => (
+            psbt_map_count(&global, PSBT_GLOBAL_INPUT_COUNT, "input count")?,
+            psbt_map_count(&global, PSBT_GLOBAL_OUTPUT_COUNT, "output count")?,
+        ),
+        _ => return Err(WalletError::UnsupportedPsbtVersion(version)),
+    };
+
+    // The counts aren't trusted for allocations, a map needs at least a byte
+    let mut inputs = vec![];
+    for _ in 0..inputs_len {
+        inputs.push(read_psbt_map(data, &mut pos)?);
+    }
+    let mut outputs = vec![];
+    for _ in 0..outputs_len {
+        outputs.push(read_psbt_map(data, &mut pos)?);
+    }
+
+    // Each version has to leave out the other's way of describing the
+    // transaction
+    let has_key = |map: &PsbtMap, key_types: &[u8]| {
+        map.iter()
+            .any(|(key, _)| key.len() == 1 && key_types.contains(&key[0]))
+    };
+    let misplaced = if version == 0 {
+        has_key(
+            &global,
+            &[
+                PSBT_GLOBAL_TX_VERSION,
+                PSBT_GLOBAL_FALLBACK_LOCKTIME,
+                PSBT_GLOBAL_INPUT_COUNT,
+                PSBT_GLOBAL_OUTPUT_COUNT,
+                PSBT_GLOBAL_TX_MODIFIABLE,
+            ],
+        ) || inputs.iter().any(|input| {
+            has_key(
+                input,
+                &[
+                    PSBT_IN_PREVIOUS_TXID,
+                    PSBT_IN_OUTPUT_INDEX,
+                    PSBT_IN_SEQUENCE,
+                    PSBT_IN_REQUIRED_TIME_LOCKTIME,
+                    PSBT_IN_REQUIRED_HEIGHT_LOCKTIME,
+                ],
+            )
+        }) || outputs
+            .iter()
+            .any(|output| has_key(output, &[PSBT_OUT_AMOUNT, PSBT_OUT_SCRIPT]))
+    } else {
+        has_key(&global, &[PSBT_GLOBAL_UNSIGNED_TX])
+    };
+    if misplaced {
+        return Err(malformed_psbt(&format!(
+            "fields of another version in a version {} PSBT",
+            version
+        )));
+    }
+
+    Ok(RawPsbt {
+        version,
+        global,
+        inputs,
+        outputs,
+    })
+}
+
+fn serialize_raw_psbt(global: &PsbtMap, inputs: &[PsbtMap], outputs: &[PsbtMap]) -> Vec<u8> {
+    let mut data = PSBT_MAGIC.to_vec();
+    for map in std::iter::once(global).chain(inputs).chain(outputs) {
+        for (key, value) in map {
+            data.extend(serialize(&VarInt(key.len() as u64)));
+            data.extend_from_slice(key);
+            data.extend(serialize(&VarInt(value.len() as u64)));
+            data.extend_from_slice(value);
+        }
+        data.push(0x00);
+    }
+    data
+}
+
+/// Rebuild the unsigned transaction of a v2 PSBT and move everything else
+/// over as is. `PSBT_GLOBAL_TX_MODIFIABLE` has no v0 equivalent and is lost.
+fn psbt_v2_to_v0(raw: RawPsbt) -> Result<Vec<u8>, WalletError> {
+    let tx_version = psbt_map_u32(&raw.global, PSBT_GLOBAL_TX_VERSION, "transaction version")?
+        .ok_or_else(|| malformed_psbt("no transaction version"))?;
+    let fallback_lock_time = psbt_map_u32(
+        &raw.global,
+        PSBT_GLOBAL_FALLBACK_LOCKTIME,
+        "fallback lock time",
+    )?;
+
+    // Serialized by hand, the same way consensus encoding lays it out
+    let mut tx = tx_version.to_le_bytes().to_vec();
+    tx.extend(serialize(&VarInt(raw.inputs.len() as u64)));
+
+    let mut lock_time_requirements = vec![];
+    for input in &raw.inputs {
+        let txid = psbt_map_value(input, PSBT_IN_PREVIOUS_TXID)
+            .filter(|txid| txid.len() == 32)
+            .ok_or_else(|| malformed_psbt("input without a previous txid"))?;
+        let vout = psbt_map_u32(input, PSBT_IN_OUTPUT_INDEX, "output index")?
+            .ok_or_else(|| malformed_psbt("input without an output index"))?;
+        let sequence = psbt_map_u32(input, PSBT_IN_SEQUENCE, "sequence")?.unwrap_or(0xffff_ffff);
+
+        tx.extend_from_slice(txid);
+        tx.extend_from_slice(&vout.to_le_bytes());
+        // Empty script_sig
+        tx.push(0x00);
+        tx.extend_from_slice(&sequence.to_le_bytes());
+
+        let time = psbt_map_u32(input, PSBT_IN_REQUIRED_TIME_LOCKTIME, "time lock")?;
+        let height = psbt_map_u32(input, PSBT_IN_REQUIRED_HEIGHT_LOCKTIME, "height lock")?;
+        if time.map_or(false, |time| time < LOCK_TIME_THRESHOLD)
+            || height.map_or(false, |height| height >= LOCK_TIME_THRESHOLD)
+        {
+            return Err(malformed_psbt("lock time requirement of the wrong kind"));
+        }
+        if time.is_some() || height.is_some() {
+            lock_time_requirements.push((time, height));
+        }
+    }
+
+    tx.extend(serialize(&VarInt(raw.outputs.len() as u64)));
+    for output in &raw.outputs {
+        let amount = psbt_map_value(output, PSBT_OUT_AMOUNT)
+            .filter(|amount| amount.len() == 8)
+            .ok_or_else(|| malformed_psbt("output without an amount"))?;
+        let script = psbt_map_value(output, PSBT_OUT_SCRIPT)
+            .ok_or_else(|| malformed_psbt("output without a script"))?;
+
+        tx.extend_from_slice(amount);
+        tx.extend(serialize(&VarInt(script.len() as u64)));
+        tx.extend_from_slice(script);
+    }
+
+    // BIP 370: heights win unless some input can only be locked by time, the
+    // highest requirement of the kind chosen applies
+    let lock_time = if lock_time_requirements.is_empty() {
+        fallback_lock_time.unwrap_or(0)
+    } else if lock_time_requirements
+        .iter()
+        .all(|(_, height)| height.is_some())
+    {
+        lock_time_requirements
+            .iter()
+            .filter_map(|(_, height)| *height)
+            .max()
+            .unwrap_or(0)
+    } else if lock_time_requirements
+        .iter()
+        .all(|(time, _)| time.is_some())
+    {
+        lock_time_requirements
+            .iter()
+            .filter_map(|(time, _)| *time)
+            .max()
+            .unwrap_or(0)
+    } else {
+        return Err(malformed_psbt("inputs require both height and time locks"));
+    };
+    tx.extend_from_slice(&lock_time.to_le_bytes());
+
+    // What was assembled has to read back as a transaction
+    deserialize::<bdk::bitcoin::Transaction>(&tx)?;
+
+    let mut global = strip_psbt_keys(
+        raw.global,
+        &[
+            PSBT_GLOBAL_TX_VERSION,
+            PSBT_GLOBAL_FALLBACK_LOCKTIME,
+            PSBT_GLOBAL_INPUT_COUNT,
+            PSBT_GLOBAL_OUTPUT_COUNT,
+            PSBT_GLOBAL_TX_MODIFIABLE,
+            PSBT_GLOBAL_VERSION,
+        ],
+    );
+    global.insert(0, (vec![PSBT_GLOBAL_UNSIGNED_TX], tx));
+
+    let inputs: Vec<PsbtMap> = raw
+        .inputs
+        .into_iter()
+        .map(|input| {
+            strip_psbt_keys(
+                input,
+                &[
+                    PSBT_IN_PREVIOUS_TXID,
+                    PSBT_IN_OUTPUT_INDEX,
+                    PSBT_IN_SEQUENCE,
+                    PSBT_IN_REQUIRED_TIME_LOCKTIME,
+                    PSBT_IN_REQUIRED_HEIGHT_LOCKTIME,
+                ],
+            )
+        })
+        .collect();
+    let outputs: Vec<PsbtMap> = raw
+        .outputs
+        .into_iter()
+        .map(|output| strip_psbt_keys(output, &[PSBT_OUT_AMOUNT, PSBT_OUT_SCRIPT]))
+        .collect();
+
+    Ok(serialize_raw_psbt(&global, &inputs, &outputs))
+}
+
+/// Move the unsigned transaction of a PSBT into the per input and output
+/// fields of BIP 370. Its lock time becomes the fallback, v0 has no per input
+/// requirements to carry over.
+fn psbt_v0_to_v2(psbt: &PartiallySignedTransaction) -> Result<Vec<u8>, WalletError> {
+    let raw = parse_raw_psbt(&serialize(psbt))?;
+    let tx = &psbt.unsigned_tx;
+
+    let mut global = strip_psbt_keys(raw.global, &[PSBT_GLOBAL_UNSIGNED_TX, PSBT_GLOBAL_VERSION]);
+    global.push((vec![PSBT_GLOBAL_TX_VERSION], serialize(&tx.version)));
+    global.push((
+        vec![PSBT_GLOBAL_FALLBACK_LOCKTIME],
+        serialize(&tx.lock_time),
+    ));
+    global.push((
+        vec![PSBT_GLOBAL_INPUT_COUNT],
+        serialize(&VarInt(tx.input.len() as u64)),
+    ));
+    global.push((
+        vec![PSBT_GLOBAL_OUTPUT_COUNT],
+        serialize(&VarInt(tx.output.len() as u64)),
+    ));
+    global.push((vec![PSBT_GLOBAL_VERSION], 2u32.to_le_bytes().to_vec()));
+    global.sort();
+
+    let mut inputs = raw.inputs;
+    for (map, input) in inputs.iter_mut().zip(&tx.input) {
+        map.push((
+            vec![PSBT_IN_PREVIOUS_TXID],
+            serialize(&input.previous_output.txid),
+        ));
+        map.push((
+            vec![PSBT_IN_OUTPUT_INDEX],
+            input.previous_output.vout.to_le_bytes().to_vec(),
+        ));
+        map.push((vec![PSBT_IN_SEQUENCE], serialize(&input.sequence)));
+        map.sort();
+    }
+
+    let mut outputs = raw.outputs;
+    for (map, output) in outputs.iter_mut().zip(&tx.output) {
+        map.push((vec![PSBT_OUT_AMOUNT], serialize(&output.value)));
+        map.push((vec![PSBT_OUT_SCRIPT], output.script_pubkey.to_bytes()));
+        map.sort();
+    }
+
+    Ok(serialize_raw_psbt(&global, &inputs, &outputs))
+}
+
+/// Decode a hex transaction from the app, checking size before the actual
+/// deserialization.
+#[doc(hidden)]
+pub fn decode_transaction_hex(tx: &str) -> Result<bdk::bitcoin::Transaction, WalletError> {
+    if tx.len() / 2 > MAX_TRANSACTION_BYTES {
+        return Err(WalletError::InputTooLarge {
+            size: tx.len() / 2,
+            max_size: MAX_TRANSACTION_BYTES,
+        });
+    }
+
+    let data = hex::decode(tx.trim())?;
+    Ok(deserialize::<bdk::bitcoin::Transaction>(&data)?)
+}
+
+fn psbt_error_return() -> Psbt {
+    Psbt {
+        sent: 0,
+        received: 0,
+        fee: 0,
+        fee_known: false,
+        base64: ptr::null(),
+        txid: ptr::null(),
+        raw_tx: ptr::null(),
+        size: 0,
+        fee_rate: 0.0,
+        inputs_len: 0,
+        inputs: ptr::null(),
+        outputs_len: 0,
+        outputs: ptr::null(),
+    }
+}
+
+// The spent output as the input's non_witness_utxo has it. That's only the
+// transaction the input spends if its txid says so, anything else could claim
+// any value.
+fn psbt_non_witness_output(
+    psbt: &PartiallySignedTransaction,
+    index: usize,
+) -> Option<&bdk::bitcoin::TxOut> {
+    let previous_output = &psbt.unsigned_tx.input[index].previous_output;
+    psbt.inputs[index]
+        .non_witness_utxo
+        .as_ref()
+        .filter(|tx| tx.txid() == previous_output.txid)
+        .and_then(|tx| tx.output.get(previous_output.vout as usize))
+}
+
+fn psbt_input_script(psbt: &PartiallySignedTransaction, index: usize) -> Option<Script> {
+    match &psbt.inputs[index].witness_utxo {
+        Some(utxo) => Some(utxo.script_pubkey.clone()),
+        None => psbt_non_witness_output(psbt, index).map(|o| o.script_pubkey.clone()),
+    }
+}
+
+// Falls back to the wallet's own transactions for inputs that carry neither
+// witness_utxo nor a matching non_witness_utxo, as foreign PSBTs often leave
+// them out
+fn psbt_input_value(
+    wallet: &Wallet<Tree>,
+    psbt: &PartiallySignedTransaction,
+    index: usize,
+) -> Option<u64> {
+    if let Some(utxo) = &psbt.inputs[index].witness_utxo {
+        return Some(utxo.value);
+    }
+
+    if let Some(output) = psbt_non_witness_output(psbt, index) {
+        return Some(output.value);
+    }
+
+    let previous_output = &psbt.unsigned_tx.input[index].previous_output;
+    wallet
+        .database()
+        .get_previous_output(previous_output)
+        .unwrap_or(None)
+        .map(|o| o.value)
+}
+
+fn psbt_fee(wallet: &Wallet<Tree>, psbt: &PartiallySignedTransaction) -> Option<u64> {
+    let mut inputs_value: u64 = 0;
+    for index in 0..psbt.inputs.len() {
+        inputs_value = inputs_value.checked_add(psbt_input_value(wallet, psbt, index)?)?;
+    }
+    let outputs_value: u64 = psbt.unsigned_tx.output.iter().map(|o| o.value).sum();
+
+    inputs_value.checked_sub(outputs_value)
+}
+
+fn psbt_breakdown(
+    wallet: &Wallet<Tree>,
+    psbt: &PartiallySignedTransaction,
+) -> (Vec<PsbtInput>, Vec<PsbtOutput>) {
+    let tx = &psbt.unsigned_tx;
+
+    let inputs = tx
+        .input
+        .iter()
+        .enumerate()
+        .map(|(index, input)| {
+            let value = psbt_input_value(wallet, psbt, index);
+            PsbtInput {
+                previous_txid: CString::new(input.previous_output.txid.to_string())
+                    .unwrap()
+                    .into_raw(),
+                previous_vout: input.previous_output.vout,
+                value: value.unwrap_or(0),
+                value_known: value.is_some(),
+                is_mine: psbt_input_script(psbt, index)
+                    .map_or(false, |script| wallet.is_mine(&script).unwrap_or(false)),
+            }
+        })
+        .collect();
+
+    let outputs = tx
+        .output
+        .iter()
+        .map(|output| {
+            let address = match Address::from_script(&output.script_pubkey, wallet.network()) {
+                Some(address) => CString::new(address.to_string()).unwrap().into_raw(),
+                None => ptr::null_mut(),
+            };
+            let path = wallet
+                .database()
+                .get_path_from_script_pubkey(&output.script_pubkey)
+                .unwrap_or(None);
+
+            PsbtOutput {
+                address,
+                value: output.value,
+                is_mine: path.is_some(),
+                is_change: matches!(path, Some((KeychainKind::Internal, _))),
+            }
+        })
+        .collect();
+
+    (inputs, outputs)
+}
+
+fn psbt_extract_details(wallet: &Wallet<Tree>, psbt: &PartiallySignedTransaction) -> Psbt {
+    let tx = psbt.clone().extract_tx();
+    let raw_tx = serialize::<bdk::bitcoin::Transaction>(&tx).to_hex();
+
+    let sent = tx
+        .output
+        .iter()
+        .filter(|o| !wallet.is_mine(&o.script_pubkey).unwrap_or(false))
+        .map(|o| o.value)
+        .sum();
+
+    let received = tx
+        .output
+        .iter()
+        .filter(|o| wallet.is_mine(&o.script_pubkey).unwrap_or(false))
+        .map(|o| o.value)
+        .sum();
+
+    let fee = psbt_fee(wallet, psbt);
+    let vsize = estimated_vsize(wallet, &tx);
+
+    let (inputs, outputs) = psbt_breakdown(wallet, psbt);
+
+    let serialized = serialize(&psbt);
+    let encoded = base64::encode(&serialized);
+    let psbt = CString::new(encoded).unwrap().into_raw();
+
+    return Psbt {
+        sent,
+        received,
+        fee: fee.unwrap_or(0),
+        fee_known: fee.is_some(),
+        base64: psbt,
+        txid: CString::new(tx.txid().to_hex()).unwrap().into_raw(),
+        raw_tx: CString::new(raw_tx).unwrap().into_raw(),
+        size: serialized.len() as u64,
+        fee_rate: match fee {
+            Some(fee) if vsize > 0 => fee as f64 / vsize as f64,
+            _ => 0.0,
+        },
+        inputs_len: inputs.len() as u32,
+        inputs: Box::into_raw(inputs.into_boxed_slice()) as _,
+        outputs_len: outputs.len() as u32,
+        outputs: Box::into_raw(outputs.into_boxed_slice()) as _,
+    };
+}
+
+// Virtual size the transaction will have once signed. Inputs without a
+// script_sig or witness yet are counted at our descriptor's worst case.
+fn estimated_vsize(wallet: &Wallet<Tree>, tx: &bdk::bitcoin::Transaction) -> u64 {
+    let satisfaction_weight = wallet
+        .get_descriptor_for_keychain(KeychainKind::External)
+        .max_satisfaction_weight()
+        .unwrap_or(0);
+
+    let unsigned_inputs = tx
+        .input
+        .iter()
+        .filter(|i| i.script_sig.is_empty() && i.witness.is_empty())
+        .count();
+
+    let mut weight = tx.weight() + unsigned_inputs * satisfaction_weight;
+    if unsigned_inputs == tx.input.len() {
+        // Segwit marker and flag, not serialized while there are no witnesses
+        weight += 2;
+    }
+
+    ((weight + 3) / 4) as u64
+}
+
+/// Put the coin at `txid`:`vout` in a do-not-combine group, or take it out of
+/// its group when `group` is null or empty. Coins in different groups are
+/// never spent in the same transaction; ungrouped coins can go with any group.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_set_coin_group(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    txid: *const c_char,
+    vout: u32,
+    group: *const c_char,
+) -> bool {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), false);
+    let txid = unwrap_or_return!(CStr::from_ptr(txid).to_str(), false);
+    let txid = unwrap_or_return!(Txid::from_str(txid), false);
+    let key = format!("{}{}:{}", COIN_GROUPS_PREFIX, txid, vout);
+
+    let group = if group.is_null() {
+        ""
+    } else {
+        unwrap_or_return!(CStr::from_ptr(group).to_str(), false)
+    };
+
+    let db = wallet.database();
+    if group.is_empty() {
+        unwrap_or_return!(db.remove(key), false);
+    } else {
+        unwrap_or_return!(db.insert(key, group.as_bytes()), false);
+    }
+
+    true
+}
+
+/// The do-not-combine group of a coin, or null if it isn't in one.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_coin_group(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    txid: *const c_char,
+    vout: u32,
+) -> *const c_char {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), ptr::null());
+    let txid = unwrap_or_return!(CStr::from_ptr(txid).to_str(), ptr::null());
+    let txid = unwrap_or_return!(Txid::from_str(txid), ptr::null());
+    let key = format!("{}{}:{}", COIN_GROUPS_PREFIX, txid, vout);
+
+    match unwrap_or_return!(wallet.database().get(key), ptr::null()) {
+        None => ptr::null(),
+        Some(group) => CString::new(String::from_utf8_lossy(&group).into_owned())
+            .unwrap()
+            .into_raw(),
+    }
+}
+
+/// Freeze a coin so automatic coin selection never spends it, or unfreeze it.
+/// Picking it by hand, e.g. with `wallet_create_psbt_from_utxos`, still works.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_set_utxo_frozen(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    txid: *const c_char,
+    vout: u32,
+    frozen: bool,
+) -> bool {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), false);
+    let txid = unwrap_or_return!(CStr::from_ptr(txid).to_str(), false);
+    let txid = unwrap_or_return!(Txid::from_str(txid), false);
+    let key = format!("{}{}:{}", FROZEN_PREFIX, txid, vout);
+
+    let db = wallet.database();
+    if frozen {
+        unwrap_or_return!(db.insert(key, &[1u8][..]), false);
+    } else {
+        unwrap_or_return!(db.remove(key), false);
+    }
+
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_is_utxo_frozen(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    txid: *const c_char,
+    vout: u32,
+) -> bool {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), false);
+    let txid = unwrap_or_return!(CStr::from_ptr(txid).to_str(), false);
+    let txid = unwrap_or_return!(Txid::from_str(txid), false);
+    let key = format!("{}{}:{}", FROZEN_PREFIX, txid, vout);
+
+    unwrap_or_return!(wallet.database().contains_key(key), false)
+}
+
+fn frozen_outpoints(wallet: &Wallet<Tree>) -> Result<Vec<OutPoint>, WalletError> {
+    let mut frozen = vec![];
+    for entry in wallet.database().scan_prefix(FROZEN_PREFIX) {
+        let (key, _) = entry?;
+        if let Some(outpoint) = std::str::from_utf8(&key[FROZEN_PREFIX.len()..])
+            .ok()
+            .and_then(|o| OutPoint::from_str(o).ok())
+        {
+            frozen.push(outpoint);
+        }
+    }
+
+    Ok(frozen)
+}
+
+// Coins none of our transactions may spend: immature coinbase and frozen ones
+fn unspendable_outpoints(wallet: &Wallet<Tree>) -> Result<Vec<OutPoint>, WalletError> {
+    let mut unspendable = immature_coinbase_outpoints(wallet)?;
+    unspendable.extend(frozen_outpoints(wallet)?);
+    Ok(unspendable)
+}
+
+fn unconfirmed_outpoints(wallet: &Wallet<Tree>) -> Result<Vec<OutPoint>, WalletError> {
+    let mut unconfirmed = vec![];
+    for utxo in wallet.list_unspent()? {
+        let confirmed = wallet
+            .get_tx(&utxo.outpoint.txid, false)?
+            .map_or(false, |details| details.confirmation_time.is_some());
+        if !confirmed {
+            unconfirmed.push(utxo.outpoint);
+        }
+    }
+
+    Ok(unconfirmed)
+}
+
+// Our unspent coins by do-not-combine group, groups in name order
+fn coin_groups(wallet: &Wallet<Tree>) -> Result<Vec<Vec<OutPoint>>, WalletError> {
+    let unspent: HashSet<OutPoint> = wallet
+        .list_unspent()?
+        .into_iter()
+        .map(|u| u.outpoint)
+        .collect();
+
+    let mut groups: HashMap<String, Vec<OutPoint>> = HashMap::new();
+    for entry in wallet.database().scan_prefix(COIN_GROUPS_PREFIX) {
+        let (key, value) = entry?;
+        let outpoint = match std::str::from_utf8(&key[COIN_GROUPS_PREFIX.len()..])
+            .ok()
+            .and_then(|o| OutPoint::from_str(o).ok())
+        {
+            Some(outpoint) => outpoint,
+            None => continue,
+        };
+
+        // Spent coins can't be selected anyway
+        if unspent.contains(&outpoint) {
+            groups
+                .entry(String::from_utf8_lossy(&value).into_owned())
+                .or_insert_with(Vec::new)
+                .push(outpoint);
+        }
+    }
+
+    let mut groups: Vec<(String, Vec<OutPoint>)> = groups.into_iter().collect();
+    groups.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(groups.into_iter().map(|(_, outpoints)| outpoints).collect())
+}
+
+// Our unspent coins outside the do-not-combine group `group`
+fn outside_coin_group(wallet: &Wallet<Tree>, group: &str) -> Result<Vec<OutPoint>, WalletError> {
+    let mut members = HashSet::new();
+    for entry in wallet.database().scan_prefix(COIN_GROUPS_PREFIX) {
+        let (key, value) = entry?;
+        if &*value != group.as_bytes() {
+            continue;
+        }
+        if let Some(outpoint) = std::str::from_utf8(&key[COIN_GROUPS_PREFIX.len()..])
+            .ok()
+            .and_then(|o| OutPoint::from_str(o).ok())
+        {
+            members.insert(outpoint);
+        }
+    }
+
+    Ok(wallet
+        .list_unspent()?
+        .into_iter()
+        .map(|u| u.outpoint)
+        .filter(|o| !members.contains(o))
+        .collect())
+}
+
+// Weight of an input without its satisfaction: outpoint, sequence and script length
+const TXIN_BASE_WEIGHT: usize = (32 + 4 + 4 + 1) * 4;
+
+/// Turn BDK's insufficient funds error into a `SelectionFailure` by looking at
+/// what the wallet holds. Other errors pass through untouched.
+fn selection_error(wallet: &Wallet<Tree>, fee_rate: FeeRate, error: bdk::Error) -> WalletError {
+    let (needed, available) = match error {
+        bdk::Error::InsufficientFunds { needed, available } => (needed, available),
+        e => return e.into(),
+    };
+
+    let diagnose = || -> Result<SelectionFailure, WalletError> {
+        let utxos = wallet.list_unspent()?;
+        if utxos.is_empty() {
+            return Ok(SelectionFailure::NoCoins);
+        }
+
+        let immature: HashSet<OutPoint> =
+            immature_coinbase_outpoints(wallet)?.into_iter().collect();
+        let (immature, spendable): (Vec<&LocalUtxo>, Vec<&LocalUtxo>) =
+            utxos.iter().partition(|u| immature.contains(&u.outpoint));
+
+        let frozen: HashSet<OutPoint> = frozen_outpoints(wallet)?.into_iter().collect();
+        let (frozen, spendable): (Vec<&LocalUtxo>, Vec<&LocalUtxo>) = spendable
+            .into_iter()
+            .partition(|u| frozen.contains(&u.outpoint));
+
+        let immature_value: u64 = immature.iter().map(|u| u.txout.value).sum();
+        let frozen_value: u64 = frozen.iter().map(|u| u.txout.value).sum();
+        let spendable_value: u64 = spendable.iter().map(|u| u.txout.value).sum();
+        if spendable_value < needed && spendable_value + frozen_value >= needed {
+            return Ok(SelectionFailure::FrozenCoins);
+        }
+        if spendable_value < needed && spendable_value + frozen_value + immature_value >= needed {
+            return Ok(SelectionFailure::ImmatureCoins);
+        }
+
+        // What it costs to add one of our inputs at this fee rate
+        let satisfaction_weight = wallet
+            .get_descriptor_for_keychain(KeychainKind::External)
+            .max_satisfaction_weight()
+            .map_err(|e| WalletError::Descriptor(e.to_string()))?;
+        let input_cost = fee_rate.fee_wu(TXIN_BASE_WEIGHT + satisfaction_weight);
+
+        let effective_value: u64 = spendable
+            .iter()
+            .map(|u| u.txout.value.saturating_sub(input_cost))
+            .sum();
+        if !spendable.is_empty() && effective_value == 0 {
+            return Ok(SelectionFailure::DustCoinsOnly);
+        }
+
+        if spendable_value >= needed {
+            return Ok(SelectionFailure::InsufficientAfterFees);
+        }
+
+        Ok(SelectionFailure::InsufficientFunds)
+    };
+
+    match diagnose() {
+        Ok(failure) => WalletError::SelectionFailed {
+            failure,
+            needed,
+            available,
+        },
+        Err(e) => e,
+    }
+}
+
+// Like Bitcoin Core, one in ten transactions gets a locktime up to this many
+// blocks back so ours don't all stand out with the exact tip height
+const LOCKTIME_RANDOMIZATION_BLOCKS: u32 = 100;
+
+// BDK already sets nLockTime to the tip as of the last sync against fee
+// sniping. This is the lower one to set instead when it's a randomized
+// transaction's turn, None otherwise and before the first sync. It only goes
+// into nlocktime(): BDK also checks coinbase maturity against current_height,
+// which has to stay at the real tip.
+fn randomized_lock_time(wallet: &Wallet<Tree>) -> Option<u32> {
+    let tip_height = match get_tip_height(wallet) {
+        Ok(height) if height > 0 => height,
+        _ => return None,
+    };
+
+    let mut rng = rand::thread_rng();
+    if rng.gen_range(0, 10) == 0 {
+        Some(tip_height.saturating_sub(rng.gen_range(0, LOCKTIME_RANDOMIZATION_BLOCKS)))
+    } else {
+        None
+    }
+}
+
+// add_global_xpubs() fails on xpubs below the master without an origin, as
+// some watch-only imports have them. Single keys aren't added so don't matter.
+fn descriptor_keys_have_origins(wallet: &Wallet<Tree>) -> bool {
+    [KeychainKind::External, KeychainKind::Internal]
+        .iter()
+        .all(|keychain| {
+            wallet
+                .get_descriptor_for_keychain(*keychain)
+                .for_each_key(|key| match key {
+                    DescriptorPublicKey::XPub(xpub) => {
+                        xpub.origin.is_some() || xpub.xkey.depth == 0
+                    }
+                    _ => true,
+                })
+        })
+}
+
+// Every transaction we create starts here so they all get the same locktime
+// treatment, and the xpubs, derivations and change scripts Passport needs to
+// recognize change and verify multisig policy
+fn build_tx(wallet: &Wallet<Tree>) -> TxBuilder<'_, Tree, DefaultCoinSelectionAlgorithm, CreateTx> {
+    let mut builder = wallet.build_tx();
+    if let Some(lock_time) = randomized_lock_time(wallet) {
+        builder.nlocktime(lock_time);
+    }
+    builder.include_output_redeem_witness_script();
+    if descriptor_keys_have_origins(wallet) {
+        builder.add_global_xpubs();
+    }
+    builder
+}
+
+/// Run `build` with the unspendable set every builder needs (immature
+/// coinbase and frozen coins, plus unconfirmed ones unless
+/// `spend_unconfirmed`) and, when coins are grouped, retry it so no two
+/// groups end up in the same transaction: first with ungrouped coins only,
+/// then ungrouped coins plus each group in turn. Only a lack of funds moves on
+/// to the next attempt. Running out of funds is reported as a
+/// `SelectionFailure`.
+fn finish_with_coin_groups<F>(
+    wallet: &Wallet<Tree>,
+    fee_rate: FeeRate,
+    spend_unconfirmed: bool,
+    build: F,
+) -> Result<PartiallySignedTransaction, WalletError>
+where
+    F: Fn(Vec<OutPoint>) -> Result<(PartiallySignedTransaction, TransactionDetails), bdk::Error>,
+{
+    let unspendable = unspendable_outpoints(wallet)?;
+    if spend_unconfirmed {
+        return finish_excluding(wallet, fee_rate, unspendable, &build);
+    }
+
+    let mut confirmed_only = unspendable.clone();
+    confirmed_only.extend(unconfirmed_outpoints(wallet)?);
+
+    // Only blame the unconfirmed coins if spending them would have worked
+    match finish_excluding(wallet, fee_rate, confirmed_only, &build) {
+        Err(WalletError::SelectionFailed { .. })
+            if finish_excluding(wallet, fee_rate, unspendable.clone(), &build).is_ok() =>
+        {
+            Err(WalletError::SelectionFailed {
+                failure: SelectionFailure::UnconfirmedCoins,
+                needed: 0,
+                available: 0,
+            })
+        }
+        result => result,
+    }
+}
+
+fn finish_excluding<F>(
+    wallet: &Wallet<Tree>,
+    fee_rate: FeeRate,
+    immature: Vec<OutPoint>,
+    build: &F,
+) -> Result<PartiallySignedTransaction, WalletError>
+where
+    F: Fn(Vec<OutPoint>) -> Result<(PartiallySignedTransaction, TransactionDetails), bdk::Error>,
+{
+    let groups = coin_groups(wallet)?;
+    if groups.is_empty() {
+        return build(immature)
+            .map(|(psbt, _)| psbt)
+            .map_err(|e| selection_error(wallet, fee_rate, e));
+    }
+
+    let attempts = std::iter::once(None).chain((0..groups.len()).map(Some));
+    for allowed in attempts {
+        let mut unspendable = immature.clone();
+        for (index, group) in groups.iter().enumerate() {
+            if Some(index) != allowed {
+                unspendable.extend(group.iter().cloned());
+            }
+        }
+
+        match build(unspendable) {
+            Ok((psbt, _)) => return Ok(psbt),
+            Err(bdk::Error::InsufficientFunds { .. }) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    // Only blame the groups if ignoring them would have worked
+    match build(immature) {
+        Ok(_) => Err(WalletError::SelectionFailed {
+            failure: SelectionFailure::CoinGroupConflict,
+            needed: 0,
+            available: 0,
+        }),
+        Err(e) => Err(selection_error(wallet, fee_rate, e)),
+    }
+}
+
+// Largest OP_RETURN payload Bitcoin Core relays by default
+const MAX_OP_RETURN_BYTES: usize = 80;
+
+/// Pay `amount` to `send_to` at `fee_rate`, in BTC/kvB as Electrum reports
+/// fee estimates, built as `options` says. `fee_rate` is ignored when the
+/// options set an absolute fee. Fee caps in the options that are
+/// exceeded fail with `FeeAboveCap`, whose fields are in sats and sat/vB, and
+/// with `FeeUnknownForCap` if an input's value can't be found to check them.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_create_psbt(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    send_to: *const c_char,
+    amount: u64,
+    fee_rate: f64,
+    options: *const CreatePsbtOptions,
+) -> Psbt {
+    let error_return = psbt_error_return();
+    let default_options = CreatePsbtOptions::default();
+    let options = options.as_ref().unwrap_or(&default_options);
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let address = unwrap_or_return!(CStr::from_ptr(send_to).to_str(), error_return);
+
+    let send_to = unwrap_or_return!(parse_address(&wallet, address), error_return);
+
+    let fee_rate = if options.fee_absolute > 0 {
+        // Only used to tell why selection failed, the minimum relay rate is
+        // as good a guess as any
+        FeeRate::default_min_relay_fee()
+    } else {
+        unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), error_return)
+    };
+    // BTC/kvB like fee_rate, the cap is checked in sat/vB
+    let max_fee_rate = if options.max_fee_rate > 0.0 {
+        unwrap_or_return!(
+            normalize_fee_rate(options.max_fee_rate, FeeUnit::BtcPerKvb),
+            error_return
+        )
+    } else {
+        0.0
+    };
+    let excluded = unwrap_or_return!(
+        parse_outpoints(options.excluded, options.excluded_len),
+        error_return
+    );
+    let change = if options.change_address.is_null() {
+        None
+    } else {
+        let address = unwrap_or_return!(
+            CStr::from_ptr(options.change_address).to_str(),
+            error_return
+        );
+        Some(unwrap_or_return!(parse_address(&wallet, address), error_return).script_pubkey())
+    };
+
+    let outside_group = if options.group.is_null() {
+        vec![]
+    } else {
+        let group = unwrap_or_return!(CStr::from_ptr(options.group).to_str(), error_return);
+        unwrap_or_return!(outside_coin_group(&wallet, group), error_return)
+    };
+    // What selection found short is the group, not the wallet, so the
+    // diagnosis from the wallet's other coins doesn't apply
+    let blame = |e: WalletError| match e {
+        WalletError::SelectionFailed {
+            failure,
+            needed,
+            available,
+        } if !options.group.is_null() && !matches!(failure, SelectionFailure::UnconfirmedCoins) => {
+            WalletError::SelectionFailed {
+                failure: SelectionFailure::InsufficientGroupFunds,
+                needed,
+                available,
+            }
+        }
+        e => e,
+    };
+
+    let data = if options.data_len == 0 || options.data.is_null() {
+        vec![]
+    } else {
+        std::slice::from_raw_parts(options.data, options.data_len as usize).to_vec()
+    };
+    if data.len() > MAX_OP_RETURN_BYTES {
+        update_last_error(WalletError::InputTooLarge {
+            size: data.len(),
+            max_size: MAX_OP_RETURN_BYTES,
+        });
+        return error_return;
+    }
+
+    let payment = Payment {
+        recipients: vec![(send_to.script_pubkey(), amount)],
+        change,
+        data,
+        ordering: options.ordering,
+        fee_rate,
+        fee_absolute: Some(options.fee_absolute).filter(|fee| *fee > 0),
+        lock_time: Some(options.lock_time).filter(|lock_time| *lock_time > 0),
+    };
+    unwrap_or_return!(
+        check_recipients_dust(&wallet, &payment.recipients),
+        error_return
+    );
+    let build_with = |changeless: bool, mut unspendable: Vec<OutPoint>| {
+        unspendable.extend_from_slice(&excluded);
+        unspendable.extend_from_slice(&outside_group);
+        if changeless {
+            let coin_selection = ChangelessCoinSelection {
+                max_excess: options.max_excess,
+            };
+            build_payment(&wallet, coin_selection, unspendable, &payment)
+        } else {
+            build_payment_with_strategy(&wallet, options.strategy, unspendable, &payment)
+        }
+    };
+    let build = |unspendable: Vec<OutPoint>| build_with(options.changeless, unspendable);
+
+    let psbt = match finish_with_coin_groups(&wallet, fee_rate, options.spend_unconfirmed, build) {
+        Ok(psbt) => psbt,
+        // Only blame the change if the payment works with it
+        Err(_) if options.changeless => {
+            let with_change = |unspendable: Vec<OutPoint>| build_with(false, unspendable);
+            match finish_with_coin_groups(&wallet, fee_rate, options.spend_unconfirmed, with_change)
+            {
+                Ok(_) => update_last_error(WalletError::SelectionFailed {
+                    failure: SelectionFailure::NoChangelessSolution,
+                    needed: 0,
+                    available: 0,
+                }),
+                Err(e) => update_last_error(blame(e)),
+            }
+            return error_return;
+        }
+        Err(e) => {
+            update_last_error(blame(e));
+            return error_return;
+        }
+    };
+    unwrap_or_return!(
+        check_fee_cap(&wallet, &psbt, options.max_fee, max_fee_rate),
+        error_return
+    );
+
+    let size = serialize(&psbt).len();
+    if options.max_psbt_bytes > 0 && size as u64 > options.max_psbt_bytes {
+        update_last_error(WalletError::PsbtTooLarge {
+            size,
+            max_size: options.max_psbt_bytes as usize,
+        });
+        return error_return;
+    }
+
+    psbt_extract_details(&wallet, &psbt)
+}
+
+// Last line of defence against fee unit mixups: refuse a transaction paying
+// more than `max_fee` sats or `max_fee_rate` sat/vB, zero meaning no cap. A
+// fee that can't be worked out can't be under the cap either.
+fn check_fee_cap(
+    wallet: &Wallet<Tree>,
+    psbt: &PartiallySignedTransaction,
+    max_fee: u64,
+    max_fee_rate: f64,
+) -> Result<(), WalletError> {
+    if max_fee == 0 && max_fee_rate <= 0.0 {
+        return Ok(());
+    }
+
+    let fee = psbt_fee(wallet, psbt).ok_or(WalletError::FeeUnknownForCap)?;
+    let fee_rate = fee as f64 / estimated_vsize(wallet, &psbt.unsigned_tx).max(1) as f64;
+
+    if (max_fee > 0 && fee > max_fee) || (max_fee_rate > 0.0 && fee_rate > max_fee_rate) {
+        return Err(WalletError::FeeAboveCap {
+            fee,
+            fee_rate,
+            max_fee,
+            max_fee_rate,
+        });
+    }
+    Ok(())
+}
+
+// The wallet's dust limit setting, or what the network enforces for `script`
+// if that's higher
+fn dust_limit(wallet: &Wallet<Tree>, script: &Script) -> Result<u64, WalletError> {
+    let defaults = get_wallet_defaults(wallet)?;
+    Ok((defaults.dust_limit as u64).max(script.dust_value().to_sat()))
+}
+
+// Every builder paying recipients refuses to create dust for them
+fn check_recipients_dust(
+    wallet: &Wallet<Tree>,
+    recipients: &[(Script, u64)],
+) -> Result<(), WalletError> {
+    for (script, amount) in recipients {
+        let limit = dust_limit(wallet, script)?;
+        if *amount < limit {
+            return Err(WalletError::BelowDustLimit {
+                amount: *amount,
+                limit,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[repr(C)]
+pub enum DustWarning {
+    None,
+    // The payment itself would be dust and can't be sent
+    RecipientBelowDustLimit,
+    // There is change but it's below the wallet's dust limit
+    ChangeBelowDustLimit,
+    // Change was too small to keep and went to the miners instead
+    ChangeDropped,
+}
+
+/// Check what `wallet_create_psbt` would make of this payment dust-wise, so
+/// the UI can warn before building. Errors other than dust (e.g. not enough
+/// funds) come back as `DustWarning::None` with LAST_ERROR set.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_check_dust(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    send_to: *const c_char,
+    amount: u64,
+    fee_rate: f64,
+) -> DustWarning {
+    let error_return = DustWarning::None;
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let address = unwrap_or_return!(CStr::from_ptr(send_to).to_str(), error_return);
+    let send_to = unwrap_or_return!(parse_address(&wallet, address), error_return);
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), error_return);
+
+    let limit = unwrap_or_return!(dust_limit(&wallet, &send_to.script_pubkey()), error_return);
+    if amount < limit {
+        return DustWarning::RecipientBelowDustLimit;
+    }
+
+    let build = |unspendable: Vec<OutPoint>| {
+        let mut builder = build_tx(&wallet);
+        builder
+            .ordering(TxOrdering::Shuffle)
+            .only_witness_utxo()
+            .unspendable(unspendable)
+            .add_recipient(send_to.script_pubkey(), amount)
+            .enable_rbf()
+            .fee_rate(fee_rate);
+        builder.finish()
+    };
+    let psbt = unwrap_or_return!(
+        finish_with_coin_groups(&wallet, fee_rate, true, build),
+        error_return
+    );
+
+    let recipient = send_to.script_pubkey();
+    let mut change = None;
+    for output in &psbt.unsigned_tx.output {
+        if output.script_pubkey != recipient
+            && unwrap_or_return!(wallet.is_mine(&output.script_pubkey), error_return)
+        {
+            change = Some(output.value);
+        }
+    }
+
+    match change {
+        Some(value) if value < limit => DustWarning::ChangeBelowDustLimit,
+        Some(_) => DustWarning::None,
+        None => {
+            let inputs_value: u64 = psbt
+                .inputs
+                .iter()
+                .filter_map(|i| i.witness_utxo.as_ref().map(|o| o.value))
+                .sum();
+            let fee = inputs_value.saturating_sub(amount);
+            let vsize = estimated_vsize(&wallet, &psbt.unsigned_tx);
+
+            // Anything over a sat per vbyte more than the fee rate asks for
+            // was change that didn't make it
+            if fee > fee_rate.fee_vb(vsize as usize) + vsize {
+                DustWarning::ChangeDropped
+            } else {
+                DustWarning::None
+            }
+        }
+    }
+}
+
+// "<txid>:<vout>" strings as handed out in `Utxo`
+unsafe fn parse_outpoints(
+    outpoints: *const *const c_char,
+    outpoints_len: u32,
+) -> Result<Vec<OutPoint>, WalletError> {
+    let outpoints: &[*const c_char] = if outpoints_len == 0 || outpoints.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(outpoints, outpoints_len as usize)
+    };
+
+    outpoints
+        .iter()
+        .map(|outpoint| {
+            let outpoint = CStr::from_ptr(*outpoint).to_string_lossy();
+            OutPoint::from_str(&outpoint).map_err(|_| WalletError::InvalidOutpoint(outpoint.into()))
+        })
+        .collect()
+}
+
+// Coins picked by hand must be ours, unspent, spendable now and not mix
+// do-not-combine groups
+fn check_manual_selection(wallet: &Wallet<Tree>, selected: &[OutPoint]) -> Result<(), WalletError> {
+    let unspent: HashSet<OutPoint> = wallet.list_unspent()?.iter().map(|u| u.outpoint).collect();
+    if let Some(outpoint) = selected.iter().find(|o| !unspent.contains(o)) {
+        return Err(WalletError::UnknownCoin(*outpoint));
+    }
+
+    let immature = immature_coinbase_outpoints(wallet)?;
+    if let Some(outpoint) = selected.iter().find(|o| immature.contains(o)) {
+        return Err(WalletError::ImmatureCoinSelected(*outpoint));
+    }
+
+    let groups_selected = coin_groups(wallet)?
+        .iter()
+        .filter(|group| group.iter().any(|o| selected.contains(o)))
+        .count();
+    if groups_selected > 1 {
+        return Err(WalletError::SelectionFailed {
+            failure: SelectionFailure::CoinGroupConflict,
+            needed: 0,
+            available: 0,
+        });
+    }
+
+    Ok(())
+}
+
+/// Sweep up to `max_inputs` of the smallest coins (0 for no limit) into one
+/// output at a fresh internal address, for tidying up while fees are low.
+/// Coins worth less than it costs to spend them at `fee_rate` are left alone,
+/// as are frozen, immature and grouped coins so no groups get linked, and
+/// unconfirmed coins unless `spend_unconfirmed`.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_consolidate_utxos(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    fee_rate: f64,
+    max_inputs: u32,
+    ordering: OutputOrdering,
+    spend_unconfirmed: bool,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), error_return);
+
+    let mut excluded: HashSet<OutPoint> = HashSet::new();
+    excluded.extend(unwrap_or_return!(
+        unspendable_outpoints(&wallet),
+        error_return
+    ));
+    for group in unwrap_or_return!(coin_groups(&wallet), error_return) {
+        excluded.extend(group);
+    }
+    if !spend_unconfirmed {
+        excluded.extend(unwrap_or_return!(
+            unconfirmed_outpoints(&wallet),
+            error_return
+        ));
+    }
+
+    let satisfaction_weight = unwrap_or_return!(
+        wallet
+            .get_descriptor_for_keychain(KeychainKind::External)
+            .max_satisfaction_weight()
+            .map_err(|e| WalletError::Descriptor(e.to_string())),
+        error_return
+    );
+    let input_cost = fee_rate.fee_wu(TXIN_BASE_WEIGHT + satisfaction_weight);
+
+    let mut utxos: Vec<LocalUtxo> = unwrap_or_return!(wallet.list_unspent(), error_return)
+        .into_iter()
+        .filter(|u| !excluded.contains(&u.outpoint) && u.txout.value > input_cost)
+        .collect();
+    utxos.sort_by_key(|u| u.txout.value);
+    if max_inputs > 0 {
+        utxos.truncate(max_inputs as usize);
+    }
+
+    if utxos.len() < 2 {
+        update_last_error(WalletError::NothingToConsolidate);
+        return error_return;
+    }
+
+    let selected: Vec<OutPoint> = utxos.iter().map(|u| u.outpoint).collect();
+    let destination =
+        unwrap_or_return!(wallet.get_internal_address(AddressIndex::New), error_return).address;
+
+    let mut builder = build_tx(&wallet);
+    unwrap_or_return!(builder.add_utxos(&selected), error_return);
+    builder
+        .ordering(ordering.into())
+        .only_witness_utxo()
+        .manually_selected_only()
+        .drain_to(destination.script_pubkey())
+        .enable_rbf()
+        .fee_rate(fee_rate);
+
+    let (psbt, _) = unwrap_or_return!(builder.finish(), error_return);
+    psbt_extract_details(&wallet, &psbt)
+}
+
+/// Like `wallet_create_psbt` but the fee comes out of `amount`, so the
+/// recipient gets `amount` minus the fee and exactly `amount` leaves the
+/// wallet. Handy for sending a whole balance without guessing the fee.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_create_psbt_subtract_fee(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    send_to: *const c_char,
+    amount: u64,
+    fee_rate: f64,
+    ordering: OutputOrdering,
+    spend_unconfirmed: bool,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let address = unwrap_or_return!(CStr::from_ptr(send_to).to_str(), error_return);
+    let send_to = unwrap_or_return!(parse_address(&wallet, address), error_return);
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), error_return);
+
+    // Select coins for the full amount at no fee first, to learn the size
+    let build = |unspendable: Vec<OutPoint>| {
+        let mut builder = build_tx(&wallet);
+        builder
+            .ordering(ordering.into())
+            .only_witness_utxo()
+            .unspendable(unspendable)
+            .add_recipient(send_to.script_pubkey(), amount)
+            .enable_rbf()
+            .fee_absolute(0);
+        builder.finish()
+    };
+    let sizing = unwrap_or_return!(
+        finish_with_coin_groups(&wallet, fee_rate, spend_unconfirmed, build),
+        error_return
+    );
+
+    let fee = fee_rate.fee_vb(estimated_vsize(&wallet, &sizing.unsigned_tx) as usize);
+    let dust = unwrap_or_return!(dust_limit(&wallet, &send_to.script_pubkey()), error_return);
+    if amount < fee + dust {
+        update_last_error(WalletError::AmountBelowFee { amount, fee });
+        return error_return;
+    }
+
+    // Same coins, so the same change and size, with the fee taken off the recipient
+    let selected: Vec<OutPoint> = sizing
+        .unsigned_tx
+        .input
+        .iter()
+        .map(|i| i.previous_output)
+        .collect();
+
+    let mut builder = build_tx(&wallet);
+    unwrap_or_return!(builder.add_utxos(&selected), error_return);
+    builder
+        .ordering(ordering.into())
+        .only_witness_utxo()
+        .manually_selected_only()
+        .add_recipient(send_to.script_pubkey(), amount - fee)
+        .enable_rbf()
+        .fee_absolute(fee);
+
+    let (psbt, _) = unwrap_or_return!(builder.finish(), error_return);
+    psbt_extract_details(&wallet, &psbt)
+}
+
+/// Like `wallet_create_psbt` but spends only the coins in `outpoints`, each a
+/// "<txid>:<vout>" string as listed by `wallet_list_utxos`. There's no falling
+/// back to automatic selection: if the chosen coins can't cover amount and fee
+/// this fails with BDK's insufficient funds error. Picking an immature coinbase
+/// output or coins from different do-not-combine groups fails too.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_create_psbt_from_utxos(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    send_to: *const c_char,
+    amount: u64,
+    fee_rate: f64,
+    outpoints: *const *const c_char,
+    outpoints_len: u32,
+    ordering: OutputOrdering,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let address = unwrap_or_return!(CStr::from_ptr(send_to).to_str(), error_return);
+    let send_to = unwrap_or_return!(parse_address(&wallet, address), error_return);
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), error_return);
+
+    let selected = unwrap_or_return!(parse_outpoints(outpoints, outpoints_len), error_return);
+
+    unwrap_or_return!(check_manual_selection(&wallet, &selected), error_return);
+    unwrap_or_return!(
+        check_recipients_dust(&wallet, &[(send_to.script_pubkey(), amount)]),
+        error_return
+    );
+
+    let mut builder = build_tx(&wallet);
+    unwrap_or_return!(builder.add_utxos(&selected), error_return);
+    builder
+        .ordering(ordering.into())
+        .only_witness_utxo()
+        .manually_selected_only()
+        .add_recipient(send_to.script_pubkey(), amount)
+        .enable_rbf()
+        .fee_rate(fee_rate);
+
+    let (psbt, _) = unwrap_or_return!(builder.finish(), error_return);
+    psbt_extract_details(&wallet, &psbt)
+}
+
+/// Send everything to `send_to`, the fee coming out of that single output.
+/// With `outpoints` ("<txid>:<vout>" strings) only those coins are swept,
+/// otherwise every spendable coin is, keeping to one do-not-combine group.
+/// `sent` of the result is what the recipient gets. Fails rather than create
+/// an output below the dust limit.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_create_max_psbt(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    send_to: *const c_char,
+    fee_rate: f64,
+    outpoints: *const *const c_char,
+    outpoints_len: u32,
+    ordering: OutputOrdering,
+    spend_unconfirmed: bool,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let address = unwrap_or_return!(CStr::from_ptr(send_to).to_str(), error_return);
+    let send_to = unwrap_or_return!(parse_address(&wallet, address), error_return);
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), error_return);
+
+    let selected = unwrap_or_return!(parse_outpoints(outpoints, outpoints_len), error_return);
+
+    if selected.is_empty() {
+        let build = |unspendable: Vec<OutPoint>| {
+            let mut builder = build_tx(&wallet);
+            builder
+                .ordering(ordering.into())
+                .only_witness_utxo()
+                .unspendable(unspendable)
+                .drain_wallet()
+                .drain_to(send_to.script_pubkey())
+                .enable_rbf()
+                .fee_rate(fee_rate);
+            builder.finish()
+        };
+
+        return match finish_with_coin_groups(&wallet, fee_rate, spend_unconfirmed, build) {
+            Ok(psbt) => psbt_extract_details(&wallet, &psbt),
+            Err(e) => {
+                update_last_error(e);
+                error_return
+            }
+        };
+    }
+
+    unwrap_or_return!(check_manual_selection(&wallet, &selected), error_return);
+
+    let mut builder = build_tx(&wallet);
+    unwrap_or_return!(builder.add_utxos(&selected), error_return);
+    builder
+        .ordering(ordering.into())
+        .only_witness_utxo()
+        .manually_selected_only()
+        .drain_to(send_to.script_pubkey())
+        .enable_rbf()
+        .fee_rate(fee_rate);
+
+    // BDK refuses to drain into a dust output by reporting insufficient funds
+    let (psbt, _) = unwrap_or_return!(
+        builder.finish().map_err(|e| match e {
+            bdk::Error::InsufficientFunds { needed, available } => WalletError::SelectionFailed {
+                failure: SelectionFailure::DustCoinsOnly,
+                needed,
+                available,
+            },
+            e => e.into(),
+        }),
+        error_return
+    );
+    psbt_extract_details(&wallet, &psbt)
+}
+
+/// The most `wallet_create_max_psbt` could send to `send_to` at `fee_rate`
+/// (BTC/kvB) from automatically selected coins, i.e. the balance that's
+/// spendable in one transaction less the fee for spending it, counting
+/// unconfirmed coins only if `spend_unconfirmed`. Returns 0 with LAST_ERROR
+/// set if nothing can be sent.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_get_max_spendable(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    send_to: *const c_char,
+    fee_rate: f64,
+    spend_unconfirmed: bool,
+) -> u64 {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), 0);
+    let address = unwrap_or_return!(CStr::from_ptr(send_to).to_str(), 0);
+    let send_to = unwrap_or_return!(parse_address(&wallet, address), 0);
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), 0);
+
+    let build = |unspendable: Vec<OutPoint>| {
+        let mut builder = build_tx(&wallet);
+        builder
+            .only_witness_utxo()
+            .unspendable(unspendable)
+            .drain_wallet()
+            .drain_to(send_to.script_pubkey())
+            .enable_rbf()
+            .fee_rate(fee_rate);
+        builder.finish()
+    };
+    let psbt = unwrap_or_return!(
+        finish_with_coin_groups(&wallet, fee_rate, spend_unconfirmed, build),
+        0
+    );
+
+    psbt.unsigned_tx.output.iter().map(|o| o.value).sum()
+}
+
+/// What `wallet_create_psbt` would build, including how much change comes back
+/// and to which address, without building anything for real. The change
+/// address is peeked so the internal index doesn't move; it is the one the
+/// real build hands out next. A changeless transaction reports zero change.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_preview_send(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    send_to: *const c_char,
+    amount: u64,
+    fee_rate: f64,
+) -> SendPreview {
+    let error_return = SendPreview {
+        sent: 0,
+        fee: 0,
+        change_amount: 0,
+        change_address: ptr::null(),
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let address = unwrap_or_return!(CStr::from_ptr(send_to).to_str(), error_return);
+    let send_to = unwrap_or_return!(parse_address(&wallet, address), error_return);
+
+    let next_change_index = unwrap_or_return!(
+        wallet.database().get_last_index(KeychainKind::Internal),
+        error_return
+    )
+    .map_or(0, |last| last + 1);
+    let change = unwrap_or_return!(
+        wallet.get_internal_address(AddressIndex::Peek(next_change_index)),
+        error_return
+    )
+    .address;
+
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), error_return);
+
+    let build = |unspendable: Vec<OutPoint>| {
+        let mut builder = build_tx(&wallet);
+        builder
+            .ordering(TxOrdering::Shuffle)
+            .only_witness_utxo()
+            .unspendable(unspendable)
+            .add_recipient(send_to.script_pubkey(), amount)
+            .drain_to(change.script_pubkey())
+            .enable_rbf()
+            .fee_rate(fee_rate);
+        builder.finish()
+    };
+
+    let psbt = unwrap_or_return!(
+        finish_with_coin_groups(&wallet, fee_rate, true, build),
+        error_return
+    );
+    let tx = &psbt.unsigned_tx;
+
+    let change_script = change.script_pubkey();
+    let change_amount = tx
+        .output
+        .iter()
+        .find(|o| o.script_pubkey == change_script)
+        .map_or(0, |o| o.value);
+
+    // The peeked change address may not be in the database yet, so is_mine
+    // alone can't be trusted to recognize it
+    let sent = tx
+        .output
+        .iter()
+        .filter(|o| o.script_pubkey != change_script)
+        .filter(|o| !wallet.is_mine(&o.script_pubkey).unwrap_or(false))
+        .map(|o| o.value)
+        .sum();
+
+    let inputs_value: u64 = psbt
+        .inputs
+        .iter()
+        .map(|i| i.witness_utxo.as_ref().map_or(0, |o| o.value))
+        .sum();
+    let outputs_value: u64 = tx.output.iter().map(|o| o.value).sum();
+
+    SendPreview {
+        sent,
+        fee: inputs_value.saturating_sub(outputs_value),
+        change_amount,
+        change_address: if change_amount > 0 {
+            CString::new(change.to_string()).unwrap().into_raw()
+        } else {
+            ptr::null()
+        },
+    }
+}
+
+// Weight of an output paying to `script`, which is never long enough to need
+// more than a byte for its length
+fn output_weight(script: &Script) -> usize {
+    (8 + 1 + script.len()) * 4
+}
+
+/// Rough size and fee of paying `recipients` at `fee_rate` (BTC/kvB), for
+/// updating a fee slider as it moves. Nothing is built: coins are added
+/// largest first until they cover the payment, with change if it's above the
+/// dust limit. Do-not-combine groups aren't taken into account, so the real
+/// transaction can come out larger. When funds run short the estimate spends
+/// every coin.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_estimate_tx(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    recipients: *const Recipient,
+    recipients_len: u32,
+    fee_rate: f64,
+) -> TxEstimate {
+    let error_return = TxEstimate {
+        vsize: 0,
+        fee: 0,
+        sufficient: false,
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), error_return);
+
+    let recipients: &[Recipient] = if recipients_len == 0 || recipients.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(recipients, recipients_len as usize)
+    };
+
+    // Version, locktime, input and output counts, segwit marker and flag
+    let mut weight = (4 + 4 + 1 + 1) * 4 + 2;
+    let mut amount = 0;
+    for recipient in recipients {
+        let address = unwrap_or_return!(CStr::from_ptr(recipient.address).to_str(), error_return);
+        let address = unwrap_or_return!(parse_address(&wallet, address), error_return);
+        weight += output_weight(&address.script_pubkey());
+        amount = match amount.checked_add(recipient.amount) {
+            Some(amount) => amount,
+            None => {
+                update_last_error(WalletError::AmountOverflow);
+                return error_return;
+            }
+        };
+    }
+
+    let change = unwrap_or_return!(
+        wallet.get_internal_address(AddressIndex::Peek(0)),
+        error_return
+    )
+    .address
+    .script_pubkey();
+    let change_weight = output_weight(&change);
+    let change_limit = unwrap_or_return!(dust_limit(&wallet, &change), error_return);
+
+    let satisfaction_weight = unwrap_or_return!(
+        wallet
+            .get_descriptor_for_keychain(KeychainKind::External)
+            .max_satisfaction_weight()
+            .map_err(|e| WalletError::Descriptor(e.to_string())),
+        error_return
+    );
+
+    let mut excluded: HashSet<OutPoint> = HashSet::new();
+    excluded.extend(unwrap_or_return!(
+        unspendable_outpoints(&wallet),
+        error_return
+    ));
+
+    let mut utxos: Vec<LocalUtxo> = unwrap_or_return!(wallet.list_unspent(), error_return)
+        .into_iter()
+        .filter(|u| !excluded.contains(&u.outpoint))
+        .collect();
+    utxos.sort_by_key(|u| Reverse(u.txout.value));
+
+    let mut selected = 0;
+    for utxo in &utxos {
+        weight += TXIN_BASE_WEIGHT + satisfaction_weight;
+        selected += utxo.txout.value;
+
+        let fee = fee_rate.fee_wu(weight);
+        if selected < amount.saturating_add(fee) {
+            continue;
+        }
+
+        let fee_with_change = fee_rate.fee_wu(weight + change_weight);
+        let needed_with_change = amount
+            .saturating_add(fee_with_change)
+            .saturating_add(change_limit);
+        let (weight, fee) = if selected >= needed_with_change {
+            (weight + change_weight, fee_with_change)
+        } else {
+            // Whatever's left over goes to the miners
+            (weight, selected - amount)
+        };
+
+        return TxEstimate {
+            vsize: ((weight + 3) / 4) as u64,
+            fee,
+            sufficient: true,
+        };
+    }
+
+    TxEstimate {
+        vsize: ((weight + 3) / 4) as u64,
+        fee: fee_rate.fee_wu(weight),
+        sufficient: false,
+    }
+}
+
+// Fee rate coins are assumed to be spendable at eventually when weighing
+// spending them now against later, Bitcoin Core's -consolidatefeerate default
+const LONG_TERM_FEE_RATE_SAT_PER_VB: f64 = 10.0;
+
+/// Run coin selection for paying `recipients` at `fee_rate` (BTC/kvB) with
+/// `strategy` and report what it picked, without handing out a PSBT or a
+/// change address. For showing what a payment will do and for checking how
+/// the strategies behave.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_compose(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    recipients: *const Recipient,
+    recipients_len: u32,
+    fee_rate: f64,
+    strategy: CoinSelectionStrategy,
+) -> ComposeReport {
+    let error_return = ComposeReport {
+        inputs_len: 0,
+        inputs: ptr::null(),
+        fee: 0,
+        fee_rate: 0.0,
+        change_amount: 0,
+        waste: 0,
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), error_return);
+
+    let recipients: &[Recipient] = if recipients_len == 0 || recipients.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(recipients, recipients_len as usize)
+    };
+    let mut outputs = vec![];
+    for recipient in recipients {
+        let address = unwrap_or_return!(CStr::from_ptr(recipient.address).to_str(), error_return);
+        let address = unwrap_or_return!(parse_address(&wallet, address), error_return);
+        outputs.push((address.script_pubkey(), recipient.amount));
+    }
+
+    // Peeked like `wallet_preview_send` does, so the internal index stays put
+    let next_change_index = unwrap_or_return!(
+        wallet.database().get_last_index(KeychainKind::Internal),
+        error_return
+    )
+    .map_or(0, |last| last + 1);
+    let change = unwrap_or_return!(
+        wallet.get_internal_address(AddressIndex::Peek(next_change_index)),
+        error_return
+    )
+    .address
+    .script_pubkey();
+
+    let payment = Payment {
+        recipients: outputs,
+        change: Some(change.clone()),
+        data: vec![],
+        ordering: OutputOrdering::Shuffle,
+        fee_rate,
+        fee_absolute: None,
+        lock_time: None,
+    };
+    let build = |unspendable: Vec<OutPoint>| {
+        build_payment_with_strategy(&wallet, strategy, unspendable, &payment)
+    };
+    let psbt = unwrap_or_return!(
+        finish_with_coin_groups(&wallet, fee_rate, true, build),
+        error_return
+    );
+    let tx = &psbt.unsigned_tx;
+
+    let mut inputs = vec![];
+    let mut inputs_value = 0;
+    for (input, psbt_input) in tx.input.iter().zip(psbt.inputs.iter()) {
+        let value = psbt_input.witness_utxo.as_ref().map(|o| o.value);
+        inputs_value += value.unwrap_or(0);
+        inputs.push(TransactionInput {
+            previous_txid: CString::new(input.previous_output.txid.to_string())
+                .unwrap()
+                .into_raw(),
+            previous_vout: input.previous_output.vout,
+            value: value.unwrap_or(0),
+            value_known: value.is_some(),
+        });
+    }
+
+    let change_amount = tx
+        .output
+        .iter()
+        .find(|o| o.script_pubkey == change)
+        .map_or(0, |o| o.value);
+    let outputs_value: u64 = tx.output.iter().map(|o| o.value).sum();
+    let fee = inputs_value.saturating_sub(outputs_value);
+    let vsize = estimated_vsize(&wallet, tx);
+
+    let satisfaction_weight = wallet
+        .get_descriptor_for_keychain(KeychainKind::External)
+        .max_satisfaction_weight()
+        .unwrap_or(0);
+    let input_vsize = (TXIN_BASE_WEIGHT + satisfaction_weight) as f64 / 4.0;
+    let rate = fee_rate.as_sat_vb() as f64;
+
+    // Spending the inputs now rather than at the long term rate, plus either
+    // making and later spending the change or what went to the miners instead
+    let mut waste = tx.input.len() as f64 * input_vsize * (rate - LONG_TERM_FEE_RATE_SAT_PER_VB);
+    if change_amount > 0 {
+        waste += output_weight(&change) as f64 / 4.0 * rate;
+        waste += input_vsize * LONG_TERM_FEE_RATE_SAT_PER_VB;
+    } else {
+        waste += fee as f64 - rate * vsize as f64;
+    }
+
+    ComposeReport {
+        inputs_len: inputs.len() as u32,
+        inputs: Box::into_raw(inputs.into_boxed_slice()) as _,
+        fee,
+        fee_rate: if vsize > 0 {
+            fee as f64 / vsize as f64
+        } else {
+            0.0
+        },
+        change_amount,
+        waste: waste.round() as i64,
+    }
+}
+
+/// Spends a single coin whenever one is enough, smallest first, so no two of
+/// our addresses get linked by a common input. Only when no coin covers the
+/// payment alone does it fall back to branch and bound, which at least keeps
+/// the input count down and tries to avoid change.
+#[derive(Debug)]
+struct PrivacyCoinSelection;
+
+impl CoinSelectionAlgorithm<Tree> for PrivacyCoinSelection {
+    fn coin_select(
+        &self,
+        database: &Tree,
+        required_utxos: Vec<WeightedUtxo>,
+        mut optional_utxos: Vec<WeightedUtxo>,
+        fee_rate: FeeRate,
+        target_amount: u64,
+        drain_script: &Script,
+    ) -> Result<CoinSelectionResult, bdk::Error> {
+        if required_utxos.is_empty() {
+            optional_utxos.sort_by_key(|u| u.utxo.txout().value);
+
+            for utxo in &optional_utxos {
+                let single = LargestFirstCoinSelection.coin_select(
+                    database,
+                    vec![utxo.clone()],
+                    vec![],
+                    fee_rate,
+                    target_amount,
+                    drain_script,
+                );
+                if let Ok(result) = single {
+                    return Ok(result);
+                }
+            }
+        }
+
+        BranchAndBoundCoinSelection::default().coin_select(
+            database,
+            required_utxos,
+            optional_utxos,
+            fee_rate,
+            target_amount,
+            drain_script,
+        )
+    }
+}
+
+/// Only accepts input sets that need no change output, the leftover of at most
+/// `max_excess` sats going to the miners instead. Branch and bound is told a
+/// change output costs exactly that much so it searches the right window.
+#[derive(Debug)]
+struct ChangelessCoinSelection {
+    max_excess: u64,
+}
+
+impl CoinSelectionAlgorithm<Tree> for ChangelessCoinSelection {
+    fn coin_select(
+        &self,
+        database: &Tree,
+        required_utxos: Vec<WeightedUtxo>,
+        optional_utxos: Vec<WeightedUtxo>,
+        fee_rate: FeeRate,
+        target_amount: u64,
+        drain_script: &Script,
+    ) -> Result<CoinSelectionResult, bdk::Error> {
+        let available = required_utxos
+            .iter()
+            .chain(optional_utxos.iter())
+            .map(|u| u.utxo.txout().value)
+            .sum();
+
+        let size_of_change = (self.max_excess as f32 / fee_rate.as_sat_per_vb()) as u64;
+        let result = BranchAndBoundCoinSelection::new(size_of_change).coin_select(
+            database,
+            required_utxos,
+            optional_utxos,
+            fee_rate,
+            target_amount,
+            drain_script,
+        )?;
+
+        // Branch and bound falls back to a random draw that may well have
+        // change, only take what it found if it doesn't
+        match result.excess {
+            Excess::NoChange {
+                remaining_amount, ..
+            } if remaining_amount <= self.max_excess => Ok(result),
+            _ => Err(bdk::Error::InsufficientFunds {
+                needed: target_amount,
+                available,
+            }),
+        }
+    }
+}
+
+/// Spends coins received on a single address whenever they're enough, the
+/// cluster with the least in it first, as coins on one address are already
+/// linked and spending them together gives nothing new away. Falls back to
+/// `PrivacyCoinSelection` when no address holds enough on its own.
+#[derive(Debug)]
+struct AddressClusterCoinSelection;
+
+impl CoinSelectionAlgorithm<Tree> for AddressClusterCoinSelection {
+    fn coin_select(
+        &self,
+        database: &Tree,
+        required_utxos: Vec<WeightedUtxo>,
+        optional_utxos: Vec<WeightedUtxo>,
+        fee_rate: FeeRate,
+        target_amount: u64,
+        drain_script: &Script,
+    ) -> Result<CoinSelectionResult, bdk::Error> {
+        if required_utxos.is_empty() {
+            let mut clusters: HashMap<Script, Vec<WeightedUtxo>> = HashMap::new();
+            for utxo in &optional_utxos {
+                clusters
+                    .entry(utxo.utxo.txout().script_pubkey.clone())
+                    .or_insert_with(Vec::new)
+                    .push(utxo.clone());
+            }
+
+            let mut clusters: Vec<Vec<WeightedUtxo>> =
+                clusters.into_iter().map(|(_, c)| c).collect();
+            clusters.sort_by_key(|c| c.iter().map(|u| u.utxo.txout().value).sum::<u64>());
+
+            for cluster in clusters {
+                let result = LargestFirstCoinSelection.coin_select(
+                    database,
+                    vec![],
+                    cluster,
+                    fee_rate,
+                    target_amount,
+                    drain_script,
+                );
+                if let Ok(result) = result {
+                    return Ok(result);
+                }
+            }
+        }
+
+        PrivacyCoinSelection.coin_select(
+            database,
+            required_utxos,
+            optional_utxos,
+            fee_rate,
+            target_amount,
+            drain_script,
+        )
+    }
+}
+
+// Everything about a payment but the coins paying for it
+struct Payment {
+    recipients: Vec<(Script, u64)>,
+    // None for the wallet's next internal address
+    change: Option<Script>,
+    // OP_RETURN payload, none if empty
+    data: Vec<u8>,
+    ordering: OutputOrdering,
+    fee_rate: FeeRate,
+    // Exact fee in sats overriding `fee_rate`
+    fee_absolute: Option<u64>,
+    // None for `build_tx`'s anti fee sniping locktime
+    lock_time: Option<u32>,
+}
+
+fn build_payment<Cs: CoinSelectionAlgorithm<Tree>>(
+    wallet: &Wallet<Tree>,
+    coin_selection: Cs,
+    unspendable: Vec<OutPoint>,
+    payment: &Payment,
+) -> Result<(PartiallySignedTransaction, TransactionDetails), bdk::Error> {
+    let mut builder = build_tx(wallet).coin_selection(coin_selection);
+    builder
+        .ordering(payment.ordering.into())
+        .only_witness_utxo()
+        .unspendable(unspendable)
+        .set_recipients(payment.recipients.clone())
+        .enable_rbf();
+    match payment.fee_absolute {
+        Some(fee) => builder.fee_absolute(fee),
+        None => builder.fee_rate(payment.fee_rate),
+    };
+    if let Some(change) = &payment.change {
+        builder.drain_to(change.clone());
+    }
+    if !payment.data.is_empty() {
+        builder.add_data(&payment.data);
+    }
+    // RBF sequence numbers aren't final, so the locktime is enforced
+    if let Some(lock_time) = payment.lock_time {
+        builder.nlocktime(lock_time);
+    }
+    builder.finish()
+}
+
+// `build_payment` with the coin selection algorithm behind `strategy`
+fn build_payment_with_strategy(
+    wallet: &Wallet<Tree>,
+    strategy: CoinSelectionStrategy,
+    unspendable: Vec<OutPoint>,
+    payment: &Payment,
+) -> Result<(PartiallySignedTransaction, TransactionDetails), bdk::Error> {
+    match strategy {
+        CoinSelectionStrategy::BranchAndBound => build_payment(
+            wallet,
+            BranchAndBoundCoinSelection::default(),
+            unspendable,
+            payment,
+        ),
+        CoinSelectionStrategy::OldestFirst => build_payment(
+            wallet,
+            OldestFirstCoinSelection::default(),
+            unspendable,
+            payment,
+        ),
+        CoinSelectionStrategy::LargestFirst => {
+            build_payment(wallet, LargestFirstCoinSelection, unspendable, payment)
+        }
+        CoinSelectionStrategy::Privacy => {
+            build_payment(wallet, PrivacyCoinSelection, unspendable, payment)
+        }
+        CoinSelectionStrategy::AddressClusters => {
+            build_payment(wallet, AddressClusterCoinSelection, unspendable, payment)
+        }
+    }
+}
+
+/// Like `wallet_create_psbt` with `CoinSelectionStrategy::AddressClusters`,
+/// also telling how many addresses the inputs come from so the UI can warn
+/// when paying links them.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_create_psbt_cluster_private(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    send_to: *const c_char,
+    amount: u64,
+    fee_rate: f64,
+    ordering: OutputOrdering,
+    spend_unconfirmed: bool,
+) -> PrivatePsbt {
+    let error_return = PrivatePsbt {
+        psbt: psbt_error_return(),
+        input_addresses: 0,
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let address = unwrap_or_return!(CStr::from_ptr(send_to).to_str(), error_return);
+    let send_to = unwrap_or_return!(parse_address(&wallet, address), error_return);
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), error_return);
+
+    let payment = Payment {
+        recipients: vec![(send_to.script_pubkey(), amount)],
+        change: None,
+        data: vec![],
+        ordering,
+        fee_rate,
+        fee_absolute: None,
+        lock_time: None,
+    };
+    unwrap_or_return!(
+        check_recipients_dust(&wallet, &payment.recipients),
+        error_return
+    );
+    let build = |unspendable: Vec<OutPoint>| {
+        build_payment(&wallet, AddressClusterCoinSelection, unspendable, &payment)
+    };
+    let psbt = unwrap_or_return!(
+        finish_with_coin_groups(&wallet, fee_rate, spend_unconfirmed, build),
+        error_return
+    );
+
+    let input_addresses: HashSet<&Script> = psbt
+        .inputs
+        .iter()
+        .filter_map(|i| i.witness_utxo.as_ref().map(|o| &o.script_pubkey))
+        .collect();
+
+    PrivatePsbt {
+        psbt: psbt_extract_details(&wallet, &psbt),
+        input_addresses: input_addresses.len() as u32,
+    }
+}
+
+/// Build the same unsigned transaction on every device of a multisig setup so
+/// the coordinators' PSBTs can be combined. Nothing random goes in: coins are
+/// picked largest first, inputs and outputs follow `ordering` instead of being
+/// shuffled, nLockTime is 0 rather than the local tip and change goes to the
+/// internal address at `change_index`, which the coordinators agree on.
+/// Devices must also have synced to the same UTXO set, have the same coins
+/// frozen, as frozen coins are never spent, and agree on `spend_unconfirmed`.
+/// Local coin groups are ignored.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_create_psbt_coordinated(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    send_to: *const c_char,
+    amount: u64,
+    fee_rate: f64,
+    ordering: OutputOrdering,
+    change_index: u32,
+    spend_unconfirmed: bool,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let address = unwrap_or_return!(CStr::from_ptr(send_to).to_str(), error_return);
+    let send_to = unwrap_or_return!(parse_address(&wallet, address), error_return);
+    let mut unspendable = unwrap_or_return!(unspendable_outpoints(&wallet), error_return);
+    if !spend_unconfirmed {
+        unspendable.extend(unwrap_or_return!(
+            unconfirmed_outpoints(&wallet),
+            error_return
+        ));
+    }
+
+    let change = unwrap_or_return!(
+        wallet.get_internal_address(AddressIndex::Peek(change_index)),
+        error_return
+    )
+    .address;
+
+    // Every device would come up with a different order
+    if ordering == OutputOrdering::Shuffle {
+        update_last_error(WalletError::NondeterministicOrdering);
+        return error_return;
+    }
+    unwrap_or_return!(
+        check_recipients_dust(&wallet, &[(send_to.script_pubkey(), amount)]),
+        error_return
+    );
+
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), error_return);
+
+    let mut builder = build_tx(&wallet).coin_selection(LargestFirstCoinSelection);
+    builder
+        .ordering(ordering.into())
+        .only_witness_utxo()
+        .unspendable(unspendable)
+        .add_recipient(send_to.script_pubkey(), amount)
+        .drain_to(change.script_pubkey())
+        .nlocktime(0)
+        .enable_rbf()
+        .fee_rate(fee_rate);
+
+    let (psbt, _) = unwrap_or_return!(
+        builder
+            .finish()
+            .map_err(|e| selection_error(&wallet, fee_rate, e)),
+        error_return
+    );
+    psbt_extract_details(&wallet, &psbt)
+}
+
+// An unconfirmed transaction of ours and its fee rate in sat/vB, checked to be
+// worth replacing at `new_rate`
+fn replaceable_transaction(
+    wallet: &Wallet<Tree>,
+    txid: Txid,
+    new_rate: f64,
+) -> Result<(TransactionDetails, f64), WalletError> {
+    let details = match wallet.get_tx(&txid, true)? {
+        Some(details) => details,
+        None => return Err(WalletError::TransactionNotFound(txid)),
+    };
+
+    if details.confirmation_time.is_some() {
+        return Err(WalletError::TransactionConfirmed(txid));
+    }
+
+    // Incoming payments can only be sped up with CPFP
+    if details.sent == 0 {
+        return Err(WalletError::TransactionNotOurs(txid));
+    }
+
+    let signals_rbf = details.transaction.as_ref().map_or(false, |tx| {
+        tx.input.iter().any(|input| input.sequence.is_rbf())
+    });
+    if !signals_rbf {
+        return Err(WalletError::NotReplaceable(txid));
+    }
+
+    let original_rate = match transaction_fee_rate(&details) {
+        Some(rate) => rate,
+        None => return Err(WalletError::TransactionNotFound(txid)),
+    };
+    if new_rate <= original_rate {
+        return Err(WalletError::FeeRateNotHigher {
+            original: original_rate,
+            requested: new_rate,
+        });
+    }
+
+    Ok((details, original_rate))
+}
+
+/// Whether `wallet_bump_fee` can replace `txid`: it must be ours, unconfirmed
+/// and signal RBF on at least one input. When it can't, LAST_ERROR says why,
+/// e.g. for hiding the boost button with an explanation.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_tx_is_replaceable(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    txid: *const c_char,
+) -> bool {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), false);
+    let txid = unwrap_or_return!(CStr::from_ptr(txid).to_str(), false);
+    let txid = unwrap_or_return!(Txid::from_str(txid), false);
+
+    // Nothing beats an infinite fee rate, so the rate check always passes
+    unwrap_or_return!(replaceable_transaction(&wallet, txid, f64::INFINITY), false);
+    true
+}
+
+/// Replacement for an unconfirmed transaction of ours paying `new_fee_rate`
+/// (BTC/kvB), built with BDK's fee bump so recipients stay the same and the
+/// extra fee comes out of change or extra inputs. The returned `fee_rate` is
+/// the replacement's, for the UI to compare with the original.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_bump_fee(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    txid: *const c_char,
+    new_fee_rate: f64,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let txid = unwrap_or_return!(CStr::from_ptr(txid).to_str(), error_return);
+    let txid = unwrap_or_return!(Txid::from_str(txid), error_return);
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(new_fee_rate), error_return);
+
+    unwrap_or_return!(
+        replaceable_transaction(&wallet, txid, fee_rate.as_sat_vb() as f64),
+        error_return
+    );
+
+    // Extra inputs, if needed, can't be frozen ones
+    let frozen = unwrap_or_return!(frozen_outpoints(&wallet), error_return);
+
+    let mut builder = unwrap_or_return!(wallet.build_fee_bump(txid), error_return);
+    builder
+        .unspendable(frozen)
+        .enable_rbf()
+        .fee_rate(fee_rate)
+        .include_output_redeem_witness_script();
+    if descriptor_keys_have_origins(&wallet) {
+        builder.add_global_xpubs();
+    }
+
+    let (psbt, _) = unwrap_or_return!(builder.finish(), error_return);
+    psbt_extract_details(&wallet, &psbt)
+}
+
+/// Cancel an unconfirmed payment by double spending its inputs back to a fresh
+/// internal address at `new_fee_rate` (BTC/kvB). The absolute fee is raised
+/// if needed to cover the original fee plus relay, as BIP125 requires, so the
+/// returned `fee_rate` can end up above the one asked for.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_cancel_tx(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    txid: *const c_char,
+    new_fee_rate: f64,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let txid = unwrap_or_return!(CStr::from_ptr(txid).to_str(), error_return);
+    let txid = unwrap_or_return!(Txid::from_str(txid), error_return);
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(new_fee_rate), error_return);
+    let new_rate = fee_rate.as_sat_vb() as f64;
+
+    let (details, _) = unwrap_or_return!(
+        replaceable_transaction(&wallet, txid, new_rate),
+        error_return
+    );
+    let original_fee = details.fee.unwrap_or(0);
+    let inputs: Vec<OutPoint> = match details.transaction {
+        Some(tx) => tx.input.iter().map(|i| i.previous_output).collect(),
+        None => {
+            update_last_error(WalletError::TransactionNotFound(txid));
+            return error_return;
+        }
+    };
+
+    let destination =
+        unwrap_or_return!(wallet.get_internal_address(AddressIndex::New), error_return).address;
+
+    let (psbt, _) = unwrap_or_return!(
+        build_replacement(&wallet, &inputs, fee_rate, original_fee, |builder| {
+            builder
+                .manually_selected_only()
+                .drain_to(destination.script_pubkey());
+        }),
+        error_return
+    );
+    psbt_extract_details(&wallet, &psbt)
+}
+
+/// Replace an unconfirmed payment with one sending the same amount to
+/// `send_to` instead, e.g. when the wrong address was pasted. Everything the
+/// original paid out to others goes to the new address as one output. The
+/// original inputs are all spent again, plus more confirmed coins if the
+/// higher fee needs them, and the fee covers the original's plus relay as
+/// BIP125 requires.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_redirect_tx(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    txid: *const c_char,
+    send_to: *const c_char,
+    new_fee_rate: f64,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let txid = unwrap_or_return!(CStr::from_ptr(txid).to_str(), error_return);
+    let txid = unwrap_or_return!(Txid::from_str(txid), error_return);
+    let address = unwrap_or_return!(CStr::from_ptr(send_to).to_str(), error_return);
+    let send_to = unwrap_or_return!(parse_address(&wallet, address), error_return);
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(new_fee_rate), error_return);
+    let new_rate = fee_rate.as_sat_vb() as f64;
+
+    let (details, _) = unwrap_or_return!(
+        replaceable_transaction(&wallet, txid, new_rate),
+        error_return
+    );
+    let original_fee = details.fee.unwrap_or(0);
+    let tx = match details.transaction {
+        Some(tx) => tx,
+        None => {
+            update_last_error(WalletError::TransactionNotFound(txid));
+            return error_return;
+        }
+    };
+    let inputs: Vec<OutPoint> = tx.input.iter().map(|i| i.previous_output).collect();
+
+    let mut amount = 0;
+    for output in &tx.output {
+        if !unwrap_or_return!(wallet.is_mine(&output.script_pubkey), error_return) {
+            amount += output.value;
+        }
+    }
+    if amount == 0 {
+        update_last_error(WalletError::ZeroAmount(send_to.to_string()));
+        return error_return;
+    }
+
+    // Replacements may only add confirmed inputs
+    let mut unspendable = unwrap_or_return!(unspendable_outpoints(&wallet), error_return);
+    unspendable.extend(unwrap_or_return!(
+        unconfirmed_outpoints(&wallet),
+        error_return
+    ));
+
+    let (psbt, _) = unwrap_or_return!(
+        build_replacement(&wallet, &inputs, fee_rate, original_fee, |builder| {
+            builder
+                .unspendable(unspendable.clone())
+                .add_recipient(send_to.script_pubkey(), amount);
+        }),
+        error_return
+    );
+    psbt_extract_details(&wallet, &psbt)
+}
+
+// Fee of a transaction whose inputs aren't all ours, from the previous
+// transactions as the server has them
+fn fetch_transaction_fee(
+    tor_port: i32,
+    electrum_address: &str,
+    tx: &bdk::bitcoin::Transaction,
+) -> Result<u64, WalletError> {
+    let client = get_electrum_client(tor_port, electrum_address)?;
+    let txids: Vec<Txid> = tx.input.iter().map(|i| i.previous_output.txid).collect();
+    let previous = client.batch_transaction_get(&txids)?;
+
+    let input_value: Option<u64> = tx
+        .input
+        .iter()
+        .zip(previous.iter())
+        .map(|(input, previous)| {
+            previous
+                .output
+                .get(input.previous_output.vout as usize)
+                .map(|o| o.value)
+        })
+        .sum();
+    let output_value: u64 = tx.output.iter().map(|o| o.value).sum();
+
+    input_value
+        .and_then(|value| value.checked_sub(output_value))
+        .ok_or_else(|| WalletError::FeeUnknown(tx.txid()))
+}
+
+/// Child-pays-for-parent: spend our outputs of the unconfirmed `txid`, other
+/// than frozen ones, back to a fresh internal address, paying enough that
+/// parent and child together reach `fee_rate` (BTC/kvB). When the parent
+/// spends coins that aren't ours the wallet doesn't know its fee, so its
+/// inputs are looked up on the Electrum server.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_create_cpfp(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    txid: *const c_char,
+    fee_rate: f64,
+    electrum_address: *const c_char,
+    tor_port: i32,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let txid = unwrap_or_return!(CStr::from_ptr(txid).to_str(), error_return);
+    let txid = unwrap_or_return!(Txid::from_str(txid), error_return);
+    let electrum_address =
+        unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), error_return);
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), error_return);
+
+    let details = match unwrap_or_return!(wallet.get_tx(&txid, true), error_return) {
+        Some(details) => details,
+        None => {
+            update_last_error(WalletError::TransactionNotFound(txid));
+            return error_return;
+        }
+    };
+    if details.confirmation_time.is_some() {
+        update_last_error(WalletError::TransactionConfirmed(txid));
+        return error_return;
+    }
+    let parent = match details.transaction.as_ref() {
+        Some(tx) => tx,
+        None => {
+            update_last_error(WalletError::TransactionNotFound(txid));
+            return error_return;
+        }
+    };
+
+    let unspendable = unwrap_or_return!(unspendable_outpoints(&wallet), error_return);
+    let outputs: Vec<OutPoint> = unwrap_or_return!(wallet.list_unspent(), error_return)
+        .iter()
+        .map(|u| u.outpoint)
+        .filter(|o| o.txid == txid && !unspendable.contains(o))
+        .collect();
+    if outputs.is_empty() {
+        update_last_error(WalletError::NoOutputToSpend(txid));
+        return error_return;
+    }
+
+    let parent_fee = match details.fee {
+        Some(fee) => fee,
+        None => unwrap_or_return!(
+            fetch_transaction_fee(tor_port, electrum_address, parent),
+            error_return
+        ),
+    };
+    let parent_vsize = (parent.weight() as u64 + 3) / 4;
+
+    let destination =
+        unwrap_or_return!(wallet.get_internal_address(AddressIndex::New), error_return).address;
+
+    // First pass only to learn the child's size
+    let mut builder = build_tx(&wallet);
+    unwrap_or_return!(builder.add_utxos(&outputs), error_return);
+    builder
+        .only_witness_utxo()
+        .manually_selected_only()
+        .drain_to(destination.script_pubkey())
+        .enable_rbf()
+        .fee_absolute(0);
+    let (sizing, _) = unwrap_or_return!(builder.finish(), error_return);
+    let child_vsize = estimated_vsize(&wallet, &sizing.unsigned_tx);
+
+    // The child has to be relayable on its own too
+    let package_fee = fee_rate.fee_vb((parent_vsize + child_vsize) as usize);
+    let child_fee = package_fee
+        .saturating_sub(parent_fee)
+        .max((child_vsize as f64 * INCREMENTAL_RELAY_FEE_SAT_PER_VB).ceil() as u64);
+
+    let mut builder = build_tx(&wallet);
+    unwrap_or_return!(builder.add_utxos(&outputs), error_return);
+    builder
+        .only_witness_utxo()
+        .manually_selected_only()
+        .drain_to(destination.script_pubkey())
+        .enable_rbf()
+        .fee_absolute(child_fee);
+
+    let (psbt, _) = unwrap_or_return!(builder.finish(), error_return);
+    psbt_extract_details(&wallet, &psbt)
+}
+
+/// Sweep everything a WIF private key holds, e.g. from a paper wallet or gift
+/// card, to a fresh receive address. The key's addresses are looked up on the
+/// Electrum server: native segwit, nested segwit and legacy for a compressed
+/// key, legacy only for an uncompressed one. The result is signed and ready to
+/// broadcast; its `received` is what lands in the wallet.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_sweep_private_key(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    wif: *const c_char,
+    fee_rate: f64,
+    electrum_address: *const c_char,
+    tor_port: i32,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = get_wallet_mutex(wallet);
+    let network = unwrap_or_return!(wallet.lock(), error_return).network();
+    let wif = unwrap_or_return!(CStr::from_ptr(wif).to_str(), error_return);
+    let electrum_address =
+        unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), error_return);
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), error_return);
+
+    let key = unwrap_or_return!(
+        PrivateKey::from_wif(wif).map_err(|e| WalletError::InvalidPrivateKey(e.to_string())),
+        error_return
+    );
+    if (key.network == Network::Bitcoin) != (network == Network::Bitcoin) {
+        update_last_error(WalletError::InvalidPrivateKey(format!(
+            "key is not for {}",
+            network
+        )));
+        return error_return;
+    }
+
+    let wif = key.to_wif();
+    let descriptors = if key.compressed {
+        vec![
+            format!("wpkh({})", wif),
+            format!("sh(wpkh({}))", wif),
+            format!("pkh({})", wif),
+        ]
+    } else {
+        vec![format!("pkh({})", wif)]
+    };
+
+    let blockchain = unwrap_or_return!(
+        get_electrum_blockchain(tor_port, electrum_address, MIN_SYNC_STOP_GAP),
+        error_return
+    );
+
+    // Throwaway in-memory wallets, one per script the key can be behind
+    let mut funded = vec![];
+    for descriptor in descriptors {
+        let sweeper = unwrap_or_return!(
+            Wallet::new(
+                descriptor.as_str(),
+                None,
+                network,
+                MemoryDatabase::default()
+            ),
+            error_return
+        );
+        unwrap_or_return!(
+            sweeper.sync(&blockchain, SyncOptions::default()),
+            error_return
+        );
+
+        let utxos = unwrap_or_return!(sweeper.list_unspent(), error_return);
+        if !utxos.is_empty() {
+            funded.push((sweeper, utxos));
+        }
+    }
+
+    if funded.is_empty() {
+        update_last_error(WalletError::NothingToSweep);
+        return error_return;
+    }
+
+    // Everything from here on is offline, only now hold up the wallet
+    let wallet = unwrap_or_return!(wallet.lock(), error_return);
+    let destination =
+        unwrap_or_return!(wallet.get_address(AddressIndex::New), error_return).address;
+
+    // The first funded sweeper builds the transaction, the coins of the
+    // others go in as foreign ones
+    let (sweeper, _) = &funded[0];
+    let mut builder = sweeper.build_tx();
+    if let Some(lock_time) = randomized_lock_time(&wallet) {
+        builder.nlocktime(lock_time);
+    }
+    builder
+        .drain_wallet()
+        .drain_to(destination.script_pubkey())
+        .enable_rbf()
+        .fee_rate(fee_rate);
+    for (other, utxos) in funded.iter().skip(1) {
+        let satisfaction_weight = unwrap_or_return!(
+            other
+                .get_descriptor_for_keychain(KeychainKind::External)
+                .max_satisfaction_weight()
+                .map_err(|e| WalletError::Descriptor(e.to_string())),
+            error_return
+        );
+        for utxo in utxos {
+            let input = unwrap_or_return!(
+                other.get_psbt_input(utxo.clone(), None, false),
+                error_return
+            );
+            unwrap_or_return!(
+                builder.add_foreign_utxo(utxo.outpoint, input, satisfaction_weight),
+                error_return
+            );
+        }
+    }
+    let (mut psbt, details) = unwrap_or_return!(builder.finish(), error_return);
+
+    unwrap_or_return!(sign_sweep(&mut psbt, &key), error_return);
+
+    // None of the inputs are the wallet's, so build the result by hand
+    let tx = psbt.clone().extract_tx();
+    let fee = details.fee.unwrap_or(0);
+    let serialized = serialize(&psbt);
+    let (inputs, outputs) = psbt_breakdown(&wallet, &psbt);
+
+    Psbt {
+        sent: 0,
+        received: tx.output.iter().map(|o| o.value).sum(),
+        fee,
+        fee_known: details.fee.is_some(),
+        base64: CString::new(base64::encode(&serialized))
+            .unwrap()
+            .into_raw(),
+        txid: CString::new(tx.txid().to_hex()).unwrap().into_raw(),
+        raw_tx: CString::new(serialize(&tx).to_hex()).unwrap().into_raw(),
+        size: serialized.len() as u64,
+        fee_rate: fee as f64 / tx.vsize() as f64,
+        inputs_len: inputs.len() as u32,
+        inputs: Box::into_raw(inputs.into_boxed_slice()) as _,
+        outputs_len: outputs.len() as u32,
+        outputs: Box::into_raw(outputs.into_boxed_slice()) as _,
+    }
+}
+
+// Sign and finalize every input of a sweep, each paying to one of the scripts
+// `key` can be behind. BDK's single key signers sign every input with their
+// own sighash algorithm, which breaks legacy inputs mixed with segwit ones.
+fn sign_sweep(psbt: &mut PartiallySignedTransaction, key: &PrivateKey) -> Result<(), WalletError> {
+    let secp = Secp256k1::new();
+    let public_key = key.public_key(&secp);
+    let tx = psbt.unsigned_tx.clone();
+    let mut cache = SighashCache::new(&tx);
+
+    for (index, input) in psbt.inputs.iter_mut().enumerate() {
+        let vout = tx.input[index].previous_output.vout as usize;
+        let spent = input
+            .witness_utxo
+            .clone()
+            .or_else(|| {
+                input
+                    .non_witness_utxo
+                    .as_ref()
+                    .and_then(|prev| prev.output.get(vout).cloned())
+            })
+            .ok_or(WalletError::NothingSigned)?;
+
+        let sighash = if spent.script_pubkey.is_p2pkh() {
+            cache
+                .legacy_signature_hash(index, &spent.script_pubkey, EcdsaSighashType::All.to_u32())
+                .map_err(|_| WalletError::NothingSigned)?
+        } else {
+            let script_code = Script::new_p2pkh(&public_key.pubkey_hash());
+            cache
+                .segwit_signature_hash(index, &script_code, spent.value, EcdsaSighashType::All)
+                .map_err(|_| WalletError::NothingSigned)?
+        };
+        let message = Message::from_slice(&sighash[..]).unwrap();
+        let signature = EcdsaSig::sighash_all(secp.sign_ecdsa(&message, &key.inner)).to_vec();
+
+        if spent.script_pubkey.is_p2pkh() {
+            input.final_script_sig = Some(
+                ScriptBuilder::new()
+                    .push_slice(&signature)
+                    .push_key(&public_key)
+                    .into_script(),
+            );
+        } else {
+            if spent.script_pubkey.is_p2sh() {
+                let wpubkey_hash = public_key
+                    .wpubkey_hash()
+                    .ok_or(WalletError::NothingSigned)?;
+                let redeem_script = Script::new_v0_p2wpkh(&wpubkey_hash);
+                input.final_script_sig = Some(
+                    ScriptBuilder::new()
+                        .push_slice(redeem_script.as_bytes())
+                        .into_script(),
+                );
+            }
+            input.final_script_witness =
+                Some(Witness::from_vec(vec![signature, public_key.to_bytes()]));
+        }
+    }
+
+    Ok(())
+}
+
+// BIP125 rule 4: a replacement pays for its own relay at this rate on top of
+// the fees of what it replaces (Bitcoin Core's default incremental relay fee)
+const INCREMENTAL_RELAY_FEE_SAT_PER_VB: f64 = 1.0;
+
+// A replacement spending `inputs`, with the rest set up by `configure`. It is
+// built at `fee_rate` first only to learn its size, then again paying that
+// rate or the replaced transaction's `original_fee` plus relay for its own
+// size, whichever is more, as BIP125 requires.
+fn build_replacement<F>(
+    wallet: &Wallet<Tree>,
+    inputs: &[OutPoint],
+    fee_rate: FeeRate,
+    original_fee: u64,
+    configure: F,
+) -> Result<(PartiallySignedTransaction, TransactionDetails), bdk::Error>
+where
+    F: Fn(&mut TxBuilder<'_, Tree, DefaultCoinSelectionAlgorithm, CreateTx>),
+{
+    let mut builder = build_tx(wallet);
+    builder.add_utxos(inputs)?;
+    configure(&mut builder);
+    builder.only_witness_utxo().enable_rbf().fee_rate(fee_rate);
+    let (sizing, sized) = builder.finish()?;
+    let vsize = estimated_vsize(wallet, &sizing.unsigned_tx) as f64;
+
+    let required_fee = (sized.fee.unwrap_or(0) as f64)
+        .max(original_fee as f64 + vsize * INCREMENTAL_RELAY_FEE_SAT_PER_VB)
+        .ceil() as u64;
+
+    let mut builder = build_tx(wallet);
+    builder.add_utxos(inputs)?;
+    configure(&mut builder);
+    builder
+        .ordering(TxOrdering::Shuffle)
+        .only_witness_utxo()
+        .enable_rbf()
+        .fee_absolute(required_fee);
+    builder.finish()
+}
+
+/// Replace an unconfirmed transaction of ours that overpays with one paying a
+/// lower fee rate, if the replacement rules allow it.
+///
+/// BIP125 requires the replacement to pay at least the original absolute fee
+/// plus relay for its own size, so the fee in sats never goes down. The fee
+/// rate can only drop if the transaction gets smaller, which we try by paying
+/// the same recipients from as few of the original inputs as possible, with
+/// whatever is left going to change. This fails if no subset of inputs gets
+/// below the original fee rate, or if the original didn't signal RBF or spends
+/// coins that aren't ours.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_reduce_fee(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    txid: *const c_char,
+    new_fee_rate: f64,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let txid = unwrap_or_return!(CStr::from_ptr(txid).to_str(), error_return);
+    let txid = unwrap_or_return!(Txid::from_str(txid), error_return);
+
+    let details = match unwrap_or_return!(wallet.get_tx(&txid, true), error_return) {
+        Some(details) => details,
+        None => {
+            update_last_error(WalletError::TransactionNotFound(txid));
+            return error_return;
+        }
+    };
+
+    let fail = |reason: &str| {
+        update_last_error(WalletError::FeeReductionNotPossible(reason.to_string()));
+        psbt_error_return()
+    };
+
+    if details.confirmation_time.is_some() {
+        return fail("transaction is already confirmed");
+    }
+
+    let (original_fee, original_rate, tx) = match (
+        details.fee,
+        transaction_fee_rate(&details),
+        details.transaction,
+    ) {
+        (Some(fee), Some(rate), Some(tx)) => (fee, rate, tx),
+        _ => return fail("original fee is unknown"),
+    };
+
+    if !tx.input.iter().any(|i| i.sequence.is_rbf()) {
+        return fail("transaction doesn't signal replaceability");
+    }
+
+    let new_rate = unwrap_or_return!(
+        normalize_fee_rate(new_fee_rate, FeeUnit::BtcPerKvb),
+        error_return
+    );
+    if new_rate >= original_rate {
+        return fail("new fee rate is not lower than the current one");
+    }
+
+    let mut inputs = vec![];
+    for input in &tx.input {
+        let previous = unwrap_or_return!(
+            wallet
+                .database()
+                .get_previous_output(&input.previous_output),
+            error_return
+        );
+        let txout = match previous {
+            Some(txout) => txout,
+            None => return fail("transaction spends coins that aren't ours"),
+        };
+        if !unwrap_or_return!(wallet.is_mine(&txout.script_pubkey), error_return) {
+            return fail("transaction spends coins that aren't ours");
+        }
+        inputs.push((input.previous_output, txout.value));
+    }
+    inputs.sort_by_key(|(_, value)| Reverse(*value));
+
+    let mut recipients = vec![];
+    let mut change = None;
+    for output in &tx.output {
+        if unwrap_or_return!(wallet.is_mine(&output.script_pubkey), error_return) {
+            change.get_or_insert_with(|| output.script_pubkey.clone());
+        } else {
+            recipients.push((output.script_pubkey.clone(), output.value));
+        }
+    }
+    let change = match change {
+        Some(change) => change,
+        None => unwrap_or_return!(
+            wallet.get_internal_address(AddressIndex::LastUnused),
+            error_return
+        )
+        .address
+        .script_pubkey(),
+    };
+    let recipients_total: u64 = recipients.iter().map(|(_, value)| value).sum();
+    let fee_rate = FeeRate::from_sat_per_vb(new_rate as f32);
+
+    // Fewer inputs means a smaller transaction and so a lower rate for the
+    // same fee, take the first (smallest) set that works
+    for count in 1..=inputs.len() {
+        let selected: Vec<OutPoint> = inputs[..count].iter().map(|(o, _)| *o).collect();
+        let selected_value: u64 = inputs[..count].iter().map(|(_, value)| value).sum();
+        if selected_value <= recipients_total + original_fee {
+            continue;
+        }
+
+        let replacement =
+            build_replacement(&wallet, &selected, fee_rate, original_fee, |builder| {
+                builder
+                    .manually_selected_only()
+                    .set_recipients(recipients.clone())
+                    .drain_to(change.clone());
+            });
+        let (psbt, details) = match replacement {
+            Ok(replacement) => replacement,
+            Err(_) => continue,
+        };
+
+        let vsize = estimated_vsize(&wallet, &psbt.unsigned_tx) as f64;
+        if details.fee.unwrap_or(0) as f64 / vsize < original_rate {
+            return psbt_extract_details(&wallet, &psbt);
+        }
+    }
+
+    fail("replacement rules require a fee rate at least as high as the original")
+}
+
+// Pending labels of transactions that never go out are dropped after this long
+const PENDING_LABELS_MAX_AGE: u64 = 30 * 24 * 60 * 60;
+
+// Pending labels are stored behind the unix time they were queued at
+fn insert_pending_label(
+    db: &Tree,
+    key: &str,
+    label: &str,
+    now: u64,
+) -> Result<(), bdk::sled::Error> {
+    let mut value = now.to_be_bytes().to_vec();
+    value.extend_from_slice(label.as_bytes());
+    db.insert(key, value)?;
+    Ok(())
+}
+
+fn expire_pending_labels(db: &Tree, now: u64) -> Result<(), bdk::sled::Error> {
+    for entry in db.scan_prefix(PENDING_LABELS_PREFIX) {
+        let (key, value) = entry?;
+        let queued = value
+            .get(..8)
+            .and_then(|t| t.try_into().ok())
+            .map(u64::from_be_bytes);
+        if queued.map_or(true, |q| now.saturating_sub(q) > PENDING_LABELS_MAX_AGE) {
+            db.remove(key)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Pay several recipients in one transaction. Recipient labels are kept as
+/// pending and only become transaction and output labels once the transaction
+/// goes out through `wallet_broadcast_labeled_tx`. Pending labels of
+/// transactions that aren't broadcast within 30 days are dropped.
+///
+/// With a non-null `drain_to` the whole wallet is spent: recipients get their
+/// amounts and everything left after the fee goes to `drain_to`.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_create_psbt_multi(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    recipients: *const Recipient,
+    recipients_len: u32,
+    fee_rate: f64,
+    drain_to: *const c_char,
+    ordering: OutputOrdering,
+    spend_unconfirmed: bool,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+
+    let recipients: &[Recipient] = if recipients_len == 0 || recipients.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(recipients, recipients_len as usize)
+    };
+
+    let mut outputs = vec![];
+    let mut labels = vec![];
+    for recipient in recipients {
+        let address = unwrap_or_return!(CStr::from_ptr(recipient.address).to_str(), error_return);
+        let address = unwrap_or_return!(parse_address(&wallet, address), error_return);
+
+        let label = if recipient.label.is_null() {
+            None
+        } else {
+            Some(
+                unwrap_or_return!(CStr::from_ptr(recipient.label).to_str(), error_return)
+                    .to_string(),
+            )
+        };
+
+        if recipient.amount == 0 {
+            update_last_error(WalletError::ZeroAmount(address.to_string()));
+            return error_return;
+        }
+
+        outputs.push((address.script_pubkey(), recipient.amount));
+        labels.push(label);
+    }
+    unwrap_or_return!(check_recipients_dust(&wallet, &outputs), error_return);
+
+    let drain_to = if drain_to.is_null() {
+        None
+    } else {
+        let address = unwrap_or_return!(CStr::from_ptr(drain_to).to_str(), error_return);
+        Some(unwrap_or_return!(parse_address(&wallet, address), error_return).script_pubkey())
+    };
+
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), error_return);
+
+    let build = |unspendable: Vec<OutPoint>| {
+        let mut builder = build_tx(&wallet);
+        builder
+            .ordering(ordering.into())
+            .only_witness_utxo()
+            .unspendable(unspendable)
+            .set_recipients(outputs.clone())
+            .enable_rbf()
+            .fee_rate(fee_rate);
+        if let Some(drain_to) = &drain_to {
+            builder.drain_wallet().drain_to(drain_to.clone());
+        }
+        builder.finish()
+    };
+
+    let psbt = unwrap_or_return!(
+        finish_with_coin_groups(&wallet, fee_rate, spend_unconfirmed, build),
+        error_return
+    );
+
+    // Outputs are shuffled, so find each recipient's vout again
+    let txid = psbt.unsigned_tx.txid();
+    let mut claimed = HashSet::new();
+    let mut tx_labels = vec![];
+    let db = wallet.database();
+    let now = unix_time_now();
+    unwrap_or_return!(expire_pending_labels(&db, now), error_return);
+    for ((script, amount), label) in outputs.iter().zip(labels) {
+        let label = match label {
+            None => continue,
+            Some(label) => label,
+        };
+
+        let vout = psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .enumerate()
+            .position(|(vout, o)| {
+                &o.script_pubkey == script && o.value == *amount && !claimed.contains(&vout)
+            });
+
+        if let Some(vout) = vout {
+            claimed.insert(vout);
+            let key = format!("{}{}:{}", PENDING_LABELS_PREFIX, txid, vout);
+            unwrap_or_return!(insert_pending_label(&db, &key, &label, now), error_return);
+        }
+
+        tx_labels.push(label);
+    }
+
+    if !tx_labels.is_empty() {
+        let key = format!("{}{}", PENDING_LABELS_PREFIX, txid);
+        unwrap_or_return!(
+            insert_pending_label(&db, &key, &tx_labels.join(", "), now),
+            error_return
+        );
+    }
+
+    psbt_extract_details(&wallet, &psbt)
+}
+
+/// `wallet_create_psbt_multi` for callers holding parallel address and amount
+/// arrays. The same address may appear more than once, each gets its own output.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_create_batch_psbt(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    addresses: *const *const c_char,
+    amounts: *const u64,
+    len: u32,
+    fee_rate: f64,
+    ordering: OutputOrdering,
+    spend_unconfirmed: bool,
+) -> Psbt {
+    let (addresses, amounts): (&[*const c_char], &[u64]) =
+        if len == 0 || addresses.is_null() || amounts.is_null() {
+            (&[], &[])
+        } else {
+            (
+                std::slice::from_raw_parts(addresses, len as usize),
+                std::slice::from_raw_parts(amounts, len as usize),
+            )
+        };
+
+    let recipients: Vec<Recipient> = addresses
+        .iter()
+        .zip(amounts)
+        .map(|(address, amount)| Recipient {
+            address: *address,
+            amount: *amount,
+            label: ptr::null(),
+        })
+        .collect();
+
+    wallet_create_psbt_multi(
+        wallet,
+        recipients.as_ptr(),
+        recipients.len() as u32,
+        fee_rate,
+        ptr::null(),
+        ordering,
+        spend_unconfirmed,
+    )
+}
+
+/// Details of a PSBT, finalized if its signatures allow. `raw_tx` is only set
+/// once finalized, so a PSBT still waiting on signers comes back with it null
+/// rather than with an unsigned transaction that looks ready to broadcast.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_decode_psbt(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    psbt: *const c_char,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let psbt = unwrap_or_return!(CStr::from_ptr(psbt).to_str(), error_return);
+    let psbt = unwrap_or_return!(decode_psbt_base64(psbt), error_return);
+
+    let secp = Secp256k1::verification_only();
+    let (psbt, finalized) = match PsbtExt::finalize(psbt, &secp) {
+        Ok(psbt) => (psbt, true),
+        Err((psbt, _)) => (psbt, false),
+    };
+
+    let mut details = psbt_extract_details(&wallet, &psbt);
+    if !finalized {
+        free_c_string(details.raw_tx);
+        details.raw_tx = ptr::null();
+    }
+
+    details
+}
+
+fn psbt_signature_count(psbt: &PartiallySignedTransaction) -> usize {
+    psbt.inputs
+        .iter()
+        .map(|i| i.partial_sigs.len() + i.tap_script_sigs.len() + i.tap_key_sig.is_some() as usize)
+        .sum()
+}
+
+fn set_psbt_sighash(psbt: &mut PartiallySignedTransaction, sighash: SighashType) {
+    for input in psbt.inputs.iter_mut() {
+        input.sighash_type = sighash.psbt_sighash();
+    }
+}
+
+// Every PSBT built here only carries witness_utxo, which BDK won't sign
+// without trust_witness_utxo. That's safe once each of our inputs claims
+// exactly the output the wallet recorded for its outpoint.
+fn check_own_witness_utxos(
+    wallet: &Wallet<Tree>,
+    psbt: &PartiallySignedTransaction,
+) -> Result<(), WalletError> {
+    for (index, (input, txin)) in psbt.inputs.iter().zip(&psbt.unsigned_tx.input).enumerate() {
+        let utxo = match (&input.witness_utxo, &input.non_witness_utxo) {
+            (Some(utxo), None) => utxo,
+            _ => continue,
+        };
+        if !wallet.is_mine(&utxo.script_pubkey)? {
+            continue;
+        }
+
+        let recorded = wallet
+            .database()
+            .get_previous_output(&txin.previous_output)?;
+        if recorded.as_ref() != Some(utxo) {
+            return Err(WalletError::PsbtTampered(format!(
+                "input {} doesn't match the coin the wallet holds",
+                index
+            )));
+        }
+    }
+    Ok(())
+}
+
+// Whether any signature was added and whether the PSBT got finalized. With
+// `SighashType::Default` the inputs keep whatever sighash the PSBT asks for,
+// and BDK refuses to sign anything but ALL/SIGHASH_DEFAULT.
+fn sign_psbt(
+    wallet: &Wallet<Tree>,
+    psbt: &mut PartiallySignedTransaction,
+    finalize: bool,
+    sighash: SighashType,
+) -> Result<(bool, bool), WalletError> {
+    if !wallet_can_sign(wallet) {
+        return Err(WalletError::NotSigner);
+    }
+
+    if sighash != SighashType::Default {
+        set_psbt_sighash(psbt, sighash);
+    }
+    check_own_witness_utxos(wallet, psbt)?;
+
+    // Finalizing clears the signatures, count them before that
+    let signatures_before = psbt_signature_count(psbt);
+    let sign_options = SignOptions {
+        try_finalize: false,
+        trust_witness_utxo: true,
+        allow_all_sighashes: sighash != SighashType::Default,
+        ..Default::default()
+    };
+    wallet.sign(psbt, sign_options)?;
+    let signed = psbt_signature_count(psbt) > signatures_before;
+
+    let finalized = finalize && wallet.finalize_psbt(psbt, SignOptions::default())?;
+    Ok((signed, finalized))
+}
+
+/// Sign every input we hold keys for and, with `finalize`, finalize the PSBT
+/// if that completes it. `raw_tx` is only set once finalized, so a multisig
+/// PSBT still waiting on other signers comes back with it null.
+///
+/// A PSBT we couldn't add any signature to (e.g. none of the inputs are ours)
+/// is still returned, with `ErrorCode::NothingSigned` left in LAST_ERROR.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_sign_psbt(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    psbt: *const c_char,
+    finalize: bool,
+) -> Psbt {
+    wallet_sign_psbt_with_sighash(wallet, psbt, finalize, SighashType::Default)
+}
+
+/// `wallet_sign_psbt` signing every input with `sighash` instead of the
+/// default, as payjoin and other collaborative constructions need. The PSBT's
+/// inputs are updated to say so for the other signers.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_sign_psbt_with_sighash(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    psbt: *const c_char,
+    finalize: bool,
+    sighash: SighashType,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let psbt = unwrap_or_return!(CStr::from_ptr(psbt).to_str(), error_return);
+    let mut psbt = unwrap_or_return!(decode_psbt_base64(psbt), error_return);
+
+    let (signed, finalized) = unwrap_or_return!(
+        sign_psbt(&wallet, &mut psbt, finalize, sighash),
+        error_return
+    );
+
+    let mut details = psbt_extract_details(&wallet, &psbt);
+    if !finalized {
+        free_c_string(details.raw_tx);
+        details.raw_tx = ptr::null();
+    }
+
+    if !signed {
+        update_last_error(WalletError::NothingSigned);
+    }
+
+    details
+}
+
+/// Ask the signers of the base64 `psbt` for `sighash` on every input, e.g.
+/// right after creating it and before handing it to Passport. Null on error.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_set_psbt_sighash(
+    psbt: *const c_char,
+    sighash: SighashType,
+) -> *const c_char {
+    let psbt = unwrap_or_return!(CStr::from_ptr(psbt).to_str(), ptr::null());
+    let mut psbt = unwrap_or_return!(decode_psbt_base64(psbt), ptr::null());
+
+    set_psbt_sighash(&mut psbt, sighash);
+    CString::new(base64::encode(&serialize(&psbt)))
+        .unwrap()
+        .into_raw()
+}
+
+fn psbt_bytes(psbt: &PartiallySignedTransaction) -> PsbtBytes {
+    let data = serialize(psbt).into_boxed_slice();
+    PsbtBytes {
+        len: data.len() as u32,
+        data: Box::into_raw(data) as _,
+    }
+}
+
+unsafe fn psbt_bytes_arg<'a>(data: *const u8, len: u32) -> &'a [u8] {
+    if len == 0 || data.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, len as usize)
+    }
+}
+
+/// `wallet_decode_psbt` for a binary PSBT of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_decode_psbt_bytes(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    data: *const u8,
+    len: u32,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let psbt = unwrap_or_return!(decode_psbt_bytes(psbt_bytes_arg(data, len)), error_return);
+
+    let secp = Secp256k1::verification_only();
+    // A PSBT that isn't fully signed yet won't finalize, show it as-is
+    let finalized_psbt = PsbtExt::finalize(psbt, &secp).unwrap_or_else(|(psbt, _)| psbt);
+    psbt_extract_details(&wallet, &finalized_psbt)
+}
+
+/// `wallet_sign_psbt` for a binary PSBT, returning the signed PSBT the same
+/// way. Fails with a null `data` rather than returning an unsigned PSBT.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_sign_psbt_bytes(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    data: *const u8,
+    len: u32,
+    finalize: bool,
+) -> PsbtBytes {
+    let error_return = PsbtBytes {
+        len: 0,
+        data: ptr::null(),
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let mut psbt = unwrap_or_return!(decode_psbt_bytes(psbt_bytes_arg(data, len)), error_return);
+
+    let (signed, _) = unwrap_or_return!(
+        sign_psbt(&wallet, &mut psbt, finalize, SighashType::Default),
+        error_return
+    );
+    if !signed {
+        update_last_error(WalletError::NothingSigned);
+        return error_return;
+    }
+
+    psbt_bytes(&psbt)
+}
+
+/// Finalize a binary PSBT whose inputs are all signed, e.g. by Passport,
+/// ready for `wallet_decode_psbt_bytes` to extract the transaction.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_finalize_psbt_bytes(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    data: *const u8,
+    len: u32,
+) -> PsbtBytes {
+    let error_return = PsbtBytes {
+        len: 0,
+        data: ptr::null(),
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let mut psbt = unwrap_or_return!(decode_psbt_bytes(psbt_bytes_arg(data, len)), error_return);
+
+    let finalized = unwrap_or_return!(
+        wallet.finalize_psbt(&mut psbt, SignOptions::default()),
+        error_return
+    );
+    if !finalized {
+        update_last_error(WalletError::PsbtIncomplete);
+        return error_return;
+    }
+
+    psbt_bytes(&psbt)
+}
+
+/// Convert the base64 `psbt` to `version` 0 or 2 (BIP 370). Every function
+/// taking a PSBT reads both versions but hands back version 0, so v2 callers
+/// convert the result back with this. Null on error.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_psbt_convert(psbt: *const c_char, version: u32) -> *const c_char {
+    let psbt = unwrap_or_return!(CStr::from_ptr(psbt).to_str(), ptr::null());
+    let psbt = unwrap_or_return!(decode_psbt_base64(psbt), ptr::null());
+
+    let data = match version {
+        0 => serialize(&psbt),
+        2 => unwrap_or_return!(psbt_v0_to_v2(&psbt), ptr::null()),
+        _ => {
+            update_last_error(WalletError::UnsupportedPsbtVersion(version));
+            return ptr::null();
+        }
+    };
+
+    CString::new(base64::encode(&data)).unwrap().into_raw()
+}
+
+const UR_PSBT_TYPE: &str = "crypto-psbt";
+
+// crypto-psbt is the PSBT as a CBOR byte string
+fn cbor_wrap_bytes(data: &[u8]) -> Vec<u8> {
+    let len = data.len();
+    let mut cbor = if len < 24 {
+        vec![0x40 | len as u8]
+    } else if len <= u8::MAX as usize {
+        vec![0x58, len as u8]
+    } else if len <= u16::MAX as usize {
+        let mut header = vec![0x59];
+        header.extend_from_slice(&(len as u16).to_be_bytes());
+        header
+    } else {
+        let mut header = vec![0x5a];
+        header.extend_from_slice(&(len as u32).to_be_bytes());
+        header
+    };
+    cbor.extend_from_slice(data);
+    cbor
+}
+
+fn cbor_unwrap_bytes(cbor: &[u8]) -> Result<&[u8], WalletError> {
+    let malformed = || WalletError::InvalidUr("not a CBOR byte string".to_string());
+
+    let (&first, rest) = cbor.split_first().ok_or_else(malformed)?;
+    if first & 0xe0 != 0x40 {
+        return Err(malformed());
+    }
+
+    let (len, data) = match first & 0x1f {
+        len @ 0..=23 => (len as usize, rest),
+        24 if !rest.is_empty() => (rest[0] as usize, &rest[1..]),
+        25 if rest.len() >= 2 => (u16::from_be_bytes([rest[0], rest[1]]) as usize, &rest[2..]),
+        26 if rest.len() >= 4 => (
+            u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize,
+            &rest[4..],
+        ),
+        _ => return Err(malformed()),
+    };
+
+    if data.len() != len {
+        return Err(malformed());
+    }
+    Ok(data)
+}
+
+/// The base64 `psbt` as a single part `ur:crypto-psbt/...` string, as Passport
+/// and other airgapped signers read it from a QR code. Null on error.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_psbt_to_ur(psbt: *const c_char) -> *const c_char {
+    let psbt = unwrap_or_return!(CStr::from_ptr(psbt).to_str(), ptr::null());
+    let psbt = unwrap_or_return!(decode_psbt_base64(psbt), ptr::null());
+
+    let cbor = cbor_wrap_bytes(&serialize(&psbt));
+    let encoded = ur::ur::encode(&cbor, &ur::ur::Type::Custom(UR_PSBT_TYPE));
+    CString::new(encoded).unwrap().into_raw()
+}
+
+/// Base64 PSBT from a single part `ur:crypto-psbt/...` string. Null on error,
+/// multi part URs need the animated QR decoder.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_psbt_from_ur(ur: *const c_char) -> *const c_char {
+    let ur = unwrap_or_return!(CStr::from_ptr(ur).to_str(), ptr::null());
+    // QR codes carry URs upper case to use the denser alphanumeric mode
+    let ur = ur.trim().to_lowercase();
+
+    if !ur.starts_with(&format!("ur:{}/", UR_PSBT_TYPE)) {
+        update_last_error(WalletError::InvalidUr(format!("not a {}", UR_PSBT_TYPE)));
+        return ptr::null();
+    }
+
+    let cbor = match ur::ur::decode(&ur) {
+        Ok((ur::ur::Kind::SinglePart, cbor)) => cbor,
+        Ok((ur::ur::Kind::MultiPart, _)) => {
+            update_last_error(WalletError::InvalidUr(
+                "part of a multi part UR".to_string(),
+            ));
+            return ptr::null();
+        }
+        Err(e) => {
+            update_last_error(WalletError::InvalidUr(e.to_string()));
+            return ptr::null();
+        }
+    };
+
+    let psbt = unwrap_or_return!(psbt_from_ur_cbor(&cbor), ptr::null());
+    CString::new(base64::encode(&serialize(&psbt)))
+        .unwrap()
+        .into_raw()
+}
+
+fn psbt_from_ur_cbor(cbor: &[u8]) -> Result<PartiallySignedTransaction, WalletError> {
+    decode_psbt_bytes(cbor_unwrap_bytes(cbor)?)
+}
+
+/// Split the base64 `psbt` into `ur:crypto-psbt/<seq>-<total>/...` parts of at
+/// most `max_fragment_len` bytes each, for showing as an animated QR code.
+/// The first `total` parts carry the PSBT as is, the same number of fountain
+/// coded parts follow so a scanner that missed some frames catches up quicker.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_psbt_to_ur_parts(
+    psbt: *const c_char,
+    max_fragment_len: u32,
+) -> QrParts {
+    let error_return = QrParts {
+        parts_len: 0,
+        parts: ptr::null(),
+    };
+
+    let psbt = unwrap_or_return!(CStr::from_ptr(psbt).to_str(), error_return);
+    let psbt = unwrap_or_return!(decode_psbt_base64(psbt), error_return);
+    let cbor = cbor_wrap_bytes(&serialize(&psbt));
+
+    let mut encoder = match ur::ur::Encoder::new(&cbor, max_fragment_len as usize, UR_PSBT_TYPE) {
+        Ok(encoder) => encoder,
+        Err(e) => {
+            update_last_error(WalletError::InvalidUr(e.to_string()));
+            return error_return;
+        }
+    };
+
+    let mut parts: Vec<*const c_char> = vec![];
+    for _ in 0..encoder.fragment_count() * 2 {
+        match encoder.next_part() {
+            Ok(part) => parts.push(CString::new(part).unwrap().into_raw()),
+            Err(e) => {
+                for part in parts {
+                    free_c_string(part);
+                }
+                update_last_error(WalletError::InvalidUr(e.to_string()));
+                return error_return;
+            }
+        }
+    }
+
+    QrParts {
+        parts_len: parts.len() as u32,
+        parts: Box::into_raw(parts.into_boxed_slice()) as _,
+    }
+}
+
+/// Reassembles a PSBT from scanned UR frames, see `wallet_ur_decoder_receive`.
+pub struct UrDecoder {
+    decoder: ur::ur::Decoder,
+    // Sequence numbers received so far and how many parts there are, for
+    // reporting progress
+    seen: HashSet<u32>,
+    total: u32,
+    psbt: Option<PartiallySignedTransaction>,
+}
+
+#[no_mangle]
+pub extern "C" fn wallet_ur_decoder_new() -> *mut UrDecoder {
+    Box::into_raw(Box::new(UrDecoder {
+        decoder: ur::ur::Decoder::default(),
+        seen: HashSet::new(),
+        total: 0,
+        psbt: None,
+    }))
+}
+
+/// Feed one scanned QR frame to the decoder. Returns progress from 0 to 1,
+/// reaching 1 once `wallet_ur_decoder_result` has the PSBT, or -1 with
+/// LAST_ERROR set if the frame isn't part of a crypto-psbt UR. Frames can come
+/// in any order and repeat. A single part UR completes the decoder at once.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_ur_decoder_receive(
+    decoder: *mut UrDecoder,
+    part: *const c_char,
+) -> f64 {
+    let decoder = match decoder.as_mut() {
+        Some(decoder) => decoder,
+        None => return -1.0,
+    };
+    if decoder.psbt.is_some() {
+        return 1.0;
+    }
+
+    let part = unwrap_or_return!(CStr::from_ptr(part).to_str(), -1.0);
+    let part = part.trim().to_lowercase();
+
+    let path: Vec<&str> = match part.strip_prefix(&format!("ur:{}/", UR_PSBT_TYPE)) {
+        Some(rest) => rest.split('/').collect(),
+        None => {
+            update_last_error(WalletError::InvalidUr(format!("not a {}", UR_PSBT_TYPE)));
+            return -1.0;
+        }
+    };
+
+    if path.len() == 1 {
+        let cbor = match ur::ur::decode(&part) {
+            Ok((_, cbor)) => cbor,
+            Err(e) => {
+                update_last_error(WalletError::InvalidUr(e.to_string()));
+                return -1.0;
+            }
+        };
+        decoder.psbt = Some(unwrap_or_return!(psbt_from_ur_cbor(&cbor), -1.0));
+        return 1.0;
+    }
+
+    if let Err(e) = decoder.decoder.receive(&part) {
+        update_last_error(WalletError::InvalidUr(e.to_string()));
+        return -1.0;
+    }
+
+    // "<seq>-<total>". Fountain coded parts have a seq past total and mix
+    // several parts together, so only the plain ones count towards progress.
+    let sequence: Vec<u32> = path[0].split('-').filter_map(|n| n.parse().ok()).collect();
+    if let [seq, total] = sequence[..] {
+        if seq <= total {
+            decoder.seen.insert(seq);
+        }
+        decoder.total = total;
+    }
+
+    if !decoder.decoder.complete() {
+        if decoder.total == 0 {
+            return 0.0;
+        }
+        // Close enough, fountain codes may need a few frames more than total
+        return (decoder.seen.len() as f64 / decoder.total as f64).min(0.99);
+    }
+
+    let cbor = match decoder.decoder.message() {
+        Ok(Some(cbor)) => cbor,
+        Ok(None) => return 0.99,
+        Err(e) => {
+            update_last_error(WalletError::InvalidUr(e.to_string()));
+            return -1.0;
+        }
+    };
+    decoder.psbt = Some(unwrap_or_return!(psbt_from_ur_cbor(&cbor), -1.0));
+    1.0
+}
+
+/// The base64 PSBT once the decoder is complete, null before that.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_ur_decoder_result(decoder: *const UrDecoder) -> *const c_char {
+    match decoder.as_ref().and_then(|d| d.psbt.as_ref()) {
+        Some(psbt) => CString::new(base64::encode(&serialize(psbt)))
+            .unwrap()
+            .into_raw(),
+        None => ptr::null(),
+    }
+}
+
+const BBQR_HEADER_LEN: usize = 8;
+// Two base 36 digits for the part count
+const BBQR_MAX_PARTS: usize = 36 * 36 - 1;
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// RFC 4648 without padding, as BBQr uses it
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer.iter().fold(0u64, |acc, b| acc << 8 | *b as u64);
+
+        let chars = (chunk.len() * 8 + 4) / 5;
+        for i in 0..chars {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            encoded.push(BASE32_ALPHABET[index as usize] as char);
+        }
+    }
+    encoded
+}
+
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut data = vec![];
+    let (mut bits, mut bit_count) = (0u32, 0);
+    for c in encoded.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|a| *a == c)? as u32;
+        bits = bits << 5 | value;
+        bit_count += 5;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            data.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+    Some(data)
+}
+
+fn base36_pair(n: usize) -> String {
+    let digit = |d: usize| {
+        std::char::from_digit(d as u32, 36)
+            .unwrap()
+            .to_ascii_uppercase()
+    };
+    format!("{}{}", digit(n / 36), digit(n % 36))
+}
+
+/// Split `data` into BBQr frames of at most `max_part_chars` characters each,
+/// for Coldcard and other wallets that scan BBQr rather than UR. Data goes in
+/// uncompressed base32, which every BBQr reader understands.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_bbqr_encode(
+    data: *const u8,
+    len: u32,
+    file_type: BbqrType,
+    max_part_chars: u32,
+) -> QrParts {
+    let error_return = QrParts {
+        parts_len: 0,
+        parts: ptr::null(),
+    };
+
+    let data = psbt_bytes_arg(data, len);
+    if data.is_empty() {
+        update_last_error(WalletError::InvalidBbqr("nothing to encode".to_string()));
+        return error_return;
+    }
+
+    // Parts other than the last must hold whole 5 byte groups
+    let max_part_chars = max_part_chars as usize;
+    let chars_per_part = max_part_chars.saturating_sub(BBQR_HEADER_LEN) / 8 * 8;
+    if chars_per_part == 0 {
+        update_last_error(WalletError::InvalidBbqr(format!(
+            "parts of {} characters are too small",
+            max_part_chars
+        )));
+        return error_return;
+    }
+
+    let encoded = base32_encode(data);
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(chars_per_part).collect();
+    if chunks.len() > BBQR_MAX_PARTS {
+        update_last_error(WalletError::InvalidBbqr(format!(
+            "{} parts needed, at most {} fit",
+            chunks.len(),
+            BBQR_MAX_PARTS
+        )));
+        return error_return;
+    }
+
+    let parts: Vec<*const c_char> = chunks
+        .iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let part = format!(
+                "B$2{}{}{}{}",
+                file_type.code(),
+                base36_pair(chunks.len()),
+                base36_pair(index),
+                std::str::from_utf8(chunk).unwrap()
+            );
+            CString::new(part).unwrap().into_raw() as *const c_char
+        })
+        .collect();
+
+    QrParts {
+        parts_len: parts.len() as u32,
+        parts: Box::into_raw(parts.into_boxed_slice()) as _,
+    }
+}
+
+/// Reassembles data from scanned BBQr frames, see `wallet_bbqr_decoder_receive`.
+pub struct BbqrDecoder {
+    encoding: char,
+    file_type: Option<BbqrType>,
+    parts: Vec<Option<String>>,
+}
+
+#[no_mangle]
+pub extern "C" fn wallet_bbqr_decoder_new() -> *mut BbqrDecoder {
+    Box::into_raw(Box::new(BbqrDecoder {
+        encoding: ' ',
+        file_type: None,
+        parts: vec![],
+    }))
+}
+
+/// Feed one scanned BBQr frame to the decoder. Returns progress from 0 to 1,
+/// reaching 1 once every part is in, or -1 with LAST_ERROR set if the frame
+/// isn't BBQr or belongs to a different one than the frames before it.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_bbqr_decoder_receive(
+    decoder: *mut BbqrDecoder,
+    part: *const c_char,
+) -> f64 {
+    let decoder = match decoder.as_mut() {
+        Some(decoder) => decoder,
+        None => return -1.0,
+    };
+    let part = unwrap_or_return!(CStr::from_ptr(part).to_str(), -1.0).trim();
+
+    let invalid = |reason: &str| {
+        update_last_error(WalletError::InvalidBbqr(reason.to_string()));
+        -1.0
+    };
+
+    if !part.is_ascii() {
+        return invalid("not a BBQr frame");
+    }
+    if part.len() < BBQR_HEADER_LEN {
+        return invalid("frame too short");
+    }
+    let (header, body) = part.split_at(BBQR_HEADER_LEN);
+    let header: Vec<char> = header.chars().collect();
+    if header[0] != 'B' || header[1] != '$' {
+        return invalid("not a BBQr frame");
+    }
+
+    let encoding = header[2];
+    if !['H', '2', 'Z'].contains(&encoding) {
+        return invalid("unknown encoding");
+    }
+    let file_type = match BbqrType::from_code(header[3]) {
+        Some(file_type) => file_type,
+        None => return invalid("unsupported file type"),
+    };
+    let base36 = |digits: &[char]| {
+        let digits: String = digits.iter().collect();
+        usize::from_str_radix(&digits, 36).ok()
+    };
+    let (total, index) = match (base36(&header[4..6]), base36(&header[6..8])) {
+        (Some(total), Some(index)) if total > 0 && index < total => (total, index),
+        _ => return invalid("bad part numbers"),
+    };
+
+    if decoder.parts.is_empty() {
+        decoder.encoding = encoding;
+        decoder.file_type = Some(file_type);
+        decoder.parts = vec![None; total];
+    } else if decoder.encoding != encoding
+        || decoder.file_type != Some(file_type)
+        || decoder.parts.len() != total
+    {
+        return invalid("frame from a different BBQr");
+    }
+
+    decoder.parts[index] = Some(body.to_string());
+    let received = decoder.parts.iter().filter(|p| p.is_some()).count();
+    received as f64 / total as f64
+}
+
+/// The decoded data once every part is in, null `data` before that or if the
+/// parts don't decode.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_bbqr_decoder_result(decoder: *const BbqrDecoder) -> BbqrData {
+    let error_return = BbqrData {
+        file_type: BbqrType::Text,
+        len: 0,
+        data: ptr::null(),
+    };
+
+    let decoder = match decoder.as_ref() {
+        Some(decoder) => decoder,
+        None => return error_return,
+    };
+    let file_type = match decoder.file_type {
+        Some(file_type) if decoder.parts.iter().all(|p| p.is_some()) => file_type,
+        _ => return error_return,
+    };
+    let encoded: String = decoder.parts.iter().flatten().map(|p| p.as_str()).collect();
+
+    let decoded = match decoder.encoding {
+        'H' => hex::decode(&encoded).ok(),
+        _ => base32_decode(&encoded),
+    };
+    let mut data = match decoded {
+        Some(data) => data,
+        None => {
+            update_last_error(WalletError::InvalidBbqr("parts don't decode".to_string()));
+            return error_return;
+        }
+    };
+
+    if decoder.encoding == 'Z' {
+        data = match miniz_oxide::inflate::decompress_to_vec(&data) {
+            Ok(data) => data,
+            Err(e) => {
+                update_last_error(WalletError::InvalidBbqr(format!("{:?}", e)));
+                return error_return;
+            }
+        };
+    }
+
+    let data = data.into_boxed_slice();
+    BbqrData {
+        file_type,
+        len: data.len() as u32,
+        data: Box::into_raw(data) as _,
+    }
+}
+
+/// Merge the signatures of several copies of the same PSBT (base64), e.g. one
+/// from each cosigner of a multisig. If that completes it the result comes
+/// back finalized, otherwise `raw_tx` is null as with `wallet_sign_psbt`.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_combine_psbts(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    psbts: *const *const c_char,
+    psbts_len: u32,
+) -> CombinedPsbt {
+    let error_return = CombinedPsbt {
+        psbt: psbt_error_return(),
+        complete: false,
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+
+    let psbts: &[*const c_char] = if psbts_len == 0 || psbts.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(psbts, psbts_len as usize)
+    };
+
+    let mut combined: Option<PartiallySignedTransaction> = None;
+    for psbt in psbts {
+        let psbt = unwrap_or_return!(CStr::from_ptr(*psbt).to_str(), error_return);
+        let psbt = unwrap_or_return!(decode_psbt_base64(psbt), error_return);
+        match combined.as_mut() {
+            None => combined = Some(psbt),
+            // Fails unless both are for the same unsigned transaction
+            Some(combined) => unwrap_or_return!(combined.combine(psbt), error_return),
+        }
+    }
+    let combined = match combined {
+        Some(combined) => combined,
+        None => {
+            update_last_error(WalletError::NotAPsbt);
+            return error_return;
+        }
+    };
+
+    let secp = Secp256k1::verification_only();
+    match PsbtExt::finalize(combined, &secp) {
+        Ok(finalized) => CombinedPsbt {
+            psbt: psbt_extract_details(&wallet, &finalized),
+            complete: true,
+        },
+        Err((combined, _)) => {
+            let mut details = psbt_extract_details(&wallet, &combined);
+            free_c_string(details.raw_tx);
+            details.raw_tx = ptr::null();
+            CombinedPsbt {
+                psbt: details,
+                complete: false,
+            }
+        }
+    }
+}
+
+// k if the input's script is a plain k-of-n (multi, sortedmulti or multi_a in
+// any leaf), 1 for single key scripts and 0 for other policies, e.g. ones
+// with timelocks or hash locks, where no single threshold applies
+fn signature_threshold(input: &bdk::bitcoin::util::psbt::Input) -> u32 {
+    use bdk::bitcoin::{PublicKey, XOnlyPublicKey};
+    use bdk::miniscript::{
+        Legacy, Miniscript, MiniscriptKey, ScriptContext, Segwitv0, Tap, Terminal,
+    };
+
+    fn threshold<Pk: MiniscriptKey, Ctx: ScriptContext>(ms: Miniscript<Pk, Ctx>) -> Option<usize> {
+        match ms.node {
+            Terminal::Multi(k, _) | Terminal::MultiA(k, _) => Some(k),
+            Terminal::Check(inner) => match &inner.node {
+                Terminal::PkK(_) | Terminal::PkH(_) => Some(1),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    let k = if let Some(script) = &input.witness_script {
+        Miniscript::<PublicKey, Segwitv0>::parse_insane(script)
+            .ok()
+            .and_then(threshold)
+    } else if let Some(script) = &input.redeem_script {
+        if script.is_v0_p2wpkh() {
+            Some(1)
+        } else {
+            Miniscript::<PublicKey, Legacy>::parse_insane(script)
+                .ok()
+                .and_then(threshold)
+        }
+    } else if !input.tap_scripts.is_empty() {
+        // The cheapest leaf to satisfy
+        input
+            .tap_scripts
+            .values()
+            .filter_map(|(script, _)| Miniscript::<XOnlyPublicKey, Tap>::parse_insane(script).ok())
+            .filter_map(threshold)
+            .min()
+    } else {
+        // wpkh, pkh and taproot key path spends
+        Some(1)
+    };
+
+    k.unwrap_or(0) as u32
+}
+
+fn input_signing_status(psbt: &PartiallySignedTransaction, index: usize) -> InputSigningStatus {
+    let input = &psbt.inputs[index];
+    let finalized = input.final_script_sig.is_some() || input.final_script_witness.is_some();
+
+    let mut signed = vec![];
+    let mut unsigned = vec![];
+    for (key, (fingerprint, _)) in &input.bip32_derivation {
+        if input.partial_sigs.keys().any(|k| k.inner == *key) {
+            signed.push(fingerprint.to_string());
+        } else {
+            unsigned.push(fingerprint.to_string());
+        }
+    }
+    for (key, (_, (fingerprint, _))) in &input.tap_key_origins {
+        let key_path_signed = input.tap_key_sig.is_some() && input.tap_internal_key == Some(*key);
+        if key_path_signed || input.tap_script_sigs.keys().any(|(k, _)| k == key) {
+            signed.push(fingerprint.to_string());
+        } else {
+            unsigned.push(fingerprint.to_string());
+        }
+    }
+    if finalized {
+        signed.clear();
+        unsigned.clear();
+    }
+
+    // Signatures count whether or not the PSBT says where their keys come from
+    let signatures =
+        input.partial_sigs.len() + input.tap_script_sigs.len() + input.tap_key_sig.iter().count();
+
+    // Done when miniscript can build the final script from what's there
+    let complete = finalized || {
+        let secp = Secp256k1::verification_only();
+        psbt.clone().finalize_inp_mut(&secp, index).is_ok()
+    };
+
+    let required = signature_threshold(input);
+    let missing = if complete {
+        0
+    } else {
+        // However many signatures there are, at least one more is needed
+        required.saturating_sub(signatures as u32).max(1)
+    };
+
+    let into_c_strings = |fingerprints: Vec<String>| -> Vec<*const c_char> {
+        fingerprints
+            .into_iter()
+            .map(|f| CString::new(f).unwrap().into_raw() as *const c_char)
+            .collect()
+    };
+    let signed = into_c_strings(signed);
+    let unsigned = into_c_strings(unsigned);
+
+    InputSigningStatus {
+        signed_len: signed.len() as u32,
+        signed: Box::into_raw(signed.into_boxed_slice()) as _,
+        unsigned_len: unsigned.len() as u32,
+        unsigned: Box::into_raw(unsigned.into_boxed_slice()) as _,
+        required,
+        missing,
+        finalized,
+    }
+}
+
+/// Per input, which keys have signed the base64 `psbt` and how many
+/// signatures are still missing, e.g. for "1 of 2 signatures collected". The
+/// threshold comes from the input's script, an input counts as complete once
+/// it can be finalized.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_psbt_signing_status(psbt: *const c_char) -> SigningStatus {
+    let error_return = SigningStatus {
+        inputs_len: 0,
+        inputs: ptr::null(),
+        complete: false,
+    };
+
+    let psbt = unwrap_or_return!(CStr::from_ptr(psbt).to_str(), error_return);
+    let psbt = unwrap_or_return!(decode_psbt_base64(psbt), error_return);
+
+    let inputs: Vec<InputSigningStatus> = (0..psbt.inputs.len())
+        .map(|index| input_signing_status(&psbt, index))
+        .collect();
+    let complete = inputs.iter().all(|input| input.missing == 0);
+
+    SigningStatus {
+        inputs_len: inputs.len() as u32,
+        inputs: Box::into_raw(inputs.into_boxed_slice()) as _,
+        complete,
+    }
+}
+
+// Why `returned` isn't the transaction `original` proposed, None if it is
+fn psbt_alteration(
+    wallet: &Wallet<Tree>,
+    original: &PartiallySignedTransaction,
+    returned: &PartiallySignedTransaction,
+) -> Option<String> {
+    let (proposed, received) = (&original.unsigned_tx, &returned.unsigned_tx);
+
+    let same_inputs = proposed.input.len() == received.input.len()
+        && proposed
+            .input
+            .iter()
+            .zip(&received.input)
+            .all(|(a, b)| a.previous_output == b.previous_output);
+    if !same_inputs {
+        return Some("inputs changed".to_string());
+    }
+
+    if proposed.output.len() != received.output.len() {
+        return Some(format!(
+            "{} outputs instead of {}",
+            received.output.len(),
+            proposed.output.len()
+        ));
+    }
+    for (index, (a, b)) in proposed.output.iter().zip(&received.output).enumerate() {
+        if a.script_pubkey != b.script_pubkey {
+            return Some(format!("output {} pays a different address", index));
+        }
+        if a.value != b.value {
+            return Some(format!(
+                "output {} changed from {} to {} sats",
+                index, a.value, b.value
+            ));
+        }
+    }
+
+    // Same inputs and outputs, but a lie about what an input is worth would
+    // still skew the fee shown
+    for index in 0..proposed.input.len() {
+        let values = (
+            psbt_input_value(wallet, original, index),
+            psbt_input_value(wallet, returned, index),
+        );
+        if let (Some(a), Some(b)) = values {
+            if a != b {
+                return Some(format!(
+                    "input {} claims {} sats instead of {}",
+                    index, b, a
+                ));
+            }
+        }
+    }
+
+    if proposed.txid() != received.txid() {
+        return Some("version, lock time or sequence numbers changed".to_string());
+    }
+
+    None
+}
+
+/// Check that `returned`, a base64 PSBT back from a signer, an SD card or a
+/// QR relay, is still the transaction `original` proposed: same inputs and
+/// input amounts, same outputs in the same order, hence the same fee. False
+/// with `PsbtTampered` in LAST_ERROR explaining the first difference found.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_verify_psbt(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    original: *const c_char,
+    returned: *const c_char,
+) -> bool {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), false);
+    let original = unwrap_or_return!(CStr::from_ptr(original).to_str(), false);
+    let original = unwrap_or_return!(decode_psbt_base64(original), false);
+    let returned = unwrap_or_return!(CStr::from_ptr(returned).to_str(), false);
+    let returned = unwrap_or_return!(decode_psbt_base64(returned), false);
+
+    match psbt_alteration(&wallet, &original, &returned) {
+        None => true,
+        Some(reason) => {
+            update_last_error(WalletError::PsbtTampered(reason));
+            false
+        }
+    }
+}
+
+/// Check the base64 `psbt` against what the user asked for when there's no
+/// original to compare with: exactly `amount` sats to `send_to`, every other
+/// output back to this wallet and, unless `max_fee` is 0, at most `max_fee`
+/// sats of fee. Every input must be a coin the wallet knows about, the fee is
+/// worked out from the wallet's records rather than the PSBT's claims.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_verify_psbt_payment(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    psbt: *const c_char,
+    send_to: *const c_char,
+    amount: u64,
+    max_fee: u64,
+) -> bool {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), false);
+    let psbt = unwrap_or_return!(CStr::from_ptr(psbt).to_str(), false);
+    let psbt = unwrap_or_return!(decode_psbt_base64(psbt), false);
+    let send_to = unwrap_or_return!(CStr::from_ptr(send_to).to_str(), false);
+    let address = unwrap_or_return!(parse_address(&wallet, send_to), false);
+
+    let tampered = |reason: String| {
+        update_last_error(WalletError::PsbtTampered(reason));
+        false
+    };
+
+    let mut paid = 0;
+    for (index, output) in psbt.unsigned_tx.output.iter().enumerate() {
+        if output.script_pubkey == address.script_pubkey() {
+            paid += output.value;
+        } else if !unwrap_or_return!(wallet.is_mine(&output.script_pubkey), false) {
+            return tampered(format!(
+                "output {} pays an address outside the wallet",
+                index
+            ));
+        }
+    }
+    if paid != amount {
+        return tampered(format!(
+            "pays {} sats to {} instead of {}",
+            paid, address, amount
+        ));
+    }
+
+    // Input values come from the wallet's own records, never from the PSBT
+    let mut spent: u64 = 0;
+    for (index, txin) in psbt.unsigned_tx.input.iter().enumerate() {
+        let previous_output = unwrap_or_return!(
+            wallet.database().get_previous_output(&txin.previous_output),
+            false
+        );
+        let owned = match &previous_output {
+            Some(txout) => unwrap_or_return!(wallet.is_mine(&txout.script_pubkey), false),
+            None => false,
+        };
+        match previous_output {
+            Some(txout) if owned => spent = spent.saturating_add(txout.value),
+            _ => {
+                return tampered(format!(
+                    "input {} doesn't spend a coin the wallet holds",
+                    index
+                ))
+            }
+        }
+    }
+
+    let sent = psbt
+        .unsigned_tx
+        .output
+        .iter()
+        .fold(0u64, |sum, output| sum.saturating_add(output.value));
+    match spent.checked_sub(sent) {
+        None => tampered("outputs are worth more than the inputs".to_string()),
+        Some(fee) if max_fee > 0 && fee > max_fee => {
+            tampered(format!("fee of {} sats is above {}", fee, max_fee))
+        }
+        Some(_) => true,
+    }
+}
+
+/// Sign only the inputs at `input_indices`, leaving every other input untouched.
+/// Fails if any requested index is out of range or isn't spending one of our coins.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_sign_psbt_inputs(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    psbt: *const c_char,
+    input_indices: *const u32,
+    indices_len: u32,
+) -> Psbt {
+    let error_return = psbt_error_return();
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+
+    if !wallet_can_sign(&wallet) {
+        update_last_error(WalletError::NotSigner);
+        return error_return;
+    }
+
+    let psbt = unwrap_or_return!(CStr::from_ptr(psbt).to_str(), error_return);
+    let mut psbt = unwrap_or_return!(decode_psbt_base64(psbt), error_return);
+
+    let input_indices: &[u32] = if indices_len == 0 || input_indices.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(input_indices, indices_len as usize)
+    };
+
+    for index in input_indices {
+        if *index as usize >= psbt.inputs.len() {
+            update_last_error(WalletError::InputIndexOutOfRange(*index));
+            return error_return;
+        }
+
+        let owned = match psbt_input_script(&psbt, *index as usize) {
+            Some(script) => unwrap_or_return!(wallet.is_mine(&script), error_return),
+            None => false,
+        };
+
+        if !owned {
+            update_last_error(WalletError::InputNotOwned(*index));
+            return error_return;
+        }
+    }
+
+    unwrap_or_return!(check_own_witness_utxos(&wallet, &psbt), error_return);
+
+    // Sign a copy and only carry over the inputs we were asked to sign
+    let mut signed = psbt.clone();
+    let sign_options = SignOptions {
+        try_finalize: false,
+        trust_witness_utxo: true,
+        ..Default::default()
+    };
+    unwrap_or_return!(wallet.sign(&mut signed, sign_options), error_return);
+
+    for index in input_indices {
+        psbt.inputs[*index as usize] = signed.inputs[*index as usize].clone();
+    }
+
+    psbt_extract_details(&wallet, &psbt)
+}
+
+// Decrypt every encrypted note of `from` and seal it again for `to`. Notes are
+// bound to a wallet's keys, so nothing moves unless both wallets hold them.
+fn migrate_encrypted_notes(from: &Wallet<Tree>, to: &Wallet<Tree>) -> Result<u32, WalletError> {
+    let (from_key, to_key) = match (notes_key(from), notes_key(to)) {
+        (Ok(from_key), Ok(to_key)) => (from_key, to_key),
+        (Err(WalletError::NotSigner), _) | (_, Err(WalletError::NotSigner)) => return Ok(0),
+        (Err(e), _) | (_, Err(e)) => return Err(e),
+    };
+
+    let mut migrated = 0;
+    for entry in from.database().scan_prefix(ENCRYPTED_NOTES_PREFIX) {
+        let (key, blob) = entry?;
+        let txid = std::str::from_utf8(&key[ENCRYPTED_NOTES_PREFIX.len()..])
+            .ok()
+            .and_then(|txid| Txid::from_str(txid).ok());
+        let txid = match txid {
+            Some(txid) => txid,
+            None => continue,
+        };
+
+        let note = decrypt_note(&from_key, &txid, &blob)?;
+        to.database()
+            .insert(key, encrypt_note(&to_key, &txid, &note))?;
+        migrated += 1;
+    }
+
+    Ok(migrated)
+}
+
+// `wallet_migrate_to` once the old wallet is synced
+fn migrate(
+    wallet_old: &Wallet<Tree>,
+    wallet_new: &Wallet<Tree>,
+    fee_rate: FeeRate,
+) -> Result<Migration, WalletError> {
+    let destination = wallet_new.get_address(AddressIndex::LastUnused)?.address;
+    let unspendable = unspendable_outpoints(wallet_old)?;
+
+    let mut builder = build_tx(wallet_old);
+    builder
+        .ordering(TxOrdering::Shuffle)
+        .only_witness_utxo()
+        .unspendable(unspendable)
+        .drain_wallet()
+        .drain_to(destination.script_pubkey())
+        .enable_rbf()
+        .fee_rate(fee_rate);
+    let (psbt, _) = builder
+        .finish()
+        .map_err(|e| selection_error(wallet_old, fee_rate, e))?;
+
+    let labels_copied = metadata_copy_prefix(
+        &wallet_old.database(),
+        &wallet_new.database(),
+        LABELS_PREFIX,
+        LABELS_PREFIX,
+    )?;
+    let notes_copied = migrate_encrypted_notes(wallet_old, wallet_new)?;
+
+    Ok(Migration {
+        psbt: psbt_extract_details(wallet_old, &psbt),
+        destination: CString::new(destination.to_string()).unwrap().into_raw(),
+        labels_copied,
+        notes_copied,
+    })
+}
+
+/// Sweep everything from `wallet_old` to the next unused receive address of
+/// `wallet_new`, carrying labels and encrypted notes over. Notes are sealed
+/// again under the new wallet's keys and stay behind if either wallet is
+/// watch-only. Frozen and immature coinbase coins stay behind too. The
+/// returned PSBT still needs to be signed by the old wallet.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_migrate_to(
+    wallet_old: *mut Mutex<Wallet<Tree>>,
+    wallet_new: *mut Mutex<Wallet<Tree>>,
+    fee_rate: f64,
+    electrum_address: *const c_char,
+    tor_port: i32,
+) -> Migration {
+    let error_return = Migration {
+        psbt: psbt_error_return(),
+        destination: ptr::null(),
+        labels_copied: 0,
+        notes_copied: 0,
+    };
+
+    if wallet_old == wallet_new {
+        update_last_error(WalletError::SameWallet);
+        return error_return;
+    }
+
+    // Always lock the wallet at the lower address first, so two migrations
+    // between the same wallets in opposite directions can't deadlock
+    let old_first = (wallet_old as usize) < (wallet_new as usize);
+    let (first, second) = if old_first {
+        (wallet_old, wallet_new)
+    } else {
+        (wallet_new, wallet_old)
+    };
+    let first = unwrap_or_return!(get_wallet_mutex(first).lock(), error_return);
+    let second = unwrap_or_return!(get_wallet_mutex(second).lock(), error_return);
+    let (wallet_old, wallet_new) = if old_first {
+        (first, second)
+    } else {
+        (second, first)
+    };
+
+    if wallet_old.network() != wallet_new.network() {
+        update_last_error(WalletError::NetworkMismatch(
+            wallet_old.network(),
+            wallet_new.network(),
+        ));
+        return error_return;
+    }
+
+    let fee_rate = unwrap_or_return!(fee_rate_from_btc_per_kvb(fee_rate), error_return);
+
+    let electrum_address =
+        unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), error_return);
+    let blockchain = unwrap_or_return!(
+        get_electrum_blockchain(tor_port, electrum_address, MIN_SYNC_STOP_GAP),
+        error_return
+    );
+    unwrap_or_return!(
+        wallet_old.sync(&blockchain, SyncOptions { progress: None }),
+        error_return
+    );
+
+    unwrap_or_return!(migrate(&wallet_old, &wallet_new, fee_rate), error_return)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_broadcast_tx(
+    electrum_address: *const c_char,
+    tor_port: i32,
+    tx: *const c_char,
+) -> *const c_char {
+    let error_return = CString::new("").unwrap().into_raw();
+
+    let electrum_address =
+        unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), error_return);
+    let client = unwrap_or_return!(
+        get_electrum_client(tor_port, electrum_address),
+        error_return
+    );
+
+    let hex_tx = unwrap_or_return!(CStr::from_ptr(tx).to_str(), error_return);
+    let tx = unwrap_or_return!(decode_transaction_hex(hex_tx), error_return);
+    let txid = unwrap_or_return!(client.transaction_broadcast(&tx), error_return);
+
+    unwrap_or_return!(CString::new(txid.to_string()), error_return).into_raw()
+}
+
+/// Broadcast like `wallet_broadcast_tx`, then turn any pending recipient labels
+/// of the transaction into real labels. Nothing is labeled if the broadcast fails.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_broadcast_labeled_tx(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    electrum_address: *const c_char,
+    tor_port: i32,
+    tx: *const c_char,
+) -> *const c_char {
+    let result = wallet_broadcast_tx(electrum_address, tor_port, tx);
+    let txid = CStr::from_ptr(result).to_str().unwrap_or_default();
+
+    if txid.is_empty() {
+        return result;
+    }
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), result);
+    let db = wallet.database();
+
+    let pending_prefix = format!("{}{}", PENDING_LABELS_PREFIX, txid);
+    for entry in db.scan_prefix(&pending_prefix) {
+        let (key, value) = unwrap_or_return!(entry, result);
+        let mut label_key = LABELS_PREFIX.as_bytes().to_vec();
+        label_key.extend_from_slice(&key[PENDING_LABELS_PREFIX.len()..]);
+        let label = value.get(8..).unwrap_or_default();
+        unwrap_or_return!(db.insert(label_key, label), result);
+        unwrap_or_return!(db.remove(key), result);
+    }
+
+    result
+}
+
+// Signet shares testnet's address prefixes, so addresses parse as testnet
+// ones. Regtest has its own bech32 prefix but shares the base58 ones, so its
+// legacy and P2SH addresses parse as testnet too.
+fn address_network_matches(wallet: Network, address: Network) -> bool {
+    match (wallet, address) {
+        (Network::Signet, Network::Testnet) => true,
+        (Network::Regtest, Network::Testnet) => true,
+        (wallet, address) => wallet == address,
+    }
+}
+
+fn parse_address(wallet: &Wallet<Tree>, address: &str) -> Result<Address, WalletError> {
+    match Address::from_str(address) {
+        Ok(a) if address_network_matches(wallet.network(), a.network) => Ok(a),
+        _ => Err(WalletError::InvalidAddress(address.to_string())),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_validate_address(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    address: *const c_char,
+) -> bool {
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), false);
+
+    let address = unwrap_or_return!(CStr::from_ptr(address).to_str(), false);
+
+    match Address::from_str(address) {
+        Ok(a) => wallet.network() == a.network, // Only valid if it's on same network
+        Err(_) => false,
+    }
+}
+
+const SATS_PER_BTC: u64 = 100_000_000;
+
+// BTC decimal to sats without going through f64
+fn btc_to_sats(amount: &str) -> Result<u64, WalletError> {
+    let invalid = || WalletError::MalformedUri(format!("invalid amount {}", amount));
+
+    let (whole, fraction) = match amount.split_once('.') {
+        Some((whole, fraction)) => (whole, fraction),
+        None => (amount, ""),
+    };
+    if (whole.is_empty() && fraction.is_empty())
+        || fraction.len() > 8
+        || !whole
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+    {
+        return Err(invalid());
+    }
+
+    let whole: u64 = if whole.is_empty() {
+        0
+    } else {
+        whole.parse().map_err(|_| invalid())?
+    };
+    let fraction: u64 = if fraction.is_empty() {
+        0
+    } else {
+        format!("{:0<8}", fraction).parse().map_err(|_| invalid())?
+    };
+
+    whole
+        .checked_mul(SATS_PER_BTC)
+        .and_then(|sats| sats.checked_add(fraction))
+        .ok_or_else(invalid)
+}
+
+fn percent_decode(value: &str) -> Result<String, WalletError> {
+    let invalid = || WalletError::MalformedUri(format!("invalid escape in {}", value));
+
+    let mut bytes = vec![];
+    let mut chars = value.bytes();
+    while let Some(c) = chars.next() {
+        if c == b'%' {
+            let digits = [
+                chars.next().ok_or_else(invalid)?,
+                chars.next().ok_or_else(invalid)?,
+            ];
+            let decoded = hex::decode(digits).map_err(|_| invalid())?;
+            bytes.extend_from_slice(&decoded);
+        } else {
+            bytes.push(c);
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+struct ParsedUri {
+    address: Address,
+    amount: u64,
+    label: Option<String>,
+    message: Option<String>,
+    payjoin_supported: bool,
+}
+
+// BIP21, also taking a bare address. Unknown parameters are ignored unless
+// they're "req-" ones, which a wallet that doesn't understand them must reject.
+fn parse_payment_uri(wallet: &Wallet<Tree>, uri: &str) -> Result<ParsedUri, WalletError> {
+    let uri = uri.trim();
+    let (address, query) = match uri.get(..8) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("bitcoin:") => match uri[8..].split_once('?') {
+            Some((address, query)) => (address, query),
+            None => (&uri[8..], ""),
+        },
+        _ => (uri, ""),
+    };
+
+    let mut parsed = ParsedUri {
+        address: parse_address(wallet, address)?,
+        amount: 0,
+        label: None,
+        message: None,
+        payjoin_supported: false,
+    };
+
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        match key.to_ascii_lowercase().as_str() {
+            "amount" => parsed.amount = btc_to_sats(value)?,
+            "label" => parsed.label = Some(percent_decode(value)?),
+            "message" => parsed.message = Some(percent_decode(value)?),
+            "pj" => parsed.payjoin_supported = !value.is_empty(),
+            key if key.starts_with("req-") => {
+                return Err(WalletError::MalformedUri(format!(
+                    "unsupported required parameter {}",
+                    key
+                )))
+            }
+            _ => {}
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Address, amount, label and message of a scanned `bitcoin:` URI or plain
+/// address, for showing before building a transaction. An address on another
+/// network is rejected like `wallet_validate_address` does.
+#[no_mangle]
+pub unsafe extern "C" fn wallet_parse_uri(
+    wallet: *mut Mutex<Wallet<Tree>>,
+    uri: *const c_char,
+) -> PaymentUri {
+    let error_return = PaymentUri {
+        address: ptr::null(),
+        amount: 0,
+        label: ptr::null(),
+        message: ptr::null(),
+        payjoin_supported: false,
+    };
+
+    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
+    let uri = unwrap_or_return!(CStr::from_ptr(uri).to_str(), error_return);
+    let parsed = unwrap_or_return!(parse_payment_uri(&wallet, uri), error_return);
+
+    let optional_string = |s: Option<String>| match s.and_then(|s| CString::new(s).ok()) {
+        Some(s) => s.into_raw() as *const c_char,
+        None => ptr::null(),
+    };
+
+    PaymentUri {
+        address: CString::new(parsed.address.to_string()).unwrap().into_raw(),
+        amount: parsed.amount,
+        label: optional_string(parsed.label),
+        message: optional_string(parsed.message),
+        payjoin_supported: parsed.payjoin_supported,
+    }
+}
+
+// Destructors for what we hand out. Each must get exactly the pointer or
+// struct one of our functions returned, and only once; null pointers are
+// ignored. Strings inside other structs (e.g. `SendPreview.change_address`,
+// `OutputScripts`) go through `wallet_string_free`.
+
+unsafe fn free_c_string(ptr: *const c_char) {
+    if !ptr.is_null() {
+        drop(CString::from_raw(ptr as *mut c_char));
+    }
+}
+
+unsafe fn free_boxed_slice<T>(ptr: *const T, len: u32) -> Option<Box<[T]>> {
+    if ptr.is_null() {
+        return None;
+    }
+
+    Some(Box::from_raw(std::slice::from_raw_parts_mut(
+        ptr as *mut T,
+        len as usize,
+    )))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_string_free(ptr: *mut c_char) {
+    free_c_string(ptr);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_psbt_free(psbt: Psbt) {
+    free_c_string(psbt.base64);
+    free_c_string(psbt.txid);
+    free_c_string(psbt.raw_tx);
+    if let Some(inputs) = free_boxed_slice(psbt.inputs, psbt.inputs_len) {
+        for input in inputs.iter() {
+            free_c_string(input.previous_txid);
+        }
+    }
+    if let Some(outputs) = free_boxed_slice(psbt.outputs, psbt.outputs_len) {
+        for output in outputs.iter() {
+            free_c_string(output.address);
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_transaction_list_free(list: TransactionList) {
+    if let Some(transactions) = free_boxed_slice(list.transactions, list.transactions_len) {
+        for transaction in transactions.iter() {
+            free_c_string(transaction.txid);
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_utxo_list_free(list: UtxoList) {
+    if let Some(utxos) = free_boxed_slice(list.utxos, list.utxos_len) {
+        for utxo in utxos.iter() {
+            free_c_string(utxo.txid);
+            free_c_string(utxo.address);
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_address_list_free(list: AddressList) {
+    if let Some(addresses) = free_boxed_slice(list.addresses, list.addresses_len) {
+        for address in addresses.iter() {
+            free_c_string(*address);
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_seed_free(seed: Seed) {
+    free_c_string(seed.mnemonic);
+    free_c_string(seed.xprv);
+    free_c_string(seed.fingerprint);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_descriptors_free(descriptors: Descriptors) {
+    free_c_string(descriptors.external);
+    free_c_string(descriptors.internal);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_payment_uri_free(uri: PaymentUri) {
+    free_c_string(uri.address);
+    free_c_string(uri.label);
+    free_c_string(uri.message);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_transaction_info_free(info: TransactionInfo) {
+    free_c_string(info.txid);
+    if let Some(inputs) = free_boxed_slice(info.inputs, info.inputs_len) {
+        for input in inputs.iter() {
+            free_c_string(input.previous_txid);
+        }
+    }
+    if let Some(outputs) = free_boxed_slice(info.outputs, info.outputs_len) {
+        for output in outputs.iter() {
+            free_c_string(output.address);
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_compose_report_free(report: ComposeReport) {
+    if let Some(inputs) = free_boxed_slice(report.inputs, report.inputs_len) {
+        for input in inputs.iter() {
+            free_c_string(input.previous_txid);
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_psbt_bytes_free(psbt: PsbtBytes) {
+    drop(free_boxed_slice(psbt.data, psbt.len));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_signing_status_free(status: SigningStatus) {
+    if let Some(inputs) = free_boxed_slice(status.inputs, status.inputs_len) {
+        for input in inputs.iter() {
+            if let Some(signed) = free_boxed_slice(input.signed, input.signed_len) {
+                for fingerprint in signed.iter() {
+                    free_c_string(*fingerprint);
+                }
+            }
+            if let Some(unsigned) = free_boxed_slice(input.unsigned, input.unsigned_len) {
+                for fingerprint in unsigned.iter() {
+                    free_c_string(*fingerprint);
+                }
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_qr_parts_free(parts: QrParts) {
+    if let Some(parts) = free_boxed_slice(parts.parts, parts.parts_len) {
+        for part in parts.iter() {
+            free_c_string(*part);
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_bbqr_data_free(data: BbqrData) {
+    drop(free_boxed_slice(data.data, data.len));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_bbqr_decoder_free(decoder: *mut BbqrDecoder) {
+    if !decoder.is_null() {
+        drop(Box::from_raw(decoder));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_ur_decoder_free(decoder: *mut UrDecoder) {
+    if !decoder.is_null() {
+        drop(Box::from_raw(decoder));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_private_psbt_free(psbt: PrivatePsbt) {
+    wallet_psbt_free(psbt.psbt);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_combined_psbt_free(combined: CombinedPsbt) {
+    wallet_psbt_free(combined.psbt);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_migration_free(migration: Migration) {
+    wallet_psbt_free(migration.psbt);
+    free_c_string(migration.destination);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_network_confirmation_free(confirmation: NetworkConfirmation) {
+    free_c_string(confirmation.expected);
+    free_c_string(confirmation.server_reported);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_script_info_free(info: ScriptInfo) {
+    free_c_string(info.address);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_output_scripts_free(scripts: OutputScripts) {
+    free_c_string(scripts.redeem_script);
+    free_c_string(scripts.witness_script);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn wallet_server_features_free(features: ServerFeatures) {
+    free_c_string(features.server_version);
+    free_c_string(features.protocol_min);
+    free_c_string(features.protocol_max);
+    drop(free_boxed_slice(
+        features.genesis_hash,
+        features.genesis_hash_len,
+    ));
+}
+
+// Due to its simple signature this function is the one added (unused) to iOS swift codebase to force Xcode to link the lib
+#[no_mangle]
+pub unsafe extern "C" fn wallet_hello() {
+    println!("Hello wallet");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bdk::database::BatchOperations;
+    use bdk::BlockTime;
+    use std::sync::atomic::AtomicU32;
+
+    // BDK's own test key
+    const TPRV: &str = "tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCjKgitaPNqMzsA7HMChprVsGmtNXT";
+
+    fn temporary_tree() -> Tree {
+        sled::Config::new()
+            .temporary(true)
+            .open()
+            .unwrap()
+            .open_tree("test")
+            .unwrap()
+    }
+
+    fn test_wallet_for_account(account: u32) -> Wallet<Tree> {
+        let external = format!("wpkh({}/84'/1'/{}'/0/*)", TPRV, account);
+        let internal = format!("wpkh({}/84'/1'/{}'/1/*)", TPRV, account);
+        Wallet::new(
+            external.as_str(),
+            Some(internal.as_str()),
+            Network::Regtest,
+            temporary_tree(),
+        )
+        .unwrap()
+    }
+
+    fn test_wallet() -> Wallet<Tree> {
+        test_wallet_for_account(0)
+    }
+
+    // An address no test wallet owns
+    fn foreign_address() -> Address {
+        test_wallet_for_account(99)
+            .get_address(AddressIndex::New)
+            .unwrap()
+            .address
+    }
+
+    // Laid out by hand so it doesn't depend on rust-bitcoin's field types
+    fn test_transaction(
+        inputs: &[OutPoint],
+        outputs: &[(Script, u64)],
+    ) -> bdk::bitcoin::Transaction {
+        let mut data = 2i32.to_le_bytes().to_vec();
+        data.extend(serialize(&VarInt(inputs.len() as u64)));
+        for input in inputs {
+            data.extend(serialize(input));
+            data.push(0x00);
+            data.extend_from_slice(&0xffff_fffdu32.to_le_bytes());
+        }
+        data.extend(serialize(&VarInt(outputs.len() as u64)));
+        for (script, value) in outputs {
+            data.extend_from_slice(&value.to_le_bytes());
+            data.extend(serialize(script));
+        }
+        data.extend_from_slice(&0u32.to_le_bytes());
+        deserialize(&data).unwrap()
+    }
+
+    // Every funding transaction spends a different made up coin
+    fn made_up_outpoint() -> OutPoint {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let n = NEXT.fetch_add(1, Ordering::SeqCst);
+        OutPoint {
+            txid: Txid::hash(&n.to_le_bytes()),
+            vout: 0,
+        }
+    }
+
+    // Record a payment of `value` sats to the wallet's next receive address,
+    // confirmed at `height` (0 for unconfirmed), the way a sync would
+    fn fund(wallet: &Wallet<Tree>, value: u64, height: u32) -> OutPoint {
+        fund_spending(wallet, &[made_up_outpoint()], value, height)
+    }
+
+    // `fund` with a funding transaction spending `inputs`, OutPoint::null()
+    // alone making it a coinbase
+    fn fund_spending(
+        wallet: &Wallet<Tree>,
+        inputs: &[OutPoint],
+        value: u64,
+        height: u32,
+    ) -> OutPoint {
+        let script = wallet
+            .get_address(AddressIndex::New)
+            .unwrap()
+            .script_pubkey();
+        fund_script(wallet, script, inputs, value, height)
+    }
+
+    // `fund_spending` paying to `script`, e.g. to reuse an address
+    fn fund_script(
+        wallet: &Wallet<Tree>,
+        script: Script,
+        inputs: &[OutPoint],
+        value: u64,
+        height: u32,
+    ) -> OutPoint {
+        let tx = test_transaction(inputs, &[(script, value)]);
+        let outpoint = OutPoint {
+            txid: tx.txid(),
+            vout: 0,
+        };
+
+        let mut db = wallet.database().clone();
+        db.set_utxo(&LocalUtxo {
+            outpoint,
+            txout: tx.output[0].clone(),
+            keychain: KeychainKind::External,
+            is_spent: false,
+        })
+        .unwrap();
+        db.set_tx(&TransactionDetails {
+            txid: tx.txid(),
+            received: value,
+            sent: 0,
+            fee: Some(0),
+            confirmation_time: if height > 0 {
+                Some(BlockTime {
+                    height,
+                    timestamp: 1_600_000_000 + height as u64 * 600,
+                })
+            } else {
+                None
+            },
+            transaction: Some(tx),
+        })
+        .unwrap();
+        outpoint
+    }
+
+    // Record the chain tip a sync would have seen
+    fn set_tip(wallet: &Wallet<Tree>, height: u32) {
+        let mut db = wallet.database().clone();
+        db.set_sync_time(bdk::database::SyncTime {
+            block_time: BlockTime {
+                height,
+                timestamp: 1_600_000_000 + height as u64 * 600,
+            },
+        })
+        .unwrap();
+    }
+
+    fn into_handle(wallet: Wallet<Tree>) -> *mut Mutex<Wallet<Tree>> {
+        Box::into_raw(Box::new(Mutex::new(wallet)))
+    }
+
+    fn c_string(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    unsafe fn string_from(ptr: *const c_char) -> String {
+        assert!(
+            !ptr.is_null(),
+            "{:?}",
+            take_last_error().map(|e| e.to_string())
+        );
+        CStr::from_ptr(ptr).to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn signs_psbt_it_created() {
+        let wallet = test_wallet();
+        fund(&wallet, 100_000, 100);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+
+        unsafe {
+            let created = wallet_create_psbt(
+                wallet,
+                send_to.as_ptr(),
+                50_000,
+                0.00001,
+                &CreatePsbtOptions {
+                    ordering: OutputOrdering::Bip69,
+                    ..Default::default()
+                },
+            );
+            let base64 = c_string(&string_from(created.base64));
+
+            let signed = wallet_sign_psbt(wallet, base64.as_ptr(), true);
+            assert!(take_last_error().is_none());
+            assert!(!signed.raw_tx.is_null());
+
+            let tx = decode_transaction_hex(&string_from(signed.raw_tx)).unwrap();
+            assert!(!tx.input[0].witness.is_empty());
+
+            wallet_psbt_free(created);
+            wallet_psbt_free(signed);
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn verifies_payment_fee_from_wallet_records() {
+        let wallet = test_wallet();
+        fund(&wallet, 100_000, 100);
+        let wallet = into_handle(wallet);
+        let send_to = foreign_address().to_string();
+        let send_to_c = c_string(&send_to);
+
+        unsafe {
+            let created = wallet_create_psbt(
+                wallet,
+                send_to_c.as_ptr(),
+                50_000,
+                0.00001,
+                &CreatePsbtOptions {
+                    ordering: OutputOrdering::Bip69,
+                    ..Default::default()
+                },
+            );
+            let mut psbt = decode_psbt_base64(&string_from(created.base64)).unwrap();
+            wallet_psbt_free(created);
+            let sent: u64 = psbt.unsigned_tx.output.iter().map(|o| o.value).sum();
+            let fee = 100_000 - sent;
+
+            // Claiming the coin is worth more makes the fee look smaller
+            psbt.inputs[0].witness_utxo.as_mut().unwrap().value += 10_000;
+            let claimed = c_string(&base64::encode(&serialize(&psbt)));
+
+            assert!(wallet_verify_psbt_payment(
+                wallet,
+                claimed.as_ptr(),
+                send_to_c.as_ptr(),
+                50_000,
+                fee
+            ));
+            assert!(!wallet_verify_psbt_payment(
+                wallet,
+                claimed.as_ptr(),
+                send_to_c.as_ptr(),
+                50_000,
+                fee - 1
+            ));
+            assert!(take_last_error().is_some());
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn rejects_payment_spending_unknown_coin() {
+        let wallet = test_wallet();
+        let coin = fund(&wallet, 100_000, 100);
+        let change = wallet
+            .get_internal_address(AddressIndex::New)
+            .unwrap()
+            .script_pubkey();
+        let send_to = foreign_address();
+        let tx = test_transaction(
+            &[coin, made_up_outpoint()],
+            &[(send_to.script_pubkey(), 50_000), (change, 49_000)],
+        );
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        let psbt = c_string(&base64::encode(&serialize(&psbt)));
+        let send_to = c_string(&send_to.to_string());
+        let wallet = into_handle(wallet);
+
+        unsafe {
+            assert!(!wallet_verify_psbt_payment(
+                wallet,
+                psbt.as_ptr(),
+                send_to.as_ptr(),
+                50_000,
+                0
+            ));
+            let error = take_last_error().unwrap();
+            assert!(matches!(
+                error.downcast_ref::<WalletError>(),
+                Some(WalletError::PsbtTampered(_))
+            ));
+            wallet_drop(wallet);
+        }
+    }
+
+    // Status of the first input of the PSBT `wallet` makes paying 50k sats out
+    // of a funded wallet, before and after it signs without finalizing
+    unsafe fn first_input_status(wallet: Wallet<Tree>) -> [(u32, u32); 2] {
+        fund(&wallet, 100_000, 100);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+
+        let created = wallet_create_psbt(
+            wallet,
+            send_to.as_ptr(),
+            50_000,
+            0.00001,
+            &CreatePsbtOptions {
+                ordering: OutputOrdering::Bip69,
+                ..Default::default()
+            },
+        );
+        let unsigned = c_string(&string_from(created.base64));
+        let signed = wallet_sign_psbt(wallet, unsigned.as_ptr(), false);
+        let signed = c_string(&string_from(signed.base64));
+
+        let mut statuses = [(0, 0); 2];
+        for (status, psbt) in statuses.iter_mut().zip(&[unsigned, signed]) {
+            let signing_status = wallet_psbt_signing_status(psbt.as_ptr());
+            let input = &*signing_status.inputs;
+            *status = (input.required, input.missing);
+            wallet_signing_status_free(signing_status);
+        }
+
+        wallet_psbt_free(created);
+        wallet_drop(wallet);
+        statuses
+    }
+
+    #[test]
+    fn single_key_input_needs_one_signature() {
+        let statuses = unsafe { first_input_status(test_wallet()) };
+        assert_eq!(statuses, [(1, 1), (1, 0)]);
+    }
+
+    #[test]
+    fn multisig_input_counts_signatures() {
+        let secp = Secp256k1::new();
+        let master = ExtendedPrivKey::from_str(TPRV).unwrap();
+        let cosigner = master
+            .derive_priv(&secp, &DerivationPath::from_str("m/48'/1'/1'/2'").unwrap())
+            .unwrap();
+        let cosigner = bdk::bitcoin::util::bip32::ExtendedPubKey::from_priv(&secp, &cosigner);
+
+        // We hold one of the two keys
+        let descriptor = |keychain| {
+            format!(
+                "wsh(multi(2,{}/48'/1'/0'/2'/{}/*,{}/{}/*))",
+                TPRV, keychain, cosigner, keychain
+            )
+        };
+        let wallet = Wallet::new(
+            descriptor(0).as_str(),
+            Some(descriptor(1).as_str()),
+            Network::Regtest,
+            temporary_tree(),
+        )
+        .unwrap();
+
+        let statuses = unsafe { first_input_status(wallet) };
+        assert_eq!(statuses, [(2, 2), (2, 1)]);
+    }
+
+    #[test]
+    fn fee_rate_cap_is_in_btc_per_kvb() {
+        let wallet = test_wallet();
+        fund(&wallet, 100_000, 100);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+
+        unsafe {
+            // 10 sat/vB against caps of 5 and 20 sat/vB
+            for (max_fee_rate, allowed) in &[(0.00005, false), (0.0002, true)] {
+                let psbt = wallet_create_psbt(
+                    wallet,
+                    send_to.as_ptr(),
+                    50_000,
+                    0.0001,
+                    &CreatePsbtOptions {
+                        ordering: OutputOrdering::Bip69,
+                        max_fee_rate: *max_fee_rate,
+                        ..Default::default()
+                    },
+                );
+                assert_eq!(!psbt.base64.is_null(), *allowed);
+                let error = take_last_error();
+                assert_eq!(error.is_none(), *allowed);
+                if let Some(error) = error {
+                    assert!(matches!(
+                        error.downcast_ref::<WalletError>(),
+                        Some(WalletError::FeeAboveCap { .. })
+                    ));
+                }
+                wallet_psbt_free(psbt);
+            }
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn estimate_rejects_amounts_that_overflow() {
+        let wallet = test_wallet();
+        fund(&wallet, 100_000, 100);
+        let wallet = into_handle(wallet);
+        let address = c_string(&foreign_address().to_string());
+        let recipient = || Recipient {
+            address: address.as_ptr(),
+            amount: u64::MAX / 2 + 1,
+            label: ptr::null(),
+        };
+        let recipients = [recipient(), recipient()];
+
+        unsafe {
+            let estimate = wallet_estimate_tx(wallet, recipients.as_ptr(), 2, 0.00001);
+            assert!(!estimate.sufficient);
+            let error = take_last_error().unwrap();
+            assert!(matches!(
+                error.downcast_ref::<WalletError>(),
+                Some(WalletError::AmountOverflow)
+            ));
+            wallet_drop(wallet);
+        }
+    }
+
+    // Serialized unsigned PSBT spending a made up coin to a foreign address
+    fn test_psbt_bytes() -> Vec<u8> {
+        let tx = test_transaction(
+            &[made_up_outpoint()],
+            &[(foreign_address().script_pubkey(), 50_000)],
+        );
+        serialize(&PartiallySignedTransaction::from_unsigned_tx(tx).unwrap())
+    }
+
+    #[test]
+    fn decodes_psbts() {
+        let data = test_psbt_bytes();
+        let psbt = decode_psbt_bytes(&data).unwrap();
+        assert_eq!(serialize(&psbt), data);
+        // Whitespace around base64 from a text field is fine
+        let encoded = format!(" {}\n", base64::encode(&data));
+        assert_eq!(decode_psbt_base64(&encoded).unwrap(), psbt);
+    }
+
+    #[test]
+    fn rejects_oversized_psbts() {
+        let data = vec![0u8; MAX_PSBT_BYTES + 1];
+        assert!(matches!(
+            decode_psbt_bytes(&data),
+            Err(WalletError::InputTooLarge { .. })
+        ));
+        // Rejected by length alone, before decoding
+        let encoded = "A".repeat((MAX_PSBT_BYTES + 3) / 3 * 4 + 4);
+        assert!(matches!(
+            decode_psbt_base64(&encoded),
+            Err(WalletError::InputTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_psbts_with_bad_magic() {
+        let mut data = test_psbt_bytes();
+        data[4] = 0x00;
+        assert!(matches!(
+            decode_psbt_bytes(&data),
+            Err(WalletError::NotAPsbt)
+        ));
+        assert!(matches!(
+            decode_psbt_base64(&base64::encode(&data)),
+            Err(WalletError::NotAPsbt)
+        ));
+        assert!(matches!(decode_psbt_bytes(&[]), Err(WalletError::NotAPsbt)));
+        assert!(matches!(
+            decode_psbt_base64("not base64!"),
+            Err(WalletError::Base64(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_psbts() {
+        let data = test_psbt_bytes();
+        for len in PSBT_MAGIC.len()..data.len() {
+            assert!(decode_psbt_bytes(&data[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn decodes_transactions() {
+        let tx = test_transaction(&[made_up_outpoint()], &[(Script::new(), 1_000)]);
+        let encoded = serialize(&tx).to_hex();
+        assert_eq!(decode_transaction_hex(&encoded).unwrap(), tx);
+
+        let oversized = "00".repeat(MAX_TRANSACTION_BYTES + 1);
+        assert!(matches!(
+            decode_transaction_hex(&oversized),
+            Err(WalletError::InputTooLarge { .. })
+        ));
+        assert!(matches!(
+            decode_transaction_hex("not hex"),
+            Err(WalletError::Hex(_))
+        ));
+        assert!(matches!(
+            decode_transaction_hex(&encoded[..encoded.len() - 2]),
+            Err(WalletError::Encode(_))
+        ));
+    }
+
+    // Global, input and output maps of a BIP 370 PSBT paying 50k sats out of
+    // one made up coin, for tests to add to or remove from
+    fn bip370_maps() -> (PsbtMap, PsbtMap, PsbtMap) {
+        let global = vec![
+            (vec![PSBT_GLOBAL_TX_VERSION], 2u32.to_le_bytes().to_vec()),
+            (vec![PSBT_GLOBAL_INPUT_COUNT], vec![1]),
+            (vec![PSBT_GLOBAL_OUTPUT_COUNT], vec![1]),
+            (vec![PSBT_GLOBAL_VERSION], 2u32.to_le_bytes().to_vec()),
+        ];
+        let input = vec![
+            (
+                vec![PSBT_IN_PREVIOUS_TXID],
+                serialize(&made_up_outpoint().txid),
+            ),
+            (vec![PSBT_IN_OUTPUT_INDEX], 0u32.to_le_bytes().to_vec()),
+        ];
+        let output = vec![
+            (vec![PSBT_OUT_AMOUNT], 50_000u64.to_le_bytes().to_vec()),
+            (
+                vec![PSBT_OUT_SCRIPT],
+                foreign_address().script_pubkey().to_bytes(),
+            ),
+        ];
+        (global, input, output)
+    }
+
+    fn lock_time_of(data: &[u8]) -> Result<u32, WalletError> {
+        let psbt = decode_psbt_bytes(data)?;
+        let lock_time = serialize(&psbt.unsigned_tx.lock_time);
+        Ok(u32::from_le_bytes(lock_time.as_slice().try_into().unwrap()))
+    }
+
+    #[test]
+    fn decodes_bip370_psbts() {
+        let (global, input, output) = bip370_maps();
+        let txid = input[0].1.clone();
+        let psbt = decode_psbt_bytes(&serialize_raw_psbt(&global, &[input], &[output])).unwrap();
+
+        let tx = &psbt.unsigned_tx;
+        assert_eq!(serialize(&tx.version), 2u32.to_le_bytes());
+        assert_eq!(serialize(&tx.input[0].previous_output.txid), txid);
+        // Sequence and lock time default to final and 0
+        assert_eq!(serialize(&tx.input[0].sequence), [0xff; 4]);
+        assert_eq!(serialize(&tx.lock_time), [0; 4]);
+        assert_eq!(tx.output[0].value, 50_000);
+        assert_eq!(
+            tx.output[0].script_pubkey,
+            foreign_address().script_pubkey()
+        );
+    }
+
+    #[test]
+    fn picks_bip370_lock_times() {
+        let (mut global, input, output) = bip370_maps();
+        global.push((
+            vec![PSBT_GLOBAL_FALLBACK_LOCKTIME],
+            1_000u32.to_le_bytes().to_vec(),
+        ));
+        for (key, value) in global.iter_mut() {
+            if key.as_slice() == [PSBT_GLOBAL_INPUT_COUNT] {
+                *value = vec![2];
+            }
+        }
+
+        let with = |time: Option<u32>, height: Option<u32>| {
+            let mut input = input.clone();
+            if let Some(time) = time {
+                let value = time.to_le_bytes().to_vec();
+                input.push((vec![PSBT_IN_REQUIRED_TIME_LOCKTIME], value));
+            }
+            if let Some(height) = height {
+                let value = height.to_le_bytes().to_vec();
+                input.push((vec![PSBT_IN_REQUIRED_HEIGHT_LOCKTIME], value));
+            }
+            input
+        };
+        let lock_time = |inputs: &[PsbtMap]| {
+            lock_time_of(&serialize_raw_psbt(&global, inputs, &[output.clone()]))
+        };
+
+        let time = 1_700_000_000;
+        // No requirements, the fallback applies
+        assert_eq!(
+            lock_time(&[with(None, None), with(None, None)]).unwrap(),
+            1_000
+        );
+        // Heights when every input with a requirement can take one
+        assert_eq!(
+            lock_time(&[with(Some(time), Some(100)), with(None, Some(200))]).unwrap(),
+            200
+        );
+        // Times when some input can only be locked by time
+        assert_eq!(
+            lock_time(&[with(Some(time), Some(100)), with(Some(time + 1), None)]).unwrap(),
+            time + 1
+        );
+        assert!(lock_time(&[with(Some(time), None), with(None, Some(100))]).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_bip370_psbts() {
+        let (global, input, output) = bip370_maps();
+        let without = |map: &PsbtMap, key_type: u8| -> PsbtMap {
+            map.iter()
+                .filter(|(key, _)| key.as_slice() != [key_type])
+                .cloned()
+                .collect()
+        };
+        let with = |map: &PsbtMap, key_type: u8, value: Vec<u8>| -> PsbtMap {
+            let mut map = map.clone();
+            map.push((vec![key_type], value));
+            map.sort();
+            map
+        };
+        let decode = |global: &PsbtMap, input: &PsbtMap, output: &PsbtMap| {
+            decode_psbt_bytes(&serialize_raw_psbt(
+                global,
+                &[input.clone()],
+                &[output.clone()],
+            ))
+        };
+        let tx = test_transaction(
+            &[made_up_outpoint()],
+            &[(foreign_address().script_pubkey(), 50_000)],
+        );
+
+        let invalid = [
+            // Required global fields missing
+            decode(&without(&global, PSBT_GLOBAL_INPUT_COUNT), &input, &output),
+            decode(&without(&global, PSBT_GLOBAL_OUTPUT_COUNT), &input, &output),
+            decode(&without(&global, PSBT_GLOBAL_TX_VERSION), &input, &output),
+            // An unsigned transaction on top of the v2 fields
+            decode(
+                &with(&global, PSBT_GLOBAL_UNSIGNED_TX, serialize(&tx)),
+                &input,
+                &output,
+            ),
+            // Required input and output fields missing
+            decode(&global, &without(&input, PSBT_IN_PREVIOUS_TXID), &output),
+            decode(&global, &without(&input, PSBT_IN_OUTPUT_INDEX), &output),
+            decode(&global, &input, &without(&output, PSBT_OUT_AMOUNT)),
+            decode(&global, &input, &without(&output, PSBT_OUT_SCRIPT)),
+            // Lock time requirements of the wrong kind
+            decode(
+                &global,
+                &with(
+                    &input,
+                    PSBT_IN_REQUIRED_TIME_LOCKTIME,
+                    499_999_999u32.to_le_bytes().to_vec(),
+                ),
+                &output,
+            ),
+            decode(
+                &global,
+                &with(
+                    &input,
+                    PSBT_IN_REQUIRED_HEIGHT_LOCKTIME,
+                    500_000_000u32.to_le_bytes().to_vec(),
+                ),
+                &output,
+            ),
+        ];
+        for (index, result) in invalid.iter().enumerate() {
+            assert!(result.is_err(), "vector {}", index);
+        }
+
+        let version_1 = with(
+            &without(&global, PSBT_GLOBAL_VERSION),
+            PSBT_GLOBAL_VERSION,
+            1u32.to_le_bytes().to_vec(),
+        );
+        assert!(matches!(
+            decode(&version_1, &input, &output),
+            Err(WalletError::UnsupportedPsbtVersion(1))
+        ));
+    }
+
+    #[test]
+    fn rejects_v0_psbts_with_v2_fields() {
+        let psbt = decode_psbt_bytes(&test_psbt_bytes()).unwrap();
+        let raw = parse_raw_psbt(&serialize(&psbt)).unwrap();
+        let mut inputs = raw.inputs.clone();
+        inputs[0].push((vec![PSBT_IN_OUTPUT_INDEX], 0u32.to_le_bytes().to_vec()));
+
+        assert!(matches!(
+            decode_psbt_bytes(&serialize_raw_psbt(&raw.global, &inputs, &raw.outputs)),
+            Err(WalletError::MalformedPsbt(_))
+        ));
+    }
+
+    #[test]
+    fn round_trips_between_psbt_versions() {
+        let wallet = test_wallet();
+        fund(&wallet, 100_000, 100);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+
+        unsafe {
+            let created = wallet_create_psbt(
+                wallet,
+                send_to.as_ptr(),
+                50_000,
+                0.00001,
+                &CreatePsbtOptions {
+                    ordering: OutputOrdering::Bip69,
+                    ..Default::default()
+                },
+            );
+            let v0 = decode_psbt_base64(&string_from(created.base64)).unwrap();
+            wallet_psbt_free(created);
+
+            let v2 = psbt_v0_to_v2(&v0).unwrap();
+            assert_eq!(parse_raw_psbt(&v2).unwrap().version, 2);
+            assert_eq!(decode_psbt_bytes(&v2).unwrap(), v0);
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn normalizes_fee_rates_from_each_unit() {
+        let cases = [
+            (FeeUnit::SatPerVb, 12.5),
+            (FeeUnit::SatPerKvb, 12_500.0),
+            (FeeUnit::BtcPerKvb, 0.000125),
+        ];
+        for (unit, input) in cases {
+            let sat_per_vb = wallet_normalize_fee_rate(input, unit);
+            assert!((sat_per_vb - 12.5).abs() < 1e-9, "{}", sat_per_vb);
+        }
+    }
+
+    #[test]
+    fn rejects_nonsensical_fee_rates() {
+        let invalid = [
+            f64::NAN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            0.0,
+            -1.0,
+            MAX_FEE_RATE_SAT_PER_VB + 1.0,
+        ];
+        for input in invalid {
+            assert_eq!(wallet_normalize_fee_rate(input, FeeUnit::SatPerVb), -1.0);
+            assert!(take_last_error().is_some());
+        }
+        // Sat/vB typed into a BTC/kvB field is absurd, not a fee rate
+        assert_eq!(wallet_normalize_fee_rate(5.0, FeeUnit::BtcPerKvb), -1.0);
+        assert_eq!(
+            wallet_normalize_fee_rate(MAX_FEE_RATE_SAT_PER_VB, FeeUnit::SatPerVb),
+            MAX_FEE_RATE_SAT_PER_VB
+        );
+    }
+
+    // Why `wallet_create_psbt` couldn't pay `amount` at `fee_rate` (BTC/kvB)
+    unsafe fn selection_failure(
+        wallet: *mut Mutex<Wallet<Tree>>,
+        amount: u64,
+        fee_rate: f64,
+        spend_unconfirmed: bool,
+    ) -> SelectionFailure {
+        let send_to = c_string(&foreign_address().to_string());
+        let psbt = wallet_create_psbt(
+            wallet,
+            send_to.as_ptr(),
+            amount,
+            fee_rate,
+            &CreatePsbtOptions {
+                ordering: OutputOrdering::Bip69,
+                spend_unconfirmed,
+                ..Default::default()
+            },
+        );
+        assert!(psbt.base64.is_null());
+        let failure = wallet_last_selection_failure();
+        take_last_error();
+        failure
+    }
+
+    unsafe fn set_frozen(wallet: *mut Mutex<Wallet<Tree>>, coin: OutPoint) {
+        let txid = c_string(&coin.txid.to_string());
+        assert!(wallet_set_utxo_frozen(
+            wallet,
+            txid.as_ptr(),
+            coin.vout,
+            true
+        ));
+    }
+
+    unsafe fn set_group(wallet: *mut Mutex<Wallet<Tree>>, coin: OutPoint, group: &str) {
+        let txid = c_string(&coin.txid.to_string());
+        let group = c_string(group);
+        assert!(wallet_set_coin_group(
+            wallet,
+            txid.as_ptr(),
+            coin.vout,
+            group.as_ptr()
+        ));
+    }
+
+    #[test]
+    fn diagnoses_empty_wallet() {
+        let wallet = into_handle(test_wallet());
+        unsafe {
+            let failure = selection_failure(wallet, 50_000, 0.00001, true);
+            assert!(matches!(failure, SelectionFailure::NoCoins));
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn diagnoses_frozen_coins() {
+        let wallet = test_wallet();
+        let coin = fund(&wallet, 100_000, 100);
+        let wallet = into_handle(wallet);
+        unsafe {
+            set_frozen(wallet, coin);
+            let failure = selection_failure(wallet, 50_000, 0.00001, true);
+            assert!(matches!(failure, SelectionFailure::FrozenCoins));
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn diagnoses_immature_coins() {
+        let wallet = test_wallet();
+        fund_spending(&wallet, &[OutPoint::null()], 100_000, 100);
+        let wallet = into_handle(wallet);
+        unsafe {
+            let failure = selection_failure(wallet, 50_000, 0.00001, true);
+            assert!(matches!(failure, SelectionFailure::ImmatureCoins));
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn diagnoses_dust_coins() {
+        let wallet = test_wallet();
+        for _ in 0..3 {
+            fund(&wallet, 500, 100);
+        }
+        let wallet = into_handle(wallet);
+        unsafe {
+            // 100 sat/vB, spending any of them costs more than it's worth
+            let failure = selection_failure(wallet, 1_000, 0.001, true);
+            assert!(matches!(failure, SelectionFailure::DustCoinsOnly));
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn diagnoses_insufficient_funds_after_fees() {
+        let wallet = test_wallet();
+        fund(&wallet, 50_000, 100);
+        let wallet = into_handle(wallet);
+        unsafe {
+            // The coin covers the payment but not the fee of spending it
+            let failure = selection_failure(wallet, 49_920, 0.00001, true);
+            assert!(matches!(failure, SelectionFailure::InsufficientAfterFees));
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn diagnoses_insufficient_funds() {
+        let wallet = test_wallet();
+        fund(&wallet, 10_000, 100);
+        let wallet = into_handle(wallet);
+        unsafe {
+            let failure = selection_failure(wallet, 50_000, 0.00001, true);
+            assert!(matches!(failure, SelectionFailure::InsufficientFunds));
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn diagnoses_unconfirmed_coins() {
+        let wallet = test_wallet();
+        fund(&wallet, 100_000, 0);
+        let wallet = into_handle(wallet);
+        unsafe {
+            let failure = selection_failure(wallet, 50_000, 0.00001, false);
+            assert!(matches!(failure, SelectionFailure::UnconfirmedCoins));
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn diagnoses_coin_group_conflicts() {
+        let wallet = test_wallet();
+        let coins = [fund(&wallet, 30_000, 100), fund(&wallet, 30_000, 100)];
+        let wallet = into_handle(wallet);
+        unsafe {
+            set_group(wallet, coins[0], "exchange");
+            set_group(wallet, coins[1], "donations");
+            let failure = selection_failure(wallet, 50_000, 0.00001, true);
+            assert!(matches!(failure, SelectionFailure::CoinGroupConflict));
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn coordinated_psbts_match_across_devices() {
+        // Two devices of the same wallet, synced to the same coins
+        let sources: Vec<OutPoint> = (0..3).map(|_| made_up_outpoint()).collect();
+        let devices: Vec<*mut Mutex<Wallet<Tree>>> = (0..2)
+            .map(|_| {
+                let wallet = test_wallet();
+                for (value, source) in [40_000, 25_000, 60_000].iter().zip(&sources) {
+                    fund_spending(&wallet, &[*source], *value, 100);
+                }
+                into_handle(wallet)
+            })
+            .collect();
+        let send_to = c_string(&foreign_address().to_string());
+
+        unsafe {
+            for ordering in [OutputOrdering::Bip69, OutputOrdering::Untouched] {
+                let unsigned_txs: Vec<Vec<u8>> = devices
+                    .iter()
+                    .map(|device| {
+                        let psbt = wallet_create_psbt_coordinated(
+                            *device,
+                            send_to.as_ptr(),
+                            70_000,
+                            0.00002,
+                            ordering,
+                            3,
+                            true,
+                        );
+                        let decoded = decode_psbt_base64(&string_from(psbt.base64)).unwrap();
+                        wallet_psbt_free(psbt);
+                        serialize(&decoded.unsigned_tx)
+                    })
+                    .collect();
+                assert_eq!(unsigned_txs[0], unsigned_txs[1]);
+            }
+
+            let psbt = wallet_create_psbt_coordinated(
+                devices[0],
+                send_to.as_ptr(),
+                70_000,
+                0.00002,
+                OutputOrdering::Shuffle,
+                3,
+                true,
+            );
+            assert!(psbt.base64.is_null());
+            assert!(take_last_error().is_some());
+
+            for device in devices {
+                wallet_drop(device);
+            }
+        }
+    }
+
+    // Privacy scores by outpoint, as wallet_list_utxos reports them
+    unsafe fn privacy_scores(wallet: *mut Mutex<Wallet<Tree>>) -> HashMap<OutPoint, u8> {
+        let list = wallet_list_utxos(wallet);
+        let utxos = std::slice::from_raw_parts(list.utxos, list.utxos_len as usize);
+        let scores = utxos
+            .iter()
+            .map(|utxo| {
+                let outpoint = OutPoint {
+                    txid: Txid::from_str(&string_from(utxo.txid)).unwrap(),
+                    vout: utxo.vout,
+                };
+                (outpoint, utxo.privacy_score)
+            })
+            .collect();
+        wallet_utxo_list_free(list);
+        scores
+    }
+
+    #[test]
+    fn ranks_utxo_privacy() {
+        let wallet = test_wallet();
+        let fresh = fund(&wallet, 10_000, 100);
+        let inputs: Vec<OutPoint> = (0..5).map(|_| made_up_outpoint()).collect();
+        let consolidated = fund_spending(&wallet, &inputs, 10_000, 100);
+        let reused_script = wallet
+            .get_address(AddressIndex::New)
+            .unwrap()
+            .script_pubkey();
+        let reused = fund_script(
+            &wallet,
+            reused_script.clone(),
+            &[made_up_outpoint()],
+            10_000,
+            100,
+        );
+        fund_script(&wallet, reused_script, &[made_up_outpoint()], 10_000, 100);
+        let young = fund(&wallet, 10_000, 1_000);
+        set_tip(&wallet, 1_000);
+        update_script_index(&wallet).unwrap();
+        let wallet = into_handle(wallet);
+
+        unsafe {
+            let scores = privacy_scores(wallet);
+            assert_eq!(scores[&fresh], 100);
+            assert!(scores[&consolidated] < scores[&fresh]);
+            assert!(scores[&reused] < scores[&fresh]);
+            assert!(scores[&young] < scores[&fresh]);
+            // Reuse weighs the most by default
+            assert!(scores[&reused] < scores[&consolidated]);
+
+            // Weights of zero switch a signal off
+            let mut defaults = WalletDefaults::default();
+            defaults.privacy_weight_address_reuse = 0;
+            assert!(wallet_set_defaults(wallet, defaults));
+            let scores = privacy_scores(wallet);
+            assert_eq!(scores[&reused], scores[&fresh]);
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn recovery_probes_report_errors_to_the_caller() {
+        // Nothing listens there, so the probe fails connecting
+        let server = c_string("tcp://127.0.0.1:1");
+        let descriptor = c_string(&format!("wpkh({}/84'/1'/0'/0/*)", TPRV));
+
+        unsafe {
+            let probes: Vec<*mut RecoveryProbeHandle> = (0..2)
+                .map(|_| {
+                    wallet_recovery_probe_start(
+                        server.as_ptr(),
+                        0,
+                        descriptor.as_ptr(),
+                        NetworkType::Regtest,
+                        100,
+                    )
+                })
+                .collect();
+            // Cancelling one probe leaves the other alone
+            wallet_recovery_probe_cancel(probes[0]);
+            assert!(!probes[1].as_ref().unwrap().cancelled.load(Ordering::SeqCst));
+
+            for probe in probes {
+                let result = wallet_recovery_probe_finish(probe);
+                assert_eq!(result.addresses_scanned, 0);
+                assert!(take_last_error().is_some());
+            }
+        }
+    }
+
+    // Coins funded in the order given, always to the same address and from the
+    // same made up coin so every wallet ends up with identical transactions
+    fn fund_in_order(coins: &[(u32, OutPoint, u64, u32)]) -> *mut Mutex<Wallet<Tree>> {
+        let wallet = test_wallet();
+        for (index, source, value, height) in coins {
+            let script = wallet
+                .get_address(AddressIndex::Peek(*index))
+                .unwrap()
+                .script_pubkey();
+            fund_script(&wallet, script, &[*source], *value, *height);
+        }
+        into_handle(wallet)
+    }
+
+    #[test]
+    fn lists_in_canonical_order_whatever_the_insertion_order() {
+        // Ties in value, confirmation time and between unconfirmed ones
+        let mut coins: Vec<(u32, OutPoint, u64, u32)> = [
+            (5_000, 0),
+            (5_000, 0),
+            (7_000, 120),
+            (5_000, 120),
+            (9_000, 130),
+            (7_000, 130),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(index, (value, height))| (index as u32, made_up_outpoint(), *value, *height))
+        .collect();
+
+        let mut rng = rand::thread_rng();
+        let mut first: Option<(Vec<String>, Vec<(String, u32)>)> = None;
+        for _ in 0..10 {
+            rng.shuffle(&mut coins);
+            let wallet = fund_in_order(&coins);
+
+            unsafe {
+                let list = wallet_get_transactions(wallet);
+                let transactions =
+                    std::slice::from_raw_parts(list.transactions, list.transactions_len as usize);
+                let keys: Vec<(bool, Reverse<u64>, String)> = transactions
+                    .iter()
+                    .map(|t| {
+                        (
+                            t.confirmation_height > 0,
+                            Reverse(t.confirmation_time),
+                            string_from(t.txid),
+                        )
+                    })
+                    .collect();
+                wallet_transaction_list_free(list);
+                assert!(keys.windows(2).all(|pair| pair[0] <= pair[1]));
+                let txids = keys.into_iter().map(|(_, _, txid)| txid).collect();
+
+                let list = wallet_list_utxos(wallet);
+                let utxos = std::slice::from_raw_parts(list.utxos, list.utxos_len as usize);
+                let keys: Vec<(Reverse<u64>, String, u32)> = utxos
+                    .iter()
+                    .map(|u| (Reverse(u.value), string_from(u.txid), u.vout))
+                    .collect();
+                wallet_utxo_list_free(list);
+                assert!(keys.windows(2).all(|pair| pair[0] <= pair[1]));
+                let outpoints = keys
+                    .into_iter()
+                    .map(|(_, txid, vout)| (txid, vout))
+                    .collect();
+
+                let emitted = (txids, outpoints);
+                match &first {
+                    None => first = Some(emitted),
+                    Some(first) => assert_eq!(*first, emitted),
+                }
+                wallet_drop(wallet);
+            }
+        }
+    }
+
+    #[test]
+    fn lists_addresses_by_index() {
+        let wallet = test_wallet();
+        let expected: Vec<String> = (0..5)
+            .map(|_| {
+                wallet
+                    .get_address(AddressIndex::New)
+                    .unwrap()
+                    .address
+                    .to_string()
+            })
+            .collect();
+        let wallet = into_handle(wallet);
+
+        unsafe {
+            let list = wallet_list_addresses(wallet, KeychainType::External);
+            let addresses: Vec<String> =
+                std::slice::from_raw_parts(list.addresses, list.addresses_len as usize)
+                    .iter()
+                    .map(|a| string_from(*a))
+                    .collect();
+            wallet_address_list_free(list);
+            assert_eq!(addresses, expected);
+
+            let list = wallet_list_addresses(wallet, KeychainType::Internal);
+            assert_eq!(list.addresses_len, 0);
+            wallet_address_list_free(list);
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn frees_transaction_list() {
+        let wallet = test_wallet();
+        fund(&wallet, 10_000, 100);
+        fund(&wallet, 20_000, 0);
+        let wallet = into_handle(wallet);
+
+        unsafe {
+            let list = wallet_get_transactions(wallet);
+            assert_eq!(list.transactions_len, 2);
+            wallet_transaction_list_free(list);
+
+            wallet_transaction_list_free(TransactionList {
+                transactions_len: 0,
+                transactions: ptr::null(),
+            });
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn frees_server_features() {
+        let genesis_hash = vec![0u8; 32].into_boxed_slice();
+        let features = ServerFeatures {
+            server_version: CString::new("ElectrumX 1.16.0").unwrap().into_raw(),
+            protocol_min: CString::new("1.4").unwrap().into_raw(),
+            protocol_max: CString::new("1.4.2").unwrap().into_raw(),
+            pruning: -1,
+            genesis_hash_len: genesis_hash.len() as u32,
+            genesis_hash: Box::into_raw(genesis_hash) as *const u8,
+        };
+
+        unsafe {
+            wallet_server_features_free(features);
+            wallet_server_features_free(ServerFeatures {
+                server_version: ptr::null(),
+                protocol_min: ptr::null(),
+                protocol_max: ptr::null(),
+                pruning: 0,
+                genesis_hash_len: 0,
+                genesis_hash: ptr::null(),
+            });
+        }
+    }
+
+    #[test]
+    fn frees_strings_and_failed_psbts() {
+        let wallet = into_handle(test_wallet());
+        let garbage = c_string("not a psbt");
+
+        unsafe {
+            // Failures hand back a Psbt of null pointers, which is fine to free
+            let psbt = wallet_decode_psbt(wallet, garbage.as_ptr());
+            assert!(psbt.base64.is_null());
+            wallet_psbt_free(psbt);
+
+            wallet_string_free(wallet_last_error_message() as *mut c_char);
+            wallet_string_free(ptr::null_mut());
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn frees_results_wrapping_psbts_and_strings() {
+        unsafe {
+            wallet_migration_free(Migration {
+                psbt: psbt_error_return(),
+                destination: c_string("bc1qdestination").into_raw(),
+                labels_copied: 0,
+                notes_copied: 0,
+            });
+            wallet_private_psbt_free(PrivatePsbt {
+                psbt: psbt_error_return(),
+                input_addresses: 0,
+            });
+            wallet_combined_psbt_free(CombinedPsbt {
+                psbt: psbt_error_return(),
+                complete: false,
+            });
+            wallet_network_confirmation_free(NetworkConfirmation {
+                expected: c_string("bitcoin").into_raw(),
+                server_reported: ptr::null(),
+                matches: false,
+            });
+            wallet_script_info_free(ScriptInfo {
+                found: true,
+                address: c_string("bc1qaddress").into_raw(),
+                keychain: KeychainType::External,
+                index: 0,
+            });
+            wallet_output_scripts_free(OutputScripts {
+                redeem_script: ptr::null(),
+                witness_script: c_string("0014").into_raw(),
+            });
+        }
+    }
+
+    #[test]
+    fn resolves_scripts_after_the_cache_lock_is_poisoned() {
+        let wallet = test_wallet();
+        let address = wallet.get_address(AddressIndex::New).unwrap().address;
+        let wallet = into_handle(wallet);
+        let script_hex = c_string(&hex::encode(address.script_pubkey().as_bytes()));
+
+        let _ = std::thread::spawn(|| {
+            let _caches = SCRIPT_CACHES.lock().unwrap();
+            panic!("poisoning the script caches");
+        })
+        .join();
+
+        unsafe {
+            let info = wallet_resolve_script(wallet, script_hex.as_ptr());
+            assert!(info.found);
+            assert_eq!(string_from(info.address), address.to_string());
+            wallet_script_info_free(info);
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn rejects_watch_only_exports_with_private_keys() {
+        let data_dir = std::env::temp_dir().join(format!(
+            "envoy-watch-only-{}-{}",
+            std::process::id(),
+            line!()
+        ));
+        let export = c_string(&format!(
+            "network:testnet\nbirthday:0\nexternal:wpkh({tprv}/84'/1'/0'/0/*)\ninternal:wpkh({tprv}/84'/1'/0'/1/*)",
+            tprv = TPRV
+        ));
+        let name = c_string("watch-only");
+        let dir = c_string(data_dir.to_str().unwrap());
+
+        unsafe {
+            let wallet = wallet_init_watch_only(name.as_ptr(), export.as_ptr(), dir.as_ptr());
+            assert!(wallet.is_null());
+        }
+        let error = take_last_error().unwrap();
+        assert!(matches!(
+            error.downcast_ref::<WalletError>(),
+            Some(WalletError::MalformedWatchOnlyExport)
+        ));
+        assert!(!data_dir.exists());
+    }
+
+    #[test]
+    fn regtest_wallets_accept_base58_test_addresses() {
+        let wallet = test_wallet();
+        let pubkey = bdk::bitcoin::PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        let p2pkh = Address::p2pkh(&pubkey, Network::Testnet).to_string();
+        let bech32 = Address::p2wpkh(&pubkey, Network::Regtest)
+            .unwrap()
+            .to_string();
+        let mainnet = Address::p2pkh(&pubkey, Network::Bitcoin).to_string();
+
+        assert!(parse_address(&wallet, &p2pkh).is_ok());
+        assert!(parse_address(&wallet, &bech32).is_ok());
+        assert!(parse_address(&wallet, &mainnet).is_err());
+    }
+
+    #[test]
+    fn single_recipient_builders_reject_other_networks() {
+        let wallet = test_wallet();
+        fund(&wallet, 100_000, 100);
+        let wallet = into_handle(wallet);
+        let mainnet = c_string("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4");
+
+        unsafe {
+            let preview = wallet_preview_send(wallet, mainnet.as_ptr(), 10_000, 0.00001);
+            assert_eq!(preview.sent, 0);
+            let error = take_last_error().unwrap();
+            assert!(matches!(
+                error.downcast_ref::<WalletError>(),
+                Some(WalletError::InvalidAddress(_))
+            ));
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn balance_at_needs_a_known_tip() {
+        let wallet = test_wallet();
+        fund(&wallet, 100_000, 100);
+        let wallet = into_handle(wallet);
+
+        unsafe {
+            assert_eq!(wallet_get_balance_at(wallet, 0), 100_000);
+            assert_eq!(wallet_get_balance_at(wallet, 1), 0);
+            let error = take_last_error().unwrap();
+            assert!(matches!(
+                error.downcast_ref::<WalletError>(),
+                Some(WalletError::TipUnknown)
+            ));
+
+            set_tip(&(*wallet).lock().unwrap(), 105);
+            assert_eq!(wallet_get_balance_at(wallet, 6), 100_000);
+            assert_eq!(wallet_get_balance_at(wallet, 7), 0);
+            assert!(take_last_error().is_none());
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn checks_xpub_origins() {
+        let secp = Secp256k1::new();
+        let master = ExtendedPrivKey::from_str(TPRV).unwrap();
+        let account = master
+            .derive_priv(&secp, &DerivationPath::from_str("m/84'/1'/0'").unwrap())
+            .unwrap();
+        let master_xpub = bdk::bitcoin::util::bip32::ExtendedPubKey::from_priv(&secp, &master);
+        let account_xpub = bdk::bitcoin::util::bip32::ExtendedPubKey::from_priv(&secp, &account);
+        let watch_only = |external: String, internal: String| {
+            Wallet::new(
+                external.as_str(),
+                Some(internal.as_str()),
+                Network::Regtest,
+                temporary_tree(),
+            )
+            .unwrap()
+        };
+
+        let with_origin = watch_only(
+            format!(
+                "wpkh([{}/84'/1'/0']{}/0/*)",
+                master.fingerprint(&secp),
+                account_xpub
+            ),
+            format!(
+                "wpkh([{}/84'/1'/0']{}/1/*)",
+                master.fingerprint(&secp),
+                account_xpub
+            ),
+        );
+        let at_master = watch_only(
+            format!("wpkh({}/0/*)", master_xpub),
+            format!("wpkh({}/1/*)", master_xpub),
+        );
+        let without_origin = watch_only(
+            format!("wpkh({}/0/*)", account_xpub),
+            format!("wpkh({}/1/*)", account_xpub),
+        );
+
+        assert!(descriptor_keys_have_origins(&test_wallet()));
+        assert!(descriptor_keys_have_origins(&with_origin));
+        assert!(descriptor_keys_have_origins(&at_master));
+        assert!(!descriptor_keys_have_origins(&without_origin));
+    }
+
+    #[test]
+    fn ur_progress_counts_only_accepted_plain_parts() {
+        let psbt = c_string(&base64::encode(&test_psbt_bytes()));
+
+        unsafe {
+            let qr_parts = wallet_psbt_to_ur_parts(psbt.as_ptr(), 10);
+            let parts: Vec<String> =
+                std::slice::from_raw_parts(qr_parts.parts, qr_parts.parts_len as usize)
+                    .iter()
+                    .map(|part| string_from(*part))
+                    .collect();
+            wallet_qr_parts_free(qr_parts);
+            let total = parts.len() / 2;
+            assert!(total > 2);
+
+            let decoder = wallet_ur_decoder_new();
+            let receive = |part: &str| wallet_ur_decoder_receive(decoder, c_string(part).as_ptr());
+
+            // A fountain coded part alone isn't progress
+            assert_eq!(receive(&parts[total]), 0.0);
+
+            // Neither is a frame the decoder rejects
+            let (prefix, _) = parts[0].rsplit_once('/').unwrap();
+            assert_eq!(receive(&format!("{}/notbytewords", prefix)), -1.0);
+            assert!(take_last_error().is_some());
+
+            assert_eq!(receive(&parts[0]), 1.0 / total as f64);
+            assert_eq!(receive(&parts[0]), 1.0 / total as f64);
+
+            // The fountain coded part may complete it a frame early
+            let mut progress = 0.0;
+            for part in &parts[1..total] {
+                progress = receive(part);
+            }
+            assert_eq!(progress, 1.0);
+
+            let result = wallet_ur_decoder_result(decoder);
+            assert_eq!(string_from(result), base64::encode(&test_psbt_bytes()));
+            wallet_string_free(result as *mut c_char);
+            wallet_ur_decoder_free(decoder);
+        }
+    }
+
+    #[test]
+    fn coordinated_and_cpfp_psbts_leave_frozen_coins_alone() {
+        let wallet = test_wallet();
+        let frozen = fund(&wallet, 60_000, 100);
+        let spendable = fund(&wallet, 40_000, 100);
+        let unconfirmed = fund(&wallet, 20_000, 0);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+        let parent = c_string(&unconfirmed.txid.to_string());
+        let electrum = c_string("tcp://127.0.0.1:1");
+
+        unsafe {
+            set_frozen(wallet, frozen);
+            let psbt = wallet_create_psbt_coordinated(
+                wallet,
+                send_to.as_ptr(),
+                30_000,
+                0.00002,
+                OutputOrdering::Bip69,
+                0,
+                true,
+            );
+            let decoded = decode_psbt_base64(&string_from(psbt.base64)).unwrap();
+            wallet_psbt_free(psbt);
+            let inputs: Vec<OutPoint> = decoded
+                .unsigned_tx
+                .input
+                .iter()
+                .map(|input| input.previous_output)
+                .collect();
+            assert!(inputs.contains(&spendable));
+            assert!(!inputs.contains(&frozen));
+
+            set_frozen(wallet, unconfirmed);
+            let psbt = wallet_create_cpfp(wallet, parent.as_ptr(), 0.0001, electrum.as_ptr(), 0);
+            assert!(psbt.base64.is_null());
+            let error = take_last_error().unwrap();
+            assert!(matches!(
+                error.downcast_ref::<WalletError>(),
+                Some(WalletError::NoOutputToSpend(_))
+            ));
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn randomized_lock_times_keep_mature_coinbase_spendable() {
+        let wallet = test_wallet();
+        // Mature as of the tip, but not as of a randomized lower height
+        fund_spending(&wallet, &[OutPoint::null()], 100_000, 900);
+        set_tip(&wallet, 1000);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+
+        unsafe {
+            for _ in 0..100 {
+                let psbt = wallet_create_psbt(
+                    wallet,
+                    send_to.as_ptr(),
+                    50_000,
+                    0.00002,
+                    &CreatePsbtOptions {
+                        ordering: OutputOrdering::Shuffle,
+                        spend_unconfirmed: false,
+                        ..Default::default()
+                    },
+                );
+                let data = base64::decode(string_from(psbt.base64)).unwrap();
+                wallet_psbt_free(psbt);
+
+                let lock_time = lock_time_of(&data).unwrap();
+                assert!(lock_time <= 1000);
+                assert!(lock_time > 1000 - LOCKTIME_RANDOMIZATION_BLOCKS);
+            }
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn create_psbt_never_spends_excluded_coins() {
+        let wallet = test_wallet();
+        let excluded = fund(&wallet, 60_000, 100);
+        let spendable = fund(&wallet, 40_000, 100);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+        let excluded_c = c_string(&excluded.to_string());
+        let excluded_list = [excluded_c.as_ptr()];
+
+        unsafe {
+            for _ in 0..10 {
+                let psbt = wallet_create_psbt(
+                    wallet,
+                    send_to.as_ptr(),
+                    30_000,
+                    0.00001,
+                    &CreatePsbtOptions {
+                        excluded_len: 1,
+                        excluded: excluded_list.as_ptr(),
+                        ..Default::default()
+                    },
+                );
+                let decoded = decode_psbt_base64(&string_from(psbt.base64)).unwrap();
+                wallet_psbt_free(psbt);
+                let inputs: Vec<OutPoint> = decoded
+                    .unsigned_tx
+                    .input
+                    .iter()
+                    .map(|input| input.previous_output)
+                    .collect();
+                assert_eq!(inputs, vec![spendable]);
+            }
+
+            // Too much to pay without the excluded coin
+            let psbt = wallet_create_psbt(
+                wallet,
+                send_to.as_ptr(),
+                50_000,
+                0.00001,
+                &CreatePsbtOptions {
+                    excluded_len: 1,
+                    excluded: excluded_list.as_ptr(),
+                    ..Default::default()
+                },
+            );
+            assert!(psbt.base64.is_null());
+            assert!(take_last_error().is_some());
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn create_psbt_picks_coins_by_strategy() {
+        let wallet = test_wallet();
+        let oldest = fund(&wallet, 20_000, 100);
+        let largest = fund(&wallet, 80_000, 200);
+        set_tip(&wallet, 300);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+
+        unsafe {
+            for (strategy, expected) in [
+                (CoinSelectionStrategy::OldestFirst, oldest),
+                (CoinSelectionStrategy::LargestFirst, largest),
+            ] {
+                let psbt = wallet_create_psbt(
+                    wallet,
+                    send_to.as_ptr(),
+                    10_000,
+                    0.00001,
+                    &CreatePsbtOptions {
+                        strategy,
+                        ..Default::default()
+                    },
+                );
+                let decoded = decode_psbt_base64(&string_from(psbt.base64)).unwrap();
+                wallet_psbt_free(psbt);
+                let inputs: Vec<OutPoint> = decoded
+                    .unsigned_tx
+                    .input
+                    .iter()
+                    .map(|input| input.previous_output)
+                    .collect();
+                assert_eq!(inputs, vec![expected]);
+            }
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn create_psbt_sends_change_where_asked() {
+        let wallet = test_wallet();
+        fund(&wallet, 100_000, 100);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+        let cold_storage = test_wallet_for_account(1)
+            .get_address(AddressIndex::New)
+            .unwrap()
+            .address;
+        let change_address = c_string(&cold_storage.to_string());
+
+        unsafe {
+            let psbt = wallet_create_psbt(
+                wallet,
+                send_to.as_ptr(),
+                30_000,
+                0.00001,
+                &CreatePsbtOptions {
+                    change_address: change_address.as_ptr(),
+                    ..Default::default()
+                },
+            );
+            let decoded = decode_psbt_base64(&string_from(psbt.base64)).unwrap();
+            // Another wallet's change is money leaving this one
+            assert_eq!(psbt.sent + psbt.fee, 100_000);
+            assert_eq!(psbt.received, 0);
+            wallet_psbt_free(psbt);
+
+            let outputs = &decoded.unsigned_tx.output;
+            assert_eq!(outputs.len(), 2);
+            assert!(outputs
+                .iter()
+                .any(|o| o.script_pubkey == cold_storage.script_pubkey() && o.value > 60_000));
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn create_psbt_carries_op_return_data() {
+        let wallet = test_wallet();
+        fund(&wallet, 100_000, 100);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+        let proof = b"invoice 42";
+        let too_long = [0u8; MAX_OP_RETURN_BYTES + 1];
+
+        unsafe {
+            let psbt = wallet_create_psbt(
+                wallet,
+                send_to.as_ptr(),
+                30_000,
+                0.00001,
+                &CreatePsbtOptions {
+                    data_len: proof.len() as u32,
+                    data: proof.as_ptr(),
+                    ..Default::default()
+                },
+            );
+            let decoded = decode_psbt_base64(&string_from(psbt.base64)).unwrap();
+            wallet_psbt_free(psbt);
+            assert!(decoded.unsigned_tx.output.iter().any(|o| o.value == 0
+                && o.script_pubkey.is_op_return()
+                && o.script_pubkey.as_bytes().ends_with(proof)));
+
+            let psbt = wallet_create_psbt(
+                wallet,
+                send_to.as_ptr(),
+                30_000,
+                0.00001,
+                &CreatePsbtOptions {
+                    data_len: too_long.len() as u32,
+                    data: too_long.as_ptr(),
+                    ..Default::default()
+                },
+            );
+            assert!(psbt.base64.is_null());
+            let error = take_last_error().unwrap();
+            assert!(matches!(
+                error.downcast_ref::<WalletError>(),
+                Some(WalletError::InputTooLarge { .. })
+            ));
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn create_psbt_pays_an_absolute_fee() {
+        let wallet = test_wallet();
+        fund(&wallet, 100_000, 100);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+
+        unsafe {
+            // The fee rate is garbage on purpose, it must not be looked at
+            let psbt = wallet_create_psbt(
+                wallet,
+                send_to.as_ptr(),
+                30_000,
+                -1.0,
+                &CreatePsbtOptions {
+                    fee_absolute: 1_234,
+                    ..Default::default()
+                },
+            );
+            assert!(!psbt.base64.is_null());
+            assert!(psbt.fee_known);
+            assert_eq!(psbt.fee, 1_234);
+            wallet_psbt_free(psbt);
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn create_psbt_pays_without_change_when_asked() {
+        let wallet = test_wallet();
+        fund(&wallet, 50_000, 100);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+        let changeless = CreatePsbtOptions {
+            changeless: true,
+            max_excess: 1_000,
+            ..Default::default()
+        };
+
+        unsafe {
+            // Leaves far more than max_excess over, so it needs change
+            let psbt = wallet_create_psbt(wallet, send_to.as_ptr(), 20_000, 0.00001, &changeless);
+            assert!(psbt.base64.is_null());
+            assert!(matches!(
+                wallet_last_selection_failure(),
+                SelectionFailure::NoChangelessSolution
+            ));
+            take_last_error();
+
+            let psbt = wallet_create_psbt(wallet, send_to.as_ptr(), 49_500, 0.00001, &changeless);
+            assert!(!psbt.base64.is_null());
+            let decoded = decode_psbt_base64(&string_from(psbt.base64)).unwrap();
+            assert_eq!(decoded.unsigned_tx.output.len(), 1);
+            assert_eq!(psbt.received, 0);
+            wallet_psbt_free(psbt);
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn create_psbt_spends_only_the_chosen_group() {
+        let wallet = test_wallet();
+        let kyc_free = fund(&wallet, 40_000, 100);
+        let exchange = fund(&wallet, 90_000, 100);
+        fund(&wallet, 70_000, 100);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+        let group = c_string("KYC-free");
+
+        unsafe {
+            set_group(wallet, kyc_free, "KYC-free");
+            set_group(wallet, exchange, "exchange");
+            let options = CreatePsbtOptions {
+                group: group.as_ptr(),
+                ..Default::default()
+            };
+
+            let psbt = wallet_create_psbt(wallet, send_to.as_ptr(), 30_000, 0.00001, &options);
+            let decoded = decode_psbt_base64(&string_from(psbt.base64)).unwrap();
+            wallet_psbt_free(psbt);
+            let inputs: Vec<OutPoint> = decoded
+                .unsigned_tx
+                .input
+                .iter()
+                .map(|i| i.previous_output)
+                .collect();
+            assert_eq!(inputs, vec![kyc_free]);
+
+            // The rest of the wallet could pay, the group can't
+            let psbt = wallet_create_psbt(wallet, send_to.as_ptr(), 60_000, 0.00001, &options);
+            assert!(psbt.base64.is_null());
+            assert!(matches!(
+                wallet_last_selection_failure(),
+                SelectionFailure::InsufficientGroupFunds
+            ));
+            take_last_error();
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn create_psbt_schedules_payments_with_a_locktime() {
+        let wallet = test_wallet();
+        fund(&wallet, 100_000, 100);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+
+        unsafe {
+            let psbt = wallet_create_psbt(
+                wallet,
+                send_to.as_ptr(),
+                30_000,
+                0.00001,
+                &CreatePsbtOptions {
+                    lock_time: 900_000,
+                    ..Default::default()
+                },
+            );
+            let data = base64::decode(string_from(psbt.base64)).unwrap();
+            wallet_psbt_free(psbt);
+            assert_eq!(lock_time_of(&data).unwrap(), 900_000);
+            wallet_drop(wallet);
+        }
+    }
+
+    #[test]
+    fn create_psbt_keeps_under_the_size_cap() {
+        let wallet = test_wallet();
+        for _ in 0..20 {
+            fund(&wallet, 5_000, 100);
+        }
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+        let capped = |max_psbt_bytes| CreatePsbtOptions {
+            strategy: CoinSelectionStrategy::LargestFirst,
+            max_psbt_bytes,
+            ..Default::default()
         };
-        config = ConfigBuilder::new()
-            .validate_domain(false)
-            .socks5(Some(tor_config))
-            .unwrap()
-            .build();
-    } else {
-        config = ConfigBuilder::new()
-            .validate_domain(false)
-            .socks5(None)
-            .unwrap()
-            .timeout(Some(5))
-            .unwrap()
-            .build();
+
+        unsafe {
+            let psbt = wallet_create_psbt(wallet, send_to.as_ptr(), 4_000, 0.00001, &capped(1_000));
+            assert!(!psbt.base64.is_null());
+            wallet_psbt_free(psbt);
+
+            // Takes most of the coins, far too many inputs for the cap
+            let psbt =
+                wallet_create_psbt(wallet, send_to.as_ptr(), 80_000, 0.00001, &capped(1_000));
+            assert!(psbt.base64.is_null());
+            let error = take_last_error().unwrap();
+            assert!(matches!(
+                error.downcast_ref::<WalletError>(),
+                Some(WalletError::PsbtTooLarge { .. })
+            ));
+            wallet_drop(wallet);
+        }
     }
 
-    Client::from_config(electrum_address, config)
-}
+    #[test]
+    fn consolidation_and_max_spendable_can_leave_unconfirmed_coins() {
+        let wallet = test_wallet();
+        let confirmed = [fund(&wallet, 30_000, 100), fund(&wallet, 40_000, 100)];
+        let unconfirmed = fund(&wallet, 50_000, 0);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
 
-#[no_mangle]
-pub unsafe extern "C" fn wallet_get_balance(wallet: *mut Mutex<Wallet<Tree>>) -> u64 {
-    let wallet = get_wallet_mutex(wallet).lock().unwrap();
-    let balance = wallet.get_balance().unwrap();
-    balance.confirmed + balance.immature + balance.trusted_pending + balance.untrusted_pending
-}
+        unsafe {
+            let psbt = wallet_consolidate_utxos(wallet, 0.00001, 0, OutputOrdering::Bip69, false);
+            let decoded = decode_psbt_base64(&string_from(psbt.base64)).unwrap();
+            wallet_psbt_free(psbt);
+            let inputs: HashSet<OutPoint> = decoded
+                .unsigned_tx
+                .input
+                .iter()
+                .map(|i| i.previous_output)
+                .collect();
+            assert_eq!(inputs, confirmed.iter().cloned().collect());
+            assert!(!inputs.contains(&unconfirmed));
 
-#[no_mangle]
-pub unsafe extern "C" fn wallet_get_fee_rate(
-    electrum_address: *const c_char,
-    tor_port: i32,
-    target: u16,
-) -> f64 {
-    let electrum_address = CStr::from_ptr(electrum_address).to_str().unwrap();
-    let client = match get_electrum_client(tor_port, electrum_address) {
-        Ok(c) => c,
-        Err(e) => {
-            update_last_error(e);
-            return -1.0;
+            let confirmed_only = wallet_get_max_spendable(wallet, send_to.as_ptr(), 0.00001, false);
+            let everything = wallet_get_max_spendable(wallet, send_to.as_ptr(), 0.00001, true);
+            assert!(confirmed_only > 69_000 && confirmed_only < 70_000);
+            assert!(everything > 119_000 && everything < 120_000);
+            wallet_drop(wallet);
         }
-    };
+    }
 
-    // BTC per kb
-    client.estimate_fee(target as usize).unwrap_or(-1.0)
-}
+    #[test]
+    fn dust_limit_goes_by_the_recipient_script() {
+        let wallet = test_wallet();
+        fund(&wallet, 100_000, 100);
+        let wallet = into_handle(wallet);
+        // P2WPKH dust is 294 sats, P2PKH's 546
+        let segwit = c_string(&foreign_address().to_string());
+        let legacy = c_string(
+            &Address::p2pkh(
+                &bdk::bitcoin::PublicKey::from_str(
+                    "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+                )
+                .unwrap(),
+                Network::Regtest,
+            )
+            .to_string(),
+        );
 
-#[no_mangle]
-pub unsafe extern "C" fn wallet_get_server_features(
-    electrum_address: *const c_char,
-    tor_port: i32,
-) -> ServerFeatures {
-    let error_return = ServerFeatures {
-        server_version: ptr::null(),
-        protocol_min: ptr::null(),
-        protocol_max: ptr::null(),
-        pruning: 0,
-        genesis_hash: ptr::null(),
-    };
+        unsafe {
+            let psbt = wallet_create_psbt(wallet, segwit.as_ptr(), 400, 0.00001, ptr::null());
+            assert!(!psbt.base64.is_null());
+            wallet_psbt_free(psbt);
 
-    let electrum_address = CStr::from_ptr(electrum_address).to_str().unwrap();
-    let client = unwrap_or_return!(
-        get_electrum_client(tor_port, electrum_address),
-        error_return
-    );
+            let recipient = Recipient {
+                address: legacy.as_ptr(),
+                amount: 400,
+                label: ptr::null(),
+            };
+            let psbt = wallet_create_psbt_multi(
+                wallet,
+                &recipient,
+                1,
+                0.00001,
+                ptr::null(),
+                OutputOrdering::Bip69,
+                true,
+            );
+            assert!(psbt.base64.is_null());
+            let error = take_last_error().unwrap();
+            assert!(matches!(
+                error.downcast_ref::<WalletError>(),
+                Some(WalletError::BelowDustLimit { limit: 546, .. })
+            ));
+            wallet_drop(wallet);
+        }
+    }
 
-    match client.server_features() {
-        Ok(f) => {
-            let genesis_hash = f.genesis_hash.clone();
+    // Record the transaction of a PSBT built by `wallet` as sent but not
+    // confirmed yet, as if it had been broadcast
+    fn record_unconfirmed_spend(wallet: &Wallet<Tree>, psbt: &PartiallySignedTransaction) {
+        let tx = psbt.unsigned_tx.clone();
+        let (sent, received) = tx.output.iter().fold((0, 0), |(sent, received), o| {
+            if wallet.is_mine(&o.script_pubkey).unwrap() {
+                (sent, received + o.value)
+            } else {
+                (sent + o.value, received)
+            }
+        });
+        let fee = psbt_fee(wallet, psbt).unwrap();
+        let mut db = wallet.database().clone();
+        db.set_tx(&TransactionDetails {
+            txid: tx.txid(),
+            received,
+            sent: sent + received + fee,
+            fee: Some(fee),
+            confirmation_time: None,
+            transaction: Some(tx),
+        })
+        .unwrap();
+    }
 
-            // Freed on Dart side
-            std::mem::forget(genesis_hash);
+    #[test]
+    fn replacements_pay_the_original_fee_plus_relay() {
+        let wallet = test_wallet();
+        fund(&wallet, 100_000, 100);
+        let wallet = into_handle(wallet);
+        let send_to = c_string(&foreign_address().to_string());
+        let redirect_to = c_string(
+            &test_wallet_for_account(7)
+                .get_address(AddressIndex::New)
+                .unwrap()
+                .address
+                .to_string(),
+        );
 
-            ServerFeatures {
-                server_version: CString::new(f.server_version).unwrap().into_raw(),
-                protocol_min: CString::new(f.protocol_min).unwrap().into_raw(),
-                protocol_max: CString::new(f.protocol_max).unwrap().into_raw(),
-                pruning: f.pruning.unwrap_or(-1),
-                genesis_hash: genesis_hash.as_ptr(),
+        unsafe {
+            let original = wallet_create_psbt(
+                wallet,
+                send_to.as_ptr(),
+                30_000,
+                0.00001,
+                &CreatePsbtOptions {
+                    fee_absolute: 5_000,
+                    ..Default::default()
+                },
+            );
+            let decoded = decode_psbt_base64(&string_from(original.base64)).unwrap();
+            let txid = c_string(&decoded.unsigned_tx.txid().to_string());
+            wallet_psbt_free(original);
+            record_unconfirmed_spend(&get_wallet_mutex(wallet).lock().unwrap(), &decoded);
+
+            // 5000 sats on a ~140 vB transaction is well above 2 sat/vB, BIP125
+            // still wants the old fee plus relay for the replacement's size
+            for replacement in [
+                wallet_cancel_tx(wallet, txid.as_ptr(), 0.00002),
+                wallet_redirect_tx(wallet, txid.as_ptr(), redirect_to.as_ptr(), 0.00002),
+            ] {
+                let fee = replacement.fee;
+                let psbt = decode_psbt_base64(&string_from(replacement.base64)).unwrap();
+                wallet_psbt_free(replacement);
+                let vsize =
+                    estimated_vsize(&get_wallet_mutex(wallet).lock().unwrap(), &psbt.unsigned_tx);
+                assert_eq!(fee, 5_000 + vsize);
+                assert_eq!(
+                    psbt.unsigned_tx.input[0].previous_output,
+                    decoded.unsigned_tx.input[0].previous_output
+                );
             }
+            wallet_drop(wallet);
         }
-        Err(e) => {
-            update_last_error(e);
-            error_return
+    }
+
+    #[test]
+    fn psbt_outputs_show_every_change_output() {
+        let wallet = test_wallet();
+        fund(&wallet, 100_000, 100);
+        let own_change = wallet
+            .get_internal_address(AddressIndex::Peek(3))
+            .unwrap()
+            .address;
+        let wallet = into_handle(wallet);
+        let foreign = c_string(&foreign_address().to_string());
+        let own_change = c_string(&own_change.to_string());
+        let recipients = [
+            Recipient {
+                address: foreign.as_ptr(),
+                amount: 30_000,
+                label: ptr::null(),
+            },
+            Recipient {
+                address: own_change.as_ptr(),
+                amount: 20_000,
+                label: ptr::null(),
+            },
+        ];
+
+        unsafe {
+            let psbt = wallet_create_psbt_multi(
+                wallet,
+                recipients.as_ptr(),
+                recipients.len() as u32,
+                0.00001,
+                ptr::null(),
+                OutputOrdering::Bip69,
+                true,
+            );
+            assert!(!psbt.base64.is_null());
+            let outputs = std::slice::from_raw_parts(psbt.outputs, psbt.outputs_len as usize);
+            assert_eq!(outputs.len(), 3);
+            assert_eq!(outputs.iter().filter(|o| o.is_change).count(), 2);
+            let foreign_outputs: Vec<u64> = outputs
+                .iter()
+                .filter(|o| !o.is_mine)
+                .map(|o| o.value)
+                .collect();
+            assert_eq!(foreign_outputs, vec![30_000]);
+            wallet_psbt_free(psbt);
+            wallet_drop(wallet);
         }
     }
-}
 
-#[no_mangle]
-pub unsafe extern "C" fn wallet_get_transactions(
-    wallet: *mut Mutex<Wallet<Tree>>,
-) -> TransactionList {
-    let wallet = get_wallet_mutex(wallet).lock().unwrap();
+    #[test]
+    fn notes_decrypt_on_a_wallet_restored_from_the_same_seed() {
+        let txid = c_string(&made_up_outpoint().txid.to_string());
+        let note = c_string("rent for March");
+        let master = ExtendedPrivKey::from_str(TPRV).unwrap();
+        let (external, internal) = bip84_descriptors(&master, Network::Regtest).unwrap();
+        let restored = into_handle(
+            Wallet::new(
+                external.as_str(),
+                Some(internal.as_str()),
+                Network::Regtest,
+                temporary_tree(),
+            )
+            .unwrap(),
+        );
+        let wallet = into_handle(test_wallet());
+        let other_account = into_handle(test_wallet_for_account(1));
 
-    let transactions = wallet.list_transactions(true).unwrap();
-    let transactions_len = transactions.len() as u32;
+        unsafe {
+            assert!(wallet_set_encrypted_note(
+                wallet,
+                txid.as_ptr(),
+                note.as_ptr()
+            ));
+            assert_eq!(
+                string_from(wallet_get_encrypted_note(wallet, txid.as_ptr())),
+                "rent for March"
+            );
+            let backup = wallet_export_encrypted_notes(wallet);
 
-    let mut transactions_vec: Vec<Transaction> = vec![];
+            // The master key with the path vs. the account key with its origin
+            assert_eq!(wallet_recover_notes(restored, backup), 1);
+            assert_eq!(
+                string_from(wallet_get_encrypted_note(restored, txid.as_ptr())),
+                "rent for March"
+            );
 
-    for transaction in transactions {
-        let confirmation_height: u32;
-        let confirmation_time: u64;
+            assert_eq!(wallet_recover_notes(other_account, backup), -1);
+            let error = take_last_error().unwrap();
+            assert!(matches!(
+                error.downcast_ref::<WalletError>(),
+                Some(WalletError::NoteDecryptionFailed(_))
+            ));
 
-        match transaction.confirmation_time.as_ref() {
-            None => {
-                confirmation_height = 0;
-                confirmation_time = 0;
-            }
-            Some(_) => {
-                confirmation_height = transaction.confirmation_time.as_ref().unwrap().height;
-                confirmation_time = transaction.confirmation_time.as_ref().unwrap().timestamp;
+            for handle in [wallet, restored, other_account] {
+                wallet_drop(handle);
             }
         }
+    }
 
-        let tx = Transaction {
-            txid: CString::new(format!("{}", transaction.txid))
+    #[test]
+    fn migration_moves_labels_and_notes_but_not_frozen_coins() {
+        let wallet_old = test_wallet();
+        fund(&wallet_old, 50_000, 100);
+        let frozen = fund(&wallet_old, 70_000, 100);
+        let wallet_new = test_wallet_for_account(1);
+        let txid = made_up_outpoint().txid;
+        let wallet_old = into_handle(wallet_old);
+        let wallet_new = into_handle(wallet_new);
+
+        unsafe {
+            set_frozen(wallet_old, frozen);
+            let txid_string = c_string(&txid.to_string());
+            let note = c_string("bought the couch");
+            assert!(wallet_set_encrypted_note(
+                wallet_old,
+                txid_string.as_ptr(),
+                note.as_ptr()
+            ));
+            let label_key = format!("{}{}", LABELS_PREFIX, txid);
+            get_wallet_mutex(wallet_old)
+                .lock()
                 .unwrap()
-                .into_raw(),
-            received: transaction.received,
-            sent: transaction.sent,
-            fee: transaction.fee.unwrap(),
-            confirmation_height,
-            confirmation_time,
-        };
+                .database()
+                .insert(label_key.as_str(), "furniture")
+                .unwrap();
 
-        transactions_vec.push(tx);
+            let migration = migrate(
+                &get_wallet_mutex(wallet_old).lock().unwrap(),
+                &get_wallet_mutex(wallet_new).lock().unwrap(),
+                FeeRate::from_sat_per_vb(1.0),
+            )
+            .unwrap();
+            assert_eq!(migration.labels_copied, 1);
+            assert_eq!(migration.notes_copied, 1);
+
+            let psbt = decode_psbt_base64(&string_from(migration.psbt.base64)).unwrap();
+            assert!(psbt
+                .unsigned_tx
+                .input
+                .iter()
+                .all(|i| i.previous_output != frozen));
+            wallet_migration_free(migration);
+
+            // Readable with the new wallet's keys, not just copied over
+            assert_eq!(
+                string_from(wallet_get_encrypted_note(wallet_new, txid_string.as_ptr())),
+                "bought the couch"
+            );
+            assert!(get_wallet_mutex(wallet_new)
+                .lock()
+                .unwrap()
+                .database()
+                .get(label_key.as_str())
+                .unwrap()
+                .is_some());
+
+            wallet_drop(wallet_old);
+            wallet_drop(wallet_new);
+        }
     }
 
-    let transactions_box = transactions_vec.into_boxed_slice();
-    let txs_ptr = Box::into_raw(transactions_box);
+    #[test]
+    fn closing_the_sync_relay_cuts_off_a_round_trip_in_flight() {
+        // Stands in for an Electrum server that takes forever to answer
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_port = server.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = server.accept().unwrap();
+            let mut request = [0u8; 5];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&request).unwrap();
+            std::thread::sleep(Duration::from_secs(60));
+        });
 
-    TransactionList {
-        transactions_len,
-        transactions: txs_ptr as _,
+        let relay = SyncRelay::start(0).unwrap();
+        let mut client = TcpStream::connect(("127.0.0.1", relay.port)).unwrap();
+        client.write_all(&[5, 1, 0]).unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, [5, 0]);
+
+        let mut connect = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        connect.extend_from_slice(&server_port.to_be_bytes());
+        client.write_all(&connect).unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(reply[1], 0);
+
+        client.write_all(b"hello").unwrap();
+        let mut echo = [0u8; 5];
+        client.read_exact(&mut echo).unwrap();
+        assert_eq!(&echo, b"hello");
+
+        // Nothing more is coming from the server, only closing unblocks this
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let started = std::time::Instant::now();
+        relay.close();
+        let mut rest = [0u8; 1];
+        assert!(matches!(client.read(&mut rest), Ok(0) | Err(_)));
+        assert!(started.elapsed() < Duration::from_secs(5));
     }
-}
 
-fn psbt_extract_details(wallet: &Wallet<Tree>, psbt: &PartiallySignedTransaction) -> Psbt {
-    let tx = psbt.clone().extract_tx();
-    let raw_tx = serialize::<bdk::bitcoin::Transaction>(&tx).to_hex();
+    // Grind the nonce until the header meets regtest's trivial target
+    fn mine_regtest_header(prev_blockhash: BlockHash) -> BlockHeader {
+        let mut header = genesis_block(Network::Regtest).header;
+        header.prev_blockhash = prev_blockhash;
+        header.nonce = 0;
+        while header.validate_pow(&header.target()).is_err() {
+            header.nonce += 1;
+        }
+        header
+    }
 
-    let sent = tx
-        .output
-        .iter()
-        .filter(|o| !wallet.is_mine(&o.script_pubkey).unwrap_or(false))
-        .map(|o| o.value)
-        .sum();
+    #[test]
+    fn header_cache_rejects_headers_without_work_or_linkage() {
+        let db = temporary_tree();
+        let network = Network::Regtest;
+        let genesis = genesis_block(network).header;
+        check_header(&db, network, 0, &genesis).unwrap();
+        insert_cached_header(&db, 0, &genesis).unwrap();
 
-    let received = tx
-        .output
-        .iter()
-        .filter(|o| wallet.is_mine(&o.script_pubkey).unwrap_or(false))
-        .map(|o| o.value)
-        .sum();
+        let next = mine_regtest_header(genesis.block_hash());
+        check_header(&db, network, 1, &next).unwrap();
 
-    let inputs_value: u64 = psbt
-        .inputs
-        .iter()
-        .map(|i| match &i.witness_utxo {
-            None => 0,
-            Some(tx) => tx.value,
-        })
-        .sum();
+        let elsewhere = mine_regtest_header(BlockHash::hash(b"another chain"));
+        assert!(matches!(
+            check_header(&db, network, 1, &elsewhere),
+            Err(WalletError::HeaderNotConnected(1))
+        ));
 
-    let encoded = base64::encode(&serialize(&psbt));
-    let psbt = CString::new(encoded).unwrap().into_raw();
+        // A real mainnet header still fails if it doesn't meet its target
+        let mut unworked = genesis_block(Network::Bitcoin).header;
+        check_header(&temporary_tree(), Network::Bitcoin, 0, &unworked).unwrap();
+        unworked.nonce += 1;
+        assert!(matches!(
+            check_header(&temporary_tree(), Network::Bitcoin, 0, &unworked),
+            Err(WalletError::HeaderInvalidPow(0))
+        ));
 
-    return Psbt {
-        sent,
-        received,
-        fee: inputs_value - sent - received,
-        base64: psbt,
-        txid: CString::new(tx.txid().to_hex()).unwrap().into_raw(),
-        raw_tx: CString::new(raw_tx).unwrap().into_raw(),
-    };
-}
+        // Claiming a target easier than the network allows is no way around it
+        let mut too_easy = next;
+        too_easy.bits = 0x2100ffff;
+        while too_easy.validate_pow(&too_easy.target()).is_err() {
+            too_easy.nonce += 1;
+        }
+        assert!(matches!(
+            check_header(&db, network, 1, &too_easy),
+            Err(WalletError::HeaderInvalidPow(1))
+        ));
+    }
 
-#[no_mangle]
-pub unsafe extern "C" fn wallet_create_psbt(
-    wallet: *mut Mutex<Wallet<Tree>>,
-    send_to: *const c_char,
-    amount: u64,
-    fee_rate: f64,
-) -> Psbt {
-    let error_return = Psbt {
-        sent: 0,
-        received: 0,
-        fee: 0,
-        base64: ptr::null(),
-        txid: ptr::null(),
-        raw_tx: ptr::null(),
-    };
+    #[test]
+    fn expires_pending_labels_of_transactions_never_broadcast() {
+        let db = temporary_tree();
+        let now = 1_700_000_000;
+        let stale = format!("{}stale", PENDING_LABELS_PREFIX);
+        let fresh = format!("{}fresh", PENDING_LABELS_PREFIX);
+        insert_pending_label(&db, &stale, "rent", now - PENDING_LABELS_MAX_AGE - 1).unwrap();
+        insert_pending_label(&db, &fresh, "groceries", now - 60).unwrap();
 
-    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
-    let address = CStr::from_ptr(send_to).to_str().unwrap();
+        expire_pending_labels(&db, now).unwrap();
 
-    let send_to = unwrap_or_return!(Address::from_str(address), error_return);
+        assert!(db.get(&stale).unwrap().is_none());
+        assert_eq!(
+            &db.get(&fresh).unwrap().unwrap()[8..],
+            "groceries".as_bytes()
+        );
+    }
 
-    let mut builder = wallet.build_tx();
-    builder
-        .ordering(TxOrdering::Shuffle)
-        .only_witness_utxo()
-        .add_recipient(send_to.script_pubkey(), amount)
-        .enable_rbf()
-        .fee_rate(FeeRate::from_sat_per_vb((fee_rate * 100000.0) as f32)); // Multiplication here is to convert from BTC/vkb to sat/vb
+    // What a sync does to a transaction the server now has at `height`, 0
+    // taking it back out of a block
+    fn set_confirmation_height(wallet: &Wallet<Tree>, txid: Txid, height: u32) {
+        let mut db = wallet.database().clone();
+        let mut details = db.get_tx(&txid, true).unwrap().unwrap();
+        details.confirmation_time = if height > 0 {
+            Some(BlockTime {
+                height,
+                timestamp: 1_600_000_000 + height as u64 * 600,
+            })
+        } else {
+            None
+        };
+        db.set_tx(&details).unwrap();
+    }
 
-    match builder.finish() {
-        Ok((psbt, _)) => psbt_extract_details(&wallet, &psbt),
-        Err(e) => {
-            update_last_error(e);
-            return error_return;
+    unsafe fn reorged_txids(wallet: *mut Mutex<Wallet<Tree>>) -> Vec<String> {
+        let list = wallet_get_reorged_transactions(wallet);
+        let txids = if list.transactions.is_null() {
+            vec![]
+        } else {
+            std::slice::from_raw_parts(list.transactions, list.transactions_len as usize)
+                .iter()
+                .map(|t| CStr::from_ptr(t.txid).to_str().unwrap().to_owned())
+                .collect()
+        };
+        wallet_transaction_list_free(list);
+        txids
+    }
+
+    #[test]
+    fn flags_transactions_reorged_out_until_they_confirm_again() {
+        let wallet = test_wallet();
+        let reorged = fund(&wallet, 50_000, 100).txid;
+        let stays = fund(&wallet, 60_000, 100).txid;
+        update_transaction_tracking(&wallet).unwrap();
+        let wallet = into_handle(wallet);
+
+        unsafe {
+            assert!(reorged_txids(wallet).is_empty());
+
+            // A competing chain without `reorged` becomes the best one
+            {
+                let locked = get_wallet_mutex(wallet).lock().unwrap();
+                set_confirmation_height(&locked, reorged, 0);
+                set_confirmation_height(&locked, stays, 101);
+                update_transaction_tracking(&locked).unwrap();
+                assert_eq!(
+                    metadata_get_u32(
+                        &locked.database(),
+                        &format!("{}{}", REORGED_PREFIX, reorged)
+                    )
+                    .unwrap(),
+                    Some(100)
+                );
+            }
+            assert_eq!(reorged_txids(wallet), vec![reorged.to_string()]);
+
+            // Mined again on the new chain
+            {
+                let locked = get_wallet_mutex(wallet).lock().unwrap();
+                set_confirmation_height(&locked, reorged, 102);
+                update_transaction_tracking(&locked).unwrap();
+            }
+            assert!(reorged_txids(wallet).is_empty());
+
+            wallet_drop(wallet);
         }
     }
-}
 
-#[no_mangle]
-pub unsafe extern "C" fn wallet_decode_psbt(
-    wallet: *mut Mutex<Wallet<Tree>>,
-    psbt: *const c_char,
-) -> Psbt {
-    let error_return = Psbt {
-        sent: 0,
-        received: 0,
-        fee: 0,
-        base64: ptr::null(),
-        txid: ptr::null(),
-        raw_tx: ptr::null(),
-    };
+    #[test]
+    fn ignores_non_witness_utxos_of_other_transactions() {
+        let wallet = test_wallet();
+        let coin = fund(&wallet, 100_000, 100);
+        let tx = test_transaction(&[coin], &[(foreign_address().script_pubkey(), 90_000)]);
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
 
-    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), error_return);
-    let data = unwrap_or_return!(
-        base64::decode(CStr::from_ptr(psbt).to_str().unwrap()),
-        error_return
-    );
+        let funding = wallet.get_tx(&coin.txid, true).unwrap().unwrap();
+        psbt.inputs[0].non_witness_utxo = funding.transaction;
+        assert_eq!(psbt_input_value(&wallet, &psbt, 0), Some(100_000));
+
+        // Any transaction paying more in the same vout, the wallet knows better
+        let script = psbt_input_script(&psbt, 0).unwrap();
+        psbt.inputs[0].non_witness_utxo = Some(test_transaction(
+            &[made_up_outpoint()],
+            &[(script, 10_000_000)],
+        ));
+        assert_eq!(psbt_input_value(&wallet, &psbt, 0), Some(100_000));
+        assert_eq!(psbt_input_script(&psbt, 0), None);
+        assert_eq!(psbt_fee(&wallet, &psbt), Some(10_000));
 
-    match deserialize::<PartiallySignedTransaction>(&data) {
-        Ok(psbt) => {
-            let secp = Secp256k1::verification_only();
-            let finalized_psbt = PsbtExt::finalize(psbt, &secp).unwrap();
-            psbt_extract_details(&wallet, &finalized_psbt)
+        // Without the coin in the wallet the value stays unknown
+        let stranger = test_wallet_for_account(1);
+        assert_eq!(psbt_input_value(&stranger, &psbt, 0), None);
+        assert_eq!(psbt_fee(&stranger, &psbt), None);
+    }
+
+    #[test]
+    fn decodes_unsigned_psbts_without_a_raw_transaction() {
+        let wallet = test_wallet();
+        let coin = fund(&wallet, 100_000, 100);
+        let tx = test_transaction(&[coin], &[(foreign_address().script_pubkey(), 90_000)]);
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        let psbt = c_string(&base64::encode(serialize(&psbt)));
+        let wallet = into_handle(wallet);
+
+        unsafe {
+            let decoded = wallet_decode_psbt(wallet, psbt.as_ptr());
+            assert!(!decoded.base64.is_null());
+            assert!(decoded.raw_tx.is_null());
+            assert_eq!(decoded.fee, 10_000);
+            wallet_psbt_free(decoded);
+            wallet_drop(wallet);
         }
-        Err(e) => {
-            update_last_error(e);
-            error_return
+    }
+
+    #[test]
+    fn height_estimates_fail_on_timestamps_past_any_height() {
+        let wallet = test_wallet();
+        let genesis = genesis_block(Network::Regtest).header;
+        insert_cached_header(&wallet.database(), 0, &genesis).unwrap();
+        let wallet = into_handle(wallet);
+        let electrum = c_string("tcp://127.0.0.1:1");
+
+        unsafe {
+            let in_a_day = genesis.time as u64 + 24 * 60 * 60;
+            assert_eq!(
+                wallet_estimate_height_at_time(wallet, electrum.as_ptr(), 0, in_a_day),
+                144
+            );
+
+            assert_eq!(
+                wallet_estimate_height_at_time(wallet, electrum.as_ptr(), 0, u64::MAX),
+                0
+            );
+            let error = take_last_error().unwrap();
+            assert!(matches!(
+                error.downcast_ref::<WalletError>(),
+                Some(WalletError::TimestampOutOfRange(u64::MAX))
+            ));
+
+            wallet_drop(wallet);
         }
     }
-}
 
-#[no_mangle]
-pub unsafe extern "C" fn wallet_broadcast_tx(
-    electrum_address: *const c_char,
-    tor_port: i32,
-    tx: *const c_char,
-) -> *const c_char {
-    let error_return = CString::new("").unwrap().into_raw();
+    #[test]
+    fn only_caches_headers_next_to_the_cached_chain() {
+        let db = temporary_tree();
+        let genesis = genesis_block(Network::Regtest).header;
+        insert_cached_header(&db, 0, &genesis).unwrap();
 
-    let electrum_address =
-        unwrap_or_return!(CStr::from_ptr(electrum_address).to_str(), error_return);
-    let client = unwrap_or_return!(
-        get_electrum_client(tor_port, electrum_address),
-        error_return
-    );
+        let next = mine_regtest_header(genesis.block_hash());
+        assert!(cache_connected_header(&db, 1, &next).unwrap());
 
-    let hex_tx = unwrap_or_return!(CStr::from_ptr(tx).to_str(), error_return);
-    let raw_tx = unwrap_or_return!(hex::decode(hex_tx), error_return);
+        // Checked on its own but nothing cached around it to link up with
+        let far = mine_regtest_header(BlockHash::hash(b"somewhere back"));
+        assert!(!cache_connected_header(&db, 50, &far).unwrap());
+        assert!(get_cached_header(&db, 50).unwrap().is_none());
+        assert_eq!(cached_header_range(&db).unwrap(), Some((0, 1)));
+    }
 
-    let tx: bdk::bitcoin::Transaction = unwrap_or_return!(deserialize(&*raw_tx), error_return);
-    let txid = unwrap_or_return!(client.transaction_broadcast(&tx), error_return);
+    #[test]
+    fn fee_caps_fail_when_the_fee_is_unknown() {
+        let wallet = test_wallet();
+        let coin = fund(&wallet, 100_000, 100);
+        let tx = test_transaction(&[coin], &[(foreign_address().script_pubkey(), 90_000)]);
+        let psbt = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
 
-    unwrap_or_return!(CString::new(txid.to_string()), error_return).into_raw()
-}
+        // The wallet knows its coin even without a witness_utxo
+        assert!(check_fee_cap(&wallet, &psbt, 20_000, 0.0).is_ok());
+        assert!(matches!(
+            check_fee_cap(&wallet, &psbt, 5_000, 0.0),
+            Err(WalletError::FeeAboveCap { fee: 10_000, .. })
+        ));
 
-#[no_mangle]
-pub unsafe extern "C" fn wallet_validate_address(
-    wallet: *mut Mutex<Wallet<Tree>>,
-    address: *const c_char,
-) -> bool {
-    let wallet = unwrap_or_return!(get_wallet_mutex(wallet).lock(), false);
+        let stranger = test_wallet_for_account(1);
+        assert!(check_fee_cap(&stranger, &psbt, 0, 0.0).is_ok());
+        assert!(matches!(
+            check_fee_cap(&stranger, &psbt, 20_000, 0.0),
+            Err(WalletError::FeeUnknownForCap)
+        ));
+    }
 
-    match Address::from_str(CStr::from_ptr(address).to_str().unwrap()) {
-        Ok(a) => wallet.network() == a.network, // Only valid if it's on same network
-        Err(_) => false,
+    #[test]
+    fn clamps_sync_stop_gap() {
+        let address = "ssl://electrum.example.com:50002";
+        let stop_gap = |requested| get_electrum_blockchain_config(0, address, requested).stop_gap;
+
+        assert_eq!(stop_gap(1), MIN_SYNC_STOP_GAP as usize);
+        assert_eq!(
+            stop_gap(MIN_SYNC_STOP_GAP + 1),
+            MIN_SYNC_STOP_GAP as usize + 1
+        );
+        assert_eq!(stop_gap(u32::MAX), MAX_SYNC_STOP_GAP as usize);
+        assert_eq!(
+            get_electrum_blockchain_config(9050, address, u32::MAX).stop_gap,
+            MAX_SYNC_STOP_GAP as usize
+        );
     }
-}
 
-// Due to its simple signature this function is the one added (unused) to iOS swift codebase to force Xcode to link the lib
-#[no_mangle]
-pub unsafe extern "C" fn wallet_hello() {
-    println!("Hello wallet");
+    #[test]
+    fn picks_sync_stop_gap_for_the_connection() {
+        let defaults = WalletDefaults {
+            sync_stop_gap: 60,
+            sync_stop_gap_tor: 120,
+            ..Default::default()
+        };
+
+        assert_eq!(sync_stop_gap(&defaults, 0, 0), 60);
+        assert_eq!(sync_stop_gap(&defaults, 9050, 0), 120);
+        assert_eq!(sync_stop_gap(&defaults, 0, 80), 80);
+        assert_eq!(sync_stop_gap(&defaults, 9050, 80), 80);
+    }
 }